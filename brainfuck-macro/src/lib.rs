@@ -0,0 +1,152 @@
+//! `bf!`, which compiles a Brainfuck program to Rust as the crate using it
+//! is built, through the IR and passes of `brainfuck`:
+//!
+//! ```
+//! use brainfuck_macro::bf;
+//!
+//! let out: Vec<u8> = bf!(",[.[-],]", input = b"hi");
+//! assert_eq!(out, b"hi");
+//! ```
+//!
+//! The program is a string literal and `input`, which defaults to no
+//! input, is any expression that coerces to `&[u8]`. The macro expands to
+//! the bytes the program writes, running it as `--emit rust --io bytes`
+//! would with the default options otherwise, so only the eight commands of
+//! Brainfuck are read and `:`, `;` and `#` are comments. A program that
+//! does not parse, such as one with unbalanced brackets, fails to compile
+//! with the error at the literal.
+
+use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+
+use brainfuck::interpreter::Io;
+use brainfuck::jasmin::Config;
+use brainfuck::optimizer::{self, Level};
+use brainfuck::{constants, evaluate, lex_bytes, parse_program, rust, Commands, Dialect};
+
+/// The function the program becomes, local to the expansion.
+const FUNCTION: &str = "brainfuck";
+
+#[proc_macro]
+pub fn bf(input: TokenStream) -> TokenStream {
+  match expand(input) {
+    Ok(expanded) => expanded,
+    Err((message, span)) => compile_error(&message, span),
+  }
+}
+
+fn expand(input: TokenStream) -> Result<TokenStream, (String, Span)> {
+  let mut tokens = input.into_iter();
+  let literal = match tokens.next() {
+    Some(TokenTree::Literal(literal)) => literal,
+    Some(other) => return Err(("expected the program as a string".to_string(), other.span())),
+    None => return Err(("expected the program".to_string(), Span::call_site())),
+  };
+  let span = literal.span();
+  let code = unquote(&literal.to_string())
+    .ok_or_else(|| ("expected the program as a string".to_string(), span))?;
+  let input = arguments(tokens.collect())?;
+  let program = lex_bytes(code.as_bytes(), Dialect::Brainfuck, Commands::default())
+    .and_then(parse_program)
+    .map_err(|error| (error, span))?;
+  let (instructions, _) = optimizer::optimize(
+    program,
+    &optimizer::preset(Level::O2, true),
+    evaluate::DEFAULT_BUDGET,
+    constants::DEFAULT_UNROLL_LIMIT,
+  );
+  let config = Config {
+    io: Io::Bytes,
+    ..Config::default()
+  };
+  let function =
+    rust::produce_function(&instructions, &config, FUNCTION).map_err(|error| (error, span))?;
+  let mut block: TokenStream = function
+    .parse()
+    .map_err(|_| ("the generated code does not parse".to_string(), span))?;
+  block.extend([
+    TokenTree::Ident(Ident::new(FUNCTION, Span::call_site())),
+    TokenTree::Group(Group::new(Delimiter::Parenthesis, input)),
+  ]);
+  Ok(TokenStream::from(TokenTree::Group(Group::new(
+    Delimiter::Brace,
+    block,
+  ))))
+}
+
+/// The input expression of the tokens after the program: nothing, or a
+/// comma and `input = <expression>`, either with a trailing comma.
+fn arguments(mut tokens: Vec<TokenTree>) -> Result<TokenStream, (String, Span)> {
+  let comma =
+    |token: &TokenTree| matches!(token, TokenTree::Punct(punct) if punct.as_char() == ',');
+  if tokens.last().is_some_and(comma) {
+    tokens.pop();
+  }
+  if tokens.is_empty() {
+    return Ok("b\"\"".parse().unwrap());
+  }
+  match &tokens[..] {
+    [separator, TokenTree::Ident(name), TokenTree::Punct(equals), expression @ ..]
+      if comma(separator)
+        && name.to_string() == "input"
+        && equals.as_char() == '='
+        && !expression.is_empty() =>
+    {
+      Ok(expression.iter().cloned().collect())
+    }
+    _ => Err(("expected `input = <bytes>`".to_string(), tokens[0].span())),
+  }
+}
+
+/// The text of a string literal as written in the source.
+fn unquote(literal: &str) -> Option<String> {
+  if let Some(raw) = literal.strip_prefix('r') {
+    let hashes = raw.len() - raw.trim_start_matches('#').len();
+    return raw
+      .get(hashes + 1..raw.len().checked_sub(hashes + 1)?)
+      .map(str::to_string);
+  }
+  let body = literal.strip_prefix('"')?.strip_suffix('"')?;
+  let mut text = String::new();
+  let mut chars = body.chars().peekable();
+  while let Some(c) = chars.next() {
+    if c != '\\' {
+      text.push(c);
+      continue;
+    }
+    match chars.next()? {
+      'n' => text.push('\n'),
+      't' => text.push('\t'),
+      'r' => text.push('\r'),
+      '0' => text.push('\0'),
+      'x' => {
+        let digits: String = chars.by_ref().take(2).collect();
+        text.push(u8::from_str_radix(&digits, 16).ok()? as char);
+      }
+      'u' => {
+        let digits: String = chars.by_ref().skip(1).take_while(|&c| c != '}').collect();
+        text.push(char::from_u32(u32::from_str_radix(&digits, 16).ok()?)?);
+      }
+      // A backslash ending a line skips the whitespace starting the next.
+      '\n' => while chars.next_if(|c| c.is_whitespace()).is_some() {},
+      escaped => text.push(escaped),
+    }
+  }
+  Some(text)
+}
+
+/// `compile_error!(message)` at `span`.
+fn compile_error(message: &str, span: Span) -> TokenStream {
+  let mut bang = Punct::new('!', Spacing::Alone);
+  bang.set_span(span);
+  let mut text = Literal::string(message);
+  text.set_span(span);
+  let mut arguments = Group::new(Delimiter::Parenthesis, TokenTree::Literal(text).into());
+  arguments.set_span(span);
+  vec![
+    TokenTree::Ident(Ident::new("compile_error", span)),
+    TokenTree::Punct(bang),
+    TokenTree::Group(arguments),
+  ]
+  .into_iter()
+  .collect()
+}
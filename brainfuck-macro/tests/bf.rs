@@ -0,0 +1,11 @@
+use brainfuck_macro::bf;
+
+#[test]
+fn comments_may_hold_any_character() {
+  assert_eq!(bf!("+. note: this; not a # dump"), [1]);
+}
+
+#[test]
+fn bytes_above_127_print_as_they_are() {
+  assert_eq!(bf!("-.>++++++++[<---------------->-]<."), [255, 127]);
+}
@@ -0,0 +1,382 @@
+//! AArch64 assembly for Linux (as on a Raspberry Pi) or macOS on Apple
+//! Silicon, which differ only in symbol names, sections and how a symbol's
+//! address is formed. The pointer lives in the callee-saved `x19`; `w9` to
+//! `w12` are scratch, and `x13` to `x15` hold the bounds checks.
+
+use super::interpreter::{Bounds, Eof, ExitCell};
+use super::jasmin::Config;
+use super::native::{self, Step, Target};
+use super::Text;
+
+/// The platform whose assembler conventions the output follows.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Os {
+  Linux,
+  MacOs,
+}
+
+impl Os {
+  pub fn parse(text: &str) -> Result<Os, String> {
+    match text {
+      "linux" => Ok(Os::Linux),
+      "macos" => Ok(Os::MacOs),
+      other => Err(format!("unknown target OS {}", other)),
+    }
+  }
+
+  /// The platform the compiler itself runs on, or Linux elsewhere.
+  pub fn host() -> Os {
+    if cfg!(target_os = "macos") {
+      Os::MacOs
+    } else {
+      Os::Linux
+    }
+  }
+}
+
+pub struct AArch64 {
+  pub os: Os,
+}
+
+impl AArch64 {
+  fn emit(out: &mut Vec<String>, line: String) {
+    out.push(format!("  {}", line));
+  }
+
+  /// The name of the C-level symbol `name`.
+  fn symbol(&self, name: &str) -> String {
+    match self.os {
+      Os::Linux => name.to_string(),
+      Os::MacOs => format!("_{}", name),
+    }
+  }
+
+  /// A label local to the file, which the assemblers spell differently.
+  fn label(&self, name: &str, number: usize) -> String {
+    match self.os {
+      Os::Linux => format!(".L{}{}", name, number),
+      Os::MacOs => format!("L{}{}", name, number),
+    }
+  }
+
+  /// Puts the address of `symbol` in `register`.
+  fn address_of(&self, out: &mut Vec<String>, register: &str, symbol: &str) {
+    match self.os {
+      Os::Linux => {
+        AArch64::emit(out, format!("adrp {}, {}", register, symbol));
+        AArch64::emit(
+          out,
+          format!("add {}, {}, :lo12:{}", register, register, symbol),
+        );
+      }
+      Os::MacOs => {
+        AArch64::emit(out, format!("adrp {}, {}@PAGE", register, symbol));
+        AArch64::emit(
+          out,
+          format!("add {}, {}, {}@PAGEOFF", register, register, symbol),
+        );
+      }
+    }
+  }
+
+  /// Moves any constant into `register`, a `w` or `x` register, with a
+  /// `movz` of the low half-word and a `movk` for each other nonzero one.
+  fn constant(out: &mut Vec<String>, register: &str, value: i64) {
+    if (-65536..=65535).contains(&value) {
+      AArch64::emit(out, format!("mov {}, #{}", register, value));
+      return;
+    }
+    let (bits, halves) = if register.starts_with('w') {
+      (value as u32 as u64, 2)
+    } else {
+      (value as u64, 4)
+    };
+    AArch64::emit(out, format!("movz {}, #{}", register, bits & 0xffff));
+    for half in 1..halves {
+      let part = (bits >> (16 * half)) & 0xffff;
+      if part != 0 {
+        AArch64::emit(
+          out,
+          format!("movk {}, #{}, lsl #{}", register, part, 16 * half),
+        );
+      }
+    }
+  }
+
+  /// `target = source + value`, through `scratch` when `value` does not fit
+  /// an immediate.
+  fn add_constant(out: &mut Vec<String>, target: &str, source: &str, value: i64, scratch: &str) {
+    if (0..=4095).contains(&value) {
+      AArch64::emit(out, format!("add {}, {}, #{}", target, source, value));
+    } else if (-4095..0).contains(&value) {
+      AArch64::emit(out, format!("sub {}, {}, #{}", target, source, -value));
+    } else {
+      AArch64::constant(out, scratch, value);
+      AArch64::emit(out, format!("add {}, {}, {}", target, source, scratch));
+    }
+  }
+
+  /// The memory operand of the cell at `offset`, forming its address in
+  /// `x12` when the offset does not fit the load and store encodings.
+  fn cell(out: &mut Vec<String>, offset: isize, config: &Config) -> String {
+    let width = native::cell_width(config);
+    let bytes = offset * width;
+    if bytes == 0 {
+      "[x19]".to_string()
+    } else if (0..=4095 * width).contains(&bytes) {
+      format!("[x19, #{}]", bytes)
+    } else {
+      AArch64::add_constant(out, "x12", "x19", bytes as i64, "x12");
+      "[x12]".to_string()
+    }
+  }
+
+  fn load(out: &mut Vec<String>, register: &str, offset: isize, config: &Config) -> String {
+    let cell = AArch64::cell(out, offset, config);
+    let op = if config.wrap { "ldrb" } else { "ldr" };
+    AArch64::emit(out, format!("{} {}, {}", op, register, cell));
+    cell
+  }
+
+  /// Stores `register` to `cell`, a memory operand from `cell`.
+  fn store(out: &mut Vec<String>, register: &str, cell: &str, config: &Config) {
+    let op = if config.wrap { "strb" } else { "str" };
+    AArch64::emit(out, format!("{} {}, {}", op, register, cell));
+  }
+
+  fn call(&self, out: &mut Vec<String>, function: &str) {
+    AArch64::emit(out, format!("bl {}", self.symbol(function)));
+  }
+
+  /// Branches to `off_tape` unless the cell at `offset` lies within the
+  /// tape.
+  fn check(&self, out: &mut Vec<String>, offset: isize, config: &Config) {
+    let width = native::cell_width(config);
+    let off_tape = self.label("off_tape", 0);
+    AArch64::add_constant(out, "x13", "x19", (offset * width) as i64, "x15");
+    self.address_of(out, "x14", &self.symbol("tape"));
+    AArch64::emit(out, "cmp x13, x14".to_string());
+    AArch64::emit(out, format!("b.lo {}", off_tape));
+    AArch64::add_constant(
+      out,
+      "x14",
+      "x14",
+      config.tape_size as i64 * width as i64,
+      "x15",
+    );
+    AArch64::emit(out, "cmp x13, x14".to_string());
+    AArch64::emit(out, format!("b.hs {}", off_tape));
+  }
+
+  /// `off_tape`, which writes `native::OFF_TAPE` to standard error and
+  /// exits with status 1, flushing what `putchar` buffered.
+  fn off_tape(&self, out: &mut Vec<String>) {
+    out.push(format!("{}:", self.label("off_tape", 0)));
+    AArch64::emit(out, "mov w0, #2".to_string());
+    self.address_of(out, "x1", &self.label("off_tape_message", 0));
+    AArch64::emit(out, format!("mov x2, #{}", native::OFF_TAPE.len()));
+    self.call(out, "write");
+    AArch64::emit(out, "mov w0, #1".to_string());
+    self.call(out, "exit");
+  }
+
+  /// `print(text, length)`, writing `length` bytes through `putchar`.
+  fn print_helper(&self, out: &mut Vec<String>) {
+    let (test, done) = (self.label("print", 0), self.label("printed", 0));
+    out.push("  .p2align 2".to_string());
+    out.push("print:".to_string());
+    AArch64::emit(out, "stp x29, x30, [sp, #-32]!".to_string());
+    AArch64::emit(out, "mov x29, sp".to_string());
+    AArch64::emit(out, "stp x20, x21, [sp, #16]".to_string());
+    AArch64::emit(out, "mov x20, x0".to_string());
+    AArch64::emit(out, "mov x21, x1".to_string());
+    out.push(format!("{}:", test));
+    AArch64::emit(out, format!("cbz x21, {}", done));
+    AArch64::emit(out, "ldrb w0, [x20], #1".to_string());
+    self.call(out, "putchar");
+    AArch64::emit(out, "sub x21, x21, #1".to_string());
+    AArch64::emit(out, format!("b {}", test));
+    out.push(format!("{}:", done));
+    AArch64::emit(out, "ldp x20, x21, [sp, #16]".to_string());
+    AArch64::emit(out, "ldp x29, x30, [sp], #32".to_string());
+    AArch64::emit(out, "ret".to_string());
+  }
+}
+
+impl Target for AArch64 {
+  fn prologue(&self, out: &mut Vec<String>, config: &Config) {
+    if let Some(debug) = &config.debug {
+      out.push(format!("  .file \"{}\"", debug.file));
+    }
+    let main = self.symbol("main");
+    out.push("  .text".to_string());
+    out.push(format!("  .globl {}", main));
+    if self.os == Os::Linux {
+      out.push(format!("  .type {}, %function", main));
+    }
+    out.push("  .p2align 2".to_string());
+    out.push(format!("{}:", main));
+    AArch64::emit(out, "stp x29, x30, [sp, #-32]!".to_string());
+    AArch64::emit(out, "mov x29, sp".to_string());
+    AArch64::emit(out, "str x19, [sp, #16]".to_string());
+    self.address_of(out, "x19", &self.symbol("tape"));
+  }
+
+  fn step(&self, out: &mut Vec<String>, step: &Step, config: &Config) {
+    let width = native::cell_width(config) as i64;
+    match step {
+      Step::Add { offset, amount } => {
+        let cell = AArch64::load(out, "w9", *offset, config);
+        AArch64::add_constant(out, "w9", "w9", *amount as i64, "w10");
+        AArch64::store(out, "w9", &cell, config);
+      }
+      Step::Set { offset, value } => {
+        let cell = AArch64::cell(out, *offset, config);
+        if *value == 0 {
+          AArch64::store(out, "wzr", &cell, config);
+        } else {
+          AArch64::constant(out, "w9", *value as i64);
+          AArch64::store(out, "w9", &cell, config);
+        }
+      }
+      Step::Move(cells) => AArch64::add_constant(out, "x19", "x19", *cells as i64 * width, "x10"),
+      Step::Check { offset } => self.check(out, *offset, config),
+      Step::Put => {
+        AArch64::load(out, "w0", 0, config);
+        self.call(out, "putchar");
+      }
+      Step::PutUtf8 { label } => {
+        // `x19` survives the first call, so the trail byte reloads the cell.
+        let done = self.label("put", *label);
+        AArch64::load(out, "w0", 0, config);
+        if !config.wrap {
+          AArch64::emit(out, "and w0, w0, #255".to_string());
+        }
+        AArch64::emit(out, "cmp w0, #128".to_string());
+        AArch64::emit(out, format!("b.lo {}", done));
+        AArch64::emit(out, "lsr w0, w0, #6".to_string());
+        AArch64::emit(out, "orr w0, w0, #192".to_string());
+        self.call(out, "putchar");
+        AArch64::load(out, "w0", 0, config);
+        AArch64::emit(out, "and w0, w0, #63".to_string());
+        AArch64::emit(out, "orr w0, w0, #128".to_string());
+        out.push(format!("{}:", done));
+        self.call(out, "putchar");
+      }
+      Step::PutConst(value) => {
+        AArch64::emit(out, format!("mov w0, #{}", value));
+        self.call(out, "putchar");
+      }
+      Step::Read { label } => {
+        self.call(out, "getchar");
+        match config.eof {
+          Eof::MinusOne => AArch64::store(out, "w0", "[x19]", config),
+          Eof::Zero => {
+            AArch64::emit(out, "cmp w0, #0".to_string());
+            AArch64::emit(out, "csel w0, wzr, w0, lt".to_string());
+            AArch64::store(out, "w0", "[x19]", config);
+          }
+          Eof::Unchanged => {
+            let done = self.label("read", *label);
+            AArch64::emit(out, format!("tbnz w0, #31, {}", done));
+            AArch64::store(out, "w0", "[x19]", config);
+            out.push(format!("{}:", done));
+          }
+        }
+      }
+      Step::LoopStart { label } => {
+        out.push(format!("{}:", self.label("loop", *label)));
+        AArch64::load(out, "w9", 0, config);
+        AArch64::emit(out, format!("cbz w9, {}", self.label("end", *label)));
+      }
+      Step::LoopEnd { label } => {
+        AArch64::emit(out, format!("b {}", self.label("loop", *label)));
+        out.push(format!("{}:", self.label("end", *label)));
+      }
+      Step::Multiply { label, targets } => {
+        let done = self.label("multiplied", *label);
+        AArch64::load(out, "w9", 0, config);
+        AArch64::emit(out, format!("cbz w9, {}", done));
+        for (offset, factor) in targets {
+          if config.bounds == Bounds::Error {
+            self.check(out, *offset, config);
+          }
+          AArch64::constant(out, "w10", *factor as i64);
+          let cell = AArch64::load(out, "w11", *offset, config);
+          AArch64::emit(out, "madd w11, w9, w10, w11".to_string());
+          AArch64::store(out, "w11", &cell, config);
+        }
+        out.push(format!("{}:", done));
+      }
+      Step::ScanZero { label, stride } => {
+        let (test, done) = (self.label("scan", *label), self.label("scanned", *label));
+        out.push(format!("{}:", test));
+        AArch64::load(out, "w9", 0, config);
+        AArch64::emit(out, format!("cbz w9, {}", done));
+        AArch64::add_constant(out, "x19", "x19", *stride as i64 * width, "x10");
+        if config.bounds == Bounds::Error {
+          self.check(out, 0, config);
+        }
+        AArch64::emit(out, format!("b {}", test));
+        out.push(format!("{}:", done));
+      }
+      Step::Print { text, length } => {
+        self.address_of(out, "x0", &self.label("text", *text));
+        AArch64::constant(out, "x1", *length as i64);
+        AArch64::emit(out, "bl print".to_string());
+      }
+    }
+  }
+
+  fn epilogue(&self, out: &mut Vec<String>, config: &Config) {
+    match config.exit_cell {
+      None => AArch64::emit(out, "mov w0, #0".to_string()),
+      Some(ExitCell::Current) => {
+        AArch64::load(out, "w0", 0, config);
+      }
+      Some(ExitCell::First) => {
+        self.address_of(out, "x19", &self.symbol("tape"));
+        AArch64::load(out, "w0", 0, config);
+      }
+    }
+    AArch64::emit(out, "ldr x19, [sp, #16]".to_string());
+    AArch64::emit(out, "ldp x29, x30, [sp], #32".to_string());
+    AArch64::emit(out, "ret".to_string());
+  }
+
+  fn data(&self, out: &mut Vec<String>, texts: &[Text], config: &Config) {
+    let checked = config.bounds == Bounds::Error;
+    if !texts.is_empty() {
+      self.print_helper(out);
+    }
+    if checked {
+      self.off_tape(out);
+    }
+    if !texts.is_empty() || checked {
+      out.push(match self.os {
+        Os::Linux => "  .section .rodata".to_string(),
+        Os::MacOs => "  .section __TEXT,__const".to_string(),
+      });
+    }
+    for (index, text) in texts.iter().enumerate() {
+      out.push(format!("{}:", self.label("text", index)));
+      out.push(format!("  .ascii \"{}\"", native::quote(text.as_bytes())));
+    }
+    if checked {
+      out.push(format!("{}:", self.label("off_tape_message", 0)));
+      out.push(format!(
+        "  .ascii \"{}\"",
+        native::quote(native::OFF_TAPE.as_bytes())
+      ));
+    }
+    let bytes = config.tape_size as isize * native::cell_width(config);
+    match self.os {
+      Os::Linux => {
+        out.push("  .bss".to_string());
+        out.push(format!("  .lcomm tape, {}", bytes));
+        out.push("  .section .note.GNU-stack,\"\",%progbits".to_string());
+      }
+      Os::MacOs => out.push(format!("  .zerofill __DATA,__bss,_tape,{},4", bytes)),
+    }
+  }
+}
@@ -0,0 +1,264 @@
+//! The checks behind `analyze`, which look for likely mistakes without
+//! running the program. Each warning carries a code that `--allow` can
+//! silence.
+//!
+//! Brackets are matched first, since nothing else can be checked until
+//! they are. Loops are then reported when they can never run, being
+//! entered on a cell known to be zero, or can never end once entered,
+//! their body leaving the current cell alone. Finally the pointer is
+//! followed through every loop whose iterations leave it where they found
+//! it; the range it covers is compared with the tape, and the first loop
+//! that moves it on each iteration makes the range unbounded.
+
+use super::optimizer;
+use super::{parse_program, Inst, Op, Tape, Token};
+
+/// Every code `--allow` accepts, with what it flags.
+pub const CODES: &[(&str, &str)] = &[
+  ("W001", "a bracket without its partner"),
+  ("W002", "a loop that never runs"),
+  ("W003", "a loop that never ends once entered"),
+  ("W004", "a move off the tape"),
+  ("W005", "a pointer with no bound"),
+];
+
+/// Parses a comma-separated code list such as `W002,W005`.
+pub fn parse_codes(text: &str) -> Result<Vec<&'static str>, String> {
+  text
+    .split(',')
+    .filter(|code| !code.trim().is_empty())
+    .map(|code| {
+      let code = code.trim();
+      CODES
+        .iter()
+        .map(|&(known, _)| known)
+        .find(|&known| known.eq_ignore_ascii_case(code))
+        .ok_or_else(|| {
+          let known: Vec<&str> = CODES.iter().map(|&(known, _)| known).collect();
+          format!("unknown code {} (known: {})", code, known.join(", "))
+        })
+    })
+    .collect()
+}
+
+#[derive(Debug)]
+pub struct Warning {
+  pub code: &'static str,
+  /// Position of the command it is about.
+  pub pos: usize,
+  pub message: String,
+}
+
+/// What `analyze` found.
+#[derive(Debug)]
+pub struct Report {
+  pub warnings: Vec<Warning>,
+  /// The lowest and highest cells the pointer can reach, when they are
+  /// bounded.
+  pub range: Option<(isize, isize)>,
+}
+
+/// Brackets of `tokens` that do not pair up, loops and procedures alike.
+pub fn brackets(tokens: &[(Token, usize)]) -> Vec<Warning> {
+  let mut warnings = Vec::new();
+  let mut open: Vec<(char, usize)> = Vec::new();
+  for &(token, pos) in tokens {
+    let c = token.symbol();
+    match token {
+      Token::JumpIfZero | Token::ProcStart => open.push((c, pos)),
+      Token::JumpIfNonZero | Token::ProcEnd => {
+        let expected = if token == Token::JumpIfNonZero {
+          '['
+        } else {
+          '('
+        };
+        match open.pop() {
+          Some((opener, _)) if opener == expected => (),
+          Some((opener, at)) => warnings.push(Warning {
+            code: "W001",
+            pos,
+            message: format!("{} closes the {} at byte {}", c, opener, at),
+          }),
+          None => warnings.push(Warning {
+            code: "W001",
+            pos,
+            message: format!("{} has no {} to close", c, expected),
+          }),
+        }
+      }
+      _ => (),
+    }
+  }
+  warnings.extend(open.into_iter().map(|(c, pos)| Warning {
+    code: "W001",
+    pos,
+    message: format!("{} is never closed", c),
+  }));
+  warnings
+}
+
+/// Loops of `program`, folded into offset operations, whose body touches
+/// neither the current cell nor the pointer.
+fn endless_loops(program: &[Inst]) -> Vec<Warning> {
+  let mut warnings = Vec::new();
+  for (index, inst) in program.iter().enumerate() {
+    let Op::JumpIfZero(end) = inst.op else {
+      continue;
+    };
+    let still = program[index + 1..end as usize]
+      .iter()
+      .all(|inst| match inst.op {
+        Op::Add { offset, .. } | Op::Set { offset, .. } => offset != 0,
+        Op::PutChar(_) | Op::PutConst { .. } | Op::Print(_) | Op::Debug | Op::PutNumber => true,
+        _ => false,
+      });
+    if still {
+      warnings.push(Warning {
+        code: "W003",
+        pos: inst.span.start as usize,
+        message: "loop never ends once entered, its body leaving the current cell alone"
+          .to_string(),
+      });
+    }
+  }
+  warnings
+}
+
+/// Where the pointer can go over a tape of `tape_size` cells.
+struct Walk {
+  tape_size: usize,
+  pos: isize,
+  lo: isize,
+  hi: isize,
+  /// The first move off the tape.
+  off: Option<Warning>,
+}
+
+impl Walk {
+  fn visit(&mut self, inst: &Inst, offset: isize) {
+    let cell = self.pos + offset;
+    self.lo = self.lo.min(cell);
+    self.hi = self.hi.max(cell);
+    if self.off.is_none() && (cell < 0 || cell >= self.tape_size as isize) {
+      self.off = Some(Warning {
+        code: "W004",
+        pos: inst.span.start as usize,
+        message: format!(
+          "the pointer reaches cell {}, off a tape of {} cells",
+          cell, self.tape_size
+        ),
+      });
+    }
+  }
+
+  /// Follows the pointer through `program[start..end]`, failing with the
+  /// first loop, call or fork after which it could be anywhere.
+  fn run(&mut self, program: &[Inst], start: usize, end: usize) -> Result<(), Warning> {
+    let mut index = start;
+    while index < end {
+      let inst = &program[index];
+      match inst.op {
+        Op::Right(count) => {
+          self.pos += count as isize;
+          self.visit(inst, 0);
+        }
+        Op::Left(count) => {
+          self.pos -= count as isize;
+          self.visit(inst, 0);
+        }
+        Op::AddTo { offset, .. } | Op::Add { offset, .. } | Op::Set { offset, .. } => {
+          self.visit(inst, offset as isize)
+        }
+        Op::JumpIfZero(close) => {
+          let entry = self.pos;
+          self.run(program, index + 1, close as usize)?;
+          if self.pos != entry {
+            return Err(Warning {
+              code: "W005",
+              pos: inst.span.start as usize,
+              message: format!(
+                "loop moves the pointer by {} on each iteration, so it has no bound",
+                self.pos - entry
+              ),
+            });
+          }
+          index = close as usize;
+        }
+        // Bodies are followed from their calls, which are not.
+        Op::Procedure(ret) => index = ret as usize,
+        Op::ScanZero { .. } | Op::Call | Op::Fork | Op::Tape(Tape::Next) => {
+          return Err(Warning {
+            code: "W005",
+            pos: inst.span.start as usize,
+            message: "the pointer could be anywhere after this, so it has no bound".to_string(),
+          })
+        }
+        _ => (),
+      }
+      index += 1;
+    }
+    Ok(())
+  }
+}
+
+/// The lowest and highest cells the pointer reaches in `program`, when
+/// they are bounded.
+pub fn pointer_range(program: &[Inst]) -> Option<(isize, isize)> {
+  let mut walk = Walk {
+    tape_size: isize::MAX as usize,
+    pos: 0,
+    lo: 0,
+    hi: 0,
+    off: None,
+  };
+  walk.run(program, 0, program.len()).ok()?;
+  Some((walk.lo, walk.hi))
+}
+
+/// Checks the commands `tokens` of a program run on a tape of `tape_size`
+/// cells.
+pub fn analyze(tokens: &[(Token, usize)], tape_size: usize) -> Result<Report, String> {
+  let warnings = brackets(tokens);
+  if !warnings.is_empty() {
+    return Ok(Report {
+      warnings,
+      range: None,
+    });
+  }
+  let program = parse_program(tokens.to_vec())?;
+  let (_, stats) = optimizer::optimize(program.clone(), &["dce"], 0, 0);
+  let mut warnings: Vec<Warning> = stats
+    .notes
+    .iter()
+    .map(|note| Warning {
+      code: "W002",
+      pos: note.span.start as usize,
+      message: "loop never runs, being entered on a cell known to be zero".to_string(),
+    })
+    .collect();
+  let (folded, _) = optimizer::optimize(program.clone(), &["offset"], 0, 0);
+  for warning in endless_loops(&folded) {
+    // A loop that never runs cannot run forever.
+    if !warnings.iter().any(|dead| dead.pos == warning.pos) {
+      warnings.push(warning);
+    }
+  }
+  let mut walk = Walk {
+    tape_size,
+    pos: 0,
+    lo: 0,
+    hi: 0,
+    off: None,
+  };
+  let bounded = walk.run(&program, 0, program.len());
+  warnings.extend(walk.off);
+  let range = match bounded {
+    Ok(()) => Some((walk.lo, walk.hi)),
+    Err(warning) => {
+      warnings.push(warning);
+      None
+    }
+  };
+  warnings.sort_by_key(|warning| warning.pos);
+  Ok(Report { warnings, range })
+}
@@ -0,0 +1,459 @@
+//! The code generators behind one interface, looked up by the name `--emit`
+//! takes. A new target is an implementation of `Backend` and an entry in
+//! `REGISTRY`; `main` only ever sees the trait.
+
+use std::io::{self, ErrorKind, Write};
+use std::path::Path;
+
+#[cfg(feature = "exe")]
+use super::exe;
+use super::{
+  aarch64, bf, c, cache, classfile, dex, jar, jasmin, java, js, krakatau, llvm, native, optimizer,
+  profile, pseudo, python, riscv64, rust, wasm, x86_64, Inst,
+};
+
+/// Everything besides the IR that some backend reads.
+pub struct Options<'a> {
+  /// The source file, which names `.jar` output.
+  pub filename: &'a str,
+  /// The directory output goes into, or empty for the current one.
+  pub dir: &'a str,
+  pub config: &'a jasmin::Config,
+  pub class_version: u16,
+  pub d8: &'a str,
+  /// The C compiler `--emit exe` links with.
+  pub linker: &'a str,
+  pub dialect: krakatau::Dialect,
+  pub syntax: x86_64::Syntax,
+  pub os: aarch64::Os,
+  pub riscv_io: riscv64::Io,
+  pub js_module: bool,
+  /// The facts the optimizer proved, which `--emit ir` lists.
+  pub notes: &'a [optimizer::Note],
+  pub profile: Option<&'a profile::Profile>,
+}
+
+pub trait Backend: Sync {
+  /// The file the output goes to, or `None` for standard output.
+  fn path(&self, opts: &Options) -> Option<String>;
+  fn emit(&self, ir: &[Inst], opts: &Options, out: &mut dyn Write) -> io::Result<()>;
+  /// Runs once the output is in `path`, returning what to tell the user.
+  fn finish(&self, path: &str, _opts: &Options) -> io::Result<String> {
+    Ok(format!("Compiled code to {}", path))
+  }
+}
+
+fn invalid(message: String) -> io::Error {
+  io::Error::new(ErrorKind::InvalidInput, message)
+}
+
+/// Writes generated text, or fails with the generator's error.
+fn write_text(out: &mut dyn Write, code: Result<String, String>) -> io::Result<()> {
+  out.write_all(code.map_err(invalid)?.as_bytes())
+}
+
+/// Assembles the generated Jasmin into a class, returning its name too.
+fn class(ir: &[Inst], opts: &Options) -> io::Result<(String, Vec<u8>)> {
+  let code = jasmin::produce_code(ir.to_vec(), opts.config).map_err(invalid)?;
+  classfile::assemble(&code, opts.class_version).map_err(invalid)
+}
+
+/// `name` inside the directory output goes into.
+pub fn in_dir(opts: &Options, name: &str) -> String {
+  if opts.dir.is_empty() {
+    return name.to_string();
+  }
+  Path::new(opts.dir).join(name).display().to_string()
+}
+
+/// The source file name without its extension.
+fn stem<'a>(opts: &Options<'a>) -> &'a str {
+  Path::new(opts.filename)
+    .file_stem()
+    .and_then(|stem| stem.to_str())
+    .unwrap_or("main")
+}
+
+/// The class `jasmin` generates, and so the file `Class` writes.
+const CLASS_FILE: &str = "Main.class";
+
+struct Jasmin;
+
+impl Backend for Jasmin {
+  fn path(&self, _opts: &Options) -> Option<String> {
+    Some("main.j".to_string())
+  }
+
+  fn emit(&self, ir: &[Inst], opts: &Options, out: &mut dyn Write) -> io::Result<()> {
+    if opts.dialect == krakatau::Dialect::Jasmin {
+      return jasmin::write_code(ir, opts.config, out);
+    }
+    let code = jasmin::produce_code(ir.to_vec(), opts.config).map_err(invalid)?;
+    out.write_all(krakatau::from_jasmin(&code).map_err(invalid)?.as_bytes())
+  }
+
+  /// With `--source-map`, also writes the map of the Jasmin beside it.
+  fn finish(&self, path: &str, opts: &Options) -> io::Result<String> {
+    if !opts.config.source_map || opts.dialect != krakatau::Dialect::Jasmin {
+      return Ok(format!("Compiled code to {}", path));
+    }
+    let code = std::fs::read_to_string(path)?;
+    let name = Path::new(path)
+      .file_name()
+      .and_then(|name| name.to_str())
+      .unwrap_or(path);
+    let map = format!("{}.map", path);
+    std::fs::write(
+      &map,
+      format!("{}\n", jasmin::source_map(&code, opts.filename, name)),
+    )?;
+    Ok(format!(
+      "Compiled code to {} and its source map to {}",
+      path, map
+    ))
+  }
+}
+
+struct Class;
+
+impl Backend for Class {
+  fn path(&self, _opts: &Options) -> Option<String> {
+    Some(CLASS_FILE.to_string())
+  }
+
+  fn emit(&self, ir: &[Inst], opts: &Options, out: &mut dyn Write) -> io::Result<()> {
+    out.write_all(&class(ir, opts)?.1)
+  }
+}
+
+struct Jar;
+
+impl Backend for Jar {
+  fn path(&self, opts: &Options) -> Option<String> {
+    Some(format!("{}.jar", stem(opts)))
+  }
+
+  fn emit(&self, ir: &[Inst], opts: &Options, out: &mut dyn Write) -> io::Result<()> {
+    let (name, class) = class(ir, opts)?;
+    out.write_all(&jar::produce_jar(&name, &class))
+  }
+}
+
+struct Dex;
+
+impl Backend for Dex {
+  fn path(&self, _opts: &Options) -> Option<String> {
+    Some(CLASS_FILE.to_string())
+  }
+
+  fn emit(&self, ir: &[Inst], opts: &Options, out: &mut dyn Write) -> io::Result<()> {
+    dex::check_version(opts.class_version).map_err(invalid)?;
+    out.write_all(&class(ir, opts)?.1)
+  }
+
+  fn finish(&self, path: &str, opts: &Options) -> io::Result<String> {
+    let dir = if opts.dir.is_empty() { "." } else { opts.dir };
+    if dex::run_d8(opts.d8, path, dir)? {
+      let dex = in_dir(opts, "classes.dex");
+      dex::check_dex(&std::fs::read(&dex)?)
+        .map_err(|error| invalid(format!("{}: {}", dex, error)))?;
+      Ok(format!("Compiled code to {}", dex))
+    } else {
+      Ok(format!(
+        "Compiled code to {}; {} was not found, run `d8 --output {} {}` to convert it",
+        path, opts.d8, dir, path
+      ))
+    }
+  }
+}
+
+/// A native executable, through an object file that the C compiler links
+/// and that is removed once it has.
+struct Exe;
+
+impl Backend for Exe {
+  fn path(&self, opts: &Options) -> Option<String> {
+    Some(format!("{}.o", stem(opts)))
+  }
+
+  #[cfg(feature = "exe")]
+  fn emit(&self, ir: &[Inst], opts: &Options, out: &mut dyn Write) -> io::Result<()> {
+    out.write_all(&exe::produce_object(ir, opts.config).map_err(invalid)?)
+  }
+
+  #[cfg(not(feature = "exe"))]
+  fn emit(&self, _ir: &[Inst], _opts: &Options, _out: &mut dyn Write) -> io::Result<()> {
+    Err(invalid(
+      "--emit exe needs a build with the `exe` feature".to_string(),
+    ))
+  }
+
+  #[cfg(feature = "exe")]
+  fn finish(&self, path: &str, opts: &Options) -> io::Result<String> {
+    let output = in_dir(opts, stem(opts));
+    if exe::link(opts.linker, path, &output)? {
+      std::fs::remove_file(path)?;
+      Ok(format!("Compiled code to {}", output))
+    } else {
+      Ok(format!(
+        "Compiled code to {}; {} was not found, link it with `cc {} -o {}`",
+        path, opts.linker, path, output
+      ))
+    }
+  }
+}
+
+/// The optimized IR one instruction per line, followed by the facts the
+/// optimizer proved about it. With a profile, each line also shows how often
+/// its source ran.
+struct Ir;
+
+impl Backend for Ir {
+  fn path(&self, _opts: &Options) -> Option<String> {
+    None
+  }
+
+  fn emit(&self, ir: &[Inst], opts: &Options, out: &mut dyn Write) -> io::Result<()> {
+    for (index, inst) in ir.iter().enumerate() {
+      let mut line = format!(
+        "{:>6}  {:<40} ; {}..{}",
+        index,
+        format!("{:?}", inst.op),
+        inst.span.start,
+        inst.span.end
+      );
+      if let Some(profile) = opts.profile {
+        line.push_str(&format!(" hits={}", profile.hotness(inst.span)));
+      }
+      writeln!(out, "{}", line)?;
+    }
+    for note in opts.notes {
+      writeln!(
+        out,
+        "; {}..{}: {}: {}",
+        note.span.start, note.span.end, note.pass, note.message
+      )?;
+    }
+    Ok(())
+  }
+}
+
+/// The optimized program as the cache stores it, which `run` and
+/// `compile` take in place of a source.
+struct Brir;
+
+impl Backend for Brir {
+  fn path(&self, opts: &Options) -> Option<String> {
+    Some(format!("{}.brir", stem(opts)))
+  }
+
+  fn emit(&self, ir: &[Inst], _opts: &Options, out: &mut dyn Write) -> io::Result<()> {
+    out.write_all(&cache::encode(ir))
+  }
+}
+
+struct Bf;
+
+impl Backend for Bf {
+  fn path(&self, _opts: &Options) -> Option<String> {
+    None
+  }
+
+  fn emit(&self, ir: &[Inst], _opts: &Options, out: &mut dyn Write) -> io::Result<()> {
+    write_text(out, bf::produce_bf(ir))
+  }
+}
+
+struct Pseudo;
+
+impl Backend for Pseudo {
+  fn path(&self, _opts: &Options) -> Option<String> {
+    None
+  }
+
+  fn emit(&self, ir: &[Inst], _opts: &Options, out: &mut dyn Write) -> io::Result<()> {
+    out.write_all(pseudo::produce_pseudo(ir).as_bytes())
+  }
+}
+
+struct Java;
+
+impl Backend for Java {
+  fn path(&self, _opts: &Options) -> Option<String> {
+    Some("Main.java".to_string())
+  }
+
+  fn emit(&self, ir: &[Inst], opts: &Options, out: &mut dyn Write) -> io::Result<()> {
+    write_text(out, java::produce_java(ir, opts.config))
+  }
+}
+
+struct Llvm;
+
+impl Backend for Llvm {
+  fn path(&self, _opts: &Options) -> Option<String> {
+    Some("main.ll".to_string())
+  }
+
+  fn emit(&self, ir: &[Inst], opts: &Options, out: &mut dyn Write) -> io::Result<()> {
+    write_text(out, llvm::produce_llvm(ir, opts.config))
+  }
+}
+
+struct Wasm;
+
+impl Backend for Wasm {
+  fn path(&self, _opts: &Options) -> Option<String> {
+    Some("main.wasm".to_string())
+  }
+
+  fn emit(&self, ir: &[Inst], opts: &Options, out: &mut dyn Write) -> io::Result<()> {
+    out.write_all(&wasm::produce_wasm(ir, opts.config).map_err(invalid)?)
+  }
+}
+
+struct Wat;
+
+impl Backend for Wat {
+  fn path(&self, _opts: &Options) -> Option<String> {
+    Some("main.wat".to_string())
+  }
+
+  fn emit(&self, ir: &[Inst], opts: &Options, out: &mut dyn Write) -> io::Result<()> {
+    write_text(out, wasm::produce_wat(ir, opts.config))
+  }
+}
+
+struct X86_64;
+
+impl Backend for X86_64 {
+  fn path(&self, _opts: &Options) -> Option<String> {
+    Some("main.s".to_string())
+  }
+
+  fn emit(&self, ir: &[Inst], opts: &Options, out: &mut dyn Write) -> io::Result<()> {
+    let target = x86_64::X86_64 {
+      syntax: opts.syntax,
+    };
+    write_text(out, native::produce_asm(ir, opts.config, &target))
+  }
+}
+
+struct AArch64;
+
+impl Backend for AArch64 {
+  fn path(&self, _opts: &Options) -> Option<String> {
+    Some("main.s".to_string())
+  }
+
+  fn emit(&self, ir: &[Inst], opts: &Options, out: &mut dyn Write) -> io::Result<()> {
+    let target = aarch64::AArch64 { os: opts.os };
+    write_text(out, native::produce_asm(ir, opts.config, &target))
+  }
+}
+
+struct RiscV64;
+
+impl Backend for RiscV64 {
+  fn path(&self, _opts: &Options) -> Option<String> {
+    Some("main.s".to_string())
+  }
+
+  fn emit(&self, ir: &[Inst], opts: &Options, out: &mut dyn Write) -> io::Result<()> {
+    let target = riscv64::RiscV64 { io: opts.riscv_io };
+    write_text(out, native::produce_asm(ir, opts.config, &target))
+  }
+}
+
+struct C;
+
+impl Backend for C {
+  fn path(&self, _opts: &Options) -> Option<String> {
+    Some("main.c".to_string())
+  }
+
+  fn emit(&self, ir: &[Inst], opts: &Options, out: &mut dyn Write) -> io::Result<()> {
+    write_text(out, c::produce_c(ir, opts.config))
+  }
+}
+
+struct Rust;
+
+impl Backend for Rust {
+  fn path(&self, _opts: &Options) -> Option<String> {
+    Some("main.rs".to_string())
+  }
+
+  fn emit(&self, ir: &[Inst], opts: &Options, out: &mut dyn Write) -> io::Result<()> {
+    write_text(out, rust::produce_rust(ir, opts.config))
+  }
+}
+
+struct Python;
+
+impl Backend for Python {
+  fn path(&self, _opts: &Options) -> Option<String> {
+    Some("main.py".to_string())
+  }
+
+  fn emit(&self, ir: &[Inst], opts: &Options, out: &mut dyn Write) -> io::Result<()> {
+    write_text(out, python::produce_python(ir, opts.config))
+  }
+}
+
+struct Js;
+
+impl Backend for Js {
+  fn path(&self, opts: &Options) -> Option<String> {
+    if opts.js_module {
+      Some("main.mjs".to_string())
+    } else {
+      Some("main.js".to_string())
+    }
+  }
+
+  fn emit(&self, ir: &[Inst], opts: &Options, out: &mut dyn Write) -> io::Result<()> {
+    write_text(out, js::produce_js(ir, opts.config, opts.js_module))
+  }
+}
+
+/// Every backend under the names `--emit` accepts for it.
+pub static REGISTRY: &[(&[&str], &dyn Backend)] = &[
+  (&["jasmin"], &Jasmin),
+  (&["class"], &Class),
+  (&["jar"], &Jar),
+  (&["exe"], &Exe),
+  (&["dex"], &Dex),
+  (&["ir"], &Ir),
+  (&["brir"], &Brir),
+  (&["bf"], &Bf),
+  (&["pseudo"], &Pseudo),
+  (&["java"], &Java),
+  (&["llvm"], &Llvm),
+  (&["wasm"], &Wasm),
+  (&["wat"], &Wat),
+  (&["x86_64", "x86-64"], &X86_64),
+  (&["aarch64", "arm64"], &AArch64),
+  (&["riscv64", "riscv"], &RiscV64),
+  (&["c"], &C),
+  (&["rust"], &Rust),
+  (&["js", "javascript"], &Js),
+  (&["python", "py"], &Python),
+];
+
+/// The first name of the backend registered under `name`, which stands for
+/// it however it was spelled.
+pub fn canonical(name: &str) -> Option<&'static str> {
+  REGISTRY
+    .iter()
+    .find(|(names, _)| names.contains(&name))
+    .map(|(names, _)| names[0])
+}
+
+/// The backend registered under `name`.
+pub fn lookup(name: &str) -> Option<&'static dyn Backend> {
+  REGISTRY
+    .iter()
+    .find(|(names, _)| names.contains(&name))
+    .map(|(_, backend)| *backend)
+}
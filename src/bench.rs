@@ -0,0 +1,122 @@
+//! The runs behind `bench`, which time one program under each way this
+//! crate can execute it: the interpreter, the JIT in builds with the `jit`
+//! feature, and a compiled class on the JVM when `java` is installed.
+//!
+//! Each run gets the same input and its output is kept, so a strategy that
+//! prints something else than the interpreter can be told apart from one
+//! that is merely slow. The JVM's time includes starting it.
+
+use std::fs;
+use std::io::{self, ErrorKind, Write};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use super::interpreter::{Eof, Interpreter};
+use super::{classfile, jasmin, Inst};
+
+/// How long one strategy took and what it printed.
+pub struct Timing {
+  pub time: Duration,
+  pub output: Vec<u8>,
+}
+
+/// Counts the instructions `program` executes on `input`, stepping through
+/// the interpreter.
+pub fn count_steps(program: &[Inst], eof: Eof, input: &[u8]) -> io::Result<u64> {
+  let mut interpreter = Interpreter::new(program).with_eof(eof);
+  let mut input = input;
+  let mut steps = 0;
+  while interpreter.step(&mut input, &mut io::sink())?.is_some() {
+    steps += 1;
+  }
+  Ok(steps)
+}
+
+pub fn interpreter(program: &[Inst], eof: Eof, input: &[u8]) -> io::Result<Timing> {
+  let mut output = Vec::new();
+  let start = Instant::now();
+  Interpreter::new(program)
+    .with_eof(eof)
+    .run(&mut &input[..], &mut output, None)?;
+  Ok(Timing {
+    time: start.elapsed(),
+    output,
+  })
+}
+
+#[cfg(feature = "jit")]
+pub fn jit(program: &[Inst], eof: Eof, input: &[u8]) -> io::Result<Timing> {
+  let mut output = Vec::new();
+  let start = Instant::now();
+  super::jit::run(
+    program,
+    eof,
+    super::interpreter::Io::Chars,
+    super::interpreter::TAPE_SIZE,
+    &mut &input[..],
+    &mut output,
+  )?;
+  Ok(Timing {
+    time: start.elapsed(),
+    output,
+  })
+}
+
+/// Runs `program` compiled with `config` on `java`, returning `None` when
+/// `java` could not be found.
+pub fn jvm(
+  program: &[Inst],
+  config: &jasmin::Config,
+  class_version: u16,
+  java: &str,
+  input: &[u8],
+) -> io::Result<Option<Timing>> {
+  let code = jasmin::produce_code(program.to_vec(), config).map_err(io::Error::other)?;
+  let (name, class) = classfile::assemble(&code, class_version).map_err(io::Error::other)?;
+  let dir = std::env::temp_dir().join(format!("brainfuck-bench-{}", std::process::id()));
+  fs::create_dir_all(&dir)?;
+  fs::write(dir.join(format!("{}.class", name)), class)?;
+  let start = Instant::now();
+  let child = Command::new(java)
+    .arg("-cp")
+    .arg(&dir)
+    .arg(&name)
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .spawn();
+  let mut child = match child {
+    Ok(child) => child,
+    Err(error) => {
+      fs::remove_dir_all(&dir)?;
+      return match error.kind() {
+        ErrorKind::NotFound => Ok(None),
+        _ => Err(error),
+      };
+    }
+  };
+  // Written from another thread so that output filling its pipe cannot
+  // stall the input.
+  let mut stdin = child.stdin.take().unwrap();
+  let bytes = input.to_vec();
+  let writer = std::thread::spawn(move || stdin.write_all(&bytes));
+  let result = child.wait_with_output();
+  let written = writer.join().unwrap();
+  let time = start.elapsed();
+  fs::remove_dir_all(&dir)?;
+  let result = result?;
+  // A program that stops reading closes the pipe early, which is fine.
+  match written {
+    Err(error) if error.kind() != ErrorKind::BrokenPipe => return Err(error),
+    _ => (),
+  }
+  if !result.status.success() {
+    return Err(io::Error::other(format!(
+      "{} failed with {}",
+      java, result.status
+    )));
+  }
+  Ok(Some(Timing {
+    time,
+    output: result.stdout,
+  }))
+}
@@ -0,0 +1,217 @@
+//! Brainfuck output, so the optimizer can sit in front of other
+//! interpreters. Programs with procedures come out as pbrain, and those
+//! with forks as Brainfork.
+//!
+//! Offset operations are spelled out as moves there and back, and constant
+//! output relies on what the passes proved: `PutConst` is only produced when
+//! the current cell holds its value, and `Print` only at the start of the
+//! program, where the current cell is zero, as is the cell to the right that
+//! counts the loops building characters.
+//!
+//! `gen-text` writes programs printing a text the same way.
+
+use std::io;
+
+use super::interpreter::Interpreter;
+use super::{
+  has_ebf, has_forks, has_procedures, has_random, has_tapes, lex_dialect, parse_program, Dialect,
+  Inst, Op,
+};
+
+/// Characters per line of generated source.
+const WIDTH: usize = 72;
+
+fn repeat(out: &mut String, c: char, count: u32) {
+  out.extend(std::iter::repeat_n(c, count as usize));
+}
+
+fn mov(out: &mut String, offset: i32) {
+  if offset > 0 {
+    repeat(out, '>', offset as u32);
+  } else {
+    repeat(out, '<', offset.unsigned_abs());
+  }
+}
+
+fn add(out: &mut String, amount: i32) {
+  if amount > 0 {
+    repeat(out, '+', amount as u32);
+  } else {
+    repeat(out, '-', amount.unsigned_abs());
+  }
+}
+
+/// Adds `amount` to the cell at `offset` and returns to the current cell.
+fn add_at(out: &mut String, offset: i32, amount: i32) {
+  mov(out, offset);
+  add(out, amount);
+  mov(out, -offset);
+}
+
+/// Changes the current cell from `cell` to `byte` the cheaper way: adding
+/// the difference, or counting down a product of it in the cell to the
+/// right and adding what is left. Cells wrap, so the difference is taken
+/// the short way round.
+fn build(out: &mut String, cell: u8, byte: u8) {
+  let delta = byte.wrapping_sub(cell) as i8 as i32;
+  let (magnitude, sign) = (delta.abs(), delta.signum());
+  // `>`, `[<`, `>-]<` and the three counts.
+  let cost = |(a, b, rest): (i32, i32, i32)| a + b + rest.abs() + 7;
+  let product = (2..=magnitude.min(16))
+    .flat_map(|a| {
+      let b = magnitude / a;
+      [
+        (a, b, magnitude - a * b),
+        (a, b + 1, magnitude - a * (b + 1)),
+      ]
+    })
+    .min_by_key(|&factors| cost(factors));
+  match product {
+    Some((a, b, rest)) if cost((a, b, rest)) < magnitude => {
+      out.push('>');
+      add(out, a);
+      out.push_str("[<");
+      add(out, b * sign);
+      out.push_str(">-]<");
+      add(out, rest * sign);
+    }
+    _ => add(out, delta),
+  }
+}
+
+/// Prints `text`, whose characters must fit a cell, from a current cell
+/// holding 0, and leaves its last character there.
+fn print_text(out: &mut String, text: &str) {
+  let mut cell = 0;
+  for c in text.chars() {
+    build(out, cell, c as u8);
+    out.push('.');
+    cell = c as u8;
+  }
+}
+
+/// Splits `code` into lines of `WIDTH` characters.
+pub(crate) fn lines(code: &str) -> String {
+  let lines: Vec<String> = code
+    .as_bytes()
+    .chunks(WIDTH)
+    .map(|line| String::from_utf8(line.to_vec()).unwrap())
+    .chain(std::iter::once(String::new()))
+    .collect();
+  lines.join("\n")
+}
+
+/// A program printing `text`, checked by running it.
+pub fn produce_text(text: &str) -> Result<String, String> {
+  if let Some(c) = text.chars().find(|&c| c as u32 > 255) {
+    return Err(format!("{} does not fit a cell", c));
+  }
+  let mut code = String::new();
+  print_text(&mut code, text);
+  let program = parse_program(lex_dialect(&code, Dialect::Brainfuck)?)?;
+  let mut output = Vec::new();
+  Interpreter::new(&program)
+    .run(&mut io::empty(), &mut output, None)
+    .map_err(|e| e.to_string())?;
+  if output != text.as_bytes() {
+    return Err("the generated program prints something else".to_string());
+  }
+  Ok(lines(&code))
+}
+
+/// Serializes `instructions` into lines of `WIDTH` characters.
+pub fn produce_bf(instructions: &[Inst]) -> Result<String, String> {
+  Ok(lines(&serialize(instructions, ':')?))
+}
+
+/// Serializes `instructions` on one line, writing calls as `call`, and
+/// checks that the result parses again.
+pub fn serialize(instructions: &[Inst], call: char) -> Result<String, String> {
+  let mut code = String::new();
+  let mut index = 0;
+  while index < instructions.len() {
+    match instructions[index].op {
+      Op::Plus(count) => repeat(&mut code, '+', count),
+      Op::Minus(count) => repeat(&mut code, '-', count),
+      Op::Right(count) => repeat(&mut code, '>', count),
+      Op::Left(count) => repeat(&mut code, '<', count),
+      Op::PutChar(count) | Op::PutConst { count, .. } => repeat(&mut code, '.', count),
+      Op::ReadChar(count) => repeat(&mut code, ',', count),
+      Op::JumpIfZero(_) => code.push('['),
+      Op::JumpIfNonZero(_) => code.push(']'),
+      Op::Procedure(_) => code.push('('),
+      Op::Return(_) => code.push(')'),
+      Op::Call => code.push(call),
+      Op::Fork => code.push('Y'),
+      Op::Ebf(command) => code.push(command.symbol()),
+      Op::Debug => code.push('#'),
+      Op::PutNumber => code.push(':'),
+      Op::ReadNumber => code.push(';'),
+      Op::Random => code.push('?'),
+      Op::Tape(command) => code.push(command.symbol()),
+      Op::SetZero => code.push_str("[-]"),
+      Op::Add { offset, amount } => add_at(&mut code, offset, amount),
+      Op::Set { offset, value } => {
+        mov(&mut code, offset);
+        code.push_str("[-]");
+        add(&mut code, value);
+        mov(&mut code, -offset);
+      }
+      Op::ScanZero { stride } => {
+        code.push('[');
+        mov(&mut code, stride);
+        code.push(']');
+      }
+      Op::AddTo { .. } => {
+        // A multiplication loop only survives as a loop if the counter is
+        // cleared right after its targets, as `multiply_loops` leaves it.
+        let targets: Vec<(i32, i32)> = instructions[index..]
+          .iter()
+          .map_while(|inst| match inst.op {
+            Op::AddTo { offset, factor } => Some((offset, factor)),
+            _ => None,
+          })
+          .collect();
+        let next = instructions.get(index + targets.len()).map(|inst| &inst.op);
+        if !matches!(next, Some(Op::SetZero) | Some(Op::Set { offset: 0, .. })) {
+          return Err(format!(
+            "instruction {}: a multiplication that keeps its counter has no Brainfuck form",
+            index
+          ));
+        }
+        code.push_str("[-");
+        for (offset, factor) in &targets {
+          add_at(&mut code, *offset, *factor);
+        }
+        code.push(']');
+        // The loop leaves the counter at zero, so only a non-zero `Set`
+        // still has something to do.
+        if let Some(&Op::Set { value, .. }) = next {
+          add(&mut code, value);
+        }
+        index += targets.len() + 1;
+        continue;
+      }
+      Op::Print(ref text) => {
+        print_text(&mut code, &text.chars());
+        code.push_str("[-]");
+      }
+    }
+    index += 1;
+  }
+  let dialect = if has_procedures(instructions) {
+    Dialect::Pbrain
+  } else if has_forks(instructions) {
+    Dialect::Brainfork
+  } else if has_ebf(instructions) {
+    Dialect::Ebf1
+  } else if has_random(instructions) {
+    Dialect::Extended
+  } else if has_tapes(instructions) {
+    Dialect::MultiTape
+  } else {
+    Dialect::Brainfuck
+  };
+  parse_program(lex_dialect(&code, dialect)?)?;
+  Ok(code)
+}
@@ -0,0 +1,57 @@
+//! Brainbool, Brainfuck on cells of one bit: `+` flips the current bit,
+//! there is no `-`, `.` prints it as the character `0` or `1` and `,`
+//! reads a character, storing 1 for `1` and 0 for anything else or the
+//! end of input.
+//!
+//! Each command is lexed as the Brainfuck commands doing the same to byte
+//! cells that only ever hold 0 or 1, so every backend runs it. Bits sit in
+//! every other byte of the tape, each followed by a scratch byte the
+//! commands use and leave at zero, so the tape holds half as many bits.
+
+use super::{Commands, Token};
+
+/// Flips the bit using the scratch byte: the scratch is set, cleared
+/// again if the bit was 1 as it clears it, and moved into the bit.
+const FLIP: &str = ">+<[>-<-]>[<+>-]<";
+
+/// The Brainfuck for the Brainbool command `byte`, if it is one.
+fn expansion(byte: u8) -> Option<String> {
+  Some(match byte {
+    b'+' => FLIP.to_string(),
+    b'>' => ">>".to_string(),
+    b'<' => "<<".to_string(),
+    b'.' => format!("{}.{}", "+".repeat(48), "-".repeat(48)),
+    // The byte read is 49 less, and the bit is whether that is zero.
+    b',' => format!(",{}>+<[>-<[-]]>[<+>-]<", "-".repeat(49)),
+    b'[' => "[".to_string(),
+    b']' => "]".to_string(),
+    b'#' => "#".to_string(),
+    _ => return None,
+  })
+}
+
+/// Lexes `program`, every token at the position of the command it is
+/// part of.
+pub fn lex(program: &[u8], commands: Commands) -> Vec<(Token, usize)> {
+  let mut tokens = Vec::new();
+  for (pos, &byte) in program.iter().enumerate() {
+    if byte == b'#' && !commands.debug_dumps {
+      continue;
+    }
+    for command in expansion(byte).unwrap_or_default().bytes() {
+      let token = match command {
+        b'+' => Token::Plus,
+        b'-' => Token::Minus,
+        b'>' => Token::Right,
+        b'<' => Token::Left,
+        b'.' => Token::PutChar,
+        b',' => Token::ReadChar,
+        b'[' => Token::JumpIfZero,
+        b']' => Token::JumpIfNonZero,
+        _ => Token::Debug,
+      };
+      tokens.push((token, pos));
+    }
+  }
+  tokens
+}
@@ -0,0 +1,295 @@
+//! Portable C, for native speed through any C compiler: a static tape, a
+//! pointer into it, `while (*p)` loops and stdio.
+
+use super::interpreter::{Bounds, Eof, ExitCell};
+use super::jasmin::Config;
+use super::{output_bytes, reject_extensions, writes_bytes, Inst, Op};
+
+const INDENT: &str = "    ";
+
+/// The cell at `offset` from the pointer.
+fn cell(offset: i32) -> String {
+  match offset {
+    0 => "*p".to_string(),
+    _ => format!("p[{}]", offset),
+  }
+}
+
+/// `+=` or `-=` with a positive operand.
+fn compound(target: &str, amount: i64) -> String {
+  if amount < 0 {
+    format!("{} -= {};", target, amount.unsigned_abs())
+  } else {
+    format!("{} += {};", target, amount)
+  }
+}
+
+/// Quotes `bytes` as a C string literal. Every escape is three octal digits,
+/// so a digit after it cannot extend it, and `?` is escaped so no trigraph
+/// forms.
+fn quote(bytes: &[u8]) -> String {
+  let mut quoted = String::from("\"");
+  for &byte in bytes {
+    match byte {
+      b'"' => quoted.push_str("\\\""),
+      b'\\' => quoted.push_str("\\\\"),
+      b'?' => quoted.push_str("\\?"),
+      b'\n' => quoted.push_str("\\n"),
+      b' '..=b'~' => quoted.push(byte as char),
+      _ => quoted.push_str(&format!("\\{:03o}", byte)),
+    }
+  }
+  quoted.push('"');
+  quoted
+}
+
+/// Longest string literal written at once, within the 509 characters C90
+/// compilers must accept even after escapes.
+const CHUNK: usize = 120;
+
+/// Statements writing `bytes` to standard output.
+fn write(bytes: &[u8]) -> Vec<String> {
+  bytes
+    .chunks(CHUNK)
+    .map(|chunk| match chunk {
+      [byte] => format!("putchar({});", byte),
+      _ => format!("fwrite({}, 1, {}, stdout);", quote(chunk), chunk.len()),
+    })
+    .collect()
+}
+
+/// `put(c)`, which prints the low byte of `c` as a character in UTF-8, the
+/// way the interpreter prints a cell.
+const PUT: &str = "static void put(int c) {
+    c &= 255;
+    if (c < 128) {
+        putchar(c);
+    } else {
+        putchar(0xc0 | c >> 6);
+        putchar(0x80 | (c & 0x3f));
+    }
+}
+";
+
+/// `check(i)`, which stops the program when `i` is not the index of a
+/// cell, for `Bounds::Error`. Indices are checked before the pointer moves,
+/// since a pointer outside the tape is already undefined.
+fn check(size: usize) -> String {
+  format!(
+    "static void check(long i) {{
+    if (i < 0 || i >= {}) {{
+        fputs(\"pointer moved off the tape\\n\", stderr);
+        exit(1);
+    }}
+}}
+",
+    size
+  )
+}
+
+/// Moves the pointer by `amount`, checking where it lands first under
+/// `Bounds::Error`.
+fn moves(amount: i64, config: &Config) -> String {
+  match config.bounds {
+    Bounds::Unchecked => compound("p", amount),
+    Bounds::Error => format!("{} {}", checks(amount as i32), compound("p", amount)),
+  }
+}
+
+/// The call checking that the cell at `offset` from the pointer is on the
+/// tape.
+fn checks(offset: i32) -> String {
+  match offset {
+    0 => "check(p - tape);".to_string(),
+    _ if offset < 0 => format!("check(p - tape - {});", offset.unsigned_abs()),
+    _ => format!("check(p - tape + {});", offset),
+  }
+}
+
+/// Statements reading one byte into the current cell.
+fn read(config: &Config) -> String {
+  match config.eof {
+    Eof::Unchanged => "if ((c = getchar()) != EOF) *p = c;".to_string(),
+    Eof::Zero => "*p = (c = getchar()) == EOF ? 0 : c;".to_string(),
+    Eof::MinusOne => "*p = getchar();".to_string(),
+  }
+}
+
+/// Generates `main.c` for `instructions`.
+pub fn produce_c(instructions: &[Inst], config: &Config) -> Result<String, String> {
+  reject_extensions(instructions)?;
+  if config.tape_from_args || config.embeddable || config.runtime_args {
+    return Err(
+      "--tape-from-args, --embeddable and --runtime-args only apply to JVM output".to_string(),
+    );
+  }
+  let bytes = writes_bytes(config.io)?;
+  // Cells that do not wrap at 256 wrap at 2^32, as the `u32` cells of run
+  // do.
+  let kind = if config.wrap {
+    "unsigned char"
+  } else {
+    "uint32_t"
+  };
+  let checked = config.bounds == Bounds::Error
+    && instructions.iter().any(|inst| match inst.op {
+      Op::Right(_) | Op::Left(_) | Op::AddTo { .. } | Op::ScanZero { .. } => true,
+      Op::Add { offset, .. } | Op::Set { offset, .. } => offset != 0,
+      _ => false,
+    });
+  let mut lines = Vec::new();
+  if let Some(debug) = &config.debug {
+    lines.push(format!("/* Compiled from {} */", debug.file));
+  }
+  lines.push("#include <stdio.h>".to_string());
+  if !config.wrap {
+    lines.push("#include <stdint.h>".to_string());
+  }
+  if checked {
+    lines.push("#include <stdlib.h>".to_string());
+  }
+  lines.push(String::new());
+  // Programs -O2 reduced to constant output leave the tape unused, which
+  // compilers would warn about.
+  let uses_pointer = config.exit_cell == Some(ExitCell::Current)
+    || instructions
+      .iter()
+      .any(|inst| !matches!(inst.op, Op::PutConst { .. } | Op::Print(_)));
+  let uses_tape = uses_pointer || config.exit_cell.is_some();
+  if uses_tape {
+    lines.extend([
+      format!("static {} tape[{}];", kind, config.tape_size),
+      String::new(),
+    ]);
+    if checked {
+      lines.push(check(config.tape_size));
+    }
+  }
+  if !bytes
+    && instructions
+      .iter()
+      .any(|inst| matches!(inst.op, Op::PutChar(_)))
+  {
+    lines.push(PUT.to_string());
+  }
+  let put = if bytes { "putchar(*p);" } else { "put(*p);" };
+  lines.push("int main(void) {".to_string());
+  if uses_pointer {
+    lines.push(format!("{}{} *p = tape;", INDENT, kind));
+  }
+  if config.eof != Eof::MinusOne
+    && instructions
+      .iter()
+      .any(|inst| matches!(inst.op, Op::ReadChar(_)))
+  {
+    lines.push(format!("{}int c;", INDENT));
+  }
+  let mut depth = 1;
+  let mut index = 0;
+  while index < instructions.len() {
+    let mut emit = |line: String| lines.push(format!("{}{}", INDENT.repeat(depth), line));
+    match instructions[index].op {
+      Op::Procedure(_)
+      | Op::Return(_)
+      | Op::Call
+      | Op::Fork
+      | Op::Ebf(_)
+      | Op::Debug
+      | Op::PutNumber
+      | Op::ReadNumber
+      | Op::Random
+      | Op::Tape(_) => {
+        unreachable!("rejected by reject_extensions")
+      }
+      Op::Plus(count) => emit(compound("*p", count as i64)),
+      Op::Minus(count) => emit(compound("*p", -(count as i64))),
+      Op::Right(count) => emit(moves(count as i64, config)),
+      Op::Left(count) => emit(moves(-(count as i64), config)),
+      Op::PutChar(count) => {
+        for _ in 0..count {
+          emit(put.to_string());
+        }
+      }
+      Op::ReadChar(count) => {
+        for _ in 0..count {
+          emit(read(config));
+        }
+      }
+      Op::JumpIfZero(_) => {
+        emit("while (*p) {".to_string());
+        depth += 1;
+      }
+      Op::JumpIfNonZero(_) => {
+        depth -= 1;
+        lines.push(format!("{}}}", INDENT.repeat(depth)));
+      }
+      Op::SetZero => emit("*p = 0;".to_string()),
+      Op::AddTo { .. } => {
+        // The offset cells may lie outside the tape when the counter is
+        // zero, so they are only touched when it is not.
+        let targets: Vec<(i32, i32)> = instructions[index..]
+          .iter()
+          .map_while(|inst| match inst.op {
+            Op::AddTo { offset, factor } => Some((offset, factor)),
+            _ => None,
+          })
+          .collect();
+        emit("if (*p) {".to_string());
+        for (offset, factor) in &targets {
+          let product = match factor.unsigned_abs() {
+            1 => "*p".to_string(),
+            magnitude => format!("*p * {}", magnitude),
+          };
+          let operator = if *factor < 0 { '-' } else { '+' };
+          if checked {
+            emit(format!("{}{}", INDENT, checks(*offset)));
+          }
+          emit(format!(
+            "{}{} {}= {};",
+            INDENT,
+            cell(*offset),
+            operator,
+            product
+          ));
+        }
+        emit("}".to_string());
+        index += targets.len() - 1;
+      }
+      Op::Add { offset, amount } => {
+        if checked && offset != 0 {
+          emit(checks(offset));
+        }
+        emit(compound(&cell(offset), amount as i64))
+      }
+      Op::Set { offset, value } => {
+        if checked && offset != 0 {
+          emit(checks(offset));
+        }
+        let value = if config.wrap { value & 255 } else { value };
+        emit(format!("{} = {};", cell(offset), value));
+      }
+      Op::ScanZero { stride } if checked => {
+        emit(format!("while (*p) {{ {} }}", moves(stride as i64, config)))
+      }
+      Op::ScanZero { stride } => emit(format!("while (*p) {}", compound("p", stride as i64))),
+      Op::PutConst { value, count } => {
+        write(&output_bytes(&vec![value; count as usize], config.io))
+          .into_iter()
+          .for_each(emit)
+      }
+      Op::Print(ref text) => write(&output_bytes(text.as_bytes(), config.io))
+        .into_iter()
+        .for_each(emit),
+    }
+    index += 1;
+  }
+  let status = match config.exit_cell {
+    None => "0",
+    Some(ExitCell::Current) => "*p",
+    Some(ExitCell::First) => "tape[0]",
+  };
+  lines.push(format!("{}return {};", INDENT, status));
+  lines.push("}".to_string());
+  lines.push(String::new());
+  Ok(lines.join("\n"))
+}
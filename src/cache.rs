@@ -0,0 +1,176 @@
+//! Artifacts of earlier runs and compilations, kept under
+//! `.brainrust-cache` in files named by a hash of the source and the
+//! options it was built with, so that running or compiling an unchanged
+//! program again skips lexing, optimization and code generation.
+//!
+//! `--emit brir` writes the optimized program in the same form, which
+//! `run` and `compile` take in place of a source.
+
+use std::convert::TryInto;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::trace;
+use super::{Inst, Op, Span, Text};
+
+pub const DIR: &str = ".brainrust-cache";
+
+/// Version of what the artifacts hold, which keys them along with the
+/// crate version. Bump it whenever the IR, a pass or a backend builds
+/// something else from the same source and options, so that builds from
+/// the same release do not reuse stale entries.
+pub const FORMAT: &str = "1";
+
+/// Magic bytes and format version at the start of cached instructions.
+const MAGIC: &[u8; 5] = b"BRIR\x01";
+
+pub struct Cache {
+  key: String,
+}
+
+/// The 128-bit FNV-1a hash of `parts`, each prefixed by its length so that
+/// moving bytes from one part to the next changes it.
+fn hash(parts: &[&[u8]]) -> u128 {
+  const OFFSET: u128 = 0x6c62272e07bb014262b821756295c58d;
+  const PRIME: u128 = 0x0000000001000000000000000000013b;
+  let mut hash = OFFSET;
+  for part in parts {
+    for &byte in (part.len() as u64).to_le_bytes().iter().chain(part.iter()) {
+      hash ^= byte as u128;
+      hash = hash.wrapping_mul(PRIME);
+    }
+  }
+  hash
+}
+
+impl Cache {
+  /// The entries of the artifacts built from `parts`: the source and
+  /// everything else they depend on.
+  pub fn new(parts: &[&[u8]]) -> Cache {
+    Cache {
+      key: format!("{:032x}", hash(parts)),
+    }
+  }
+
+  fn path(&self, kind: &str) -> PathBuf {
+    Path::new(DIR).join(format!("{}.{}", self.key, kind))
+  }
+
+  /// The artifact of this kind, if one was stored.
+  pub fn load(&self, kind: &str) -> Option<Vec<u8>> {
+    fs::read(self.path(kind)).ok()
+  }
+
+  /// Stores an artifact, written beside its entry and moved into place so
+  /// that a concurrent run never loads half of it.
+  pub fn store(&self, kind: &str, bytes: &[u8]) -> io::Result<()> {
+    fs::create_dir_all(DIR)?;
+    let path = self.path(kind);
+    let partial = path.with_extension(format!("{}.partial", kind));
+    fs::write(&partial, bytes)?;
+    fs::rename(&partial, &path)
+  }
+
+  /// The optimized program, if it was stored and reads back.
+  pub fn load_instructions(&self) -> Option<Vec<Inst>> {
+    decode(&self.load("ir")?).ok()
+  }
+
+  pub fn store_instructions(&self, instructions: &[Inst]) -> io::Result<()> {
+    self.store("ir", &encode(instructions))
+  }
+}
+
+/// The fields of `op` in full, where a trace packs them into one argument.
+fn fields(op: &Op) -> (i64, i64) {
+  match *op {
+    Op::AddTo { offset, factor } => (offset as i64, factor as i64),
+    Op::Add { offset, amount } => (offset as i64, amount as i64),
+    Op::Set { offset, value } => (offset as i64, value as i64),
+    Op::ScanZero { stride } => (stride as i64, 0),
+    Op::PutConst { value, count } => (value as i64, count as i64),
+    _ => (trace::encode(op).1 as i64, 0),
+  }
+}
+
+/// `instructions` as bytes: each is its trace opcode, two fields and its
+/// span, followed by the text of a `Print`.
+pub fn encode(instructions: &[Inst]) -> Vec<u8> {
+  let mut bytes = MAGIC.to_vec();
+  bytes.extend((instructions.len() as u64).to_le_bytes());
+  for inst in instructions {
+    let (opcode, _) = trace::encode(&inst.op);
+    let (a, b) = fields(&inst.op);
+    bytes.push(opcode);
+    bytes.extend(a.to_le_bytes());
+    bytes.extend(b.to_le_bytes());
+    bytes.extend((inst.span.start as u64).to_le_bytes());
+    bytes.extend((inst.span.end as u64).to_le_bytes());
+    if let Op::Print(ref text) = inst.op {
+      bytes.extend(text.as_bytes());
+    }
+  }
+  bytes
+}
+
+/// Takes the eight bytes at `at` of `bytes`, moving past them.
+fn word(bytes: &[u8], at: &mut usize) -> Result<i64, String> {
+  let word = bytes.get(*at..*at + 8).ok_or("cached program ends early")?;
+  *at += 8;
+  Ok(i64::from_le_bytes(word.try_into().unwrap()))
+}
+
+/// Whether `bytes` are what `encode` writes, rather than a source.
+pub fn is_encoded(bytes: &[u8]) -> bool {
+  bytes.starts_with(MAGIC)
+}
+
+/// Reads back what `encode` wrote.
+pub fn decode(bytes: &[u8]) -> Result<Vec<Inst>, String> {
+  let bytes = bytes
+    .strip_prefix(&MAGIC[..])
+    .ok_or("not a cached program")?;
+  let mut at = 0;
+  let count = word(bytes, &mut at)?;
+  let mut instructions = Vec::new();
+  for _ in 0..count {
+    let opcode = *bytes.get(at).ok_or("cached program ends early")?;
+    at += 1;
+    let a = word(bytes, &mut at)?;
+    let b = word(bytes, &mut at)?;
+    let span = Span {
+      start: word(bytes, &mut at)? as u32,
+      end: word(bytes, &mut at)? as u32,
+    };
+    let op = match opcode {
+      9 => Op::AddTo {
+        offset: a as i32,
+        factor: b as i32,
+      },
+      10 => Op::Add {
+        offset: a as i32,
+        amount: b as i32,
+      },
+      11 => Op::Set {
+        offset: a as i32,
+        value: b as i32,
+      },
+      12 => Op::ScanZero { stride: a as i32 },
+      13 => Op::PutConst {
+        value: a as u8,
+        count: b as u32,
+      },
+      14 => {
+        let text = bytes
+          .get(at..at + a as usize)
+          .ok_or("cached program has a bad Print")?;
+        at += text.len();
+        Op::Print(Text::new(text))
+      }
+      _ => trace::decode(opcode, a as usize).ok_or("cached program has a bad opcode")?,
+    };
+    instructions.push(Inst { op, span });
+  }
+  Ok(instructions)
+}
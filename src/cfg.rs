@@ -0,0 +1,180 @@
+//! Control-flow graph over the IR.
+//!
+//! Only the bracket jumps transfer control, so blocks end after every
+//! `JumpIfZero`/`JumpIfNonZero` and start at every jump target.
+
+use super::{Inst, Op};
+
+/// Instructions `start..end` of the program, entered only at `start` and left
+/// only after `end - 1`.
+#[derive(Clone, Debug)]
+pub struct Block {
+  pub start: usize,
+  pub end: usize,
+  /// Indices of the blocks control may continue in. For blocks ending in a
+  /// jump, the first successor is taken when the current cell is non-zero.
+  pub successors: Vec<usize>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Cfg {
+  /// Blocks in program order. The first one is the entry and the last one is
+  /// an empty exit block at the end of the program.
+  pub blocks: Vec<Block>,
+  block_of: Vec<usize>,
+}
+
+impl Cfg {
+  /// Builds the graph of a program whose jumps have been linked.
+  pub fn build(program: &[Inst]) -> Cfg {
+    let mut leader = vec![false; program.len() + 1];
+    leader[0] = true;
+    leader[program.len()] = true;
+    for (index, inst) in program.iter().enumerate() {
+      match inst.op {
+        Op::JumpIfZero(target) | Op::JumpIfNonZero(target) => {
+          leader[index + 1] = true;
+          leader[target as usize + 1] = true;
+        }
+        _ => (),
+      }
+    }
+    let mut block_of = Vec::with_capacity(program.len() + 1);
+    let mut starts = Vec::new();
+    for (index, &is_leader) in leader.iter().enumerate() {
+      if is_leader {
+        starts.push(index);
+      }
+      block_of.push(starts.len() - 1);
+    }
+    let exit = starts.len() - 1;
+    let blocks = starts
+      .iter()
+      .enumerate()
+      .map(|(block, &start)| {
+        let end = starts.get(block + 1).copied().unwrap_or(program.len());
+        let successors = if block == exit {
+          Vec::new()
+        } else {
+          match program[end - 1].op {
+            Op::JumpIfZero(close) => vec![block_of[end], block_of[close as usize + 1]],
+            Op::JumpIfNonZero(open) => vec![block_of[open as usize + 1], block_of[end]],
+            _ => vec![block_of[end]],
+          }
+        };
+        Block {
+          start,
+          end,
+          successors,
+        }
+      })
+      .collect();
+    Cfg { blocks, block_of }
+  }
+
+  pub fn entry(&self) -> usize {
+    0
+  }
+
+  pub fn exit(&self) -> usize {
+    self.blocks.len() - 1
+  }
+
+  /// The block containing instruction `index`; the end of the program belongs
+  /// to the exit block.
+  pub fn block_of(&self, index: usize) -> usize {
+    self.block_of[index]
+  }
+
+  /// Blocks with an edge into `block`, in program order.
+  pub fn predecessors(&self, block: usize) -> Vec<usize> {
+    (0..self.blocks.len())
+      .filter(|&from| self.blocks[from].successors.contains(&block))
+      .collect()
+  }
+
+  /// Blocks reachable from the entry in reverse postorder, the usual
+  /// iteration order for forward data-flow and dominator computations.
+  pub fn reverse_postorder(&self) -> Vec<usize> {
+    let mut visited = vec![false; self.blocks.len()];
+    let mut order = Vec::with_capacity(self.blocks.len());
+    let mut stack = vec![(self.entry(), 0)];
+    visited[self.entry()] = true;
+    while let Some((block, next)) = stack.pop() {
+      match self.blocks[block].successors.get(next) {
+        Some(&successor) => {
+          stack.push((block, next + 1));
+          if !visited[successor] {
+            visited[successor] = true;
+            stack.push((successor, 0));
+          }
+        }
+        None => order.push(block),
+      }
+    }
+    order.reverse();
+    order
+  }
+}
+
+/// Quotes `text` for a DOT label, ending each line with `\l` so lines are
+/// left-aligned.
+fn label(lines: &[String]) -> String {
+  let mut quoted = String::from("\"");
+  for line in lines {
+    for c in line.chars() {
+      if c == '"' || c == '\\' {
+        quoted.push('\\');
+      }
+      quoted.push(c);
+    }
+    quoted.push_str("\\l");
+  }
+  quoted.push('"');
+  quoted
+}
+
+impl Cfg {
+  /// The graph in Graphviz DOT, one box per block listing its instructions
+  /// of `program` as `--emit ir` prints them. Edges out of a jump say when
+  /// they are taken, and edges back to the body of a loop are drawn bold
+  /// and red.
+  pub fn to_dot(&self, program: &[Inst]) -> String {
+    let mut lines = vec![
+      "digraph cfg {".to_string(),
+      "  node [shape=box, fontname=monospace];".to_string(),
+    ];
+    for (index, block) in self.blocks.iter().enumerate() {
+      let mut text = vec![match index {
+        _ if index == self.exit() => "exit".to_string(),
+        _ if index == self.entry() => "entry".to_string(),
+        _ => format!("block {}", index),
+      }];
+      text.extend((block.start..block.end).map(|at| format!("{:>6}  {:?}", at, program[at].op)));
+      lines.push(format!("  b{} [label={}];", index, label(&text)));
+    }
+    for (index, block) in self.blocks.iter().enumerate() {
+      for (n, &successor) in block.successors.iter().enumerate() {
+        let mut attributes = Vec::new();
+        if block.successors.len() == 2 {
+          attributes.push(format!(
+            "label=\"{}\"",
+            if n == 0 { "non-zero" } else { "zero" }
+          ));
+        }
+        if self.blocks[successor].start <= block.start {
+          attributes.push("color=red, style=bold".to_string());
+        }
+        let attributes = if attributes.is_empty() {
+          String::new()
+        } else {
+          format!(" [{}]", attributes.join(", "))
+        };
+        lines.push(format!("  b{} -> b{}{};", index, successor, attributes));
+      }
+    }
+    lines.push("}".to_string());
+    lines.push(String::new());
+    lines.join("\n")
+  }
+}
@@ -0,0 +1,808 @@
+//! Assembles the Jasmin subset the JVM backend emits straight into a class
+//! file, so `Main.class` can be produced without the external assembler.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use super::limits;
+use super::stackmap::{self, Frame, Insn, Type};
+
+/// Class file version written by default: 52 (Java 8) runs on every current
+/// JRE and is the oldest one they verify without falling back to inference.
+pub const DEFAULT_VERSION: u16 = 52;
+
+/// From version 50 (Java 6) on, methods carry a `StackMapTable`.
+const FIRST_STACK_MAP_VERSION: u16 = 50;
+
+/// The oldest and newest versions the assembler writes: 49 is the first
+/// with the instructions it uses, 65 is Java 21.
+pub const VERSIONS: std::ops::RangeInclusive<u16> = 49..=65;
+
+const ACC_SUPER: u16 = 0x0020;
+
+#[derive(PartialEq, Eq, Hash, Clone)]
+enum Constant {
+  Utf8(String),
+  Integer(i32),
+  Class(u16),
+  String(u16),
+  NameAndType(u16, u16),
+  Fieldref(u16, u16),
+  Methodref(u16, u16),
+}
+
+#[derive(Default)]
+struct ConstantPool {
+  entries: Vec<Constant>,
+  indices: HashMap<Constant, u16>,
+}
+
+impl ConstantPool {
+  fn add(&mut self, constant: Constant) -> Result<u16, String> {
+    if let Some(&index) = self.indices.get(&constant) {
+      return Ok(index);
+    }
+    if self.entries.len() >= u16::MAX as usize - 1 {
+      return Err("constant pool is full".to_string());
+    }
+    self.entries.push(constant.clone());
+    let index = self.entries.len() as u16;
+    self.indices.insert(constant, index);
+    Ok(index)
+  }
+
+  fn utf8(&mut self, text: &str) -> Result<u16, String> {
+    self.add(Constant::Utf8(text.to_string()))
+  }
+
+  fn class(&mut self, name: &str) -> Result<u16, String> {
+    let name = self.utf8(name)?;
+    self.add(Constant::Class(name))
+  }
+
+  /// A field or method reference written as `owner/name` plus a descriptor.
+  fn member(&mut self, path: &str, descriptor: &str, method: bool) -> Result<u16, String> {
+    let split = path
+      .rfind('/')
+      .ok_or_else(|| format!("{} has no owning class", path))?;
+    let class = self.class(&path[..split])?;
+    let name = self.utf8(&path[split + 1..])?;
+    let descriptor = self.utf8(descriptor)?;
+    let name_and_type = self.add(Constant::NameAndType(name, descriptor))?;
+    if method {
+      self.add(Constant::Methodref(class, name_and_type))
+    } else {
+      self.add(Constant::Fieldref(class, name_and_type))
+    }
+  }
+
+  fn write(&self, out: &mut Vec<u8>) {
+    put_u16(out, self.entries.len() as u16 + 1);
+    for constant in &self.entries {
+      match constant {
+        Constant::Utf8(text) => {
+          let bytes = modified_utf8(text);
+          out.push(1);
+          put_u16(out, bytes.len() as u16);
+          out.extend_from_slice(&bytes);
+        }
+        Constant::Integer(value) => {
+          out.push(3);
+          out.extend_from_slice(&value.to_be_bytes());
+        }
+        Constant::Class(name) => {
+          out.push(7);
+          put_u16(out, *name);
+        }
+        Constant::String(text) => {
+          out.push(8);
+          put_u16(out, *text);
+        }
+        Constant::Fieldref(class, name_and_type) => {
+          out.push(9);
+          put_u16(out, *class);
+          put_u16(out, *name_and_type);
+        }
+        Constant::Methodref(class, name_and_type) => {
+          out.push(10);
+          put_u16(out, *class);
+          put_u16(out, *name_and_type);
+        }
+        Constant::NameAndType(name, descriptor) => {
+          out.push(12);
+          put_u16(out, *name);
+          put_u16(out, *descriptor);
+        }
+      }
+    }
+  }
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+  out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+  out.extend_from_slice(&value.to_be_bytes());
+}
+
+/// An attribute table: the count, then each name with its length-prefixed
+/// body.
+fn put_attributes(out: &mut Vec<u8>, attributes: &[(u16, Vec<u8>)]) {
+  put_u16(out, attributes.len() as u16);
+  for (name, body) in attributes {
+    put_u16(out, *name);
+    put_u32(out, body.len() as u32);
+    out.extend_from_slice(body);
+  }
+}
+
+/// The JVM's string encoding: UTF-8, except that NUL takes two bytes and
+/// characters outside the BMP are written as surrogate pairs.
+fn modified_utf8(text: &str) -> Vec<u8> {
+  let mut bytes = Vec::with_capacity(text.len());
+  for unit in text.encode_utf16() {
+    match unit {
+      0x01..=0x7f => bytes.push(unit as u8),
+      0x00 | 0x80..=0x7ff => {
+        bytes.push(0xc0 | (unit >> 6) as u8);
+        bytes.push(0x80 | (unit & 0x3f) as u8);
+      }
+      _ => {
+        bytes.push(0xe0 | (unit >> 12) as u8);
+        bytes.push(0x80 | ((unit >> 6) & 0x3f) as u8);
+        bytes.push(0x80 | (unit & 0x3f) as u8);
+      }
+    }
+  }
+  bytes
+}
+
+/// Reads a Jasmin string literal, including its quotes.
+fn unquote(literal: &str) -> Result<String, String> {
+  let inner = literal
+    .strip_prefix('"')
+    .and_then(|rest| rest.strip_suffix('"'))
+    .ok_or_else(|| format!("malformed string {}", literal))?;
+  let mut text = String::new();
+  let mut chars = inner.chars();
+  while let Some(c) = chars.next() {
+    if c != '\\' {
+      text.push(c);
+      continue;
+    }
+    match chars.next() {
+      Some('n') => text.push('\n'),
+      Some('t') => text.push('\t'),
+      Some('r') => text.push('\r'),
+      Some('"') => text.push('"'),
+      Some('\\') => text.push('\\'),
+      Some('u') => {
+        let hex: String = chars.by_ref().take(4).collect();
+        let code = u32::from_str_radix(&hex, 16).map_err(|_| format!("bad escape \\u{}", hex))?;
+        text.push(char::from_u32(code).ok_or_else(|| format!("bad escape \\u{}", hex))?);
+      }
+      other => return Err(format!("bad escape \\{}", other.unwrap_or(' '))),
+    }
+  }
+  Ok(text)
+}
+
+/// How an instruction's operand is written after its opcode.
+#[derive(Copy, Clone)]
+enum Operand {
+  None,
+  Byte,
+  Short,
+  Local,
+  Constant,
+  Increment,
+  Branch,
+  Field,
+  Method,
+  ArrayType,
+  Class,
+}
+
+fn instruction(mnemonic: &str) -> Option<(u8, Operand)> {
+  let simple = [
+    ("nop", 0x00),
+    ("aconst_null", 0x01),
+    ("iconst_m1", 0x02),
+    ("iconst_0", 0x03),
+    ("iconst_1", 0x04),
+    ("iconst_2", 0x05),
+    ("iconst_3", 0x06),
+    ("iconst_4", 0x07),
+    ("iconst_5", 0x08),
+    ("iload_0", 0x1a),
+    ("iload_1", 0x1b),
+    ("iload_2", 0x1c),
+    ("iload_3", 0x1d),
+    ("aload_0", 0x2a),
+    ("aload_1", 0x2b),
+    ("aload_2", 0x2c),
+    ("aload_3", 0x2d),
+    ("iaload", 0x2e),
+    ("aaload", 0x32),
+    ("baload", 0x33),
+    ("caload", 0x34),
+    ("istore_0", 0x3b),
+    ("istore_1", 0x3c),
+    ("istore_2", 0x3d),
+    ("istore_3", 0x3e),
+    ("astore_0", 0x4b),
+    ("astore_1", 0x4c),
+    ("astore_2", 0x4d),
+    ("astore_3", 0x4e),
+    ("iastore", 0x4f),
+    ("aastore", 0x53),
+    ("bastore", 0x54),
+    ("castore", 0x55),
+    ("pop", 0x57),
+    ("pop2", 0x58),
+    ("dup", 0x59),
+    ("dup_x1", 0x5a),
+    ("dup_x2", 0x5b),
+    ("dup2", 0x5c),
+    ("swap", 0x5f),
+    ("iadd", 0x60),
+    ("isub", 0x64),
+    ("imul", 0x68),
+    ("idiv", 0x6c),
+    ("irem", 0x70),
+    ("ineg", 0x74),
+    ("ishl", 0x78),
+    ("ishr", 0x7a),
+    ("iushr", 0x7c),
+    ("iand", 0x7e),
+    ("ior", 0x80),
+    ("ixor", 0x82),
+    ("i2b", 0x91),
+    ("i2c", 0x92),
+    ("i2s", 0x93),
+    ("ireturn", 0xac),
+    ("areturn", 0xb0),
+    ("return", 0xb1),
+    ("arraylength", 0xbe),
+    ("athrow", 0xbf),
+  ];
+  if let Some(&(_, opcode)) = simple.iter().find(|(name, _)| *name == mnemonic) {
+    return Some((opcode, Operand::None));
+  }
+  Some(match mnemonic {
+    "bipush" => (0x10, Operand::Byte),
+    "sipush" => (0x11, Operand::Short),
+    "ldc" | "ldc_w" => (0x12, Operand::Constant),
+    "iload" => (0x15, Operand::Local),
+    "aload" => (0x19, Operand::Local),
+    "istore" => (0x36, Operand::Local),
+    "astore" => (0x3a, Operand::Local),
+    "iinc" => (0x84, Operand::Increment),
+    "ifeq" => (0x99, Operand::Branch),
+    "ifne" => (0x9a, Operand::Branch),
+    "iflt" => (0x9b, Operand::Branch),
+    "ifge" => (0x9c, Operand::Branch),
+    "ifgt" => (0x9d, Operand::Branch),
+    "ifle" => (0x9e, Operand::Branch),
+    "if_icmpeq" => (0x9f, Operand::Branch),
+    "if_icmpne" => (0xa0, Operand::Branch),
+    "if_icmplt" => (0xa1, Operand::Branch),
+    "if_icmpge" => (0xa2, Operand::Branch),
+    "if_icmpgt" => (0xa3, Operand::Branch),
+    "if_icmple" => (0xa4, Operand::Branch),
+    "goto" => (0xa7, Operand::Branch),
+    "ifnull" => (0xc6, Operand::Branch),
+    "ifnonnull" => (0xc7, Operand::Branch),
+    "getstatic" => (0xb2, Operand::Field),
+    "putstatic" => (0xb3, Operand::Field),
+    "getfield" => (0xb4, Operand::Field),
+    "putfield" => (0xb5, Operand::Field),
+    "invokevirtual" => (0xb6, Operand::Method),
+    "invokespecial" | "invokenonvirtual" => (0xb7, Operand::Method),
+    "invokestatic" => (0xb8, Operand::Method),
+    "new" => (0xbb, Operand::Class),
+    "newarray" => (0xbc, Operand::ArrayType),
+    "anewarray" => (0xbd, Operand::Class),
+    "checkcast" => (0xc0, Operand::Class),
+    _ => return None,
+  })
+}
+
+fn access_flags(words: &[&str]) -> Result<u16, String> {
+  words.iter().try_fold(0, |flags, word| {
+    Ok(
+      flags
+        | match *word {
+          "public" => 0x0001,
+          "private" => 0x0002,
+          "protected" => 0x0004,
+          "static" => 0x0008,
+          "final" => 0x0010,
+          other => return Err(format!("unknown access flag {}", other)),
+        },
+    )
+  })
+}
+
+struct Method {
+  access: u16,
+  name: u16,
+  descriptor: u16,
+  /// Name and descriptor as written, for working out the entry frame.
+  signature: (String, String),
+  max_stack: u16,
+  max_locals: u16,
+  code: Vec<u8>,
+  insns: Vec<Insn>,
+  labels: HashMap<String, usize>,
+  /// Branches waiting for their label: position of the opcode, label, line.
+  branches: Vec<(usize, String, usize)>,
+  /// The encoded `StackMapTable`, when the class version needs one.
+  stack_map: Option<Vec<u8>>,
+  /// Source lines from `.line` directives, by the offset they start at.
+  lines: Vec<(usize, u16)>,
+}
+
+impl Method {
+  fn resolve(&mut self) -> Result<(), String> {
+    for (at, label, line) in &self.branches {
+      let target = *self
+        .labels
+        .get(label)
+        .ok_or_else(|| format!("line {}: undefined label {}", line, label))?;
+      let offset = target as isize - *at as isize;
+      let offset = i16::try_from(offset)
+        .map_err(|_| format!("line {}: branch to {} is out of range", line, label))?;
+      self.code[at + 1..at + 3].copy_from_slice(&offset.to_be_bytes());
+    }
+    if self.code.len() > u16::MAX as usize {
+      return Err(format!(
+        "method code is {} bytes, the JVM allows at most 65535",
+        self.code.len()
+      ));
+    }
+    Ok(())
+  }
+
+  /// Encodes a full frame for every target of a jump in the method, if it
+  /// has any.
+  fn stack_map(&mut self, class: &str, pool: &mut ConstantPool) -> Result<(), String> {
+    let (name, descriptor) = &self.signature;
+    let entry = stackmap::entry(class, name, self.access & 0x0008 != 0, descriptor)?;
+    let frames = stackmap::frames(&self.insns, &self.labels, entry, class)
+      .map_err(|e| format!("method {}: {}", name, e))?;
+    if frames.is_empty() {
+      return Ok(());
+    }
+    let mut out = Vec::new();
+    put_u16(&mut out, frames.len() as u16);
+    let mut previous: Option<usize> = None;
+    for (offset, Frame { locals, stack }) in &frames {
+      out.push(255);
+      put_u16(
+        &mut out,
+        previous.map_or(*offset, |previous| offset - previous - 1) as u16,
+      );
+      previous = Some(*offset);
+      for types in [locals, stack] {
+        put_u16(&mut out, types.len() as u16);
+        for value in types {
+          match value {
+            Type::Top => out.push(0),
+            Type::Integer => out.push(1),
+            Type::Long => out.push(4),
+            Type::Null => out.push(5),
+            Type::UninitializedThis => out.push(6),
+            Type::Object(name) => {
+              out.push(7);
+              put_u16(&mut out, pool.class(name)?);
+            }
+            Type::Uninitialized(offset, _) => {
+              out.push(8);
+              put_u16(&mut out, *offset as u16);
+            }
+          }
+        }
+      }
+    }
+    self.stack_map = Some(out);
+    Ok(())
+  }
+}
+
+fn parse_int<T: std::str::FromStr>(text: &str, line: usize) -> Result<T, String> {
+  text
+    .parse()
+    .map_err(|_| format!("line {}: {} is out of range or not a number", line, text))
+}
+
+/// Assembles a `.j` file apart from the rest of the pipeline, as `asm`
+/// does. A file without any `.limit` directive, as written by hand, gets
+/// the ones its methods need.
+pub fn assemble_file(source: &str, version: u16) -> Result<(String, Vec<u8>), String> {
+  let has_limits = source
+    .lines()
+    .any(|line| line.trim_start().starts_with(".limit"));
+  if has_limits {
+    return assemble(source, version);
+  }
+  let mut limited = Vec::new();
+  limits::write_with_limits(source, &mut limited).map_err(|error| error.to_string())?;
+  assemble(&String::from_utf8_lossy(&limited), version)
+}
+
+/// Assembles Jasmin `source` into a class file of major `version` and
+/// returns the class name and the file.
+pub fn assemble(source: &str, version: u16) -> Result<(String, Vec<u8>), String> {
+  if !VERSIONS.contains(&version) {
+    return Err(format!(
+      "class file version {} is not supported, use {} to {}",
+      version,
+      VERSIONS.start(),
+      VERSIONS.end()
+    ));
+  }
+  let mut pool = ConstantPool::default();
+  let mut class = None;
+  let mut superclass = None;
+  let mut source_file = None;
+  // Access flags, name and descriptor of each field.
+  let mut fields: Vec<(u16, u16, u16)> = Vec::new();
+  let mut methods: Vec<Method> = Vec::new();
+  let mut method: Option<Method> = None;
+  for (number, text) in source.lines().enumerate() {
+    let line = number + 1;
+    let text = text.trim();
+    if text.is_empty() || text.starts_with(';') {
+      continue;
+    }
+    let (word, rest) = match text.find(char::is_whitespace) {
+      Some(split) => (&text[..split], text[split..].trim()),
+      None => (text, ""),
+    };
+    let args: Vec<&str> = rest.split_whitespace().collect();
+    if let Some(label) = word.strip_suffix(':').filter(|_| rest.is_empty()) {
+      let current = method
+        .as_mut()
+        .ok_or_else(|| format!("line {}: label outside a method", line))?;
+      if current
+        .labels
+        .insert(label.to_string(), current.code.len())
+        .is_some()
+      {
+        return Err(format!("line {}: label {} is already defined", line, label));
+      }
+      continue;
+    }
+    match word {
+      ".class" => {
+        let (name, flags) = args
+          .split_last()
+          .ok_or_else(|| format!("line {}: .class needs a name", line))?;
+        class = Some((name.to_string(), access_flags(flags)? | ACC_SUPER));
+        continue;
+      }
+      ".super" => {
+        superclass = Some(rest.to_string());
+        continue;
+      }
+      ".source" => {
+        source_file = Some(rest.to_string());
+        continue;
+      }
+      ".field" => {
+        let (descriptor, rest) = args
+          .split_last()
+          .ok_or_else(|| format!("line {}: .field needs a name and descriptor", line))?;
+        let (name, flags) = rest
+          .split_last()
+          .ok_or_else(|| format!("line {}: .field needs a name and descriptor", line))?;
+        fields.push((
+          access_flags(flags)?,
+          pool.utf8(name)?,
+          pool.utf8(descriptor)?,
+        ));
+        continue;
+      }
+      ".method" => {
+        let (signature, flags) = args
+          .split_last()
+          .ok_or_else(|| format!("line {}: .method needs a signature", line))?;
+        let split = signature
+          .find('(')
+          .ok_or_else(|| format!("line {}: method {} has no descriptor", line, signature))?;
+        method = Some(Method {
+          access: access_flags(flags)?,
+          name: pool.utf8(&signature[..split])?,
+          descriptor: pool.utf8(&signature[split..])?,
+          signature: (
+            signature[..split].to_string(),
+            signature[split..].to_string(),
+          ),
+          max_stack: 1,
+          max_locals: 1,
+          code: Vec::new(),
+          insns: Vec::new(),
+          labels: HashMap::new(),
+          branches: Vec::new(),
+          stack_map: None,
+          lines: Vec::new(),
+        });
+        continue;
+      }
+      ".end" if rest == "method" => {
+        let mut done = method
+          .take()
+          .ok_or_else(|| format!("line {}: .end method outside a method", line))?;
+        done.resolve()?;
+        if version >= FIRST_STACK_MAP_VERSION {
+          let class = &class
+            .as_ref()
+            .ok_or_else(|| format!("line {}: method before .class", line))?
+            .0;
+          done.stack_map(class, &mut pool)?;
+        }
+        methods.push(done);
+        continue;
+      }
+      _ => (),
+    }
+    let current = method
+      .as_mut()
+      .ok_or_else(|| format!("line {}: {} outside a method", line, word))?;
+    if word == ".limit" {
+      match args[..] {
+        ["stack", n] => current.max_stack = parse_int(n, line)?,
+        ["locals", n] => current.max_locals = parse_int(n, line)?,
+        _ => return Err(format!("line {}: unknown limit {}", line, rest)),
+      }
+      continue;
+    }
+    if word == ".line" {
+      let number = parse_int(rest, line)?;
+      let pc = current.code.len();
+      match current.lines.last_mut() {
+        Some(last) if last.0 == pc => last.1 = number,
+        _ => current.lines.push((pc, number)),
+      }
+      continue;
+    }
+    let (opcode, operand) =
+      instruction(word).ok_or_else(|| format!("line {}: unknown instruction {}", line, word))?;
+    let code = &mut current.code;
+    let start = code.len();
+    current.insns.push(Insn {
+      offset: start,
+      mnemonic: word.to_string(),
+      args: args.iter().map(|arg| arg.to_string()).collect(),
+    });
+    code.push(opcode);
+    match (operand, &args[..]) {
+      (Operand::None, []) => (),
+      (Operand::Byte, [n]) => code.push(parse_int::<i8>(n, line)? as u8),
+      (Operand::Short, [n]) => code.extend_from_slice(&parse_int::<i16>(n, line)?.to_be_bytes()),
+      (Operand::Local, [n]) => code.push(parse_int::<u8>(n, line)?),
+      (Operand::Constant, _) => {
+        let index = if rest.starts_with('"') {
+          let text = pool.utf8(&unquote(rest)?)?;
+          pool.add(Constant::String(text))?
+        } else {
+          pool.add(Constant::Integer(parse_int(rest, line)?))?
+        };
+        if index <= u8::MAX as u16 {
+          code.push(index as u8);
+        } else {
+          code[start] = 0x13;
+          put_u16(code, index);
+        }
+      }
+      (Operand::Increment, [local, n]) => {
+        let local: u16 = parse_int(local, line)?;
+        let n: i16 = parse_int(n, line)?;
+        match (u8::try_from(local), i8::try_from(n)) {
+          (Ok(local), Ok(n)) => code.extend_from_slice(&[local, n as u8]),
+          _ => {
+            code[start] = 0xc4;
+            code.push(opcode);
+            put_u16(code, local);
+            code.extend_from_slice(&n.to_be_bytes());
+          }
+        }
+      }
+      (Operand::Branch, [label]) => {
+        code.extend_from_slice(&[0, 0]);
+        current.branches.push((start, label.to_string(), line));
+      }
+      (Operand::Field, [path, descriptor]) => {
+        put_u16(code, pool.member(path, descriptor, false)?);
+      }
+      (Operand::Method, [signature]) => {
+        let split = signature
+          .find('(')
+          .ok_or_else(|| format!("line {}: method {} has no descriptor", line, signature))?;
+        put_u16(
+          code,
+          pool.member(&signature[..split], &signature[split..], true)?,
+        );
+      }
+      (Operand::ArrayType, [kind]) => code.push(match *kind {
+        "boolean" => 4,
+        "char" => 5,
+        "float" => 6,
+        "double" => 7,
+        "byte" => 8,
+        "short" => 9,
+        "int" => 10,
+        "long" => 11,
+        other => return Err(format!("line {}: unknown array type {}", line, other)),
+      }),
+      (Operand::Class, [name]) => put_u16(code, pool.class(name)?),
+      _ => return Err(format!("line {}: bad operands for {}", line, word)),
+    }
+  }
+  if method.is_some() {
+    return Err("missing .end method".to_string());
+  }
+  let (name, access) = class.ok_or("missing .class directive")?;
+  let this = pool.class(&name)?;
+  let superclass = pool.class(superclass.as_deref().unwrap_or("java/lang/Object"))?;
+  let code_name = pool.utf8("Code")?;
+  let mut bodies = Vec::new();
+  for method in &methods {
+    let mut attributes = Vec::new();
+    if let Some(table) = &method.stack_map {
+      attributes.push((pool.utf8("StackMapTable")?, table.clone()));
+    }
+    if !method.lines.is_empty() {
+      let mut table = Vec::new();
+      put_u16(&mut table, method.lines.len() as u16);
+      for &(pc, line) in &method.lines {
+        put_u16(&mut table, pc as u16);
+        put_u16(&mut table, line);
+      }
+      attributes.push((pool.utf8("LineNumberTable")?, table));
+    }
+    let mut body = Vec::new();
+    put_u16(&mut body, method.max_stack);
+    put_u16(&mut body, method.max_locals);
+    put_u32(&mut body, method.code.len() as u32);
+    body.extend_from_slice(&method.code);
+    put_u16(&mut body, 0);
+    put_attributes(&mut body, &attributes);
+    bodies.push(body);
+  }
+  let mut attributes = Vec::new();
+  if let Some(file) = &source_file {
+    let mut body = Vec::new();
+    put_u16(&mut body, pool.utf8(file)?);
+    attributes.push((pool.utf8("SourceFile")?, body));
+  }
+
+  let mut out = Vec::new();
+  put_u32(&mut out, 0xcafe_babe);
+  put_u16(&mut out, 0);
+  put_u16(&mut out, version);
+  pool.write(&mut out);
+  put_u16(&mut out, access);
+  put_u16(&mut out, this);
+  put_u16(&mut out, superclass);
+  put_u16(&mut out, 0);
+  put_u16(&mut out, fields.len() as u16);
+  for &(access, name, descriptor) in &fields {
+    put_u16(&mut out, access);
+    put_u16(&mut out, name);
+    put_u16(&mut out, descriptor);
+    put_u16(&mut out, 0);
+  }
+  put_u16(&mut out, methods.len() as u16);
+  for (method, body) in methods.iter().zip(bodies) {
+    put_u16(&mut out, method.access);
+    put_u16(&mut out, method.name);
+    put_u16(&mut out, method.descriptor);
+    put_attributes(&mut out, &[(code_name, body)]);
+  }
+  put_attributes(&mut out, &attributes);
+  Ok((name, out))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::jasmin::{produce_code, Config};
+  use super::super::{lex_dialect, parse_program, Dialect};
+  use super::{assemble, assemble_file, DEFAULT_VERSION};
+
+  fn class(code: &str, version: u16) -> (String, Vec<u8>) {
+    let program = lex_dialect(code, Dialect::Brainfuck)
+      .and_then(parse_program)
+      .unwrap();
+    let source = produce_code(program, &Config::default()).unwrap();
+    assemble(&source, version).unwrap()
+  }
+
+  fn contains(bytes: &[u8], text: &str) -> bool {
+    bytes
+      .windows(text.len())
+      .any(|window| window == text.as_bytes())
+  }
+
+  #[test]
+  fn classes_start_with_the_magic_and_the_version_asked_for() {
+    for version in [49, DEFAULT_VERSION, 65] {
+      let (_, bytes) = class("+[>+<-]>.", version);
+      assert_eq!(bytes[..4], [0xca, 0xfe, 0xba, 0xbe]);
+      assert_eq!(bytes[4..6], [0, 0]);
+      assert_eq!(bytes[6..8], version.to_be_bytes());
+    }
+  }
+
+  #[test]
+  fn stack_maps_are_written_from_version_50_on() {
+    assert!(!contains(&class("+[-].", 49).1, "StackMapTable"));
+    assert!(contains(&class("+[-].", 50).1, "StackMapTable"));
+    assert!(!contains(&class("+.", 50).1, "StackMapTable"));
+  }
+
+  #[test]
+  fn branches_are_resolved_to_their_labels() {
+    let source = "\
+.class public Loop
+.super java/lang/Object
+.method public static main([Ljava/lang/String;)V
+  iconst_0
+  istore_1
+top:
+  iload_1
+  ifne done
+  goto top
+done:
+  return
+.end method
+";
+    let (name, bytes) = assemble_file(source, 49).unwrap();
+    assert_eq!(name, "Loop");
+    // iconst_0 istore_1 iload_1 ifne +6 goto -4 return
+    let code = [0x03, 0x3c, 0x1b, 0x9a, 0, 6, 0xa7, 0xff, 0xfc, 0xb1];
+    assert!(bytes.windows(code.len()).any(|window| window == code));
+  }
+
+  #[test]
+  fn unknown_versions_and_labels_are_errors() {
+    assert!(assemble(".class public A", 48).is_err());
+    let source = "\
+.class public A
+.super java/lang/Object
+.method public static main([Ljava/lang/String;)V
+  .limit stack 1
+  goto nowhere
+.end method
+";
+    assert_eq!(
+      assemble(source, 49).unwrap_err(),
+      "line 5: undefined label nowhere"
+    );
+  }
+
+  #[test]
+  fn labels_defined_twice_are_errors() {
+    let source = "\
+.class public A
+.super java/lang/Object
+.method public static main([Ljava/lang/String;)V
+top:
+  goto top
+top:
+  return
+.end method
+";
+    assert_eq!(
+      assemble_file(source, 49).unwrap_err(),
+      "line 6: label top is already defined"
+    );
+    let limited = source.replace("top:\n  goto", "  .limit stack 1\ntop:\n  goto");
+    assert_eq!(
+      assemble(&limited, 49).unwrap_err(),
+      "line 7: label top is already defined"
+    );
+  }
+}
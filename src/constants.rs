@@ -0,0 +1,328 @@
+//! Constant-cell analysis.
+//!
+//! Abstractly interprets the IR starting from an all-zero tape, tracking the
+//! cells whose value is known at compile time. Known values let `.` print a
+//! constant and let loops entered on a known-zero cell be removed.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use super::optimizer::{link_jumps, Stats};
+use super::{Ebf, Inst, Op, Span, Tape};
+
+/// What is known about the tape at one point of the program. Positions are
+/// relative to an arbitrary frame; the frame is reset whenever the pointer
+/// position stops being statically known.
+#[derive(Clone)]
+pub(crate) struct State {
+  /// Cells mapped to `None` are known to be unknown even when `rest_zero`
+  /// holds.
+  cells: HashMap<isize, Option<i32>>,
+  pos: isize,
+  /// Whether cells missing from `cells` are known to be zero.
+  rest_zero: bool,
+}
+
+impl State {
+  pub fn new() -> State {
+    State {
+      cells: HashMap::new(),
+      pos: 0,
+      rest_zero: true,
+    }
+  }
+
+  pub fn get(&self, offset: i32) -> Option<i32> {
+    match self.cells.get(&(self.pos + offset as isize)) {
+      Some(&value) => value,
+      None if self.rest_zero => Some(0),
+      None => None,
+    }
+  }
+
+  pub fn current(&self) -> Option<i32> {
+    self.get(0)
+  }
+
+  /// Records a value; values outside a byte are forgotten, since cell
+  /// widths differ between backends.
+  fn set(&mut self, offset: i32, value: Option<i32>) {
+    let value = value.filter(|v| (0..=255).contains(v));
+    self.cells.insert(self.pos + offset as isize, value);
+  }
+
+  /// Forgets everything except that the current cell holds `current`.
+  fn reset(&mut self, current: Option<i32>) {
+    self.cells.clear();
+    self.pos = 0;
+    self.rest_zero = false;
+    self.cells.insert(0, current);
+  }
+
+  /// Applies one operation. Entering a loop body forgets everything and
+  /// leaving a loop keeps only that the current cell is zero. Nothing is
+  /// known across procedure definitions, calls, forks and tape switches,
+  /// nor of a cell whose value would overflow an `i32`.
+  pub fn apply(&mut self, op: &Op) {
+    match *op {
+      Op::Plus(count) => self.set(
+        0,
+        self
+          .current()
+          .and_then(|v| v.checked_add(i32::try_from(count).ok()?)),
+      ),
+      Op::Minus(count) => self.set(
+        0,
+        self
+          .current()
+          .and_then(|v| v.checked_sub(i32::try_from(count).ok()?)),
+      ),
+      Op::Right(count) => self.pos += count as isize,
+      Op::Left(count) => self.pos -= count as isize,
+      Op::SetZero => self.set(0, Some(0)),
+      Op::Add { offset, amount } => {
+        self.set(offset, self.get(offset).and_then(|v| v.checked_add(amount)))
+      }
+      Op::Set { offset, value } => self.set(offset, Some(value)),
+      Op::AddTo { offset, factor } => match self.current() {
+        Some(0) => (),
+        Some(value) => self.set(
+          offset,
+          self
+            .get(offset)
+            .and_then(|v| v.checked_add(value.checked_mul(factor)?)),
+        ),
+        None => self.set(offset, None),
+      },
+      Op::ReadChar(_) | Op::ReadNumber | Op::Random => self.set(0, None),
+      Op::PutChar(_) | Op::PutConst { .. } | Op::Print(_) | Op::Debug | Op::PutNumber => (),
+      Op::ScanZero { .. } => match self.current() {
+        Some(0) => (),
+        _ => self.reset(Some(0)),
+      },
+      Op::JumpIfZero(_) => self.reset(None),
+      Op::JumpIfNonZero(_) => self.reset(Some(0)),
+      Op::Procedure(_) | Op::Return(_) | Op::Call | Op::Fork => self.reset(None),
+      Op::Tape(Tape::Next) => self.reset(None),
+      Op::Tape(Tape::Copy) => (),
+      Op::Ebf(Ebf::End) | Op::Ebf(Ebf::Store) => (),
+      Op::Ebf(_) => self.set(0, None),
+    }
+  }
+}
+
+/// Largest number of instructions a single loop is unrolled into by default.
+pub const DEFAULT_UNROLL_LIMIT: usize = 64;
+
+/// Net change a loop body makes to the cell it was entered on, for bodies
+/// that are straight-line, return to that cell and never overwrite it, and
+/// whose change fits an `i32`.
+fn counter_step(body: &[Inst]) -> Option<i32> {
+  let mut offset: i32 = 0;
+  let mut step: i32 = 0;
+  for inst in body {
+    match inst.op {
+      Op::Plus(count) if offset == 0 => step = step.checked_add(i32::try_from(count).ok()?)?,
+      Op::Minus(count) if offset == 0 => step = step.checked_sub(i32::try_from(count).ok()?)?,
+      Op::Add { offset: o, amount } if offset.checked_add(o) == Some(0) => {
+        step = step.checked_add(amount)?
+      }
+      Op::Set { offset: o, .. } if offset.checked_add(o) == Some(0) => return None,
+      Op::Right(count) => offset = offset.checked_add(i32::try_from(count).ok()?)?,
+      Op::Left(count) => offset = offset.checked_sub(i32::try_from(count).ok()?)?,
+      Op::Plus(_)
+      | Op::Minus(_)
+      | Op::Add { .. }
+      | Op::Set { .. }
+      | Op::PutChar(_)
+      | Op::PutConst { .. }
+      | Op::Print(_) => (),
+      _ => return None,
+    }
+  }
+  if offset == 0 {
+    Some(step)
+  } else {
+    None
+  }
+}
+
+/// Folds output of known cells into `PutConst`, removes loops whose entry
+/// cell is known to be zero and unrolls loops whose trip count is known into
+/// at most `unroll_limit` instructions.
+pub fn constant_cells(mut program: Vec<Inst>, unroll_limit: usize, stats: &mut Stats) -> Vec<Inst> {
+  let mut instructions = Vec::with_capacity(program.len());
+  let mut state = State::new();
+  let mut pos = 0;
+  while pos < program.len() {
+    let mut inst = program[pos].clone();
+    match inst.op {
+      Op::JumpIfZero(end) if state.current() == Some(0) => {
+        let end = end as usize;
+        stats.note(
+          inst.span,
+          "loop entered on a cell proven zero, removed".to_string(),
+        );
+        pos = end + 1;
+        continue;
+      }
+      Op::JumpIfZero(end) => {
+        let end = end as usize;
+        let body = &program[pos + 1..end];
+        // Only counters that step down onto zero without wrapping, so that
+        // backends with wider cells agree on the trip count.
+        let trips = match (state.current(), counter_step(body)) {
+          (Some(value), Some(step)) if step < 0 && value % step == 0 => {
+            Some((value / -step) as usize)
+          }
+          _ => None,
+        };
+        if let Some(trips) = trips.filter(|trips| trips * body.len() <= unroll_limit) {
+          let unrolled: Vec<Inst> = body
+            .iter()
+            .cycle()
+            .take(trips * body.len())
+            .cloned()
+            .collect();
+          stats.note(
+            Span {
+              start: inst.span.start,
+              end: program[end].span.end,
+            },
+            format!("loop proven to run {} times, unrolled", trips),
+          );
+          program.splice(pos..=end, unrolled);
+          link_jumps(&mut program);
+          continue;
+        }
+        state.apply(&inst.op);
+      }
+      Op::PutChar(count) => {
+        if let Some(value) = state.current() {
+          inst.op = Op::PutConst {
+            value: value as u8,
+            count,
+          };
+          stats.note(
+            inst.span,
+            format!(
+              "cell proven to hold {}, output folded to {:?}",
+              value, value as u8 as char
+            ),
+          );
+        }
+      }
+      Op::ScanZero { .. } if state.current() == Some(0) => {
+        stats.note(
+          inst.span,
+          "scan starts on a cell proven zero, removed".to_string(),
+        );
+        pos += 1;
+        continue;
+      }
+      _ => state.apply(&inst.op),
+    }
+    instructions.push(inst);
+    pos += 1;
+  }
+  link_jumps(&mut instructions);
+  instructions
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::optimizer::Stats;
+  use super::super::{lex_dialect, parse_program, Dialect, Inst, Op, Span};
+  use super::{constant_cells, counter_step, State, DEFAULT_UNROLL_LIMIT};
+
+  fn folded(code: &str, unroll_limit: usize) -> Vec<Op> {
+    let program = lex_dialect(code, Dialect::Brainfuck)
+      .and_then(parse_program)
+      .unwrap();
+    constant_cells(program, unroll_limit, &mut Stats::default())
+      .into_iter()
+      .map(|inst| inst.op)
+      .collect()
+  }
+
+  fn has_loops(ops: &[Op]) -> bool {
+    ops.iter().any(|op| matches!(op, Op::JumpIfZero(_)))
+  }
+
+  #[test]
+  fn output_of_known_cells_is_folded() {
+    assert_eq!(
+      folded("+++.", DEFAULT_UNROLL_LIMIT),
+      [Op::Plus(3), Op::PutConst { value: 3, count: 1 }]
+    );
+    assert_eq!(folded(",.", DEFAULT_UNROLL_LIMIT)[1], Op::PutChar(1));
+  }
+
+  #[test]
+  fn loops_on_cells_proven_zero_are_removed() {
+    assert!(folded("[.]", DEFAULT_UNROLL_LIMIT).is_empty());
+    assert_eq!(
+      folded("+[-][.]", DEFAULT_UNROLL_LIMIT),
+      folded("+[-]", DEFAULT_UNROLL_LIMIT)
+    );
+  }
+
+  #[test]
+  fn loops_with_known_trip_counts_are_unrolled_within_the_limit() {
+    let code = "++[>+++<-]>.";
+    let unrolled = folded(code, DEFAULT_UNROLL_LIMIT);
+    assert!(!has_loops(&unrolled));
+    assert_eq!(unrolled.last(), Some(&Op::PutConst { value: 6, count: 1 }));
+    assert!(has_loops(&folded(code, 7)));
+    // 3 only reaches zero in steps of 2 by wrapping, so the loop is kept.
+    assert!(has_loops(&folded("+++[--]", DEFAULT_UNROLL_LIMIT)));
+  }
+
+  #[test]
+  fn loops_that_never_end_are_kept() {
+    let program = lex_dialect("+[]", Dialect::Brainfuck)
+      .and_then(parse_program)
+      .unwrap();
+    let instructions = constant_cells(program, DEFAULT_UNROLL_LIMIT, &mut Stats::default());
+    assert!(matches!(instructions[1].op, Op::JumpIfZero(_)));
+  }
+
+  #[test]
+  fn arithmetic_overflowing_an_i32_leaves_a_cell_unknown() {
+    let mut state = State::new();
+    state.apply(&Op::Plus(5));
+    state.apply(&Op::Add {
+      offset: 0,
+      amount: i32::MAX,
+    });
+    assert_eq!(state.current(), None);
+    state.apply(&Op::Set {
+      offset: 0,
+      value: 2,
+    });
+    state.apply(&Op::AddTo {
+      offset: 1,
+      factor: i32::MAX,
+    });
+    assert_eq!(state.get(1), None);
+    state.apply(&Op::Minus(u32::MAX));
+    assert_eq!(state.current(), None);
+  }
+
+  #[test]
+  fn a_step_overflowing_an_i32_is_no_counter() {
+    let body = |ops: Vec<Op>| -> Vec<Inst> {
+      ops
+        .into_iter()
+        .map(|op| Inst {
+          op,
+          span: Span::default(),
+        })
+        .collect()
+    };
+    let add = |amount| Op::Add { offset: 0, amount };
+    assert_eq!(counter_step(&body(vec![add(-1)])), Some(-1));
+    assert_eq!(counter_step(&body(vec![add(i32::MAX), add(1)])), None);
+    assert_eq!(counter_step(&body(vec![Op::Plus(u32::MAX)])), None);
+  }
+}
@@ -0,0 +1,121 @@
+//! Source coverage recorded by `run --coverage`: which commands of the
+//! program ran, written as an lcov tracefile for the usual report tools
+//! and as the source annotated the way `gcov` does.
+//!
+//! Commands are counted through the profile of an unoptimized run, so each
+//! was executed as often as the instruction folded from its run of
+//! commands. Runs that cancel out leave no instruction and are not counted
+//! at all.
+
+use super::profile::Profile;
+use super::{Inst, Span};
+
+/// How often each command, by position in `source`, ran.
+pub struct Coverage<'a> {
+  source: &'a str,
+  commands: Vec<(usize, u64)>,
+}
+
+impl<'a> Coverage<'a> {
+  /// The coverage of the commands at `positions` of `source` under
+  /// `profile`, taken running `program`. Positions past the end of
+  /// `source`, in files it includes, are left out.
+  pub fn new(
+    source: &'a str,
+    positions: &[usize],
+    program: &[Inst],
+    profile: &Profile,
+  ) -> Coverage<'a> {
+    let mut spans: Vec<Span> = program.iter().map(|inst| inst.span).collect();
+    spans.sort_by_key(|span| span.start);
+    let mut commands: Vec<(usize, u64)> = positions
+      .iter()
+      .filter(|&&pos| pos < source.len())
+      .filter(|&&pos| {
+        let after = spans.partition_point(|span| span.start as usize <= pos);
+        spans[..after]
+          .iter()
+          .rev()
+          .any(|span| pos < span.end as usize)
+      })
+      .map(|&pos| {
+        // No instruction comes from past the bytes a span can address.
+        let count = Span::new(pos, pos + 1).map_or(0, |span| profile.hotness(span));
+        (pos, count)
+      })
+      .collect();
+    // Macros can use commands defined further down.
+    commands.sort_unstable();
+    commands.dedup_by_key(|&mut (pos, _)| pos);
+    Coverage { source, commands }
+  }
+
+  /// Each line with commands, numbered from 1, with the count of the one
+  /// that ran most and the positions of those that never ran.
+  fn lines(&self) -> Vec<(usize, u64, Vec<usize>)> {
+    let starts: Vec<usize> = std::iter::once(0)
+      .chain(self.source.match_indices('\n').map(|(at, _)| at + 1))
+      .collect();
+    let mut lines: Vec<(usize, u64, Vec<usize>)> = Vec::new();
+    for &(pos, count) in &self.commands {
+      let line = starts.partition_point(|&start| start <= pos);
+      if lines.last().map(|&(last, _, _)| last) != Some(line) {
+        lines.push((line, 0, Vec::new()));
+      }
+      let entry = lines.last_mut().unwrap();
+      entry.1 = entry.1.max(count);
+      if count == 0 {
+        entry.2.push(pos);
+      }
+    }
+    lines
+  }
+
+  /// The lcov tracefile for the source at `path`.
+  pub fn lcov(&self, path: &str) -> String {
+    let lines = self.lines();
+    let mut text = format!("TN:\nSF:{}\n", path);
+    for (line, count, _) in &lines {
+      text.push_str(&format!("DA:{},{}\n", line, count));
+    }
+    let hit = lines.iter().filter(|&&(_, count, _)| count > 0).count();
+    text.push_str(&format!("LF:{}\nLH:{}\nend_of_record\n", lines.len(), hit));
+    text
+  }
+
+  /// The source with each line prefixed by how often it ran: `-` for
+  /// lines without commands and `#####` for lines whose commands never
+  /// ran. Below a line that ran only in part, `^` marks the commands that
+  /// did not.
+  pub fn annotate(&self) -> String {
+    let lines = self.lines();
+    let mut text = String::new();
+    let mut start = 0;
+    for (index, line) in self.source.split('\n').enumerate() {
+      let number = index + 1;
+      let end = start + line.len();
+      match lines.iter().find(|&&(n, _, _)| n == number) {
+        None if end == self.source.len() && line.is_empty() => break,
+        None => text.push_str(&format!("{:>9}:{:>5}:{}\n", "-", number, line)),
+        Some((_, 0, _)) => text.push_str(&format!("{:>9}:{:>5}:{}\n", "#####", number, line)),
+        Some((_, count, missed)) => {
+          text.push_str(&format!("{:>9}:{:>5}:{}\n", count, number, line));
+          if !missed.is_empty() {
+            let mut marks: String = line
+              .char_indices()
+              .map(|(at, c)| match c {
+                _ if missed.contains(&(start + at)) => '^',
+                '\t' => '\t',
+                _ => ' ',
+              })
+              .collect();
+            marks.truncate(marks.trim_end().len());
+            text.push_str(&format!("{:>9}:{:>5}:{}\n", "", "", marks));
+          }
+        }
+      }
+      start = end + 1;
+    }
+    text
+  }
+}
@@ -0,0 +1,292 @@
+//! Lowering of the folded IR to Cranelift, shared by the JIT and native
+//! executables.
+//!
+//! The generated `bf_main(tape, io_ctx, last_ptr) -> status` keeps the tape
+//! index in a register and turns each bracket pair into native branches; `.`
+//! and `,` call runtime functions, which the JIT provides in Rust and
+//! executables in Cranelift IR over libc. Each returns a status, and any
+//! status but `OK` ends the program.
+
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{types, AbiParam, InstBuilder, MemFlagsData, UserFuncName};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use cranelift_module::{DataDescription, FuncId, Linkage, Module, ModuleError};
+
+use super::{Inst, Op};
+
+/// Module errors are boxed, being much larger than anything else returned.
+pub type Result<T> = std::result::Result<T, Box<ModuleError>>;
+
+pub const OK: i32 = 0;
+pub const OFF_TAPE: i32 = 1;
+pub const IO_FAILED: i32 = 2;
+
+/// The runtime functions `bf_main` calls.
+pub struct Runtime {
+  /// `bf_putchar(io_ctx, byte: i8)`
+  pub putchar: FuncId,
+  /// `bf_getchar(io_ctx, cell: *mut u8)`
+  pub getchar: FuncId,
+  /// `bf_print(io_ctx, text: *const u8, len)`
+  pub print: FuncId,
+}
+
+/// Declares the runtime functions with `linkage`: `Import` when they are
+/// defined elsewhere, `Local` when the caller defines them in `module`.
+pub fn declare_runtime<M: Module>(module: &mut M, linkage: Linkage) -> Result<Runtime> {
+  let ptr_type = module.target_config().pointer_type();
+  let mut putchar_sig = module.make_signature();
+  putchar_sig.params.push(AbiParam::new(ptr_type));
+  putchar_sig.params.push(AbiParam::new(types::I8));
+  putchar_sig.returns.push(AbiParam::new(types::I32));
+  let mut getchar_sig = module.make_signature();
+  getchar_sig.params.push(AbiParam::new(ptr_type));
+  getchar_sig.params.push(AbiParam::new(ptr_type));
+  getchar_sig.returns.push(AbiParam::new(types::I32));
+  let mut print_sig = module.make_signature();
+  print_sig.params.push(AbiParam::new(ptr_type));
+  print_sig.params.push(AbiParam::new(ptr_type));
+  print_sig.params.push(AbiParam::new(ptr_type));
+  print_sig.returns.push(AbiParam::new(types::I32));
+  Ok(Runtime {
+    putchar: module.declare_function("bf_putchar", linkage, &putchar_sig)?,
+    getchar: module.declare_function("bf_getchar", linkage, &getchar_sig)?,
+    print: module.declare_function("bf_print", linkage, &print_sig)?,
+  })
+}
+
+/// Defines `bf_main` running `program` over a tape of `tape_size` bytes,
+/// which leaves the final pointer in `*last_ptr`.
+pub fn define_main<M: Module>(
+  module: &mut M,
+  runtime: &Runtime,
+  program: &[Inst],
+  tape_size: usize,
+) -> Result<FuncId> {
+  let ptr_type = module.target_config().pointer_type();
+  let mut sig = module.make_signature();
+  sig.params.push(AbiParam::new(ptr_type));
+  sig.params.push(AbiParam::new(ptr_type));
+  sig.params.push(AbiParam::new(ptr_type));
+  sig.returns.push(AbiParam::new(types::I32));
+  let main = module.declare_function("bf_main", Linkage::Local, &sig)?;
+
+  let mut ctx = module.make_context();
+  ctx.func.signature = sig;
+  ctx.func.name = UserFuncName::user(0, main.as_u32());
+  let mut func_ctx = FunctionBuilderContext::new();
+  {
+    let mut b = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+    let putchar = module.declare_func_in_func(runtime.putchar, b.func);
+    let getchar = module.declare_func_in_func(runtime.getchar, b.func);
+    let print = module.declare_func_in_func(runtime.print, b.func);
+    let flags = MemFlagsData::new();
+
+    let entry = b.create_block();
+    b.append_block_params_for_function_params(entry);
+    b.switch_to_block(entry);
+    b.seal_block(entry);
+    let tape = b.block_params(entry)[0];
+    let io_ctx = b.block_params(entry)[1];
+    let last_ptr = b.block_params(entry)[2];
+    let ptr = b.declare_var(ptr_type);
+    let zero = b.ins().iconst(ptr_type, 0);
+    b.def_var(ptr, zero);
+
+    let exit = b.create_block();
+    b.append_block_param(exit, types::I32);
+    let off_tape = b.create_block();
+    let mut loops = Vec::new();
+
+    for inst in program {
+      let index = b.use_var(ptr);
+      let addr = b.ins().iadd(tape, index);
+      match inst.op {
+        Op::Plus(count) | Op::Minus(count) => {
+          let delta = if let Op::Plus(_) = inst.op {
+            count as i64
+          } else {
+            -(count as i64)
+          };
+          let cell = b.ins().load(types::I8, flags, addr, 0);
+          let cell = b.ins().iadd_imm_s(cell, delta);
+          b.ins().store(flags, cell, addr, 0);
+        }
+        Op::Right(count) | Op::Left(count) => {
+          let delta = if let Op::Right(_) = inst.op {
+            count as i64
+          } else {
+            -(count as i64)
+          };
+          let index = b.ins().iadd_imm_s(index, delta);
+          b.def_var(ptr, index);
+          // Unsigned comparison also catches moves below zero.
+          let out = b
+            .ins()
+            .icmp_imm_s(IntCC::UnsignedGreaterThanOrEqual, index, tape_size as i64);
+          let next = b.create_block();
+          b.ins().brif(out, off_tape, &[], next, &[]);
+          b.switch_to_block(next);
+          b.seal_block(next);
+        }
+        Op::PutChar(count) | Op::ReadChar(count) => {
+          for _ in 0..count {
+            let call = if let Op::PutChar(_) = inst.op {
+              let cell = b.ins().load(types::I8, flags, addr, 0);
+              b.ins().call(putchar, &[io_ctx, cell])
+            } else {
+              b.ins().call(getchar, &[io_ctx, addr])
+            };
+            let status = b.inst_results(call)[0];
+            let next = b.create_block();
+            b.ins().brif(status, exit, &[status.into()], next, &[]);
+            b.switch_to_block(next);
+            b.seal_block(next);
+          }
+        }
+        Op::AddTo { offset, factor } => {
+          let value = b.ins().load(types::I8, flags, addr, 0);
+          let apply = b.create_block();
+          let next = b.create_block();
+          b.ins().brif(value, apply, &[], next, &[]);
+          b.switch_to_block(apply);
+          b.seal_block(apply);
+          let target = b.ins().iadd_imm_s(index, offset as i64);
+          let out = b
+            .ins()
+            .icmp_imm_s(IntCC::UnsignedGreaterThanOrEqual, target, tape_size as i64);
+          let in_bounds = b.create_block();
+          b.ins().brif(out, off_tape, &[], in_bounds, &[]);
+          b.switch_to_block(in_bounds);
+          b.seal_block(in_bounds);
+          let target_addr = b.ins().iadd(tape, target);
+          let cell = b.ins().load(types::I8, flags, target_addr, 0);
+          let product = b.ins().imul_imm_s(value, factor as i64);
+          let cell = b.ins().iadd(cell, product);
+          b.ins().store(flags, cell, target_addr, 0);
+          b.ins().jump(next, &[]);
+          b.switch_to_block(next);
+          b.seal_block(next);
+        }
+        Op::Add { offset, .. } | Op::Set { offset, .. } => {
+          let target = b.ins().iadd_imm_s(index, offset as i64);
+          let out = b
+            .ins()
+            .icmp_imm_s(IntCC::UnsignedGreaterThanOrEqual, target, tape_size as i64);
+          let in_bounds = b.create_block();
+          b.ins().brif(out, off_tape, &[], in_bounds, &[]);
+          b.switch_to_block(in_bounds);
+          b.seal_block(in_bounds);
+          let target_addr = b.ins().iadd(tape, target);
+          let cell = match inst.op {
+            Op::Add { amount, .. } => {
+              let cell = b.ins().load(types::I8, flags, target_addr, 0);
+              b.ins().iadd_imm_s(cell, amount as i64)
+            }
+            Op::Set { value, .. } => b.ins().iconst(types::I8, value as u8 as i64),
+            _ => unreachable!(),
+          };
+          b.ins().store(flags, cell, target_addr, 0);
+        }
+        Op::ScanZero { stride } => {
+          let header = b.create_block();
+          let advance = b.create_block();
+          let done = b.create_block();
+          b.ins().jump(header, &[]);
+          b.switch_to_block(header);
+          let index = b.use_var(ptr);
+          let addr = b.ins().iadd(tape, index);
+          let cell = b.ins().load(types::I8, flags, addr, 0);
+          b.ins().brif(cell, advance, &[], done, &[]);
+          b.switch_to_block(advance);
+          b.seal_block(advance);
+          let index = b.ins().iadd_imm_s(index, stride as i64);
+          b.def_var(ptr, index);
+          let out = b
+            .ins()
+            .icmp_imm_s(IntCC::UnsignedGreaterThanOrEqual, index, tape_size as i64);
+          b.ins().brif(out, off_tape, &[], header, &[]);
+          b.seal_block(header);
+          b.switch_to_block(done);
+          b.seal_block(done);
+        }
+        Op::PutConst { value, count } => {
+          for _ in 0..count {
+            let byte = b.ins().iconst(types::I8, value as i64);
+            let call = b.ins().call(putchar, &[io_ctx, byte]);
+            let status = b.inst_results(call)[0];
+            let next = b.create_block();
+            b.ins().brif(status, exit, &[status.into()], next, &[]);
+            b.switch_to_block(next);
+            b.seal_block(next);
+          }
+        }
+        Op::Print(ref text) => {
+          let data = module.declare_anonymous_data(false, false)?;
+          let mut description = DataDescription::new();
+          description.define(text.as_bytes().into());
+          module.define_data(data, &description)?;
+          let data = module.declare_data_in_func(data, b.func);
+          let text_ptr = b.ins().symbol_value(ptr_type, data);
+          let len = b.ins().iconst(ptr_type, text.as_bytes().len() as i64);
+          let call = b.ins().call(print, &[io_ctx, text_ptr, len]);
+          let status = b.inst_results(call)[0];
+          let next = b.create_block();
+          b.ins().brif(status, exit, &[status.into()], next, &[]);
+          b.switch_to_block(next);
+          b.seal_block(next);
+        }
+        Op::SetZero => {
+          let zero = b.ins().iconst(types::I8, 0);
+          b.ins().store(flags, zero, addr, 0);
+        }
+        Op::JumpIfZero(_) => {
+          let body = b.create_block();
+          let after = b.create_block();
+          let cell = b.ins().load(types::I8, flags, addr, 0);
+          b.ins().brif(cell, body, &[], after, &[]);
+          b.switch_to_block(body);
+          loops.push((body, after));
+        }
+        Op::Procedure(_)
+        | Op::Return(_)
+        | Op::Call
+        | Op::Fork
+        | Op::Ebf(_)
+        | Op::Debug
+        | Op::PutNumber
+        | Op::ReadNumber
+        | Op::Random
+        | Op::Tape(_) => {
+          unreachable!("rejected by reject_extensions")
+        }
+        Op::JumpIfNonZero(_) => {
+          let (body, after) = loops.pop().unwrap();
+          let cell = b.ins().load(types::I8, flags, addr, 0);
+          b.ins().brif(cell, body, &[], after, &[]);
+          b.seal_block(body);
+          b.switch_to_block(after);
+          b.seal_block(after);
+        }
+      }
+    }
+    let index = b.use_var(ptr);
+    b.ins().store(flags, index, last_ptr, 0);
+    let ok = b.ins().iconst(types::I32, OK as i64);
+    b.ins().jump(exit, &[ok.into()]);
+
+    b.switch_to_block(off_tape);
+    b.seal_block(off_tape);
+    let status = b.ins().iconst(types::I32, OFF_TAPE as i64);
+    b.ins().jump(exit, &[status.into()]);
+
+    b.switch_to_block(exit);
+    b.seal_block(exit);
+    let status = b.block_params(exit)[0];
+    b.ins().return_(&[status]);
+    b.finalize(module.target_config());
+  }
+  module.define_function(main, &mut ctx)?;
+  module.clear_context(&mut ctx);
+  Ok(main)
+}
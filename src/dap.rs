@@ -0,0 +1,725 @@
+//! The debug adapter behind `dap`, speaking the Debug Adapter Protocol over
+//! standard input and output so that editors can debug programs through
+//! the interpreter.
+//!
+//! `launch` takes the `program` to debug, the `input` it reads as a string
+//! and whether to `stopOnEntry`. The program runs unoptimized, one
+//! instruction per run of commands, and breakpoints stop it at the first
+//! instruction on or after their line. Stepping into runs one instruction,
+//! stepping over a `[` runs the whole loop and stepping out runs until the
+//! innermost loop is left. The tape shows as variables: the pointer and
+//! every cell up to the last one that is set or pointed at.
+//!
+//! Stepping back and continuing in reverse go to an earlier point of the
+//! run by restoring the last checkpoint before it, which the session takes
+//! every so many instructions, and replaying from there without output.
+//! Continuing in reverse stops at the last breakpoint passed, or at the
+//! start.
+//!
+//! Watchpoints are set from the debug console: `watch <n>` stops after any
+//! instruction that changes cell n, `watch ptr` does so for the cell the
+//! pointer is on, and `watch ptr <n>` stops when the pointer reaches cell
+//! n. `unwatch` takes the same arguments, or none to drop them all, and
+//! `watches` lists them. Each stop prints the old and new value and the
+//! position of the instruction responsible.
+//!
+//! Requests are read on another thread, so that the program can be paused
+//! or stopped while it runs.
+
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+
+use super::interpreter::{Eof, Interpreter, Snapshot, Step};
+use super::json::{read_message, write_message, Json};
+use super::report::position;
+use super::{has_forks, Inst, Op};
+
+/// Instructions run between looking for requests.
+const CHUNK: usize = 10_000;
+
+/// Instructions run between checkpoints at first. The interval doubles
+/// each time `MAX_CHECKPOINTS` are kept, dropping every other one.
+const CHECKPOINT_INTERVAL: u64 = 10_000;
+
+const MAX_CHECKPOINTS: usize = 1000;
+
+/// The only thread, which is the program's.
+const THREAD: usize = 1;
+
+/// The source of a program and its unoptimized instructions.
+pub type Loaded = (String, Vec<Inst>);
+
+/// How programs are loaded and run.
+pub struct Config<'a> {
+  /// Reads the file at a path.
+  pub load: &'a dyn Fn(&str) -> Result<Loaded, String>,
+  pub eof: Eof,
+}
+
+/// Where a running program stops of its own accord, besides breakpoints.
+#[derive(Copy, Clone)]
+enum Until {
+  /// At a breakpoint or the end.
+  Breakpoint,
+  /// After one instruction.
+  Step,
+  /// Once the program counter passes the instruction at this index.
+  Past(usize),
+  /// Right away.
+  Pause,
+}
+
+/// What a watchpoint stops at.
+#[derive(Copy, Clone, PartialEq)]
+enum Watch {
+  /// The cell at this index changing.
+  Cell(usize),
+  /// The pointer reaching this index.
+  Pointer(usize),
+}
+
+impl Watch {
+  fn describe(self) -> String {
+    match self {
+      Watch::Cell(index) => format!("cell {}", index),
+      Watch::Pointer(index) => format!("pointer at cell {}", index),
+    }
+  }
+}
+
+enum Outcome {
+  Running,
+  Stopped(&'static str),
+  Finished(io::Result<()>),
+}
+
+/// The client's side of the conversation.
+struct Client<'a> {
+  output: &'a mut dyn Write,
+  seq: usize,
+  /// What the client numbers the first line and column from.
+  line_base: usize,
+  column_base: usize,
+}
+
+impl Client<'_> {
+  fn send(&mut self, kind: &str, mut members: Vec<(&str, Json)>) -> io::Result<()> {
+    self.seq += 1;
+    members.insert(0, ("seq", self.seq.into()));
+    members.insert(1, ("type", kind.into()));
+    write_message(self.output, &Json::object(members))
+  }
+
+  fn respond(&mut self, request: &Json, result: Result<Json, String>) -> io::Result<()> {
+    let mut members = vec![
+      ("request_seq", request.get("seq").clone()),
+      ("command", request.get("command").clone()),
+      ("success", result.is_ok().into()),
+    ];
+    match result {
+      Ok(body) => members.push(("body", body)),
+      Err(message) => members.push(("message", message.into())),
+    }
+    self.send("response", members)
+  }
+
+  fn event(&mut self, event: &str, body: Json) -> io::Result<()> {
+    self.send("event", vec![("event", event.into()), ("body", body)])
+  }
+
+  fn output(&mut self, category: &str, output: &[u8]) -> io::Result<()> {
+    if output.is_empty() {
+      return Ok(());
+    }
+    let body = Json::object(vec![
+      ("category", category.into()),
+      (
+        "output",
+        String::from_utf8_lossy(output).into_owned().into(),
+      ),
+    ]);
+    self.event("output", body)
+  }
+
+  fn stopped(&mut self, reason: &str) -> io::Result<()> {
+    let body = Json::object(vec![
+      ("reason", reason.into()),
+      ("threadId", THREAD.into()),
+      ("allThreadsStopped", true.into()),
+    ]);
+    self.event("stopped", body)
+  }
+}
+
+/// The program after `steps` instructions, with `read` bytes of input read.
+struct Checkpoint {
+  steps: u64,
+  read: usize,
+  snapshot: Snapshot,
+}
+
+/// A launched program and where it is.
+struct Session<'a> {
+  path: String,
+  source: String,
+  program: &'a [Inst],
+  /// The one-based line each instruction starts on.
+  lines: Vec<usize>,
+  interpreter: Interpreter<'a>,
+  input: Vec<u8>,
+  /// Bytes of `input` read so far.
+  read: usize,
+  /// Instructions with a breakpoint.
+  breakpoints: Vec<usize>,
+  watches: Vec<Watch>,
+  /// What the watchpoint the program last stopped at saw.
+  watched: Option<String>,
+  running: Option<Until>,
+  /// Whether an instruction ran since the program last stopped, so that
+  /// resuming at a breakpoint does not stop at it again.
+  moved: bool,
+  finished: bool,
+  /// Instructions run since the start.
+  steps: u64,
+  checkpoints: Vec<Checkpoint>,
+  interval: u64,
+}
+
+impl Session<'_> {
+  /// The breakpoints requested by `arguments` of `setBreakpoints`.
+  fn set_breakpoints(&mut self, client: &Client, arguments: &Json) -> Json {
+    let path = arguments.get("source").get("path").as_str().unwrap_or("");
+    let ours = fs::canonicalize(path).ok() == fs::canonicalize(&self.path).ok();
+    self.breakpoints.clear();
+    let mut breakpoints = Vec::new();
+    for requested in arguments.get("breakpoints").as_array() {
+      let line = requested.get("line").as_u64().unwrap_or(0) as usize;
+      let wanted = (line + 1).saturating_sub(client.line_base);
+      let found = self
+        .lines
+        .iter()
+        .position(|&start| start >= wanted)
+        .filter(|_| ours);
+      breakpoints.push(match found {
+        Some(index) => {
+          self.breakpoints.push(index);
+          Json::object(vec![
+            ("id", (breakpoints.len() + 1).into()),
+            ("verified", true.into()),
+            ("line", (self.lines[index] - 1 + client.line_base).into()),
+          ])
+        }
+        None => Json::object(vec![
+          ("id", (breakpoints.len() + 1).into()),
+          ("verified", false.into()),
+          ("message", "no commands on or after this line".into()),
+        ]),
+      });
+    }
+    Json::object(vec![("breakpoints", Json::Array(breakpoints))])
+  }
+
+  /// The watchpoint `arguments` of `watch` or `unwatch` name.
+  fn watch(&self, arguments: &[&str]) -> Result<Watch, String> {
+    let index = |text: &str| {
+      text
+        .parse()
+        .map_err(|_| format!("{} is not a cell number", text))
+    };
+    match arguments {
+      ["ptr"] => Ok(Watch::Cell(self.interpreter.ptr())),
+      ["ptr", at] => index(at).map(Watch::Pointer),
+      [at] => index(at).map(Watch::Cell),
+      _ => Err("expected a cell number, ptr or ptr and a cell number".to_string()),
+    }
+  }
+
+  /// Runs a command typed in the debug console.
+  fn evaluate(&mut self, arguments: &Json) -> Result<Json, String> {
+    let expression = arguments.get("expression").as_str().unwrap_or("");
+    let words: Vec<&str> = expression.split_whitespace().collect();
+    let result = match words[..] {
+      ["watch", ref rest @ ..] => {
+        let watch = self.watch(rest)?;
+        if !self.watches.contains(&watch) {
+          self.watches.push(watch);
+        }
+        format!("watching {}", watch.describe())
+      }
+      ["unwatch"] => {
+        self.watches.clear();
+        "removed every watchpoint".to_string()
+      }
+      ["unwatch", ref rest @ ..] => {
+        let watch = self.watch(rest)?;
+        if !self.watches.contains(&watch) {
+          return Err(format!("{} is not watched", watch.describe()));
+        }
+        self.watches.retain(|&other| other != watch);
+        format!("stopped watching {}", watch.describe())
+      }
+      ["watches"] if self.watches.is_empty() => "no watchpoints".to_string(),
+      ["watches"] => {
+        let watches: Vec<String> = self.watches.iter().map(|watch| watch.describe()).collect();
+        watches.join("\n")
+      }
+      _ => return Err("expected watch, unwatch or watches".to_string()),
+    };
+    Ok(Json::object(vec![
+      ("result", result.into()),
+      ("variablesReference", 0.into()),
+    ]))
+  }
+
+  /// The cells the watchpoints watch, as they are.
+  fn watched_cells(&self) -> Vec<u8> {
+    let tape = self.interpreter.tape();
+    self
+      .watches
+      .iter()
+      .map(|&watch| match watch {
+        Watch::Cell(index) => tape.get(index).copied().unwrap_or(0),
+        Watch::Pointer(_) => 0,
+      })
+      .collect()
+  }
+
+  /// What the first watchpoint `step` set off saw, given the watched
+  /// cells and the pointer before it.
+  fn triggered(&self, cells: &[u8], ptr: usize, step: &Step) -> Option<String> {
+    let tape = self.interpreter.tape();
+    let now = self.interpreter.ptr();
+    let seen = self
+      .watches
+      .iter()
+      .zip(cells)
+      .find_map(|(&watch, &before)| match watch {
+        Watch::Cell(index) => {
+          let after = tape.get(index).copied().unwrap_or(0);
+          (after != before).then(|| format!("cell {}: {} -> {}", index, before, after))
+        }
+        Watch::Pointer(index) => {
+          (now == index && ptr != index).then(|| format!("pointer: cell {} -> cell {}", ptr, index))
+        }
+      })?;
+    let (line, column) = position(&self.source, step.inst.span.start as usize);
+    Some(format!(
+      "{}, by {:?} at line {}, column {}",
+      seen, step.inst.op, line, column
+    ))
+  }
+
+  fn stack_trace(&self, client: &Client) -> Json {
+    let pc = self.interpreter.pc();
+    let frames = match self.program.get(pc) {
+      Some(inst) if !self.finished => {
+        let (line, column) = position(&self.source, inst.span.start as usize);
+        vec![Json::object(vec![
+          ("id", 1.into()),
+          ("name", format!("{:?}", inst.op).into()),
+          (
+            "source",
+            Json::object(vec![("path", self.path.as_str().into())]),
+          ),
+          ("line", (line - 1 + client.line_base).into()),
+          ("column", (column - 1 + client.column_base).into()),
+        ])]
+      }
+      _ => Vec::new(),
+    };
+    Json::object(vec![
+      ("totalFrames", frames.len().into()),
+      ("stackFrames", Json::Array(frames)),
+    ])
+  }
+
+  fn variables(&self) -> Json {
+    let tape = self.interpreter.tape();
+    let ptr = self.interpreter.ptr();
+    let last = tape
+      .iter()
+      .rposition(|&cell| cell != 0)
+      .unwrap_or(0)
+      .max(ptr);
+    let variable = |name: String, value: String| {
+      Json::object(vec![
+        ("name", name.into()),
+        ("value", value.into()),
+        ("variablesReference", 0.into()),
+      ])
+    };
+    let mut variables = vec![variable("pointer".to_string(), ptr.to_string())];
+    for (index, &cell) in tape[..=last].iter().enumerate() {
+      variables.push(variable(format!("cell {}", index), cell.to_string()));
+    }
+    Json::object(vec![("variables", Json::Array(variables))])
+  }
+
+  /// Takes a checkpoint if one is due and there is none from here on.
+  fn checkpoint(&mut self) {
+    let taken = self
+      .checkpoints
+      .last()
+      .is_some_and(|last| last.steps >= self.steps);
+    if taken || !self.steps.is_multiple_of(self.interval) {
+      return;
+    }
+    if self.checkpoints.len() == MAX_CHECKPOINTS {
+      self.interval *= 2;
+      let interval = self.interval;
+      self
+        .checkpoints
+        .retain(|checkpoint| checkpoint.steps.is_multiple_of(interval));
+      if !self.steps.is_multiple_of(interval) {
+        return;
+      }
+    }
+    self.checkpoints.push(Checkpoint {
+      steps: self.steps,
+      read: self.read,
+      snapshot: self.interpreter.snapshot(),
+    });
+  }
+
+  /// Runs one instruction as it ran before, throwing its output away,
+  /// returning whether there was one.
+  fn replay_step(&mut self) -> bool {
+    let mut input = &self.input[self.read..];
+    let step = self.interpreter.step(&mut input, &mut io::sink());
+    self.read = self.input.len() - input.len();
+    let stepped = matches!(step, Ok(Some(_)));
+    if stepped {
+      self.steps += 1;
+    }
+    stepped
+  }
+
+  /// Goes to where the program was after `target` instructions.
+  fn travel(&mut self, target: u64) {
+    let Some(checkpoint) = self.checkpoints.iter().rev().find(|c| c.steps <= target) else {
+      return;
+    };
+    self.interpreter.restore(&checkpoint.snapshot);
+    self.read = checkpoint.read;
+    self.steps = checkpoint.steps;
+    while self.steps < target && self.replay_step() {}
+    self.running = None;
+    self.finished = false;
+  }
+
+  fn step_back(&mut self) -> &'static str {
+    self.travel(self.steps.saturating_sub(1));
+    "step"
+  }
+
+  /// Goes back to the last time the program stood at a breakpoint, or to
+  /// the start.
+  fn reverse_continue(&mut self) -> &'static str {
+    let now = self.steps;
+    let starts: Vec<u64> = self
+      .checkpoints
+      .iter()
+      .map(|checkpoint| checkpoint.steps)
+      .filter(|&steps| steps < now)
+      .collect();
+    for (at, &start) in starts.iter().enumerate().rev() {
+      let end = starts.get(at + 1).copied().unwrap_or(now);
+      self.travel(start);
+      let mut found = None;
+      while self.steps < end {
+        if self.breakpoints.contains(&self.interpreter.pc()) {
+          found = Some(self.steps);
+        }
+        if !self.replay_step() {
+          break;
+        }
+      }
+      if let Some(found) = found {
+        self.travel(found);
+        return "breakpoint";
+      }
+    }
+    self.travel(0);
+    "entry"
+  }
+
+  /// Runs up to `CHUNK` instructions, unless the program stops first.
+  fn advance(&mut self, output: &mut Vec<u8>) -> Outcome {
+    let Some(until) = self.running else {
+      return Outcome::Running;
+    };
+    for _ in 0..CHUNK {
+      let pc = self.interpreter.pc();
+      match until {
+        Until::Pause => return Outcome::Stopped("pause"),
+        Until::Step if self.moved => return Outcome::Stopped("step"),
+        Until::Past(end) if self.moved && pc > end => return Outcome::Stopped("step"),
+        _ if self.moved && self.breakpoints.contains(&pc) => return Outcome::Stopped("breakpoint"),
+        _ => (),
+      }
+      self.checkpoint();
+      let cells = self.watched_cells();
+      let ptr = self.interpreter.ptr();
+      let mut input = &self.input[self.read..];
+      let step = self.interpreter.step(&mut input, output);
+      self.read = self.input.len() - input.len();
+      match step {
+        Ok(Some(step)) => {
+          self.moved = true;
+          self.steps += 1;
+          if !self.watches.is_empty() {
+            self.watched = self.triggered(&cells, ptr, &step);
+            if self.watched.is_some() {
+              return Outcome::Stopped("data breakpoint");
+            }
+          }
+        }
+        Ok(None) => return Outcome::Finished(Ok(())),
+        Err(error) => return Outcome::Finished(Err(error)),
+      }
+    }
+    Outcome::Running
+  }
+
+  fn resume(&mut self, until: Until) {
+    if !self.finished {
+      self.running = Some(until);
+      self.moved = false;
+    }
+  }
+
+  /// Where stepping over stops: past the loop a `[` starts, or after the
+  /// instruction.
+  fn over(&self) -> Until {
+    match self.program.get(self.interpreter.pc()).map(|inst| &inst.op) {
+      Some(&Op::JumpIfZero(end)) => Until::Past(end as usize),
+      _ => Until::Step,
+    }
+  }
+
+  /// Where stepping out stops: past the innermost loop, or at the end.
+  fn out(&self) -> Until {
+    let pc = self.interpreter.pc();
+    let innermost = self.program[..pc.min(self.program.len())]
+      .iter()
+      .enumerate()
+      .rev()
+      .find_map(|(index, inst)| match inst.op {
+        Op::JumpIfZero(end) if index < pc && pc <= end as usize => Some(end as usize),
+        _ => None,
+      });
+    innermost.map_or(Until::Breakpoint, Until::Past)
+  }
+}
+
+fn capabilities() -> Json {
+  Json::object(vec![
+    ("supportsConfigurationDoneRequest", true.into()),
+    ("supportsStepBack", true.into()),
+  ])
+}
+
+/// Debugs the program `launch` asks for until the client disconnects.
+fn debug(
+  config: &Config,
+  client: &mut Client,
+  messages: &Receiver<Json>,
+  launch: &Json,
+) -> io::Result<()> {
+  let arguments = launch.get("arguments");
+  let path = arguments.get("program").as_str().unwrap_or("").to_string();
+  if path.is_empty() {
+    return client.respond(launch, Err("no program to launch".into()));
+  }
+  let (source, program) = match (config.load)(&path) {
+    Ok((_, program)) if has_forks(&program) => {
+      return client.respond(launch, Err("forking programs cannot be debugged".into()))
+    }
+    Ok(loaded) => loaded,
+    Err(error) => return client.respond(launch, Err(error)),
+  };
+  let mut session = Session {
+    lines: program
+      .iter()
+      .map(|inst| position(&source, inst.span.start as usize).0)
+      .collect(),
+    path,
+    source,
+    program: &program,
+    interpreter: Interpreter::new(&program).with_eof(config.eof),
+    input: arguments
+      .get("input")
+      .as_str()
+      .unwrap_or("")
+      .as_bytes()
+      .to_vec(),
+    read: 0,
+    breakpoints: Vec::new(),
+    watches: Vec::new(),
+    watched: None,
+    running: None,
+    moved: true,
+    finished: false,
+    steps: 0,
+    checkpoints: Vec::new(),
+    interval: CHECKPOINT_INTERVAL,
+  };
+  session.checkpoint();
+  let stop_on_entry = arguments.get("stopOnEntry").as_bool().unwrap_or(false);
+  client.respond(launch, Ok(Json::Null))?;
+  client.event("initialized", Json::Null)?;
+  loop {
+    let message = match session.running {
+      Some(_) => match messages.try_recv() {
+        Ok(message) => Some(message),
+        Err(TryRecvError::Empty) => None,
+        Err(TryRecvError::Disconnected) => return Ok(()),
+      },
+      None => match messages.recv() {
+        Ok(message) => Some(message),
+        Err(_) => return Ok(()),
+      },
+    };
+    if let Some(request) = message {
+      let arguments = request.get("arguments");
+      let result = match request.get("command").as_str().unwrap_or("") {
+        "configurationDone" => {
+          if stop_on_entry {
+            client.respond(&request, Ok(Json::Null))?;
+            client.stopped("entry")?;
+            continue;
+          }
+          session.running = Some(Until::Breakpoint);
+          Ok(Json::Null)
+        }
+        "setBreakpoints" => Ok(session.set_breakpoints(client, arguments)),
+        "threads" => Ok(Json::object(vec![(
+          "threads",
+          Json::Array(vec![Json::object(vec![
+            ("id", THREAD.into()),
+            ("name", "main".into()),
+          ])]),
+        )])),
+        "stackTrace" => Ok(session.stack_trace(client)),
+        "scopes" => Ok(Json::object(vec![(
+          "scopes",
+          Json::Array(vec![Json::object(vec![
+            ("name", "Tape".into()),
+            ("variablesReference", 1.into()),
+            ("expensive", false.into()),
+          ])]),
+        )])),
+        "variables" => Ok(session.variables()),
+        "evaluate" => session.evaluate(arguments),
+        "continue" => {
+          session.resume(Until::Breakpoint);
+          Ok(Json::object(vec![("allThreadsContinued", true.into())]))
+        }
+        "next" => {
+          session.resume(session.over());
+          Ok(Json::Null)
+        }
+        "stepIn" => {
+          session.resume(Until::Step);
+          Ok(Json::Null)
+        }
+        "stepOut" => {
+          session.resume(session.out());
+          Ok(Json::Null)
+        }
+        "stepBack" => {
+          let reason = session.step_back();
+          client.respond(&request, Ok(Json::Null))?;
+          client.stopped(reason)?;
+          continue;
+        }
+        "reverseContinue" => {
+          let reason = session.reverse_continue();
+          client.respond(&request, Ok(Json::Null))?;
+          client.stopped(reason)?;
+          continue;
+        }
+        "pause" => {
+          if session.running.is_some() {
+            session.running = Some(Until::Pause);
+          }
+          Ok(Json::Null)
+        }
+        "disconnect" | "terminate" => return client.respond(&request, Ok(Json::Null)),
+        "initialize" => Ok(capabilities()),
+        command => Err(format!("unsupported request {}", command)),
+      };
+      client.respond(&request, result)?;
+      continue;
+    }
+    let mut output = Vec::new();
+    let outcome = session.advance(&mut output);
+    client.output("stdout", &output)?;
+    match outcome {
+      Outcome::Running => (),
+      Outcome::Stopped(reason) => {
+        session.running = None;
+        if let Some(watched) = session.watched.take() {
+          client.output("console", format!("{}\n", watched).as_bytes())?;
+        }
+        client.stopped(reason)?;
+      }
+      Outcome::Finished(result) => {
+        session.running = None;
+        session.finished = true;
+        let code = match result {
+          Ok(()) => 0,
+          Err(error) => {
+            client.output("stderr", format!("{}\n", error).as_bytes())?;
+            1
+          }
+        };
+        client.event("exited", Json::object(vec![("exitCode", code.into())]))?;
+        client.event("terminated", Json::Null)?;
+      }
+    }
+  }
+}
+
+/// Serves the client writing to `input` and reading from `output` until
+/// it disconnects or closes `input`.
+pub fn serve(
+  config: &Config,
+  input: impl BufRead + Send + 'static,
+  output: &mut dyn Write,
+) -> io::Result<()> {
+  let (sender, messages) = mpsc::channel();
+  std::thread::spawn(move || {
+    let mut input = input;
+    while let Ok(Some(message)) = read_message(&mut input) {
+      if sender.send(message).is_err() {
+        break;
+      }
+    }
+  });
+  let mut client = Client {
+    output,
+    seq: 0,
+    line_base: 1,
+    column_base: 1,
+  };
+  while let Ok(request) = messages.recv() {
+    match request.get("command").as_str().unwrap_or("") {
+      "initialize" => {
+        let arguments = request.get("arguments");
+        let base = |name| usize::from(arguments.get(name).as_bool().unwrap_or(true));
+        client.line_base = base("linesStartAt1");
+        client.column_base = base("columnsStartAt1");
+        client.respond(&request, Ok(capabilities()))?;
+      }
+      "launch" => return debug(config, &mut client, &messages, &request),
+      "disconnect" | "terminate" => return client.respond(&request, Ok(Json::Null)),
+      command => {
+        let error = Err(format!("{} before launch", command));
+        client.respond(&request, error)?;
+      }
+    }
+  }
+  Ok(())
+}
@@ -0,0 +1,250 @@
+//! Android output: the generated class restricted to what D8 accepts, and
+//! converted to `classes.dex` when D8 is installed.
+//!
+//! The generated code itself needs nothing Android lacks: it only uses
+//! `System.in`, `System.out`, `PrintStream`, `BufferedInputStream` and
+//! `System.exit`, and never `invokedynamic`. What D8 does care about is the
+//! class file version.
+//!
+//! The `classes.dex` D8 writes is checked before it is reported: its header
+//! must give its own size and layout, and the Adler-32 checksum and SHA-1
+//! signature it carries must match the bytes after them.
+
+use std::io::{self, ErrorKind};
+use std::process::Command;
+
+/// The newest class file version every D8 release reads.
+pub const MAX_VERSION: u16 = 52;
+
+/// The size of a dex header, which is also where the file's data may start.
+const HEADER_SIZE: usize = 0x70;
+const ENDIAN_CONSTANT: u32 = 0x1234_5678;
+
+/// Fails when `version` is newer than D8 is guaranteed to read.
+pub fn check_version(version: u16) -> Result<(), String> {
+  if version > MAX_VERSION {
+    Err(format!(
+      "class version {} is newer than D8 reads; use {} or older",
+      version, MAX_VERSION
+    ))
+  } else {
+    Ok(())
+  }
+}
+
+/// Runs `d8` on `class`, writing `classes.dex` into `out_dir`. Returns
+/// `false` when `d8` could not be found, and an error when it ran and failed.
+pub fn run_d8(d8: &str, class: &str, out_dir: &str) -> io::Result<bool> {
+  let status = match Command::new(d8)
+    .args(["--release", "--output", out_dir, class])
+    .status()
+  {
+    Ok(status) => status,
+    Err(error) if error.kind() == ErrorKind::NotFound => return Ok(false),
+    Err(error) => return Err(error),
+  };
+  if status.success() {
+    Ok(true)
+  } else {
+    Err(io::Error::other(format!("{} failed with {}", d8, status)))
+  }
+}
+
+fn adler32(data: &[u8]) -> u32 {
+  let (mut a, mut b) = (1u32, 0u32);
+  for chunk in data.chunks(5552) {
+    for &byte in chunk {
+      a += byte as u32;
+      b += a;
+    }
+    a %= 65521;
+    b %= 65521;
+  }
+  (b << 16) | a
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+  let mut state: [u32; 5] = [
+    0x6745_2301,
+    0xefcd_ab89,
+    0x98ba_dcfe,
+    0x1032_5476,
+    0xc3d2_e1f0,
+  ];
+  let mut message = data.to_vec();
+  message.push(0x80);
+  while message.len() % 64 != 56 {
+    message.push(0);
+  }
+  message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+  for block in message.chunks(64) {
+    let mut w = [0u32; 80];
+    for (i, word) in block.chunks(4).enumerate() {
+      w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+    }
+    for i in 16..80 {
+      w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+    }
+    let [mut a, mut b, mut c, mut d, mut e] = state;
+    for (i, &word) in w.iter().enumerate() {
+      let (f, k) = match i {
+        0..=19 => ((b & c) | (!b & d), 0x5a82_7999),
+        20..=39 => (b ^ c ^ d, 0x6ed9_eba1),
+        40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1b_bcdc),
+        _ => (b ^ c ^ d, 0xca62_c1d6),
+      };
+      let t = a
+        .rotate_left(5)
+        .wrapping_add(f)
+        .wrapping_add(e)
+        .wrapping_add(k)
+        .wrapping_add(word);
+      e = d;
+      d = c;
+      c = b.rotate_left(30);
+      b = a;
+      a = t;
+    }
+    for (value, add) in state.iter_mut().zip([a, b, c, d, e]) {
+      *value = value.wrapping_add(add);
+    }
+  }
+  let mut digest = [0; 20];
+  for (bytes, value) in digest.chunks_mut(4).zip(state) {
+    bytes.copy_from_slice(&value.to_be_bytes());
+  }
+  digest
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+  u32::from_le_bytes([
+    bytes[offset],
+    bytes[offset + 1],
+    bytes[offset + 2],
+    bytes[offset + 3],
+  ])
+}
+
+/// Fails unless `bytes` start with a dex header that describes them: the
+/// magic and format version, the file and header sizes, the byte order, a
+/// map inside the file and the checksum and signature of what follows each.
+pub fn check_dex(bytes: &[u8]) -> Result<(), String> {
+  if bytes.len() < HEADER_SIZE {
+    return Err(format!(
+      "{} bytes are too few for a dex header",
+      bytes.len()
+    ));
+  }
+  let version = &bytes[4..7];
+  if &bytes[..4] != b"dex\n" || !version.iter().all(u8::is_ascii_digit) || bytes[7] != 0 {
+    return Err("no dex magic".to_string());
+  }
+  if u32_at(bytes, 32) as usize != bytes.len() {
+    return Err(format!(
+      "the header gives a size of {} bytes, the file has {}",
+      u32_at(bytes, 32),
+      bytes.len()
+    ));
+  }
+  if u32_at(bytes, 36) as usize != HEADER_SIZE {
+    return Err(format!(
+      "header size {} is not {}",
+      u32_at(bytes, 36),
+      HEADER_SIZE
+    ));
+  }
+  if u32_at(bytes, 40) != ENDIAN_CONSTANT {
+    return Err(format!(
+      "endian tag {:#x} is not little-endian",
+      u32_at(bytes, 40)
+    ));
+  }
+  let map = u32_at(bytes, 52) as usize;
+  if map < HEADER_SIZE || map >= bytes.len() {
+    return Err(format!("map offset {:#x} is outside the data", map));
+  }
+  if sha1(&bytes[32..])[..] != bytes[12..32] {
+    return Err("the SHA-1 signature does not match".to_string());
+  }
+  if adler32(&bytes[12..]) != u32_at(bytes, 8) {
+    return Err("the Adler-32 checksum does not match".to_string());
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{adler32, check_dex, sha1, HEADER_SIZE};
+
+  /// A dex file without classes: the header and a map listing only it,
+  /// signed and checksummed by `hashlib.sha1` and `zlib.adler32`.
+  fn empty_dex() -> Vec<u8> {
+    let mut bytes = b"dex\n035\0".to_vec();
+    bytes.extend_from_slice(&0x25d7_0d3c_u32.to_le_bytes());
+    bytes.extend_from_slice(&[
+      0x68, 0x19, 0xc9, 0xab, 0x8c, 0x10, 0xe8, 0x6a, 0x63, 0xb8, 0x5d, 0xf7, 0x8a, 0x7e, 0xde,
+      0x5f, 0x03, 0x24, 0x8d, 0xfa,
+    ]);
+    let mut fields = [0u32; 20];
+    fields[0] = 128; // file_size
+    fields[1] = HEADER_SIZE as u32;
+    fields[2] = 0x1234_5678;
+    fields[5] = HEADER_SIZE as u32; // map_off
+    fields[18] = 16; // data_size
+    fields[19] = HEADER_SIZE as u32; // data_off
+    for field in fields {
+      bytes.extend_from_slice(&field.to_le_bytes());
+    }
+    // One map item: the header, at offset 0.
+    for word in [1, 0, 1, 0] {
+      bytes.extend_from_slice(&(word as u32).to_le_bytes());
+    }
+    bytes
+  }
+
+  #[test]
+  fn checksums_match_known_values() {
+    assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    assert_eq!(
+      sha1(b"abc"),
+      [
+        0xa9, 0x99, 0x3e, 0x36, 0x47, 0x06, 0x81, 0x6a, 0xba, 0x3e, 0x25, 0x71, 0x78, 0x50, 0xc2,
+        0x6c, 0x9c, 0xd0, 0xd8, 0x9d,
+      ]
+    );
+    // Many blocks long.
+    assert_eq!(sha1(&[b'a'; 1000])[..2], [0x29, 0x1e]);
+  }
+
+  #[test]
+  fn a_well_formed_dex_passes() {
+    let dex = empty_dex();
+    assert_eq!(dex.len(), 128);
+    assert_eq!(check_dex(&dex), Ok(()));
+  }
+
+  #[test]
+  fn damaged_dex_files_fail() {
+    let dex = empty_dex();
+    let mut changed = dex.clone();
+    changed[120] ^= 1;
+    assert_eq!(
+      check_dex(&changed),
+      Err("the SHA-1 signature does not match".to_string())
+    );
+    let mut changed = dex.clone();
+    changed[12] ^= 1;
+    assert!(check_dex(&changed).is_err());
+    let mut changed = dex.clone();
+    changed[8] ^= 1;
+    assert_eq!(
+      check_dex(&changed),
+      Err("the Adler-32 checksum does not match".to_string())
+    );
+    assert!(check_dex(&dex[..100]).is_err());
+    assert!(check_dex(&dex[..127]).is_err());
+    let mut changed = dex;
+    changed[0] = b'D';
+    assert_eq!(check_dex(&changed), Err("no dex magic".to_string()));
+  }
+}
@@ -0,0 +1,265 @@
+//! Partial evaluation of the input-free prefix of a program.
+//!
+//! The prefix is run through the interpreter at compile time, up to the first
+//! `,`, `;`, `?`, procedure definition, fork, `$`, `#`, `%` or `=` or until
+//! a step budget runs out, and replaced by a single `Print` of its output plus
+//! the stores needed to recreate the tape it left behind.
+
+use std::io;
+
+use super::interpreter::{Interpreter, Io};
+use super::optimizer::{link_jumps, Stats};
+use super::{Ebf, Inst, Op, Span, Text};
+
+/// Default number of instructions evaluated at compile time.
+pub const DEFAULT_BUDGET: usize = 1_000_000;
+
+/// Loop and procedure nesting depth before each instruction, where the
+/// bracket closing a loop or procedure counts as inside it.
+fn depths(program: &[Inst]) -> Vec<usize> {
+  let mut depth = 0;
+  program
+    .iter()
+    .map(|inst| {
+      let here = depth;
+      match inst.op {
+        Op::JumpIfZero(_) | Op::Procedure(_) => depth += 1,
+        Op::JumpIfNonZero(_) | Op::Return(_) => depth -= 1,
+        _ => (),
+      }
+      here
+    })
+    .collect()
+}
+
+/// Runs `program` on a tape of `tape_size` cells for at most `budget` steps
+/// without reading input and returns how many steps were taken when the
+/// program counter was last at a top-level instruction, where execution can
+/// be cut and resumed.
+fn resumable_steps(program: &[Inst], budget: usize, tape_size: usize) -> usize {
+  let depths = depths(program);
+  let mut interpreter = Interpreter::new(program).with_tape_size(tape_size);
+  let mut last = 0;
+  for steps in 0..=budget {
+    let pc = interpreter.pc();
+    if pc == program.len() || depths[pc] == 0 {
+      last = steps;
+    }
+    // Neither defined procedures, threads, other tapes nor the Extended
+    // Brainfuck storage cell are part of the state recreated, and dumps and random
+    // bytes are left for run time.
+    let stops = matches!(
+      program.get(pc),
+      Some(Inst {
+        op: Op::ReadChar(_)
+          | Op::ReadNumber
+          | Op::Random
+          | Op::Procedure(_)
+          | Op::Fork
+          | Op::Tape(_)
+          | Op::Ebf(Ebf::Store)
+          | Op::Debug,
+        ..
+      })
+    );
+    if steps == budget || pc == program.len() || stops {
+      break;
+    }
+    if interpreter.step(&mut io::empty(), &mut io::sink()).is_err() {
+      break;
+    }
+  }
+  last
+}
+
+pub fn partial_eval(
+  program: Vec<Inst>,
+  budget: usize,
+  tape_size: usize,
+  stats: &mut Stats,
+) -> Vec<Inst> {
+  let steps = resumable_steps(&program, budget, tape_size);
+  if steps == 0 {
+    return program;
+  }
+  let mut output = Vec::new();
+  // The bytes printed, which every backend writes out as they are.
+  let mut interpreter = Interpreter::new(&program)
+    .with_tape_size(tape_size)
+    .with_io(Io::Bytes);
+  for _ in 0..steps {
+    // Steps up to a resumable point were all successful the first time.
+    interpreter.step(&mut io::empty(), &mut output).unwrap();
+  }
+  let pc = interpreter.pc();
+  let span = Span {
+    start: program[0].span.start,
+    end: program[pc - 1].span.end,
+  };
+  let mut instructions = Vec::new();
+  stats.note(
+    span,
+    format!(
+      "evaluated {} steps at compile time, printing {} bytes",
+      steps,
+      output.len()
+    ),
+  );
+  if !output.is_empty() {
+    instructions.push(Inst {
+      op: Op::Print(Text::new(&output)),
+      span,
+    });
+  }
+  if pc < program.len() {
+    for (offset, &value) in interpreter.tape().iter().enumerate() {
+      if value != 0 {
+        instructions.push(Inst {
+          op: Op::Set {
+            offset: offset as i32,
+            value: value as i32,
+          },
+          span,
+        });
+      }
+    }
+    if interpreter.ptr() > 0 {
+      instructions.push(Inst {
+        op: Op::Right(interpreter.ptr() as u32),
+        span,
+      });
+    }
+  }
+  instructions.extend_from_slice(&program[pc..]);
+  link_jumps(&mut instructions);
+  instructions
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io;
+
+  use super::super::constants;
+  use super::super::interpreter::{Interpreter, Io, TAPE_SIZE};
+  use super::super::optimizer::{self, Level, Stats};
+  use super::super::{lex_dialect, parse_program, Dialect, Inst, Op, Text};
+  use super::{partial_eval, DEFAULT_BUDGET};
+
+  /// Prints 255 and 254 by decrementing zero, then 126 and 128 built by
+  /// multiplication loops.
+  const HIGH: &str = "-.-.>++++++++[<---------------->-]<.[-]++++++++[>++++++++++++++++<-]>.";
+
+  fn output(level: Level, io: Io) -> Vec<u8> {
+    let program = lex_dialect(HIGH, Dialect::Brainfuck)
+      .and_then(parse_program)
+      .unwrap();
+    let (instructions, _) = optimizer::optimize(
+      program,
+      &optimizer::preset(level, true),
+      DEFAULT_BUDGET,
+      constants::DEFAULT_UNROLL_LIMIT,
+    );
+    let mut output = Vec::new();
+    Interpreter::new(&instructions)
+      .with_io(io)
+      .run(&mut &b""[..], &mut output, None)
+      .unwrap();
+    output
+  }
+
+  #[test]
+  fn prints_the_bytes_cells_hold() {
+    let program = lex_dialect(HIGH, Dialect::Brainfuck)
+      .and_then(parse_program)
+      .unwrap();
+    let instructions = partial_eval(program, DEFAULT_BUDGET, TAPE_SIZE, &mut Stats::default());
+    assert_eq!(
+      instructions[0].op,
+      Op::Print(Text::new(&[0xff, 0xfe, 0x7e, 0x80]))
+    );
+  }
+
+  /// Runs `code` as it is and partially evaluated with `budget`.
+  fn evaluated(code: &str, budget: usize) -> (Vec<Inst>, Vec<u8>, Vec<u8>) {
+    let program = lex_dialect(code, Dialect::Brainfuck)
+      .and_then(parse_program)
+      .unwrap();
+    // Endless programs are compared on what they print in their first steps.
+    let run = |instructions: &[Inst]| {
+      let mut output = Vec::new();
+      let mut interpreter = Interpreter::new(instructions).with_io(Io::Bytes);
+      for _ in 0..10_000 {
+        if let Ok(None) | Err(_) = interpreter.step(&mut io::empty(), &mut output) {
+          break;
+        }
+      }
+      output
+    };
+    let expected = run(&program);
+    let instructions = partial_eval(program, budget, TAPE_SIZE, &mut Stats::default());
+    let actual = run(&instructions);
+    (instructions, expected, actual)
+  }
+
+  #[test]
+  fn budgets_running_out_inside_loops_cut_before_them() {
+    let code = "++++++++[>++++++++<-]>.";
+    for budget in 0..40 {
+      let (instructions, expected, actual) = evaluated(code, budget);
+      assert_eq!(actual, expected, "budget {}", budget);
+      let opens = instructions
+        .iter()
+        .filter(|inst| matches!(inst.op, Op::JumpIfZero(_)))
+        .count();
+      let closes = instructions
+        .iter()
+        .filter(|inst| matches!(inst.op, Op::JumpIfNonZero(_)))
+        .count();
+      assert_eq!(opens, closes, "budget {}", budget);
+    }
+  }
+
+  #[test]
+  fn a_prefix_running_off_a_smaller_tape_is_left_to_run_time() {
+    let code = format!("{}+.", ">".repeat(100));
+    let program = lex_dialect(&code, Dialect::Brainfuck)
+      .and_then(parse_program)
+      .unwrap();
+    let instructions = partial_eval(program.clone(), DEFAULT_BUDGET, 50, &mut Stats::default());
+    assert!(!instructions
+      .iter()
+      .any(|inst| matches!(inst.op, Op::Print(_))));
+    let mut output = Vec::new();
+    let error =
+      Interpreter::new(&instructions)
+        .with_tape_size(50)
+        .run(&mut &b""[..], &mut output, None);
+    assert!(error.is_err());
+    assert!(output.is_empty());
+    let instructions = partial_eval(program, DEFAULT_BUDGET, 101, &mut Stats::default());
+    assert_eq!(instructions[0].op, Op::Print(Text::new(&[1])));
+  }
+
+  #[test]
+  fn endless_loops_are_left_to_run_time() {
+    for code in &["+[]", "+[>+]"] {
+      let (instructions, _, _) = evaluated(code, DEFAULT_BUDGET);
+      assert_eq!(
+        instructions[0].op,
+        Op::Set {
+          offset: 0,
+          value: 1
+        }
+      );
+      assert!(matches!(instructions[1].op, Op::JumpIfZero(_)));
+    }
+  }
+
+  #[test]
+  fn o2_prints_what_o0_prints() {
+    for &io in &[Io::Chars, Io::Bytes] {
+      assert_eq!(output(Level::O2, io), output(Level::O0, io));
+    }
+    assert_eq!(output(Level::O2, Io::Bytes), [0xff, 0xfe, 0x7e, 0x80]);
+  }
+}
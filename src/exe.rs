@@ -0,0 +1,363 @@
+//! Native executables: the JIT's Cranelift lowering written to an object
+//! file, with the runtime functions and a C `main` in Cranelift IR over
+//! libc, and linked by the system C compiler. Output is written the way the
+//! JIT writes it: characters in UTF-8, or bytes with `--io bytes`.
+
+use std::io::{self, ErrorKind};
+use std::process::Command;
+
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{
+  types, AbiParam, FuncRef, InstBuilder, MemFlagsData, StackSlotData, StackSlotKind, Type,
+  UserFuncName, Value,
+};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use cranelift_module::{default_libcall_names, DataDescription, FuncId, Linkage, Module};
+use cranelift_object::{ObjectBuilder, ObjectModule};
+
+use super::cranelift::{self, IO_FAILED, OFF_TAPE, OK};
+use super::interpreter::{Eof, ExitCell, Io};
+use super::jasmin::Config;
+use super::{reject_extensions, writes_bytes, Inst};
+
+fn codegen_error<E: std::fmt::Display>(error: E) -> String {
+  format!("native code generation failed: {}", error)
+}
+
+/// The libc functions the runtime calls.
+struct Libc {
+  putchar: FuncId,
+  getchar: FuncId,
+  fflush: FuncId,
+  write: FuncId,
+}
+
+fn import(
+  module: &mut ObjectModule,
+  name: &str,
+  params: &[Type],
+  returns: &[Type],
+) -> cranelift::Result<FuncId> {
+  let mut sig = module.make_signature();
+  sig
+    .params
+    .extend(params.iter().map(|&kind| AbiParam::new(kind)));
+  sig
+    .returns
+    .extend(returns.iter().map(|&kind| AbiParam::new(kind)));
+  Ok(module.declare_function(name, Linkage::Import, &sig)?)
+}
+
+fn declare_libc(module: &mut ObjectModule) -> cranelift::Result<Libc> {
+  let ptr_type = module.target_config().pointer_type();
+  Ok(Libc {
+    putchar: import(module, "putchar", &[types::I32], &[types::I32])?,
+    getchar: import(module, "getchar", &[], &[types::I32])?,
+    fflush: import(module, "fflush", &[ptr_type], &[types::I32])?,
+    write: import(
+      module,
+      "write",
+      &[types::I32, ptr_type, ptr_type],
+      &[ptr_type],
+    )?,
+  })
+}
+
+/// Defines the declared function `id` with the body `build` generates.
+fn define(
+  module: &mut ObjectModule,
+  id: FuncId,
+  build: impl FnOnce(&mut ObjectModule, &mut FunctionBuilder),
+) -> cranelift::Result<()> {
+  let mut ctx = module.make_context();
+  ctx.func.signature = module
+    .declarations()
+    .get_function_decl(id)
+    .signature
+    .clone();
+  ctx.func.name = UserFuncName::user(0, id.as_u32());
+  let mut func_ctx = FunctionBuilderContext::new();
+  let mut b = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+  let entry = b.create_block();
+  b.append_block_params_for_function_params(entry);
+  b.switch_to_block(entry);
+  b.seal_block(entry);
+  build(module, &mut b);
+  b.finalize(module.target_config());
+  module.define_function(id, &mut ctx)?;
+  module.clear_context(&mut ctx);
+  Ok(())
+}
+
+/// Returns `IO_FAILED` when the libc result `result` is negative, and `OK`
+/// otherwise.
+fn status(b: &mut FunctionBuilder, result: Value) -> Value {
+  let failed = b.ins().icmp_imm_s(IntCC::SignedLessThan, result, 0);
+  let io_failed = b.ins().iconst(types::I32, IO_FAILED as i64);
+  let ok = b.ins().iconst(types::I32, OK as i64);
+  b.ins().select(failed, io_failed, ok)
+}
+
+/// Writes the byte `byte`, an `i32`, through `putchar`, as a character in
+/// UTF-8 unless `bytes`, and returns the status of the write.
+fn put(b: &mut FunctionBuilder, putchar: FuncRef, byte: Value, bytes: bool) -> Value {
+  if bytes {
+    let call = b.ins().call(putchar, &[byte]);
+    let result = b.inst_results(call)[0];
+    return status(b, result);
+  }
+  let lead = b.create_block();
+  let last = b.create_block();
+  b.append_block_param(last, types::I32);
+  let done = b.create_block();
+  b.append_block_param(done, types::I32);
+  let ascii = b.ins().icmp_imm_u(IntCC::UnsignedLessThan, byte, 128);
+  b.ins().brif(ascii, last, &[byte.into()], lead, &[]);
+  b.switch_to_block(lead);
+  b.seal_block(lead);
+  let high = b.ins().ushr_imm_u(byte, 6);
+  let high = b.ins().bor_imm_u(high, 0xc0);
+  let call = b.ins().call(putchar, &[high]);
+  let result = b.inst_results(call)[0];
+  let written = status(b, result);
+  let low = b.ins().band_imm_u(byte, 0x3f);
+  let low = b.ins().bor_imm_u(low, 0x80);
+  b.ins()
+    .brif(written, done, &[written.into()], last, &[low.into()]);
+  b.switch_to_block(last);
+  b.seal_block(last);
+  let byte = b.block_params(last)[0];
+  let call = b.ins().call(putchar, &[byte]);
+  let result = b.inst_results(call)[0];
+  let written = status(b, result);
+  b.ins().jump(done, &[written.into()]);
+  b.switch_to_block(done);
+  b.seal_block(done);
+  b.block_params(done)[0]
+}
+
+/// Defines `bf_putchar`, `bf_getchar` and `bf_print`, which ignore their
+/// context argument.
+fn define_runtime(
+  module: &mut ObjectModule,
+  runtime: &cranelift::Runtime,
+  libc: &Libc,
+  config: &Config,
+) -> cranelift::Result<()> {
+  let flags = MemFlagsData::new();
+  let bytes = config.io == Io::Bytes;
+  define(module, runtime.putchar, |module, b| {
+    let putchar = module.declare_func_in_func(libc.putchar, b.func);
+    let entry = b.current_block().unwrap();
+    let byte = b.block_params(entry)[1];
+    let byte = b.ins().uextend(types::I32, byte);
+    let status = put(b, putchar, byte, bytes);
+    b.ins().return_(&[status]);
+  })?;
+  define(module, runtime.getchar, |module, b| {
+    let ptr_type = module.target_config().pointer_type();
+    let fflush = module.declare_func_in_func(libc.fflush, b.func);
+    let getchar = module.declare_func_in_func(libc.getchar, b.func);
+    let entry = b.current_block().unwrap();
+    let cell = b.block_params(entry)[1];
+    // Output written so far is flushed, so prompts show before input.
+    let all = b.ins().iconst(ptr_type, 0);
+    b.ins().call(fflush, &[all]);
+    let call = b.ins().call(getchar, &[]);
+    let byte = b.inst_results(call)[0];
+    let read = b.create_block();
+    let at_eof = b.create_block();
+    let done = b.create_block();
+    let is_eof = b.ins().icmp_imm_s(IntCC::SignedLessThan, byte, 0);
+    b.ins().brif(is_eof, at_eof, &[], read, &[]);
+    b.switch_to_block(read);
+    b.seal_block(read);
+    let byte = b.ins().ireduce(types::I8, byte);
+    b.ins().store(flags, byte, cell, 0);
+    b.ins().jump(done, &[]);
+    b.switch_to_block(at_eof);
+    b.seal_block(at_eof);
+    match config.eof {
+      Eof::Unchanged => (),
+      Eof::Zero => {
+        let zero = b.ins().iconst(types::I8, 0);
+        b.ins().store(flags, zero, cell, 0);
+      }
+      Eof::MinusOne => {
+        let minus_one = b.ins().iconst(types::I8, 0xff);
+        b.ins().store(flags, minus_one, cell, 0);
+      }
+    }
+    b.ins().jump(done, &[]);
+    b.switch_to_block(done);
+    b.seal_block(done);
+    let ok = b.ins().iconst(types::I32, OK as i64);
+    b.ins().return_(&[ok]);
+  })?;
+  define(module, runtime.print, |module, b| {
+    let ptr_type = module.target_config().pointer_type();
+    let putchar = module.declare_func_in_func(libc.putchar, b.func);
+    let entry = b.current_block().unwrap();
+    let text = b.block_params(entry)[1];
+    let len = b.block_params(entry)[2];
+    let header = b.create_block();
+    b.append_block_param(header, ptr_type);
+    let body = b.create_block();
+    let next = b.create_block();
+    let done = b.create_block();
+    b.append_block_param(done, types::I32);
+    let zero = b.ins().iconst(ptr_type, 0);
+    b.ins().jump(header, &[zero.into()]);
+    b.switch_to_block(header);
+    let index = b.block_params(header)[0];
+    let finished = b.ins().icmp(IntCC::Equal, index, len);
+    let ok = b.ins().iconst(types::I32, OK as i64);
+    b.ins().brif(finished, done, &[ok.into()], body, &[]);
+    b.switch_to_block(body);
+    b.seal_block(body);
+    let addr = b.ins().iadd(text, index);
+    let byte = b.ins().uload8(types::I32, flags, addr, 0);
+    let status = put(b, putchar, byte, bytes);
+    b.ins().brif(status, done, &[status.into()], next, &[]);
+    b.switch_to_block(next);
+    b.seal_block(next);
+    let index = b.ins().iadd_imm_s(index, 1);
+    b.ins().jump(header, &[index.into()]);
+    b.seal_block(header);
+    b.switch_to_block(done);
+    b.seal_block(done);
+    let status = b.block_params(done)[0];
+    b.ins().return_(&[status]);
+  })
+}
+
+/// Defines the C `main`, which runs `bf_main` over a zeroed tape and exits
+/// with the exit cell, or reports why the program stopped.
+fn define_entry(
+  module: &mut ObjectModule,
+  bf_main: FuncId,
+  libc: &Libc,
+  config: &Config,
+) -> cranelift::Result<()> {
+  let ptr_type = module.target_config().pointer_type();
+  let tape = module.declare_data("tape", Linkage::Local, true, false)?;
+  let mut description = DataDescription::new();
+  description.define_zeroinit(config.tape_size);
+  module.define_data(tape, &description)?;
+  let mut messages = Vec::new();
+  for message in ["pointer moved off the tape\n", "I/O failed\n"] {
+    let data = module.declare_anonymous_data(false, false)?;
+    let mut description = DataDescription::new();
+    description.define(message.as_bytes().into());
+    module.define_data(data, &description)?;
+    messages.push((data, message.len()));
+  }
+  let mut sig = module.make_signature();
+  sig.returns.push(AbiParam::new(types::I32));
+  let main = module.declare_function("main", Linkage::Export, &sig)?;
+  let flags = MemFlagsData::new();
+  define(module, main, |module, b| {
+    let bf_main = module.declare_func_in_func(bf_main, b.func);
+    let write = module.declare_func_in_func(libc.write, b.func);
+    let tape = module.declare_data_in_func(tape, b.func);
+    let messages: Vec<_> = messages
+      .iter()
+      .map(|&(data, len)| (module.declare_data_in_func(data, b.func), len))
+      .collect();
+    let tape = b.ins().symbol_value(ptr_type, tape);
+    let slot = b.create_sized_stack_slot(StackSlotData::new(
+      StackSlotKind::ExplicitSlot,
+      ptr_type.bytes(),
+      ptr_type.bytes().trailing_zeros() as u8,
+    ));
+    let last_ptr = b.ins().stack_addr(ptr_type, slot, 0);
+    let io_ctx = b.ins().iconst(ptr_type, 0);
+    let call = b.ins().call(bf_main, &[tape, io_ctx, last_ptr]);
+    let status = b.inst_results(call)[0];
+    let finished = b.create_block();
+    let failed = b.create_block();
+    b.ins().brif(status, failed, &[], finished, &[]);
+
+    b.switch_to_block(finished);
+    b.seal_block(finished);
+    let code = match config.exit_cell {
+      None => b.ins().iconst(types::I32, 0),
+      Some(cell) => {
+        let addr = match cell {
+          ExitCell::Current => {
+            let ptr = b.ins().load(ptr_type, flags, last_ptr, 0);
+            b.ins().iadd(tape, ptr)
+          }
+          ExitCell::First => tape,
+        };
+        b.ins().uload8(types::I32, flags, addr, 0)
+      }
+    };
+    b.ins().return_(&[code]);
+
+    b.switch_to_block(failed);
+    b.seal_block(failed);
+    let off_tape = b.ins().icmp_imm_s(IntCC::Equal, status, OFF_TAPE as i64);
+    let (off_tape_text, off_tape_len) = messages[0];
+    let (io_text, io_len) = messages[1];
+    let off_tape_text = b.ins().symbol_value(ptr_type, off_tape_text);
+    let io_text = b.ins().symbol_value(ptr_type, io_text);
+    let text = b.ins().select(off_tape, off_tape_text, io_text);
+    let off_tape_len = b.ins().iconst(ptr_type, off_tape_len as i64);
+    let io_len = b.ins().iconst(ptr_type, io_len as i64);
+    let len = b.ins().select(off_tape, off_tape_len, io_len);
+    let stderr = b.ins().iconst(types::I32, 2);
+    b.ins().call(write, &[stderr, text, len]);
+    let code = b.ins().iconst(types::I32, 1);
+    b.ins().return_(&[code]);
+  })
+}
+
+/// Compiles `instructions` to an object file for the host defining `main`.
+pub fn produce_object(instructions: &[Inst], config: &Config) -> Result<Vec<u8>, String> {
+  if config.tape_from_args || config.embeddable || config.runtime_args {
+    return Err(
+      "--tape-from-args, --embeddable and --runtime-args only apply to JVM output".to_string(),
+    );
+  }
+  if !config.wrap {
+    return Err("--emit exe only supports wrapping byte cells".to_string());
+  }
+  reject_extensions(instructions)?;
+  writes_bytes(config.io)?;
+  let mut flag_builder = settings::builder();
+  flag_builder.set("is_pic", "true").map_err(codegen_error)?;
+  flag_builder
+    .set("opt_level", "speed")
+    .map_err(codegen_error)?;
+  let isa = cranelift_native::builder()
+    .map_err(codegen_error)?
+    .finish(settings::Flags::new(flag_builder))
+    .map_err(codegen_error)?;
+  let builder = ObjectBuilder::new(isa, "main", default_libcall_names()).map_err(codegen_error)?;
+  let mut module = ObjectModule::new(builder);
+  let libc = declare_libc(&mut module).map_err(codegen_error)?;
+  let runtime = cranelift::declare_runtime(&mut module, Linkage::Local).map_err(codegen_error)?;
+  define_runtime(&mut module, &runtime, &libc, config).map_err(codegen_error)?;
+  let bf_main = cranelift::define_main(&mut module, &runtime, instructions, config.tape_size)
+    .map_err(codegen_error)?;
+  define_entry(&mut module, bf_main, &libc, config).map_err(codegen_error)?;
+  module.finish().emit().map_err(codegen_error)
+}
+
+/// Links `object` into the executable `output` with the C compiler `cc`.
+/// Returns `false` when `cc` could not be found, and an error when it ran
+/// and failed.
+pub fn link(cc: &str, object: &str, output: &str) -> io::Result<bool> {
+  let status = match Command::new(cc).args([object, "-o", output]).status() {
+    Ok(status) => status,
+    Err(error) if error.kind() == ErrorKind::NotFound => return Ok(false),
+    Err(error) => return Err(error),
+  };
+  if status.success() {
+    Ok(true)
+  } else {
+    Err(io::Error::other(format!("{} failed with {}", cc, status)))
+  }
+}
@@ -0,0 +1,102 @@
+//! The formatter behind `fmt`, which reprints a program with its loops and
+//! procedure bodies indented by depth.
+//!
+//! Commands fill lines up to a width; `[` ends its line and `]` starts one
+//! at the depth outside the loop, which the commands after it continue.
+//! Everything else is a comment and keeps its place: text on the line of a
+//! command stays at the end of that line, text on lines of its own stays on
+//! lines of its own, and a blank line between commands stays one.
+
+use super::Token;
+
+/// Default width of formatted lines, matching `--emit bf`.
+pub const DEFAULT_WIDTH: usize = 72;
+
+const INDENT: &str = "  ";
+
+struct Writer {
+  width: usize,
+  lines: Vec<String>,
+  line: String,
+  /// Whether the line takes no more commands, being ended by `[` or a
+  /// comment.
+  closed: bool,
+  depth: usize,
+}
+
+impl Writer {
+  fn flush(&mut self) {
+    if !self.line.is_empty() {
+      self.lines.push(std::mem::take(&mut self.line));
+    }
+    self.closed = false;
+  }
+
+  fn command(&mut self, c: char, token: Token) {
+    let close = matches!(token, Token::JumpIfNonZero | Token::ProcEnd);
+    if self.closed || close || self.line.chars().count() >= self.width {
+      self.flush();
+    }
+    if close {
+      self.depth = self.depth.saturating_sub(1);
+    }
+    if self.line.is_empty() {
+      self.line = INDENT.repeat(self.depth);
+    }
+    self.line.push(c);
+    if matches!(token, Token::JumpIfZero | Token::ProcStart) {
+      self.closed = true;
+      self.depth += 1;
+    }
+  }
+
+  /// Adds the text between two commands, or around all of them.
+  fn comment(&mut self, text: &str) {
+    let parts: Vec<&str> = text.split('\n').map(str::trim).collect();
+    let last = parts.len() - 1;
+    for (n, part) in parts.into_iter().enumerate() {
+      if part.is_empty() {
+        // Only a line with nothing on it is blank.
+        if n != 0 && n != last {
+          self.flush();
+          if self.lines.last().is_some_and(|line| !line.is_empty()) {
+            self.lines.push(String::new());
+          }
+        }
+      } else if n == 0 && !self.line.is_empty() {
+        self.line.push(' ');
+        self.line.push_str(part);
+        self.closed = true;
+      } else {
+        self.flush();
+        self.lines.push(INDENT.repeat(self.depth) + part);
+      }
+    }
+  }
+}
+
+/// Formats `source`, whose commands are `tokens`, into lines of `width`
+/// characters where the commands allow.
+pub fn format(source: &str, tokens: &[(Token, usize)], width: usize) -> String {
+  let mut writer = Writer {
+    width,
+    lines: Vec::new(),
+    line: String::new(),
+    closed: false,
+    depth: 0,
+  };
+  let mut pos = 0;
+  for &(token, start) in tokens {
+    writer.comment(&source[pos..start]);
+    let c = source[start..].chars().next().unwrap();
+    writer.command(c, token);
+    pos = start + c.len_utf8();
+  }
+  writer.comment(&source[pos..]);
+  writer.flush();
+  while writer.lines.last().is_some_and(|line| line.is_empty()) {
+    writer.lines.pop();
+  }
+  writer.lines.push(String::new());
+  writer.lines.join("\n")
+}
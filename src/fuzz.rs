@@ -0,0 +1,495 @@
+//! The differential testing behind `fuzz`: random programs run through the
+//! interpreter and through one backend, which must print the same.
+//!
+//! Programs are generated so that they always end and stay on the tape.
+//! The pointer keeps to the first `WIDTH` cells, and every loop has the
+//! form `[>...<-]`: its body works on cells to the right of its counter,
+//! returns to it and decrements it, so it runs at most 255 times. The
+//! interpreter runs the program as parsed, the backend as optimized, so the
+//! optimizer is tested along with it. Both print as `--io` says, in UTF-8
+//! by default, so every backend is held to the characters the interpreter
+//! prints as well as to its bytes.
+//!
+//! A program on which the two disagree is shrunk by deleting commands,
+//! loops and input bytes for as long as they still disagree. Candidates
+//! that the interpreter does not finish within `STEPS` steps, or that move
+//! off the tape, are not considered.
+
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::interpreter::{Interpreter, Random};
+use super::{c, classfile, jasmin, js, lex_dialect, optimizer, parse_program, python, Dialect};
+
+/// Cells the pointer of a generated program keeps to.
+pub const WIDTH: usize = 16;
+
+/// Steps a candidate may run for in the interpreter.
+pub const STEPS: usize = 10_000_000;
+
+/// How long a backend may take to run a program, once compiled.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How deeply generated loops nest.
+const DEPTH: usize = 2;
+
+/// A backend whose output `fuzz` can run, and what runs it.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Target {
+  /// A class on `java`.
+  Class,
+  /// C built by `cc`.
+  C,
+  /// A script on `python3`.
+  Python,
+  /// A script on `node`.
+  Js,
+  /// The JIT, in builds with the `jit` feature.
+  Jit,
+  /// The interpreter itself, on the optimized program.
+  Interpreter,
+}
+
+impl Target {
+  pub fn parse(text: &str) -> Result<Target, String> {
+    match text {
+      "class" | "jvm" => Ok(Target::Class),
+      "c" => Ok(Target::C),
+      "python" | "py" => Ok(Target::Python),
+      "js" | "javascript" => Ok(Target::Js),
+      "jit" => Ok(Target::Jit),
+      "interp" | "interpreter" => Ok(Target::Interpreter),
+      other => Err(format!(
+        "cannot run on {} (known: class, c, python, js, jit, interp)",
+        other
+      )),
+    }
+  }
+}
+
+/// Everything a backend run needs besides the program.
+pub struct Options<'a> {
+  pub target: Target,
+  pub passes: &'a [&'static str],
+  pub eval_budget: usize,
+  pub unroll_limit: usize,
+  pub config: &'a jasmin::Config,
+  pub class_version: u16,
+  /// Where generated files go.
+  pub dir: &'a Path,
+}
+
+/// What a run printed, or how it failed.
+pub type Outcome = Result<Vec<u8>, String>;
+
+/// A program the interpreter and a backend disagree on.
+#[derive(Debug)]
+pub struct Divergence {
+  pub code: String,
+  pub input: Vec<u8>,
+  pub expected: Vec<u8>,
+  pub actual: Outcome,
+}
+
+/// A number below `bound`.
+fn below(random: &mut Random, bound: usize) -> usize {
+  random.next_byte() as usize % bound
+}
+
+/// Appends about `budget` random commands that keep the pointer within
+/// `lo..WIDTH`, starting at `pos`, and returns where it ends up.
+fn walk(
+  out: &mut String,
+  random: &mut Random,
+  pos: usize,
+  lo: usize,
+  depth: usize,
+  budget: usize,
+) -> usize {
+  let mut pos = pos;
+  for _ in 0..budget {
+    match below(random, 9) {
+      0 | 1 => out.push_str(&"+".repeat(1 + below(random, 8))),
+      2 => out.push_str(&"-".repeat(1 + below(random, 4))),
+      3 | 4 if pos + 1 < WIDTH => {
+        out.push('>');
+        pos += 1;
+      }
+      5 if pos > lo => {
+        out.push('<');
+        pos -= 1;
+      }
+      6 => out.push('.'),
+      7 => out.push(','),
+      8 if depth < DEPTH && pos + 1 < WIDTH => {
+        out.push_str("[>");
+        let end = walk(out, random, pos + 1, pos + 1, depth + 1, budget / 2);
+        out.push_str(&"<".repeat(end - pos));
+        out.push_str("-]");
+      }
+      _ => (),
+    }
+  }
+  pos
+}
+
+/// A random program that ends and stays on the tape.
+pub fn generate(random: &mut Random) -> String {
+  let mut code = String::new();
+  let len = 10 + below(random, 30);
+  walk(&mut code, random, 0, 0, 0, len);
+  code
+}
+
+/// Runs the program `code` in the interpreter, returning what it printed,
+/// or `None` when it does not end within `STEPS` steps or moves off the
+/// tape.
+fn reference(code: &str, input: &[u8], options: &Options) -> Option<Vec<u8>> {
+  let program = parse_program(lex_dialect(code, Dialect::Brainfuck).ok()?).ok()?;
+  let mut interpreter = Interpreter::new(&program)
+    .with_eof(options.config.eof)
+    .with_io(options.config.io);
+  let mut input = input;
+  let mut output = Vec::new();
+  for _ in 0..STEPS {
+    match interpreter.step(&mut input, &mut output) {
+      Ok(Some(_)) => (),
+      Ok(None) => return Some(output),
+      Err(_) => return None,
+    }
+  }
+  None
+}
+
+/// Waits for `child`, which reads `input`, killing it after `TIMEOUT`.
+fn finish(mut child: Child, input: &[u8]) -> io::Result<Outcome> {
+  let mut stdin = child.stdin.take().unwrap();
+  let bytes = input.to_vec();
+  // A program that stops reading closes the pipe early, which is fine.
+  let writer = thread::spawn(move || stdin.write_all(&bytes));
+  let mut stdout = child.stdout.take().unwrap();
+  let reader = thread::spawn(move || {
+    let mut output = Vec::new();
+    stdout.read_to_end(&mut output).map(|_| output)
+  });
+  let deadline = Instant::now() + TIMEOUT;
+  let status = loop {
+    if let Some(status) = child.try_wait()? {
+      break status;
+    }
+    if Instant::now() >= deadline {
+      child.kill()?;
+      child.wait()?;
+      return Ok(Err(format!("did not finish in {:?}", TIMEOUT)));
+    }
+    thread::sleep(Duration::from_millis(5));
+  };
+  let _ = writer.join().unwrap();
+  let output = reader.join().unwrap()?;
+  if status.success() {
+    Ok(Ok(output))
+  } else {
+    Ok(Err(format!("failed with {}", status)))
+  }
+}
+
+/// Starts `program` with `args` on piped standard streams, returning
+/// `None` when it could not be found.
+fn spawn(program: &str, args: &[&OsStr]) -> io::Result<Option<Child>> {
+  match Command::new(program)
+    .args(args)
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::null())
+    .spawn()
+  {
+    Ok(child) => Ok(Some(child)),
+    Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
+    Err(error) => Err(error),
+  }
+}
+
+/// Writes `text` to `name` in `dir`, returning its path.
+fn write(dir: &Path, name: &str, text: &[u8]) -> io::Result<PathBuf> {
+  let path = dir.join(name);
+  fs::write(&path, text)?;
+  Ok(path)
+}
+
+/// Runs the program `code` through the backend, returning `Ok(None)` when
+/// the tool that runs it could not be found.
+pub fn run_target(code: &str, input: &[u8], options: &Options) -> io::Result<Option<Outcome>> {
+  let generated = |e: String| io::Error::new(ErrorKind::InvalidInput, e);
+  let tokens = lex_dialect(code, Dialect::Brainfuck).map_err(generated)?;
+  let program = parse_program(tokens).map_err(generated)?;
+  let config = options.config;
+  let (ir, _) = optimizer::Compiler::new(options.passes, options.eval_budget, options.unroll_limit)
+    .with_tape_size(config.tape_size)
+    .optimize(program);
+  let child = match options.target {
+    Target::Interpreter => {
+      let mut output = Vec::new();
+      let result = Interpreter::new(&ir)
+        .with_eof(config.eof)
+        .with_tape_size(config.tape_size)
+        .with_io(config.io)
+        .run(&mut &input[..], &mut output, None);
+      return Ok(Some(result.map(|_| output).map_err(|e| e.to_string())));
+    }
+    #[cfg(feature = "jit")]
+    Target::Jit => {
+      if config.io == super::interpreter::Io::Unicode {
+        return Err(generated(
+          "--io unicode cannot be combined with --jit".to_string(),
+        ));
+      }
+      let mut output = Vec::new();
+      let result = super::jit::run(
+        &ir,
+        config.eof,
+        config.io,
+        config.tape_size,
+        &mut &input[..],
+        &mut output,
+      );
+      return Ok(Some(result.map(|_| output).map_err(|e| e.to_string())));
+    }
+    #[cfg(not(feature = "jit"))]
+    Target::Jit => {
+      return Err(generated(
+        "fuzzing the JIT needs a build with the `jit` feature".to_string(),
+      ))
+    }
+    Target::Class => {
+      let code = jasmin::produce_code(ir, config).map_err(generated)?;
+      let (name, class) = classfile::assemble(&code, options.class_version).map_err(generated)?;
+      write(options.dir, &format!("{}.class", name), &class)?;
+      spawn(
+        "java",
+        &["-cp".as_ref(), options.dir.as_os_str(), name.as_ref()],
+      )?
+    }
+    Target::C => {
+      let source = write(
+        options.dir,
+        "main.c",
+        c::produce_c(&ir, config).map_err(generated)?.as_bytes(),
+      )?;
+      let binary = options.dir.join("main");
+      let status = match Command::new("cc")
+        .arg(&source)
+        .arg("-o")
+        .arg(&binary)
+        .status()
+      {
+        Ok(status) => status,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error),
+      };
+      if !status.success() {
+        return Ok(Some(Err(format!("cc failed with {}", status))));
+      }
+      spawn(binary.to_str().unwrap(), &[])?
+    }
+    Target::Python => {
+      let code = python::produce_python(&ir, config).map_err(generated)?;
+      let script = write(options.dir, "main.py", code.as_bytes())?;
+      spawn("python3", &[script.as_os_str()])?
+    }
+    Target::Js => {
+      let code = js::produce_js(&ir, config, false).map_err(generated)?;
+      let script = write(options.dir, "main.js", code.as_bytes())?;
+      spawn("node", &[script.as_os_str()])?
+    }
+  };
+  match child {
+    Some(child) => finish(child, input).map(Some),
+    None => Ok(None),
+  }
+}
+
+/// Whether the backend disagrees with the interpreter on `code` and
+/// `input`, returning both sides if so.
+fn diverges(code: &str, input: &[u8], options: &Options) -> io::Result<Option<Divergence>> {
+  let expected = match reference(code, input, options) {
+    Some(expected) => expected,
+    None => return Ok(None),
+  };
+  let actual = match run_target(code, input, options)? {
+    Some(actual) => actual,
+    None => return Ok(None),
+  };
+  if actual.as_ref() == Ok(&expected) {
+    return Ok(None);
+  }
+  Ok(Some(Divergence {
+    code: code.to_string(),
+    input: input.to_vec(),
+    expected,
+    actual,
+  }))
+}
+
+/// Shorter versions of `code`: each command deleted, and each loop
+/// deleted or unwrapped into its body.
+fn candidates(code: &str) -> Vec<String> {
+  let bytes = code.as_bytes();
+  let mut candidates = Vec::new();
+  let mut open = Vec::new();
+  for (at, &byte) in bytes.iter().enumerate() {
+    match byte {
+      b'[' => open.push(at),
+      b']' => {
+        let start = open.pop().unwrap();
+        candidates.push(format!("{}{}", &code[..start], &code[at + 1..]));
+        candidates.push(format!(
+          "{}{}{}",
+          &code[..start],
+          &code[start + 1..at],
+          &code[at + 1..]
+        ));
+      }
+      _ => candidates.push(format!("{}{}", &code[..at], &code[at + 1..])),
+    }
+  }
+  candidates
+}
+
+/// Shrinks `divergence` for as long as a shorter program or input still
+/// diverges.
+pub fn minimize(divergence: Divergence, options: &Options) -> io::Result<Divergence> {
+  let mut divergence = divergence;
+  'shrink: loop {
+    for code in candidates(&divergence.code) {
+      if let Some(smaller) = diverges(&code, &divergence.input, options)? {
+        divergence = smaller;
+        continue 'shrink;
+      }
+    }
+    for at in 0..divergence.input.len() {
+      let mut input = divergence.input.clone();
+      input.remove(at);
+      if let Some(smaller) = diverges(&divergence.code, &input, options)? {
+        divergence = smaller;
+        continue 'shrink;
+      }
+    }
+    return Ok(divergence);
+  }
+}
+
+/// Runs `runs` random programs with random input, returning the first
+/// divergence, minimized, if any. Fails when the backend cannot be run.
+pub fn fuzz(runs: usize, random: &mut Random, options: &Options) -> io::Result<Option<Divergence>> {
+  for _ in 0..runs {
+    let code = generate(random);
+    let input: Vec<u8> = (0..below(random, 8)).map(|_| random.next_byte()).collect();
+    let expected = reference(&code, &input, options)
+      .ok_or_else(|| io::Error::other(format!("generated program {} does not finish", code)))?;
+    let actual = run_target(&code, &input, options)?.ok_or_else(|| {
+      io::Error::new(
+        ErrorKind::NotFound,
+        format!("nothing to run {:?} output with", options.target),
+      )
+    })?;
+    if actual.as_ref() != Ok(&expected) {
+      let divergence = Divergence {
+        code,
+        input,
+        expected,
+        actual,
+      };
+      return minimize(divergence, options).map(Some);
+    }
+  }
+  Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::Path;
+
+  use super::super::interpreter::Io;
+  use super::super::{classfile, constants, evaluate, jasmin, optimizer};
+  use super::{reference, run_target, Options, Target};
+
+  /// Prints 255 and 128.
+  const HIGH: &str = "-.>++++++++[<---------------->-]<+.";
+
+  fn options<'a>(target: Target, config: &'a jasmin::Config, dir: &'a Path) -> Options<'a> {
+    Options {
+      target,
+      passes: &[],
+      eval_budget: evaluate::DEFAULT_BUDGET,
+      unroll_limit: constants::DEFAULT_UNROLL_LIMIT,
+      config,
+      class_version: classfile::DEFAULT_VERSION,
+      dir,
+    }
+  }
+
+  /// What `HIGH` prints as characters and as bytes.
+  const MODES: [(Io, &[u8]); 2] = [
+    (Io::Chars, &[0xc3, 0xbf, 0xc2, 0x80]),
+    (Io::Bytes, &[255, 128]),
+  ];
+
+  #[test]
+  fn the_reference_prints_as_io_says() {
+    for (io, printed) in MODES {
+      let config = jasmin::Config {
+        io,
+        ..jasmin::Config::default()
+      };
+      let options = options(Target::Interpreter, &config, Path::new("."));
+      assert_eq!(reference(HIGH, b"", &options), Some(printed.to_vec()));
+    }
+  }
+
+  #[test]
+  fn backends_print_what_the_reference_does() {
+    let dir = std::env::temp_dir().join(format!("brainfuck-fuzz-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for (io, printed) in MODES {
+      let config = jasmin::Config {
+        io,
+        ..jasmin::Config::default()
+      };
+      for &level in &[optimizer::Level::O0, optimizer::Level::O2] {
+        let passes = optimizer::preset(level, true);
+        let targets = vec![
+          Target::Interpreter,
+          #[cfg(feature = "jit")]
+          Target::Jit,
+          Target::Class,
+          Target::C,
+          Target::Python,
+          Target::Js,
+        ];
+        for target in targets {
+          let options = Options {
+            passes: &passes,
+            ..options(target, &config, &dir)
+          };
+          // Backends whose tools are missing are skipped.
+          if let Some(outcome) = run_target(HIGH, b"", &options).unwrap() {
+            assert_eq!(
+              outcome,
+              Ok(printed.to_vec()),
+              "{:?} at {:?} with {:?}",
+              target,
+              level,
+              io
+            );
+          }
+        }
+      }
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+}
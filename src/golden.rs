@@ -0,0 +1,98 @@
+//! Discovery and comparison behind `test`, which runs every program of a
+//! directory that has its expected output next to it, and the byte-level
+//! comparison behind `diff-backends`.
+//!
+//! `foo.bf` is a test when `foo.expected` exists beside it, and reads
+//! `foo.in` when that exists too, or nothing otherwise. Directories are
+//! searched recursively and tests run in the order of their paths.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A program with the output it should print.
+#[derive(Debug)]
+pub struct Case {
+  pub program: PathBuf,
+  pub input: Option<PathBuf>,
+  pub expected: PathBuf,
+}
+
+/// The tests under `dir`.
+pub fn discover(dir: &Path) -> io::Result<Vec<Case>> {
+  let mut cases = Vec::new();
+  let mut dirs = vec![dir.to_path_buf()];
+  while let Some(dir) = dirs.pop() {
+    for entry in fs::read_dir(&dir)? {
+      let path = entry?.path();
+      if path.is_dir() {
+        dirs.push(path);
+      } else if path.extension().is_some_and(|extension| extension == "bf") {
+        let expected = path.with_extension("expected");
+        if expected.is_file() {
+          let input = Some(path.with_extension("in")).filter(|input| input.is_file());
+          cases.push(Case {
+            program: path,
+            input,
+            expected,
+          });
+        }
+      }
+    }
+  }
+  cases.sort_by(|a, b| a.program.cmp(&b.program));
+  Ok(cases)
+}
+
+/// Describes the first line where `actual` differs from `expected`, or
+/// `None` when they are the same.
+pub fn difference(expected: &[u8], actual: &[u8]) -> Option<String> {
+  if expected == actual {
+    return None;
+  }
+  let mut expected_lines = expected.split(|&byte| byte == b'\n');
+  let mut actual_lines = actual.split(|&byte| byte == b'\n');
+  let mut number = 1;
+  loop {
+    match (expected_lines.next(), actual_lines.next()) {
+      (Some(want), Some(got)) if want == got => number += 1,
+      (want, got) => {
+        let show = |line: Option<&[u8]>| match line {
+          Some(line) => format!("{:?}", String::from_utf8_lossy(line)),
+          None => "end of output".to_string(),
+        };
+        return Some(format!(
+          "line {}: expected {}, got {}",
+          number,
+          show(want),
+          show(got)
+        ));
+      }
+    }
+  }
+}
+
+/// Bytes `context` shows on either side of a divergence.
+pub const CONTEXT: usize = 16;
+
+/// The offset of the first byte where `a` and `b` differ, which is the end
+/// of the shorter one when it is the start of the other, or `None` when they
+/// are the same.
+pub fn divergence(a: &[u8], b: &[u8]) -> Option<usize> {
+  if a == b {
+    return None;
+  }
+  Some(
+    a.iter()
+      .zip(b)
+      .position(|(x, y)| x != y)
+      .unwrap_or_else(|| a.len().min(b.len())),
+  )
+}
+
+/// The bytes of `output` within `CONTEXT` of `offset`, quoted.
+pub fn context(output: &[u8], offset: usize) -> String {
+  let start = offset.saturating_sub(CONTEXT).min(output.len());
+  let end = (offset + CONTEXT).min(output.len());
+  format!("{:?}", String::from_utf8_lossy(&output[start..end]))
+}
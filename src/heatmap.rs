@@ -0,0 +1,119 @@
+//! The source shaded by a profile, which `heatmap` prints so that hot
+//! loops stand out without reading counts: as colored text for a terminal
+//! or as an HTML page.
+//!
+//! Each command takes the count of the hottest instruction built from it,
+//! on a logarithmic scale from blue for those that ran once to red for the
+//! hottest one. Commands that never ran and comments are left dim.
+
+use super::profile::Profile;
+use super::Span;
+
+#[derive(Copy, Clone, Debug)]
+pub enum Style {
+  Ansi,
+  Html,
+}
+
+/// The 256-color palette entries of the scale, coldest first.
+const ANSI_COLORS: &[u8] = &[21, 33, 51, 46, 226, 208, 196];
+
+/// Where `count` falls between 0, running once, and 1, running as often
+/// as `max`.
+fn heat(count: u64, max: u64) -> f64 {
+  if max <= 1 {
+    return 1.0;
+  }
+  (count as f64).ln() / (max as f64).ln()
+}
+
+fn escape(text: &str) -> String {
+  text
+    .replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+}
+
+/// `source`, whose commands are at `positions`, shaded by `profile`, with
+/// `title` naming it in HTML.
+pub fn render(
+  source: &str,
+  positions: &[usize],
+  profile: &Profile,
+  style: Style,
+  title: &str,
+) -> String {
+  let mut counts: Vec<(usize, u64)> = positions
+    .iter()
+    .filter(|&&pos| pos < source.len())
+    .map(|&pos| {
+      // No instruction comes from past the bytes a span can address.
+      let count = Span::new(pos, pos + 1).map_or(0, |span| profile.hotness(span));
+      (pos, count)
+    })
+    .collect();
+  // Macros can use commands defined further down.
+  counts.sort_unstable();
+  counts.dedup_by_key(|&mut (pos, _)| pos);
+  let max = counts.iter().map(|&(_, count)| count).max().unwrap_or(0);
+  let count_at = |at: usize| match counts.binary_search_by_key(&at, |&(pos, _)| pos) {
+    Ok(index) => counts[index].1,
+    Err(_) => 0,
+  };
+  // Neighbours that ran as often are shaded together.
+  let mut runs: Vec<(String, u64)> = Vec::new();
+  for (at, c) in source.char_indices() {
+    let count = if c == '\n' { 0 } else { count_at(at) };
+    match runs.last_mut() {
+      Some((run, last)) if *last == count && c != '\n' && !run.ends_with('\n') => run.push(c),
+      _ => runs.push((c.to_string(), count)),
+    }
+  }
+  let mut text = String::new();
+  match style {
+    Style::Ansi => {
+      let last = ANSI_COLORS.len() - 1;
+      for (run, count) in runs {
+        match count {
+          _ if run == "\n" => text.push('\n'),
+          0 => text.push_str(&format!("\x1b[2m{}\x1b[0m", run)),
+          count => {
+            let color = ANSI_COLORS[(heat(count, max) * last as f64).round() as usize];
+            text.push_str(&format!("\x1b[1;38;5;{}m{}\x1b[0m", color, run));
+          }
+        }
+      }
+      if !source.ends_with('\n') {
+        text.push('\n');
+      }
+      text.push_str(&format!("hottest command ran {} times\n", max));
+    }
+    Style::Html => {
+      text.push_str(&format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n\
+         <style>\nbody {{ background: #111; color: #eee; }}\n\
+         pre {{ font: 14px monospace; }}\n.cold {{ color: #555; }}\n</style>\n\
+         </head>\n<body>\n<p>hottest command ran {} times</p>\n<pre>",
+        escape(title),
+        max
+      ));
+      for (run, count) in runs {
+        match count {
+          _ if run == "\n" => text.push('\n'),
+          0 => text.push_str(&format!("<span class=\"cold\">{}</span>", escape(&run))),
+          count => {
+            let hue = 240.0 * (1.0 - heat(count, max));
+            text.push_str(&format!(
+              "<span style=\"background: hsl({:.0}, 90%, 35%)\" title=\"{} runs\">{}</span>",
+              hue,
+              count,
+              escape(&run)
+            ));
+          }
+        }
+      }
+      text.push_str("</pre>\n</body>\n</html>\n");
+    }
+  }
+  text
+}
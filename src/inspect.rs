@@ -0,0 +1,147 @@
+//! The prompt `run --inspect` opens once the program halts, answering
+//! queries about the tape it left:
+//!
+//! ```text
+//! p <addr>          a cell
+//! range <a> <b>     cells a to b, both included
+//! find <value>      the addresses of cells holding a value
+//! q                 leave
+//! ```
+//!
+//! Addresses and values are decimal or `0x` hex; values may also be a
+//! character in single quotes.
+
+use std::convert::TryFrom;
+use std::io::{self, BufRead, Write};
+
+const HELP: &str = "p <addr>          show a cell
+range <a> <b>     show cells a to b, both included
+find <value>      list the cells holding a value, such as 72, 0x48 or 'H'
+q                 quit
+";
+
+/// Addresses `find` lists before leaving the rest out.
+const LISTED: usize = 32;
+
+fn parse_number(text: &str) -> Result<usize, String> {
+  let parsed = match text.strip_prefix("0x") {
+    Some(hex) => usize::from_str_radix(hex, 16),
+    None => text.parse(),
+  };
+  parsed.map_err(|_| format!("{} is not a number", text))
+}
+
+fn parse_address(text: &str, tape: &[u8]) -> Result<usize, String> {
+  let address = parse_number(text)?;
+  if address >= tape.len() {
+    return Err(format!("the tape ends at cell {}", tape.len() - 1));
+  }
+  Ok(address)
+}
+
+fn parse_value(text: &str) -> Result<u8, String> {
+  let quoted = text
+    .strip_prefix('\'')
+    .and_then(|rest| rest.strip_suffix('\''));
+  if let Some(quoted) = quoted {
+    let mut chars = quoted.chars();
+    return match (chars.next(), chars.next()) {
+      (Some(c), None) if c.is_ascii() => Ok(c as u8),
+      _ => Err(format!("{} is not one ASCII character", text)),
+    };
+  }
+  let value = parse_number(text)?;
+  u8::try_from(value).map_err(|_| format!("{} does not fit in a cell", text))
+}
+
+fn describe(address: usize, cell: u8, ptr: usize) -> String {
+  let mut line = format!("cell {} = {} (0x{:02x}", address, cell, cell);
+  if (0x20..=0x7e).contains(&cell) {
+    line.push_str(&format!(", '{}'", cell as char));
+  }
+  line.push(')');
+  if address == ptr {
+    line.push_str(" <- pointer");
+  }
+  line
+}
+
+/// The answer to `query`.
+fn answer(query: &[&str], tape: &[u8], ptr: usize) -> Result<String, String> {
+  match query {
+    ["p", address] => {
+      let address = parse_address(address, tape)?;
+      Ok(describe(address, tape[address], ptr))
+    }
+    ["range", start, end] => {
+      let start = parse_address(start, tape)?;
+      let end = parse_address(end, tape)?;
+      if end < start {
+        return Err(format!("the range ends before cell {}", start));
+      }
+      let lines: Vec<String> = (start..=end)
+        .map(|address| describe(address, tape[address], ptr))
+        .collect();
+      Ok(lines.join("\n"))
+    }
+    ["find", value] => {
+      let value = parse_value(value)?;
+      let found: Vec<usize> = (0..tape.len())
+        .filter(|&address| tape[address] == value)
+        .collect();
+      let listed: Vec<String> = found.iter().take(LISTED).map(usize::to_string).collect();
+      match found.len() {
+        0 => Ok(format!("no cell holds {}", value)),
+        1 => Ok(format!("{} is in cell {}", value, found[0])),
+        count if count > LISTED => Ok(format!(
+          "{} is in {} cells: {} and {} more",
+          value,
+          count,
+          listed.join(" "),
+          count - LISTED
+        )),
+        count => Ok(format!(
+          "{} is in {} cells: {}",
+          value,
+          count,
+          listed.join(" ")
+        )),
+      }
+    }
+    ["help"] => Ok(HELP.trim_end().to_string()),
+    _ => Err("unknown query; help lists them".to_string()),
+  }
+}
+
+/// Answers queries about `tape`, whose pointer rests on `ptr`, read from
+/// `input` until it ends or asks to quit.
+pub fn inspect(
+  tape: &[u8],
+  ptr: usize,
+  input: &mut dyn BufRead,
+  output: &mut dyn Write,
+) -> io::Result<()> {
+  writeln!(
+    output,
+    "the program halted with the pointer at cell {}",
+    ptr
+  )?;
+  loop {
+    write!(output, "inspect> ")?;
+    output.flush()?;
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+      writeln!(output)?;
+      return Ok(());
+    }
+    let query: Vec<&str> = line.split_whitespace().collect();
+    match query[..] {
+      [] => continue,
+      ["q" | "quit"] => return Ok(()),
+      _ => match answer(&query, tape, ptr) {
+        Ok(text) => writeln!(output, "{}", text)?,
+        Err(error) => writeln!(output, "error: {}", error)?,
+      },
+    }
+  }
+}
@@ -0,0 +1,872 @@
+use std::any::Any;
+use std::collections::VecDeque;
+use std::io;
+use std::io::prelude::*;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::trace::Tracer;
+use super::{has_forks, Ebf, Inst, Op, Tape, TAPES};
+
+pub mod cell;
+mod fork;
+pub mod observer;
+mod threaded;
+
+use cell::Cell;
+use observer::{IoEvent, Observed, Observer};
+
+pub const TAPE_SIZE: usize = 30000;
+
+/// Number of cells on either side of the pointer that `#` prints.
+pub const DUMP_RADIUS: usize = 4;
+
+/// What `#` prints: the pointer and the cells around it, the current one
+/// in brackets.
+pub fn dump<C: Cell>(tape: &[C], ptr: usize) -> String {
+  let start = ptr.saturating_sub(DUMP_RADIUS);
+  let end = (ptr + DUMP_RADIUS + 1).min(tape.len());
+  let cells: String = (start..end)
+    .map(|pos| match pos {
+      _ if pos == ptr => format!(" [{}]", tape[pos]),
+      _ => format!(" {}", tape[pos]),
+    })
+    .collect();
+  format!("# ptr {}, cells {}..{}:{}", ptr, start, end, cells)
+}
+
+/// Cells on each row of `dump_table`.
+const TABLE_WIDTH: usize = 16;
+
+/// What `--dump-tape` prints: each row of cells with one that is not zero,
+/// in hex and as ASCII after the address of its first cell, and where the
+/// pointer is.
+pub fn dump_table(tape: &[u8], ptr: usize) -> String {
+  let mut table = String::new();
+  for (row, cells) in tape.chunks(TABLE_WIDTH).enumerate() {
+    if cells.iter().all(|&cell| cell == 0) {
+      continue;
+    }
+    let hex: Vec<String> = cells.iter().map(|cell| format!("{:02x}", cell)).collect();
+    let ascii: String = cells
+      .iter()
+      .map(|&cell| match cell {
+        0x20..=0x7e => cell as char,
+        _ => '.',
+      })
+      .collect();
+    table.push_str(&format!(
+      "{:08x}  {:<width$}  |{}|\n",
+      row * TABLE_WIDTH,
+      hex.join(" "),
+      ascii,
+      width = TABLE_WIDTH * 3 - 1
+    ));
+  }
+  if table.is_empty() {
+    table.push_str("every cell is zero\n");
+  }
+  table.push_str(&format!("pointer at cell {}\n", ptr));
+  table
+}
+
+/// Everything observed while executing a single instruction, with the low
+/// byte of cells wider than one.
+#[derive(Clone, Debug)]
+pub struct Step {
+  pub index: usize,
+  pub inst: Inst,
+  pub ptr: usize,
+  pub before: u8,
+  pub after: u8,
+}
+
+/// Finds the first zero cell reachable from `ptr` in steps of `stride`.
+pub(crate) fn scan_zero<C: Cell>(tape: &[C], ptr: usize, stride: isize) -> io::Result<usize> {
+  match stride {
+    1 => tape[ptr..].iter().position(Cell::is_zero).map(|i| ptr + i),
+    -1 => tape[..=ptr].iter().rposition(Cell::is_zero),
+    _ => {
+      let mut pos = ptr as isize;
+      while pos >= 0 && (pos as usize) < tape.len() && !tape[pos as usize].is_zero() {
+        pos += stride;
+      }
+      Some(pos as usize).filter(|_| pos >= 0 && (pos as usize) < tape.len())
+    }
+  }
+  .ok_or_else(off_tape)
+}
+
+pub struct Interpreter<'a, C: Cell = u8> {
+  program: &'a [Inst],
+  tape: Vec<C>,
+  ptr: usize,
+  pc: usize,
+  eof: Eof,
+  io: Io,
+  /// Start of the body of each pbrain procedure defined so far, by number.
+  procedures: Vec<Option<usize>>,
+  /// Where each procedure call in progress returns to.
+  calls: Vec<usize>,
+  /// Threads forked by the last step, waiting to be started.
+  forks: Vec<Interpreter<'a, C>>,
+  /// The Extended Brainfuck storage cell.
+  storage: C,
+  /// The tapes other than the current one with their pointers, in the
+  /// order `%` makes them current. Empty until a program uses them.
+  tapes: VecDeque<(Vec<C>, usize)>,
+  random: Random,
+  observer: Option<&'a mut dyn Observer>,
+  /// Whether arithmetic that leaves the range of a cell is an error
+  /// instead of wrapping.
+  trap_overflow: bool,
+}
+
+/// The state of an interpreter between two steps, which `restore` returns
+/// it to.
+#[derive(Clone, Debug)]
+pub struct Snapshot<C: Cell = u8> {
+  tape: Vec<C>,
+  ptr: usize,
+  pc: usize,
+  procedures: Vec<Option<usize>>,
+  calls: Vec<usize>,
+  storage: C,
+  tapes: VecDeque<(Vec<C>, usize)>,
+  random: Random,
+}
+
+/// The generator `?` draws from, which is `java.util.Random`'s so that a
+/// seed gives the interpreter and generated classes the same bytes.
+#[derive(Copy, Clone, Debug)]
+pub struct Random(u64);
+
+impl Random {
+  const MULTIPLIER: u64 = 0x5_deec_e66d;
+  const MASK: u64 = (1 << 48) - 1;
+
+  pub fn new(seed: i64) -> Random {
+    Random((seed as u64 ^ Random::MULTIPLIER) & Random::MASK)
+  }
+
+  /// Seeded from the clock, for runs without `--seed`.
+  pub fn from_time() -> Random {
+    let nanos = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map_or(0, |time| time.as_nanos() as i64);
+    Random::new(nanos)
+  }
+
+  /// The byte `nextInt(256)` returns: the top 8 of the 48 bits.
+  pub fn next_byte(&mut self) -> u8 {
+    self.0 = self.0.wrapping_mul(Random::MULTIPLIER).wrapping_add(0xb) & Random::MASK;
+    (self.0 >> 40) as u8
+  }
+}
+
+/// What `,` and `;` leave in the cell once the input is exhausted.
+#[derive(PartialEq, Copy, Clone, Debug, Default)]
+pub enum Eof {
+  #[default]
+  Unchanged,
+  Zero,
+  MinusOne,
+}
+
+impl Eof {
+  pub fn parse(text: &str) -> Result<Eof, String> {
+    match text {
+      "unchanged" => Ok(Eof::Unchanged),
+      "zero" | "0" => Ok(Eof::Zero),
+      "minus-one" | "-1" => Ok(Eof::MinusOne),
+      other => Err(format!("unknown EOF policy {}", other)),
+    }
+  }
+
+  /// Reads one byte of `input` into `cell`, applying the policy at EOF.
+  pub fn read<C: Cell>(self, input: &mut dyn Read, cell: &mut C) -> io::Result<()> {
+    match read_byte(input)? {
+      Some(byte) => *cell = C::from_byte(byte),
+      None => self.apply(cell)?,
+    }
+    Ok(())
+  }
+
+  /// Reads one code point of `input`, encoded in UTF-8, into `cell`,
+  /// applying the policy at EOF.
+  pub fn read_code_point<C: Cell>(self, input: &mut dyn Read, cell: &mut C) -> io::Result<()> {
+    match read_code_point(input)? {
+      Some(code) => *cell = C::from_u32(code),
+      None => self.apply(cell)?,
+    }
+    Ok(())
+  }
+
+  /// Reads a decimal number of `input` into `cell`, wrapping it into the
+  /// cell. Whitespace before it is skipped, a sign may lead it and the byte
+  /// that ends it is consumed. Without any digits the policy applies, as
+  /// at EOF.
+  pub fn read_number<C: Cell>(self, input: &mut dyn Read, cell: &mut C) -> io::Result<()> {
+    let mut next = read_byte(input)?;
+    while let Some(b' ' | b'\t'..=b'\r') = next {
+      next = read_byte(input)?;
+    }
+    let negative = next == Some(b'-');
+    if let Some(b'-' | b'+') = next {
+      next = read_byte(input)?;
+    }
+    let mut value = None;
+    while let Some(digit @ b'0'..=b'9') = next {
+      let tens = value.unwrap_or_else(|| C::from_byte(0));
+      value = C::from_byte(digit - b'0').add_mul(&tens, 10);
+      next = read_byte(input)?;
+    }
+    match value {
+      Some(value) if negative => {
+        *cell = C::from_byte(0).add_mul(&value, -1).ok_or_else(below_zero)?
+      }
+      Some(value) => *cell = value,
+      None => self.apply(cell)?,
+    }
+    Ok(())
+  }
+
+  /// Leaves in `cell` what the policy stores at EOF.
+  fn apply<C: Cell>(self, cell: &mut C) -> io::Result<()> {
+    match self {
+      Eof::Unchanged => (),
+      Eof::Zero => *cell = C::from_byte(0),
+      Eof::MinusOne => *cell = C::from_byte(0).add(-1).ok_or_else(below_zero)?,
+    }
+    Ok(())
+  }
+}
+
+/// The next byte of `input`, or `None` at EOF.
+fn read_byte(input: &mut dyn Read) -> io::Result<Option<u8>> {
+  let mut byte = [0];
+  Ok(match input.read(&mut byte)? {
+    1 => Some(byte[0]),
+    _ => None,
+  })
+}
+
+/// Reads one UTF-8 encoded code point of `input`, or `None` at EOF. Bytes
+/// that do not encode one read as U+FFFD, up to the first that breaks the
+/// sequence.
+fn read_code_point(input: &mut dyn Read) -> io::Result<Option<u32>> {
+  const REPLACEMENT: u32 = char::REPLACEMENT_CHARACTER as u32;
+  let first = match read_byte(input)? {
+    Some(byte) => byte as u32,
+    None => return Ok(None),
+  };
+  let (mut code, more) = match first {
+    0x00..=0x7f => return Ok(Some(first)),
+    0xc0..=0xdf => (first & 0x1f, 1),
+    0xe0..=0xef => (first & 0x0f, 2),
+    0xf0..=0xf7 => (first & 0x07, 3),
+    _ => return Ok(Some(REPLACEMENT)),
+  };
+  for _ in 0..more {
+    match read_byte(input)? {
+      Some(byte) if byte & 0xc0 == 0x80 => code = code << 6 | (byte & 0x3f) as u32,
+      _ => return Ok(Some(REPLACEMENT)),
+    }
+  }
+  Ok(Some(char::from_u32(code).map_or(REPLACEMENT, u32::from)))
+}
+
+/// How `.` and `,` turn cells into the bytes of the streams and back.
+#[derive(PartialEq, Copy, Clone, Debug, Default)]
+pub enum Io {
+  /// `.` prints the character numbered by the low byte of the cell, in
+  /// UTF-8, and `,` reads one byte.
+  #[default]
+  Chars,
+  /// `.` prints the cell as a code point in UTF-8, and `,` decodes one.
+  Unicode,
+  /// `.` prints the low byte of the cell as it is, for binary output, and
+  /// `,` reads one byte.
+  Bytes,
+}
+
+impl Io {
+  pub fn parse(text: &str) -> Result<Io, String> {
+    match text {
+      "chars" => Ok(Io::Chars),
+      "unicode" => Ok(Io::Unicode),
+      "bytes" => Ok(Io::Bytes),
+      other => Err(format!("unknown I/O mode {}", other)),
+    }
+  }
+}
+
+/// What generated code does when the pointer moves off either end of the
+/// tape, or a cell off it is touched. The interpreters always stop there
+/// with an error.
+#[derive(PartialEq, Copy, Clone, Debug, Default)]
+pub enum Bounds {
+  /// Whatever the target does: C and native code run into other memory,
+  /// Python wraps negative indices, and the JVM throws at the next access.
+  #[default]
+  Unchecked,
+  /// Every move and every access off the current cell is checked, and the
+  /// program stops with "pointer moved off the tape" and status 1.
+  Error,
+}
+
+impl Bounds {
+  pub fn parse(text: &str) -> Result<Bounds, String> {
+    match text {
+      "unchecked" => Ok(Bounds::Unchecked),
+      "error" => Ok(Bounds::Error),
+      other => Err(format!("unknown bounds policy {}", other)),
+    }
+  }
+}
+
+/// The cell whose final value becomes the exit code with
+/// `--exit-from-cell`.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum ExitCell {
+  Current,
+  First,
+}
+
+impl ExitCell {
+  pub fn parse(text: &str) -> Result<ExitCell, String> {
+    match text {
+      "current" => Ok(ExitCell::Current),
+      "first" | "0" => Ok(ExitCell::First),
+      other => Err(format!("unknown exit cell {}", other)),
+    }
+  }
+
+  /// The value of the cell in a tape the program finished with.
+  pub fn value(self, tape: &[u8], ptr: usize) -> u8 {
+    match self {
+      ExitCell::Current => tape[ptr],
+      ExitCell::First => tape[0],
+    }
+  }
+}
+
+pub(crate) fn off_tape() -> io::Error {
+  io::Error::other("pointer moved off the tape")
+}
+
+fn below_zero() -> io::Error {
+  io::Error::other("a cell that cannot wrap went below zero")
+}
+
+/// The number of the pbrain procedure a cell holding `cell` defines or
+/// calls. There are 256 of them, so a wider cell holding more than 255 is
+/// an error rather than the procedure its low byte numbers.
+fn procedure<C: Cell>(cell: &C) -> io::Result<usize> {
+  match cell.to_u32() {
+    Some(number) if number < 256 => Ok(number as usize),
+    _ => Err(io::Error::other(format!(
+      "procedure number {} is out of range",
+      cell
+    ))),
+  }
+}
+
+impl<'a> Interpreter<'a> {
+  pub fn new(program: &'a [Inst]) -> Interpreter<'a> {
+    Interpreter::with_cells(program)
+  }
+}
+
+impl<'a, C: Cell> Interpreter<'a, C> {
+  /// An interpreter whose tape holds cells of type `C`.
+  pub fn with_cells(program: &'a [Inst]) -> Interpreter<'a, C> {
+    Interpreter {
+      program,
+      tape: vec![C::from_byte(0); TAPE_SIZE],
+      ptr: 0,
+      pc: 0,
+      eof: Eof::default(),
+      io: Io::default(),
+      procedures: vec![None; 256],
+      calls: Vec::new(),
+      forks: Vec::new(),
+      storage: C::from_byte(0),
+      tapes: VecDeque::new(),
+      random: Random::from_time(),
+      observer: None,
+      trap_overflow: false,
+    }
+  }
+
+  /// A tape of `size` cells in place of `TAPE_SIZE`.
+  pub fn with_tape_size(mut self, size: usize) -> Interpreter<'a, C> {
+    self.tape = vec![C::from_byte(0); size];
+    self
+  }
+
+  pub fn with_eof(mut self, eof: Eof) -> Interpreter<'a, C> {
+    self.eof = eof;
+    self
+  }
+
+  pub fn with_io(mut self, io: Io) -> Interpreter<'a, C> {
+    self.io = io;
+    self
+  }
+
+  pub fn with_seed(mut self, seed: i64) -> Interpreter<'a, C> {
+    self.random = Random::new(seed);
+    self
+  }
+
+  pub fn with_trap_overflow(mut self, trap_overflow: bool) -> Interpreter<'a, C> {
+    self.trap_overflow = trap_overflow;
+    self
+  }
+
+  /// Calls `observer` at every step, which rules out the fast path. Threads
+  /// the program forks are not observed.
+  pub fn with_observer(mut self, observer: &'a mut dyn Observer) -> Interpreter<'a, C> {
+    self.observer = Some(observer);
+    self
+  }
+
+  pub fn pc(&self) -> usize {
+    self.pc
+  }
+
+  pub fn ptr(&self) -> usize {
+    self.ptr
+  }
+
+  pub fn tape(&self) -> &[C] {
+    &self.tape
+  }
+
+  pub fn snapshot(&self) -> Snapshot<C> {
+    Snapshot {
+      tape: self.tape.clone(),
+      ptr: self.ptr,
+      pc: self.pc,
+      procedures: self.procedures.clone(),
+      calls: self.calls.clone(),
+      storage: self.storage.clone(),
+      tapes: self.tapes.clone(),
+      random: self.random,
+    }
+  }
+
+  /// Goes back, or forward, to where `snapshot` was taken. Forks not yet
+  /// taken are dropped.
+  pub fn restore(&mut self, snapshot: &Snapshot<C>) {
+    self.tape.clone_from(&snapshot.tape);
+    self.ptr = snapshot.ptr;
+    self.pc = snapshot.pc;
+    self.procedures.clone_from(&snapshot.procedures);
+    self.calls.clone_from(&snapshot.calls);
+    self.storage = snapshot.storage.clone();
+    self.tapes.clone_from(&snapshot.tapes);
+    self.random = snapshot.random;
+    self.forks.clear();
+  }
+
+  /// Takes the threads that `step` forked, for the caller to run.
+  pub fn take_forks(&mut self) -> Vec<Interpreter<'a, C>> {
+    std::mem::take(&mut self.forks)
+  }
+
+  /// The tapes after the current one, each as long as it is.
+  fn other_tapes(&mut self) -> &mut VecDeque<(Vec<C>, usize)> {
+    if self.tapes.is_empty() {
+      let size = self.tape.len();
+      self.tapes = (1..TAPES)
+        .map(|_| (vec![C::from_byte(0); size], 0))
+        .collect();
+    }
+    &mut self.tapes
+  }
+
+  /// The index of the cell `offset` cells from the pointer.
+  fn address(&self, offset: i32) -> io::Result<usize> {
+    let target = self.ptr as isize + offset as isize;
+    if target < 0 || target as usize >= self.tape.len() {
+      return Err(off_tape());
+    }
+    Ok(target as usize)
+  }
+
+  /// `cell + other * factor`, for the cell at `target` and the instruction
+  /// at `index`. A cell that cannot wrap must not drop below zero, and with
+  /// `trap_overflow` none may leave its range.
+  fn combine(
+    &self,
+    cell: &C,
+    other: &C,
+    factor: i64,
+    index: usize,
+    target: usize,
+  ) -> io::Result<C> {
+    if !self.trap_overflow {
+      return cell.add_mul(other, factor).ok_or_else(below_zero);
+    }
+    cell.add_mul_exact(other, factor).ok_or_else(|| {
+      io::Error::other(format!(
+        "cell {} overflowed at byte {}",
+        target, self.program[index].span.start
+      ))
+    })
+  }
+
+  /// Executes the instruction at the program counter, returning `None` once
+  /// the program has halted.
+  pub fn step(&mut self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<Option<Step>> {
+    if self.observer.is_none() {
+      return self.execute(input, output);
+    }
+    let mut observed = Observed {
+      output,
+      printed: Vec::new(),
+    };
+    let step = match self.execute(input, &mut observed)? {
+      Some(step) => step,
+      None => return Ok(None),
+    };
+    let fell_through = self.pc == step.index + 1;
+    let observer = self.observer.as_mut().unwrap();
+    observer.on_step(&step)?;
+    match step.inst.op {
+      Op::JumpIfZero(_) if fell_through => observer.on_loop_enter(step.index)?,
+      Op::JumpIfNonZero(start) if fell_through => observer.on_loop_exit(start as usize)?,
+      Op::ReadChar(_) | Op::ReadNumber => observer.on_io(&step, IoEvent::Input(step.after))?,
+      _ => (),
+    }
+    if !observed.printed.is_empty() {
+      observer.on_io(&step, IoEvent::Output(&observed.printed))?;
+    }
+    Ok(Some(step))
+  }
+
+  fn execute(&mut self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<Option<Step>> {
+    let index = self.pc;
+    let inst = match self.program.get(index) {
+      Some(inst) => inst.clone(),
+      None => return Ok(None),
+    };
+    let ptr = self.ptr;
+    let before = self.tape[ptr].clone();
+    let byte = before.low_byte();
+    self.pc += 1;
+    match inst.op {
+      Op::Plus(count) => {
+        self.tape[ptr] = self.combine(&before, &C::from_byte(1), count as i64, index, ptr)?
+      }
+      Op::Minus(count) => {
+        self.tape[ptr] = self.combine(&before, &C::from_byte(1), -(count as i64), index, ptr)?
+      }
+      Op::Right(count) => {
+        self.ptr = ptr
+          .checked_add(count as usize)
+          .filter(|&p| p < self.tape.len())
+          .ok_or_else(off_tape)?
+      }
+      Op::Left(count) => self.ptr = ptr.checked_sub(count as usize).ok_or_else(off_tape)?,
+      Op::PutChar(count) if self.io == Io::Bytes => {
+        output.write_all(&vec![byte; count as usize])?
+      }
+      Op::PutChar(count) => {
+        let c = match self.io {
+          Io::Chars | Io::Bytes => byte as char,
+          Io::Unicode => before
+            .to_u32()
+            .and_then(char::from_u32)
+            .unwrap_or(char::REPLACEMENT_CHARACTER),
+        };
+        for _ in 0..count {
+          write!(output, "{}", c)?;
+        }
+      }
+      Op::ReadChar(count) => {
+        output.flush()?;
+        for _ in 0..count {
+          match self.io {
+            Io::Chars | Io::Bytes => self.eof.read(input, &mut self.tape[ptr])?,
+            Io::Unicode => self.eof.read_code_point(input, &mut self.tape[ptr])?,
+          }
+        }
+      }
+      Op::JumpIfZero(target) => {
+        if before.is_zero() {
+          self.pc = target as usize + 1;
+        }
+      }
+      Op::JumpIfNonZero(target) => {
+        if !before.is_zero() {
+          self.pc = target as usize + 1;
+        }
+      }
+      Op::SetZero => self.tape[ptr] = C::from_byte(0),
+      Op::AddTo { offset, factor } => {
+        if !before.is_zero() {
+          let target = self.address(offset)?;
+          self.tape[target] =
+            self.combine(&self.tape[target], &before, factor as i64, index, target)?;
+        }
+      }
+      Op::Add { offset, amount } => {
+        let target = self.address(offset)?;
+        self.tape[target] = self.combine(
+          &self.tape[target],
+          &C::from_byte(1),
+          amount as i64,
+          index,
+          target,
+        )?;
+      }
+      Op::Set { offset, value } => {
+        let target = self.address(offset)?;
+        self.tape[target] = self.combine(
+          &C::from_byte(0),
+          &C::from_byte(1),
+          value as i64,
+          index,
+          target,
+        )?
+      }
+      Op::PutConst { value, count } if self.io == Io::Bytes => {
+        output.write_all(&vec![value; count as usize])?
+      }
+      Op::PutConst { value, count } => {
+        for _ in 0..count {
+          write!(output, "{}", value as char)?;
+        }
+      }
+      Op::Print(ref text) if self.io == Io::Bytes => output.write_all(text.as_bytes())?,
+      Op::Print(ref text) => write!(output, "{}", text.chars())?,
+      Op::ScanZero { stride } => self.ptr = scan_zero(&self.tape, ptr, stride as isize)?,
+      Op::Procedure(end) => {
+        self.procedures[procedure(&before)?] = Some(index + 1);
+        self.pc = end as usize + 1;
+      }
+      Op::Return(_) => {
+        self.pc = self
+          .calls
+          .pop()
+          .ok_or_else(|| io::Error::other("return from a procedure that was not called"))?
+      }
+      Op::Call => match self.procedures[procedure(&before)?] {
+        Some(start) => {
+          self.calls.push(self.pc);
+          self.pc = start;
+        }
+        None => {
+          return Err(io::Error::other(format!(
+            "call of undefined procedure {}",
+            byte
+          )))
+        }
+      },
+      Op::Fork => {
+        let mut child = Interpreter {
+          program: self.program,
+          tape: self.tape.clone(),
+          ptr: ptr + 1,
+          pc: self.pc,
+          eof: self.eof,
+          io: self.io,
+          procedures: self.procedures.clone(),
+          calls: self.calls.clone(),
+          forks: Vec::new(),
+          storage: self.storage.clone(),
+          tapes: self.tapes.clone(),
+          random: self.random,
+          observer: None,
+          trap_overflow: self.trap_overflow,
+        };
+        *child.tape.get_mut(ptr + 1).ok_or_else(off_tape)? = C::from_byte(1);
+        self.tape[ptr] = C::from_byte(0);
+        self.forks.push(child);
+      }
+      Op::Ebf(Ebf::End) => self.pc = self.program.len(),
+      Op::Ebf(Ebf::Store) => self.storage = before,
+      Op::Ebf(Ebf::Retrieve) => self.tape[ptr] = self.storage.clone(),
+      Op::Ebf(command) => {
+        self.tape[ptr] = before.ebf(command, &self.storage).ok_or_else(|| {
+          io::Error::other(format!(
+            "{:?} has no meaning for cells of this type",
+            command
+          ))
+        })?
+      }
+      Op::Debug => {
+        output.flush()?;
+        eprintln!("{}", dump(&self.tape, ptr));
+      }
+      Op::PutNumber => write!(output, "{}", before)?,
+      Op::Random => self.tape[ptr] = C::from_byte(self.random.next_byte()),
+      Op::Tape(Tape::Next) => {
+        let (tape, next) = self.other_tapes().pop_front().unwrap();
+        let tape = std::mem::replace(&mut self.tape, tape);
+        self.tapes.push_back((tape, ptr));
+        self.ptr = next;
+      }
+      Op::Tape(Tape::Copy) => {
+        let (tape, next) = &mut self.other_tapes()[0];
+        tape[*next] = before;
+      }
+      Op::ReadNumber => {
+        output.flush()?;
+        self.eof.read_number(input, &mut self.tape[ptr])?;
+      }
+    }
+    Ok(Some(Step {
+      index,
+      inst,
+      ptr,
+      before: byte,
+      after: self.tape[ptr].low_byte(),
+    }))
+  }
+
+  /// Runs the program to completion. A fresh, untraced run of a program
+  /// the threaded code supports uses that fast path when cells are bytes;
+  /// otherwise execution goes through `step` so every instruction of the
+  /// folded IR is observed. Programs that fork run every thread, and trace
+  /// only this one.
+  pub fn run(
+    &mut self,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+    mut tracer: Option<&mut Tracer>,
+  ) -> io::Result<()> {
+    if has_forks(self.program) {
+      return fork::run(self, input, output, tracer);
+    }
+    let fast = tracer.is_none()
+      && self.observer.is_none()
+      && !self.trap_overflow
+      && self.pc == 0
+      && self.io == Io::Chars
+      && threaded::supports(self.program);
+    if let Some(tape) = (&mut self.tape as &mut dyn Any).downcast_mut::<Vec<u8>>() {
+      if fast {
+        let ops = threaded::decode(self.program);
+        let mut machine = threaded::Machine {
+          program: self.program,
+          tape,
+          ptr: self.ptr,
+          eof: self.eof,
+          input,
+          output,
+        };
+        threaded::execute(&mut machine, &ops)?;
+        self.ptr = machine.ptr;
+        self.pc = self.program.len();
+        return output.flush();
+      }
+    }
+    while let Some(step) = self.step(input, output)? {
+      if let Some(tracer) = tracer.as_mut() {
+        tracer.record(&step)?;
+      }
+    }
+    output.flush()
+  }
+
+  /// The bytes the program prints, each yielded as soon as the program
+  /// prints it, for output too long to collect. Threads the program forks
+  /// are left in `take_forks` rather than run.
+  pub fn output_iter<'i>(&'i mut self, input: &'i mut dyn Read) -> OutputIter<'i, 'a, C> {
+    OutputIter {
+      interpreter: self,
+      input,
+      pending: VecDeque::new(),
+      error: None,
+    }
+  }
+}
+
+/// What `Interpreter::output_iter` returns. It ends when the program halts
+/// or fails, keeping the error for `error`.
+pub struct OutputIter<'i, 'a, C: Cell = u8> {
+  interpreter: &'i mut Interpreter<'a, C>,
+  input: &'i mut dyn Read,
+  /// Bytes printed by the last step and not yet yielded.
+  pending: VecDeque<u8>,
+  error: Option<io::Error>,
+}
+
+impl<C: Cell> OutputIter<'_, '_, C> {
+  /// Why the output ended early, if it did.
+  pub fn error(&self) -> Option<&io::Error> {
+    self.error.as_ref()
+  }
+}
+
+impl<C: Cell> Iterator for OutputIter<'_, '_, C> {
+  type Item = u8;
+
+  fn next(&mut self) -> Option<u8> {
+    while self.pending.is_empty() && self.error.is_none() {
+      match self.interpreter.step(self.input, &mut self.pending) {
+        Ok(Some(_)) => (),
+        Ok(None) => return None,
+        Err(error) => self.error = Some(error),
+      }
+    }
+    self.pending.pop_front()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::{lex_dialect, parse_program, Dialect, Inst, Op, Span};
+  use super::Interpreter;
+
+  fn pbrain(code: &str) -> Vec<Inst> {
+    lex_dialect(code, Dialect::Pbrain)
+      .and_then(parse_program)
+      .unwrap()
+  }
+
+  #[test]
+  fn a_return_without_a_call_is_an_error() {
+    let program = [Inst {
+      op: Op::Return(0),
+      span: Span { start: 0, end: 1 },
+    }];
+    let error = Interpreter::new(&program)
+      .run(&mut &b""[..], &mut Vec::new(), None)
+      .unwrap_err();
+    assert_eq!(
+      error.to_string(),
+      "return from a procedure that was not called"
+    );
+  }
+
+  #[test]
+  fn wide_cells_number_no_procedure_past_255() {
+    let defines = |count: usize| pbrain(&format!("{}(-):", "+".repeat(count)));
+    for count in [1, 255, 257] {
+      let program = defines(count);
+      assert!(Interpreter::new(&program)
+        .run(&mut &b""[..], &mut Vec::new(), None)
+        .is_ok());
+    }
+    for count in [1, 255] {
+      let program = defines(count);
+      assert!(Interpreter::<u16>::with_cells(&program)
+        .run(&mut &b""[..], &mut Vec::new(), None)
+        .is_ok());
+    }
+    for count in [256, 257] {
+      let program = defines(count);
+      let error = Interpreter::<u16>::with_cells(&program)
+        .run(&mut &b""[..], &mut Vec::new(), None)
+        .unwrap_err();
+      assert_eq!(
+        error.to_string(),
+        format!("procedure number {} is out of range", count)
+      );
+    }
+  }
+}
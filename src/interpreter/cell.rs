@@ -0,0 +1,256 @@
+//! What a cell of the interpreter's tape holds. Programs are usually
+//! written for bytes, but some only work with wider cells, or with cells
+//! that never wrap; `--cell-type` picks one of these to run them on.
+
+use std::convert::TryFrom;
+use std::fmt;
+
+use crate::Ebf;
+
+/// A cell of the tape. Output and traces see its low byte; `:` prints all
+/// of it.
+pub trait Cell: Clone + PartialEq + fmt::Display + Send + 'static {
+  fn from_byte(byte: u8) -> Self;
+
+  fn low_byte(&self) -> u8;
+
+  /// The cell holding `value`, wrapped to its width.
+  fn from_u32(value: u32) -> Self;
+
+  /// The cell as a code point, if it is in range of one.
+  fn to_u32(&self) -> Option<u32>;
+
+  fn is_zero(&self) -> bool {
+    *self == Self::from_byte(0)
+  }
+
+  /// `self + other * factor`, wrapping at the width of the cell, or `None`
+  /// when a cell that cannot wrap would drop below zero.
+  fn add_mul(&self, other: &Self, factor: i64) -> Option<Self>;
+
+  /// `self + other * factor`, or `None` when that is out of the range of
+  /// the cell.
+  fn add_mul_exact(&self, other: &Self, factor: i64) -> Option<Self>;
+
+  fn add(&self, amount: i64) -> Option<Self> {
+    self.add_mul(&Self::from_byte(1), amount)
+  }
+
+  /// The cell after an Extended Brainfuck command that changes it in
+  /// place or combines it with `storage`, or `None` when the command has
+  /// no meaning for the cell.
+  fn ebf(&self, command: Ebf, storage: &Self) -> Option<Self>;
+}
+
+macro_rules! fixed_cell {
+  ($($int:ty),*) => {$(
+    impl Cell for $int {
+      fn from_byte(byte: u8) -> $int {
+        byte as $int
+      }
+
+      fn low_byte(&self) -> u8 {
+        *self as u8
+      }
+
+      fn from_u32(value: u32) -> $int {
+        value as $int
+      }
+
+      fn to_u32(&self) -> Option<u32> {
+        u32::try_from(*self).ok()
+      }
+
+      fn add_mul(&self, other: &$int, factor: i64) -> Option<$int> {
+        Some(self.wrapping_add(other.wrapping_mul(factor as $int)))
+      }
+
+      fn add_mul_exact(&self, other: &$int, factor: i64) -> Option<$int> {
+        <$int>::try_from(*self as i128 + *other as i128 * factor as i128).ok()
+      }
+
+      fn ebf(&self, command: Ebf, storage: &$int) -> Option<$int> {
+        Some(match command {
+          Ebf::ShiftRight => self >> 1,
+          Ebf::ShiftLeft => self << 1,
+          Ebf::Not => !self,
+          Ebf::Xor => self ^ storage,
+          Ebf::And => self & storage,
+          Ebf::Or => self | storage,
+          Ebf::End | Ebf::Store | Ebf::Retrieve => *self,
+        })
+      }
+    }
+  )*};
+}
+
+fixed_cell!(u8, u16, u32, i64);
+
+/// Cells that grow as needed and never wrap, so that `-` on zero is an
+/// error.
+#[cfg(feature = "bigint")]
+impl Cell for num_bigint::BigUint {
+  fn from_byte(byte: u8) -> num_bigint::BigUint {
+    byte.into()
+  }
+
+  fn low_byte(&self) -> u8 {
+    self.iter_u32_digits().next().unwrap_or(0) as u8
+  }
+
+  fn from_u32(value: u32) -> num_bigint::BigUint {
+    value.into()
+  }
+
+  fn to_u32(&self) -> Option<u32> {
+    u32::try_from(self).ok()
+  }
+
+  fn add_mul(&self, other: &num_bigint::BigUint, factor: i64) -> Option<num_bigint::BigUint> {
+    let product = other * factor.unsigned_abs();
+    if factor < 0 {
+      (self >= &product).then(|| self - product)
+    } else {
+      Some(self + product)
+    }
+  }
+
+  fn add_mul_exact(&self, other: &num_bigint::BigUint, factor: i64) -> Option<num_bigint::BigUint> {
+    self.add_mul(other, factor)
+  }
+
+  fn ebf(&self, command: Ebf, storage: &num_bigint::BigUint) -> Option<num_bigint::BigUint> {
+    Some(match command {
+      Ebf::ShiftRight => self >> 1,
+      Ebf::ShiftLeft => self << 1,
+      // Every bit above the highest one set would turn on.
+      Ebf::Not => return None,
+      Ebf::Xor => self ^ storage,
+      Ebf::And => self & storage,
+      Ebf::Or => self | storage,
+      Ebf::End | Ebf::Store | Ebf::Retrieve => self.clone(),
+    })
+  }
+}
+
+/// The cells `--cell-type` names.
+#[derive(PartialEq, Copy, Clone, Debug, Default)]
+pub enum CellType {
+  #[default]
+  U8,
+  U16,
+  U32,
+  I64,
+  /// Arbitrary precision, with the `bigint` feature.
+  Big,
+}
+
+impl CellType {
+  pub fn parse(text: &str) -> Result<CellType, String> {
+    match text {
+      "u8" | "8" => Ok(CellType::U8),
+      "u16" | "16" => Ok(CellType::U16),
+      "u32" | "32" => Ok(CellType::U32),
+      "i64" | "64" => Ok(CellType::I64),
+      "big" | "biguint" => Ok(CellType::Big),
+      other => Err(format!("unknown cell type {}", other)),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fmt::Debug;
+  use std::io;
+
+  use super::super::Eof;
+  use super::Cell;
+
+  /// What `eof` leaves in a cell holding `cell` at the end of input, or why
+  /// it cannot.
+  fn at_eof<C: Cell>(eof: Eof, mut cell: C) -> Result<C, String> {
+    eof
+      .read(&mut io::empty(), &mut cell)
+      .map(|()| cell)
+      .map_err(|e| e.to_string())
+  }
+
+  /// Checks the policies at EOF on a cell of type `C`, where `minus_one`
+  /// is what `-1` wraps to.
+  fn assert_eof<C: Cell + Debug>(minus_one: Result<C, String>) {
+    let seven = C::from_byte(7);
+    assert_eq!(at_eof(Eof::Unchanged, seven.clone()), Ok(seven.clone()));
+    assert_eq!(at_eof(Eof::Zero, seven.clone()), Ok(C::from_byte(0)));
+    assert_eq!(at_eof(Eof::MinusOne, seven), minus_one);
+    let mut cell = C::from_byte(0);
+    Eof::MinusOne.read(&mut &b"\xe9"[..], &mut cell).unwrap();
+    assert_eq!(cell, C::from_byte(0xe9));
+  }
+
+  #[test]
+  fn fixed_cells_wrap_at_their_width() {
+    assert_eq!(0u16.add(-1), Some(u16::MAX));
+    assert_eq!(u16::MAX.add(1), Some(0));
+    assert_eq!(300u16.add_mul(&300, 300), Some(24764));
+    assert_eq!(u16::from_u32(0x1_2345), 0x2345);
+    assert_eq!(0x1234u16.low_byte(), 0x34);
+
+    assert_eq!(0u32.add(-1), Some(u32::MAX));
+    assert_eq!(u32::MAX.add(1), Some(0));
+    assert_eq!(u32::MAX.to_u32(), Some(u32::MAX));
+    assert_eq!(0x1234_5678u32.low_byte(), 0x78);
+
+    assert_eq!(0i64.add(-1), Some(-1));
+    assert_eq!(i64::MAX.add(1), Some(i64::MIN));
+    assert_eq!(i64::MIN.add(-1), Some(i64::MAX));
+    assert_eq!((-1i64).low_byte(), 0xff);
+    assert_eq!((-1i64).to_u32(), None);
+    assert_eq!((1i64 << 32).to_u32(), None);
+  }
+
+  #[test]
+  fn exact_arithmetic_leaving_the_range_of_a_cell_is_none() {
+    assert_eq!(u16::MAX.add_mul_exact(&1, 1), None);
+    assert_eq!(0u16.add_mul_exact(&1, -1), None);
+    assert_eq!(100u16.add_mul_exact(&300, 200), Some(60100));
+    assert_eq!(300u16.add_mul_exact(&300, 300), None);
+
+    assert_eq!(u32::MAX.add_mul_exact(&1, 1), None);
+    assert_eq!(0u32.add_mul_exact(&1, -1), None);
+    assert_eq!(1u32.add_mul_exact(&u32::MAX, -1), None);
+    assert_eq!(u32::MAX.add_mul_exact(&u32::MAX, -1), Some(0));
+
+    assert_eq!(i64::MAX.add_mul_exact(&1, 1), None);
+    assert_eq!(i64::MIN.add_mul_exact(&1, -1), None);
+    assert_eq!(i64::MAX.add_mul_exact(&i64::MAX, -1), Some(0));
+    assert_eq!(0i64.add_mul_exact(&1, -1), Some(-1));
+  }
+
+  #[test]
+  fn minus_one_at_eof_wraps_to_the_largest_value_of_a_fixed_cell() {
+    assert_eof::<u8>(Ok(u8::MAX));
+    assert_eof::<u16>(Ok(u16::MAX));
+    assert_eof::<u32>(Ok(u32::MAX));
+    assert_eof::<i64>(Ok(-1));
+  }
+
+  #[cfg(feature = "bigint")]
+  #[test]
+  fn big_cells_grow_instead_of_wrapping() {
+    use num_bigint::BigUint;
+
+    let max = BigUint::from_u32(u32::MAX);
+    let grown = max.add(1).unwrap();
+    assert_eq!(grown, BigUint::from(1u64 << 32));
+    assert_eq!(grown.low_byte(), 0);
+    assert_eq!(grown.to_u32(), None);
+    assert_eq!(
+      max.add_mul(&max, 1),
+      Some(BigUint::from(2 * u32::MAX as u64))
+    );
+    assert_eq!(BigUint::from_byte(0).add(-1), None);
+    assert_eq!(max.add_mul_exact(&max, -2), None);
+    assert_eq!(max.ebf(crate::Ebf::Not, &max), None);
+    assert_eof::<BigUint>(Err("a cell that cannot wrap went below zero".to_string()));
+  }
+}
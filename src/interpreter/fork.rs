@@ -0,0 +1,118 @@
+//! Brainfork threads. Each thread runs an `Interpreter` of its own on a
+//! scoped thread, and asks the calling thread, which owns the streams, to
+//! do its I/O over a channel.
+
+use std::io;
+use std::io::prelude::*;
+use std::sync::mpsc::{channel, Sender};
+use std::thread::{self, Scope};
+
+use super::cell::Cell;
+use super::Interpreter;
+use crate::trace::Tracer;
+
+enum Request {
+  Write(Vec<u8>),
+  /// One byte of input, answered with `None` at EOF.
+  Read(Sender<io::Result<Option<u8>>>),
+  /// A thread other than the first stopped with an error.
+  Failed(io::Error),
+}
+
+fn closed() -> io::Error {
+  io::Error::other("the streams of the program were closed")
+}
+
+/// The streams as a thread sees them.
+struct Remote(Sender<Request>);
+
+impl Write for Remote {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    self
+      .0
+      .send(Request::Write(buf.to_vec()))
+      .map_err(|_| closed())?;
+    Ok(buf.len())
+  }
+
+  /// Output is flushed by the calling thread before it reads input.
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+impl Read for Remote {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    if buf.is_empty() {
+      return Ok(0);
+    }
+    let (reply, answer) = channel();
+    self.0.send(Request::Read(reply)).map_err(|_| closed())?;
+    match answer.recv().map_err(|_| closed())?? {
+      Some(byte) => {
+        buf[0] = byte;
+        Ok(1)
+      }
+      None => Ok(0),
+    }
+  }
+}
+
+/// Runs `interpreter` to completion, starting the threads it forks.
+fn drive<'scope, 'a: 'scope, C: Cell>(
+  scope: &'scope Scope<'scope, '_>,
+  interpreter: &mut Interpreter<'a, C>,
+  requests: &Sender<Request>,
+  mut tracer: Option<&mut Tracer>,
+) -> io::Result<()> {
+  let mut input = Remote(requests.clone());
+  let mut output = Remote(requests.clone());
+  while let Some(step) = interpreter.step(&mut input, &mut output)? {
+    if let Some(tracer) = tracer.as_mut() {
+      tracer.record(&step)?;
+    }
+    for mut child in interpreter.take_forks() {
+      let requests = requests.clone();
+      scope.spawn(move || {
+        if let Err(error) = drive(scope, &mut child, &requests, None) {
+          // Only fails once the calling thread has given up already.
+          let _ = requests.send(Request::Failed(error));
+        }
+      });
+    }
+  }
+  Ok(())
+}
+
+/// Runs `interpreter` and every thread it forks, returning once all of them
+/// have finished.
+pub fn run<C: Cell>(
+  interpreter: &mut Interpreter<C>,
+  input: &mut dyn Read,
+  output: &mut dyn Write,
+  tracer: Option<&mut Tracer>,
+) -> io::Result<()> {
+  let (requests, received) = channel();
+  thread::scope(|scope| {
+    let first = scope.spawn(move || drive(scope, interpreter, &requests, tracer));
+    let mut failure = None;
+    // Ends once every thread has dropped its sender.
+    for request in received {
+      match request {
+        Request::Write(bytes) => output.write_all(&bytes)?,
+        Request::Read(reply) => {
+          output.flush()?;
+          let mut byte = [0];
+          let read = input
+            .read(&mut byte)
+            .map(|count| Some(byte[0]).filter(|_| count == 1));
+          let _ = reply.send(read);
+        }
+        Request::Failed(error) => failure = failure.or(Some(error)),
+      }
+    }
+    first.join().unwrap()?;
+    failure.map_or(Ok(()), Err)
+  })?;
+  output.flush()
+}
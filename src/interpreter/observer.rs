@@ -0,0 +1,56 @@
+//! Hooks into the execution of an interpreter, which `with_observer`
+//! installs and every `step` calls, so that tools watching a run need not
+//! drive it themselves.
+
+use std::io;
+
+use super::Step;
+
+/// Input or output of one instruction.
+#[derive(Copy, Clone, Debug)]
+pub enum IoEvent<'b> {
+  /// The bytes it printed.
+  Output(&'b [u8]),
+  /// The low byte of the cell it read into.
+  Input(u8),
+}
+
+/// Callbacks of a run, each after the instruction it is about. An error
+/// stops the run with it.
+pub trait Observer: Send {
+  fn on_step(&mut self, _step: &Step) -> io::Result<()> {
+    Ok(())
+  }
+
+  fn on_io(&mut self, _step: &Step, _event: IoEvent) -> io::Result<()> {
+    Ok(())
+  }
+
+  /// A loop was entered at the `[` at `start`.
+  fn on_loop_enter(&mut self, _start: usize) -> io::Result<()> {
+    Ok(())
+  }
+
+  /// The loop opened at `start` was left at its `]`.
+  fn on_loop_exit(&mut self, _start: usize) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+/// The output of a step, kept for `on_io` as it goes through.
+pub(crate) struct Observed<'w> {
+  pub output: &'w mut dyn io::Write,
+  pub printed: Vec<u8>,
+}
+
+impl io::Write for Observed<'_> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let written = self.output.write(buf)?;
+    self.printed.extend_from_slice(&buf[..written]);
+    Ok(written)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.output.flush()
+  }
+}
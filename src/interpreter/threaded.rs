@@ -0,0 +1,490 @@
+//! Threaded-code execution: the folded IR is pre-decoded into a flat array of
+//! ops whose handlers are looked up in a function-pointer table, with common
+//! instruction pairs fused into superinstructions.
+
+use std::io;
+use std::io::prelude::*;
+
+use super::{off_tape, scan_zero, Eof};
+use crate::{Inst, Op as IrOp};
+
+#[derive(Copy, Clone)]
+enum Code {
+  Add,
+  Move,
+  Out,
+  In,
+  OutConst,
+  Print,
+  JumpIfZero,
+  JumpIfNonZero,
+  SetZero,
+  AddTo,
+  AddAt,
+  SetAt,
+  ScanZero,
+  /// `+`/`-` immediately followed by `>`/`<`.
+  AddMove,
+  /// A cleared cell immediately followed by `>`/`<`.
+  SetZeroMove,
+}
+
+#[derive(Copy, Clone)]
+pub struct Op {
+  code: Code,
+  /// Amount to add, multiply by or store, repeat count for I/O, jump
+  /// target, or the IR index of a `Print`.
+  arg: usize,
+  /// Pointer movement or scan stride, the cell offset of `AddTo`, `AddAt`
+  /// and `SetAt`, or the byte printed by `OutConst`.
+  delta: isize,
+}
+
+pub struct Machine<'m> {
+  pub program: &'m [Inst],
+  pub tape: &'m mut [u8],
+  pub ptr: usize,
+  pub eof: Eof,
+  pub input: &'m mut dyn Read,
+  pub output: &'m mut dyn Write,
+}
+
+type Handler = fn(&mut Machine, &Op, usize) -> io::Result<usize>;
+
+const HANDLERS: [Handler; 15] = [
+  op_add,
+  op_move,
+  op_out,
+  op_in,
+  op_out_const,
+  op_print,
+  op_jump_if_zero,
+  op_jump_if_non_zero,
+  op_set_zero,
+  op_add_to,
+  op_add_at,
+  op_set_at,
+  op_scan_zero,
+  op_add_move,
+  op_set_zero_move,
+];
+
+fn move_delta(inst: &Inst) -> Option<isize> {
+  match inst.op {
+    IrOp::Right(count) => Some(count as isize),
+    IrOp::Left(count) => Some(-(count as isize)),
+    _ => None,
+  }
+}
+
+/// Whether `decode` takes `program`: none of the extensions of plain
+/// Brainfuck, nor `#`, `:`, `;`, `?`, `%` or `=`.
+pub fn supports(program: &[Inst]) -> bool {
+  program.iter().all(|inst| {
+    !matches!(
+      inst.op,
+      IrOp::Procedure(_)
+        | IrOp::Return(_)
+        | IrOp::Call
+        | IrOp::Fork
+        | IrOp::Ebf(_)
+        | IrOp::Debug
+        | IrOp::PutNumber
+        | IrOp::ReadNumber
+        | IrOp::Random
+        | IrOp::Tape(_)
+    )
+  })
+}
+
+pub fn decode(program: &[Inst]) -> Vec<Op> {
+  let mut ops: Vec<Op> = Vec::with_capacity(program.len());
+  let mut open = Vec::new();
+  let mut i = 0;
+  while i < program.len() {
+    let next_delta = program.get(i + 1).and_then(move_delta);
+    let op = match program[i].op {
+      IrOp::Plus(count) | IrOp::Minus(count) => {
+        let amount = if let IrOp::Plus(_) = program[i].op {
+          count as usize % 256
+        } else {
+          (256 - count as usize % 256) % 256
+        };
+        match next_delta {
+          Some(delta) => {
+            i += 1;
+            Op {
+              code: Code::AddMove,
+              arg: amount,
+              delta,
+            }
+          }
+          None => Op {
+            code: Code::Add,
+            arg: amount,
+            delta: 0,
+          },
+        }
+      }
+      IrOp::Right(_) | IrOp::Left(_) => Op {
+        code: Code::Move,
+        arg: 0,
+        delta: move_delta(&program[i]).unwrap(),
+      },
+      IrOp::PutChar(count) => Op {
+        code: Code::Out,
+        arg: count as usize,
+        delta: 0,
+      },
+      IrOp::ReadChar(count) => Op {
+        code: Code::In,
+        arg: count as usize,
+        delta: 0,
+      },
+      IrOp::SetZero => match next_delta {
+        Some(delta) => {
+          i += 1;
+          Op {
+            code: Code::SetZeroMove,
+            arg: 0,
+            delta,
+          }
+        }
+        None => Op {
+          code: Code::SetZero,
+          arg: 0,
+          delta: 0,
+        },
+      },
+      IrOp::AddTo { offset, factor } => Op {
+        code: Code::AddTo,
+        arg: factor as u8 as usize,
+        delta: offset as isize,
+      },
+      IrOp::Add { offset, amount } => Op {
+        code: Code::AddAt,
+        arg: amount as u8 as usize,
+        delta: offset as isize,
+      },
+      IrOp::Set { offset, value } => Op {
+        code: Code::SetAt,
+        arg: value as u8 as usize,
+        delta: offset as isize,
+      },
+      IrOp::PutConst { value, count } => Op {
+        code: Code::OutConst,
+        arg: count as usize,
+        delta: value as isize,
+      },
+      // Strings stay in the IR; the op refers back to its instruction.
+      IrOp::Print(_) => Op {
+        code: Code::Print,
+        arg: i,
+        delta: 0,
+      },
+      IrOp::ScanZero { stride } => Op {
+        code: Code::ScanZero,
+        arg: 0,
+        delta: stride as isize,
+      },
+      IrOp::JumpIfZero(_) => {
+        open.push(ops.len());
+        Op {
+          code: Code::JumpIfZero,
+          arg: 0,
+          delta: 0,
+        }
+      }
+      IrOp::JumpIfNonZero(_) => {
+        let start = open.pop().unwrap();
+        ops[start].arg = ops.len() + 1;
+        Op {
+          code: Code::JumpIfNonZero,
+          arg: start + 1,
+          delta: 0,
+        }
+      }
+      IrOp::Procedure(_)
+      | IrOp::Return(_)
+      | IrOp::Call
+      | IrOp::Fork
+      | IrOp::Ebf(_)
+      | IrOp::Debug
+      | IrOp::PutNumber
+      | IrOp::ReadNumber
+      | IrOp::Random
+      | IrOp::Tape(_) => unreachable!("rejected by supports"),
+    };
+    ops.push(op);
+    i += 1;
+  }
+  ops
+}
+
+/// Runs `ops` to completion.
+pub fn execute(machine: &mut Machine, ops: &[Op]) -> io::Result<()> {
+  let mut pc = 0;
+  while let Some(op) = ops.get(pc) {
+    pc = HANDLERS[op.code as usize](machine, op, pc)?;
+  }
+  Ok(())
+}
+
+fn move_ptr(machine: &mut Machine, delta: isize) -> io::Result<()> {
+  let ptr = machine.ptr as isize + delta;
+  if ptr < 0 || ptr as usize >= machine.tape.len() {
+    return Err(off_tape());
+  }
+  machine.ptr = ptr as usize;
+  Ok(())
+}
+
+fn op_add(machine: &mut Machine, op: &Op, pc: usize) -> io::Result<usize> {
+  let cell = &mut machine.tape[machine.ptr];
+  *cell = cell.wrapping_add(op.arg as u8);
+  Ok(pc + 1)
+}
+
+fn op_move(machine: &mut Machine, op: &Op, pc: usize) -> io::Result<usize> {
+  move_ptr(machine, op.delta)?;
+  Ok(pc + 1)
+}
+
+fn op_out(machine: &mut Machine, op: &Op, pc: usize) -> io::Result<usize> {
+  let c = machine.tape[machine.ptr] as char;
+  for _ in 0..op.arg {
+    write!(machine.output, "{}", c)?;
+  }
+  Ok(pc + 1)
+}
+
+fn op_out_const(machine: &mut Machine, op: &Op, pc: usize) -> io::Result<usize> {
+  let c = op.delta as u8 as char;
+  for _ in 0..op.arg {
+    write!(machine.output, "{}", c)?;
+  }
+  Ok(pc + 1)
+}
+
+fn op_print(machine: &mut Machine, op: &Op, pc: usize) -> io::Result<usize> {
+  if let IrOp::Print(text) = &machine.program[op.arg].op {
+    write!(machine.output, "{}", text.chars())?;
+  }
+  Ok(pc + 1)
+}
+
+fn op_in(machine: &mut Machine, op: &Op, pc: usize) -> io::Result<usize> {
+  machine.output.flush()?;
+  for _ in 0..op.arg {
+    machine
+      .eof
+      .read(machine.input, &mut machine.tape[machine.ptr])?;
+  }
+  Ok(pc + 1)
+}
+
+fn op_jump_if_zero(machine: &mut Machine, op: &Op, pc: usize) -> io::Result<usize> {
+  Ok(if machine.tape[machine.ptr] == 0 {
+    op.arg
+  } else {
+    pc + 1
+  })
+}
+
+fn op_jump_if_non_zero(machine: &mut Machine, op: &Op, pc: usize) -> io::Result<usize> {
+  Ok(if machine.tape[machine.ptr] != 0 {
+    op.arg
+  } else {
+    pc + 1
+  })
+}
+
+fn op_set_zero(machine: &mut Machine, _: &Op, pc: usize) -> io::Result<usize> {
+  machine.tape[machine.ptr] = 0;
+  Ok(pc + 1)
+}
+
+fn cell_at<'t>(machine: &'t mut Machine, offset: isize) -> io::Result<&'t mut u8> {
+  let target = machine.ptr as isize + offset;
+  if target < 0 {
+    return Err(off_tape());
+  }
+  machine.tape.get_mut(target as usize).ok_or_else(off_tape)
+}
+
+fn op_add_to(machine: &mut Machine, op: &Op, pc: usize) -> io::Result<usize> {
+  let value = machine.tape[machine.ptr];
+  if value != 0 {
+    let cell = cell_at(machine, op.delta)?;
+    *cell = cell.wrapping_add(value.wrapping_mul(op.arg as u8));
+  }
+  Ok(pc + 1)
+}
+
+fn op_add_at(machine: &mut Machine, op: &Op, pc: usize) -> io::Result<usize> {
+  let cell = cell_at(machine, op.delta)?;
+  *cell = cell.wrapping_add(op.arg as u8);
+  Ok(pc + 1)
+}
+
+fn op_set_at(machine: &mut Machine, op: &Op, pc: usize) -> io::Result<usize> {
+  *cell_at(machine, op.delta)? = op.arg as u8;
+  Ok(pc + 1)
+}
+
+fn op_scan_zero(machine: &mut Machine, op: &Op, pc: usize) -> io::Result<usize> {
+  machine.ptr = scan_zero(machine.tape, machine.ptr, op.delta)?;
+  Ok(pc + 1)
+}
+
+fn op_add_move(machine: &mut Machine, op: &Op, pc: usize) -> io::Result<usize> {
+  op_add(machine, op, pc)?;
+  op_move(machine, op, pc)
+}
+
+fn op_set_zero_move(machine: &mut Machine, op: &Op, pc: usize) -> io::Result<usize> {
+  op_set_zero(machine, op, pc)?;
+  op_move(machine, op, pc)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::{Eof, Interpreter};
+  use super::{decode, execute, Code, Machine};
+  use crate::optimizer;
+  use crate::{lex_dialect, parse_program, Dialect, Inst};
+
+  /// How a run ended: the tape and pointer it halted with, or its error.
+  type Ending = Result<(Vec<u8>, usize), String>;
+
+  /// Passes that leave `+`, `-` and cleared cells next to moves, to be
+  /// fused, and ones that fold those into offset accesses and loops into
+  /// multiplications and scans.
+  const PASSES: [&[&str]; 2] = [
+    &["clear-loop"],
+    &["clear-loop", "scan-loop", "multiply", "offset", "peephole"],
+  ];
+
+  fn compile(code: &str, passes: &[&'static str]) -> Vec<Inst> {
+    let program = lex_dialect(code, Dialect::Brainfuck)
+      .and_then(parse_program)
+      .unwrap();
+    let (instructions, _) = optimizer::optimize(program, passes, 0, 0);
+    instructions
+  }
+
+  /// Runs `program` as threaded code, returning what it printed and how it
+  /// ended.
+  fn threaded(program: &[Inst], tape_size: usize, eof: Eof, input: &[u8]) -> (Vec<u8>, Ending) {
+    let mut tape = vec![0; tape_size];
+    let mut input = input;
+    let mut output = Vec::new();
+    let mut machine = Machine {
+      program,
+      tape: &mut tape,
+      ptr: 0,
+      eof,
+      input: &mut input,
+      output: &mut output,
+    };
+    let ending = execute(&mut machine, &decode(program))
+      .map(|()| machine.ptr)
+      .map_err(|e| e.to_string());
+    let ending = ending.map(|ptr| (tape, ptr));
+    (output, ending)
+  }
+
+  /// Runs `program` one instruction of the IR at a time, as the interpreter
+  /// does when it cannot take the threaded path.
+  fn stepped(program: &[Inst], tape_size: usize, eof: Eof, input: &[u8]) -> (Vec<u8>, Ending) {
+    let mut interpreter = Interpreter::new(program)
+      .with_tape_size(tape_size)
+      .with_eof(eof);
+    let mut input = input;
+    let mut output = Vec::new();
+    let ending = loop {
+      match interpreter.step(&mut input, &mut output) {
+        Ok(Some(_)) => (),
+        Ok(None) => break Ok((interpreter.tape().to_vec(), interpreter.ptr())),
+        Err(e) => break Err(e.to_string()),
+      }
+    };
+    (output, ending)
+  }
+
+  fn assert_agree(code: &str, tape_size: usize, eof: Eof, input: &[u8]) {
+    for passes in &PASSES {
+      let program = compile(code, passes);
+      assert_eq!(
+        threaded(&program, tape_size, eof, input),
+        stepped(&program, tape_size, eof, input),
+        "{} after {:?} on {} cells with {:?} at EOF",
+        code,
+        passes,
+        tape_size,
+        eof
+      );
+    }
+  }
+
+  #[test]
+  fn pairs_with_a_move_fuse_into_superinstructions() {
+    let ops = decode(&compile("+>-<[-]>[-]<", PASSES[0]));
+    let codes: Vec<_> = ops.iter().map(|op| op.code as usize).collect();
+    assert_eq!(
+      codes,
+      [
+        Code::AddMove as usize,
+        Code::AddMove as usize,
+        Code::SetZeroMove as usize,
+        Code::SetZeroMove as usize,
+      ]
+    );
+    assert_eq!(ops[1].arg, 255);
+    assert_eq!(ops[1].delta, -1);
+  }
+
+  #[test]
+  fn runs_as_the_interpreter_does() {
+    for code in &[
+      "+>-<[-]>[-]<",
+      "+++>++>+<<[>]<[<]>.",
+      "++++++++[>++++++++<-]>+.>+++[<+++>-]<.",
+      ">>>+<<<-[>>>+<<<-]>>>.",
+      "+>++>+++[<]>[>]<<.",
+      "-[>+>+<<-]>.>.",
+      "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.",
+    ] {
+      assert_agree(code, 30000, Eof::Unchanged, b"");
+    }
+  }
+
+  #[test]
+  fn reads_past_the_end_of_input_as_the_interpreter_does() {
+    for &eof in &[Eof::Unchanged, Eof::Zero, Eof::MinusOne] {
+      for code in &["+,.,.", ",.,.,.", "+++,,,>,+<[->+<]>."] {
+        assert_agree(code, 30000, eof, b"a");
+      }
+    }
+  }
+
+  #[test]
+  fn fails_at_the_edges_of_the_tape_as_the_interpreter_does() {
+    for code in &[
+      ".<",
+      "+<",
+      "[-]<",
+      "+[<]",
+      "+[>]",
+      "+[<+>-]",
+      "+[>>>>+<<<<-]",
+      ">>>>+",
+      "+>>>>.",
+      "++>+<[>]",
+    ] {
+      for &tape_size in &[1, 3, 4] {
+        assert_agree(code, tape_size, Eof::Unchanged, b"");
+      }
+    }
+  }
+}
@@ -0,0 +1,91 @@
+//! Runnable JAR files: an uncompressed zip archive holding the class and a
+//! manifest naming it as the entry point.
+
+/// Zip timestamps for every entry, fixed at 1980-01-01 00:00 so the same
+/// program always produces the same archive.
+const DOS_TIME: u16 = 0;
+const DOS_DATE: u16 = (1 << 5) | 1;
+
+fn crc32(data: &[u8]) -> u32 {
+  let mut crc = !0u32;
+  for &byte in data {
+    crc ^= byte as u32;
+    for _ in 0..8 {
+      crc = if crc & 1 == 1 {
+        (crc >> 1) ^ 0xedb8_8320
+      } else {
+        crc >> 1
+      };
+    }
+  }
+  !crc
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+  out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+  out.extend_from_slice(&value.to_le_bytes());
+}
+
+/// The fields shared by the local and central headers of a stored entry.
+fn entry_header(out: &mut Vec<u8>, name: &str, data: &[u8]) {
+  put_u16(out, 10);
+  put_u16(out, 0);
+  put_u16(out, 0);
+  put_u16(out, DOS_TIME);
+  put_u16(out, DOS_DATE);
+  put_u32(out, crc32(data));
+  put_u32(out, data.len() as u32);
+  put_u32(out, data.len() as u32);
+  put_u16(out, name.len() as u16);
+  put_u16(out, 0);
+}
+
+/// Builds a zip archive storing `files` as given.
+fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+  let mut out = Vec::new();
+  let mut offsets = Vec::with_capacity(files.len());
+  for (name, data) in files {
+    offsets.push(out.len() as u32);
+    put_u32(&mut out, 0x0403_4b50);
+    entry_header(&mut out, name, data);
+    out.extend_from_slice(name.as_bytes());
+    out.extend_from_slice(data);
+  }
+  let directory = out.len() as u32;
+  for ((name, data), offset) in files.iter().zip(offsets) {
+    put_u32(&mut out, 0x0201_4b50);
+    put_u16(&mut out, 10);
+    entry_header(&mut out, name, data);
+    put_u16(&mut out, 0);
+    put_u16(&mut out, 0);
+    put_u16(&mut out, 0);
+    put_u32(&mut out, 0);
+    put_u32(&mut out, offset);
+    out.extend_from_slice(name.as_bytes());
+  }
+  let size = out.len() as u32 - directory;
+  put_u32(&mut out, 0x0605_4b50);
+  put_u16(&mut out, 0);
+  put_u16(&mut out, 0);
+  put_u16(&mut out, files.len() as u16);
+  put_u16(&mut out, files.len() as u16);
+  put_u32(&mut out, size);
+  put_u32(&mut out, directory);
+  put_u16(&mut out, 0);
+  out
+}
+
+/// Packages `class` as `<name>.class` with a manifest that runs it.
+pub fn produce_jar(name: &str, class: &[u8]) -> Vec<u8> {
+  let manifest = format!(
+    "Manifest-Version: 1.0\r\nMain-Class: {}\r\nCreated-By: brainfuck\r\n\r\n",
+    name.replace('/', ".")
+  );
+  zip(&[
+    ("META-INF/MANIFEST.MF", manifest.as_bytes()),
+    (&format!("{}.class", name), class),
+  ])
+}
@@ -0,0 +1,2775 @@
+//! Jasmin assembly for the JVM.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, ErrorKind, Write};
+use std::ops::Range;
+use std::sync::Arc;
+
+use super::interpreter::{Bounds, Eof, ExitCell, Io, TAPE_SIZE};
+use super::json::Json;
+use super::limits;
+use super::profile::Profile;
+use super::{has_debug_dumps, has_ebf, has_procedures, has_random, has_tapes, Ebf, Inst, Op, Span};
+
+impl Inst {
+  /// Appends the code of the instruction to `code`. Loop labels are named
+  /// after the index of the opening bracket, which is unique across the
+  /// program.
+  pub fn emit_bytecode(&self, code: &mut impl Sink, index: usize, config: &Config) {
+    match self.op {
+      Op::Plus(count) => bytecode::plus(code, count as i32, config),
+      Op::Minus(count) => bytecode::plus(code, -(count as i32), config),
+      Op::Left(count) => bytecode::step(code, -(count as i32), config),
+      Op::Right(count) => bytecode::step(code, count as i32, config),
+      Op::PutChar(count) => bytecode::out(code, count as usize, config),
+      Op::ReadChar(count) => bytecode::input(code, count as usize, index, config),
+      Op::JumpIfZero(_) => bytecode::loop_start(code, index, config),
+      Op::JumpIfNonZero(start) => bytecode::loop_end(code, start as usize, config),
+      Op::SetZero => bytecode::set_zero(code, config),
+      Op::AddTo { offset, factor } => bytecode::multiply(code, index, &[(offset, factor)], config),
+      Op::Add { offset, amount } => bytecode::add(code, offset, amount, config),
+      Op::Set { offset, value } => bytecode::set(code, offset, value, config),
+      Op::ScanZero { stride } => bytecode::scan_zero(code, index, stride, config),
+      Op::PutConst { value, count } => bytecode::out_const(code, value, count as usize),
+      Op::Print(ref text) => bytecode::print(code, &text.chars()),
+      Op::Procedure(_) => bytecode::define(code, index, config),
+      Op::Return(_) => unreachable!("procedure bodies end a method of their own"),
+      Op::Call => bytecode::call(code, config),
+      Op::Fork => bytecode::fork(code, index, config),
+      Op::Ebf(command) => bytecode::ebf(code, command, config),
+      Op::Debug => bytecode::dump(code, config),
+      Op::PutNumber => bytecode::out_number(code, config),
+      Op::ReadNumber => bytecode::input_number(code, config),
+      Op::Random => bytecode::random(code, config),
+      Op::Tape(command) => bytecode::tape(code, command, config),
+    }
+  }
+}
+
+/// Appends the code of `instructions[index]` as `Inst::emit_bytecode` does,
+/// except that loops and scans a profile shows hot get superinstructions:
+/// a loop tested at its `]` instead of jumping back to its `[`, and a scan
+/// that steps four times per jump. Both are larger, so cold code keeps the
+/// plain form.
+fn emit(instructions: &[Inst], index: usize, code: &mut impl Sink, config: &Config) {
+  let inst = &instructions[index];
+  match inst.op {
+    Op::JumpIfZero(end) if config.is_hot(instructions[end as usize].span) => {
+      bytecode::rotated_loop_start(code, index, config)
+    }
+    Op::JumpIfNonZero(start) if config.is_hot(inst.span) => {
+      bytecode::rotated_loop_end(code, start as usize, config)
+    }
+    Op::ScanZero { stride } if config.is_hot(inst.span) => {
+      bytecode::unrolled_scan_zero(code, index, stride, config)
+    }
+    _ => inst.emit_bytecode(code, index, config),
+  }
+}
+
+/// Appends a formatted line to a `Sink`. None of them fail: `Output` keeps
+/// its first error for the end.
+macro_rules! emit {
+  ($code:expr, $($format:tt)*) => {
+    std::fmt::Write::write_fmt($code, format_args!("{}\n", format_args!($($format)*))).unwrap()
+  };
+}
+
+/// Where code is emitted to, a line at a time: a `String`, `Size` or the
+/// class `Output` itself. Labels are named after the index of the
+/// instruction they belong to, which `label` may number afresh.
+pub trait Sink: fmt::Write {
+  /// The number in the labels of the instruction at `index`.
+  fn label(&mut self, index: usize) -> usize {
+    index
+  }
+}
+
+impl Sink for String {}
+
+/// Every helper writes whole lines to the sink it is given, so code goes
+/// straight to where it is wanted instead of being built up and joined.
+mod bytecode {
+  use super::{Config, Sink};
+  use crate::interpreter::{Bounds, Eof, ExitCell, Io, DUMP_RADIUS};
+  use crate::{Ebf, Tape, TAPES};
+
+  /// Appends `lines` to `code`.
+  pub fn lines(code: &mut impl Sink, lines: &[&str]) {
+    for line in lines {
+      emit!(code, "{}", line);
+    }
+  }
+
+  /// The shortest instruction pushing `value`.
+  fn push_int(code: &mut impl Sink, value: i32) {
+    match value {
+      -1 => lines(code, &["iconst_m1"]),
+      0..=5 => emit!(code, "iconst_{}", value),
+      -128..=127 => emit!(code, "bipush {}", value),
+      -32768..=32767 => emit!(code, "sipush {}", value),
+      _ => emit!(code, "ldc {}", value),
+    }
+  }
+
+  /// Pushes the tape length: `args[0]` when `tape_from_args` is set and an
+  /// argument was given, `size` otherwise.
+  pub fn tape_size(code: &mut impl Sink, size: i32, config: &Config) {
+    if !config.tape_from_args {
+      return push_int(code, size);
+    }
+    lines(
+      code,
+      &[
+        "aload_0",
+        "arraylength",
+        "ifeq tapeDefault",
+        "aload_0",
+        "iconst_0",
+        "aaload",
+        "invokestatic java/lang/Integer/parseInt(Ljava/lang/String;)I",
+        "goto tapeSized",
+        "tapeDefault:",
+      ],
+    );
+    push_int(code, size);
+    lines(code, &["tapeSized:"]);
+  }
+
+  /// Allocates a tape of the length on top of the stack into local 2.
+  pub fn allocate(code: &mut impl Sink, config: &Config) {
+    lines(
+      code,
+      &[
+        if config.byte_tape {
+          "newarray byte"
+        } else {
+          "newarray int"
+        },
+        "astore_2",
+      ],
+    );
+  }
+
+  /// Stores the value on top of the stack into the cell below it, reduced
+  /// to a byte when cells wrap, once `checkCell` has seen it when overflow
+  /// traps.
+  fn store(code: &mut impl Sink, config: &Config) {
+    if config.trap_overflow {
+      lines(code, &["dup2", "invokestatic Main/checkCell(II)V"]);
+    }
+    if config.byte_tape {
+      lines(code, &["bastore"]);
+    } else if config.wrap {
+      push_int(code, 255);
+      lines(code, &["iand", "iastore"]);
+    } else {
+      lines(code, &["iastore"]);
+    }
+  }
+
+  /// Stores a value already known to fit the cell.
+  fn store_exact(code: &mut impl Sink, config: &Config) {
+    lines(
+      code,
+      &[if config.byte_tape {
+        "bastore"
+      } else {
+        "iastore"
+      }],
+    );
+  }
+
+  /// Loads the cell on top of the stack. Byte tapes load it sign-extended,
+  /// which arithmetic modulo 256 and tests against zero do not mind.
+  fn load(code: &mut impl Sink, config: &Config) {
+    lines(code, &[if config.byte_tape { "baload" } else { "iaload" }]);
+  }
+
+  /// Loads the current cell as a value from 0 to 255 on a byte tape.
+  fn load_unsigned(code: &mut impl Sink, config: &Config) {
+    load(code, config);
+    if config.byte_tape {
+      // Undo the sign extension of `baload`.
+      push_int(code, 255);
+      lines(code, &["iand"]);
+    }
+  }
+
+  /// Puts a buffered input stream into local 5, reading `System.in` or,
+  /// in `run`, the stream passed in local 0. With `input_from_args` it
+  /// reads `args[0]` instead when there is one, typed as any stream so
+  /// that both paths leave local 5 the same.
+  pub fn open_input(code: &mut impl Sink, config: &Config) {
+    if config.input_from_args {
+      lines(
+        code,
+        &[
+          "getstatic java/lang/System/in Ljava/io/InputStream;",
+          "astore 5",
+          "aload_0",
+          "arraylength",
+          "ifeq inputOpened",
+          "new java/io/ByteArrayInputStream",
+          "dup",
+          "aload_0",
+          "iconst_0",
+          "aaload",
+          "ldc \"UTF-8\"",
+          "invokevirtual java/lang/String/getBytes(Ljava/lang/String;)[B",
+          "invokespecial java/io/ByteArrayInputStream/<init>([B)V",
+          "checkcast java/io/InputStream",
+          "astore 5",
+          "inputOpened:",
+          "new java/io/BufferedInputStream",
+          "dup",
+          "aload 5",
+          "invokespecial java/io/BufferedInputStream/<init>(Ljava/io/InputStream;)V",
+          "astore 5",
+        ],
+      );
+      return;
+    }
+    lines(
+      code,
+      &[
+        "new java/io/BufferedInputStream",
+        "dup",
+        if config.has_run() {
+          "aload_0"
+        } else {
+          "getstatic java/lang/System/in Ljava/io/InputStream;"
+        },
+        "invokespecial java/io/BufferedInputStream/<init>(Ljava/io/InputStream;)V",
+        "astore 5",
+      ],
+    );
+  }
+
+  /// Puts the stream all output goes through into local 4: a print stream
+  /// over `System.out` or, in `run`, the stream passed in local 1, with a
+  /// buffer in front of it unless output is unbuffered. Characters are
+  /// encoded in UTF-8 whatever the platform's charset, as the interpreter
+  /// prints them, and bytes in ISO-8859-1, which maps each character below
+  /// 256 back to its byte.
+  pub fn open_output(code: &mut impl Sink, config: &Config) {
+    let target = if config.has_run() {
+      "aload_1"
+    } else {
+      "getstatic java/lang/System/out Ljava/io/PrintStream;"
+    };
+    lines(code, &["new java/io/PrintStream", "dup"]);
+    if config.buffered {
+      lines(code, &["new java/io/BufferedOutputStream", "dup", target]);
+      push_int(code, 1 << 16);
+      lines(
+        code,
+        &["invokespecial java/io/BufferedOutputStream/<init>(Ljava/io/OutputStream;I)V"],
+      );
+    } else {
+      lines(code, &[target]);
+    }
+    lines(code, &["iconst_0"]);
+    if config.io == Io::Bytes {
+      lines(code, &["ldc \"ISO-8859-1\""]);
+    } else {
+      lines(code, &["ldc \"UTF-8\""]);
+    }
+    lines(
+      code,
+      &[
+        "invokespecial java/io/PrintStream/<init>(Ljava/io/OutputStream;ZLjava/lang/String;)V",
+        "astore 4",
+      ],
+    );
+  }
+
+  /// Tests whether `args[i]` is `flag`, jumping to `otherwise` if not.
+  fn match_flag(code: &mut impl Sink, flag: &str, otherwise: &str) {
+    lines(code, &["aload_0", "iload_1", "aaload"]);
+    emit!(code, "ldc \"{}\"", flag);
+    lines(
+      code,
+      &["invokevirtual java/lang/String/equals(Ljava/lang/Object;)Z"],
+    );
+    emit!(code, "ifeq {}", otherwise);
+  }
+
+  /// Opens the file named by `args[i + 1]` as `class`, into `local` typed as
+  /// `base` so it merges with the standard stream held there before.
+  fn open_file(code: &mut impl Sink, class: &str, base: &str, local: u8) {
+    emit!(code, "new {}", class);
+    lines(
+      code,
+      &["dup", "aload_0", "iload_1", "iconst_1", "iadd", "aaload"],
+    );
+    emit!(code, "invokespecial {}/<init>(Ljava/lang/String;)V", class);
+    emit!(code, "checkcast {}", base);
+    emit!(code, "astore {}", local);
+  }
+
+  /// A `main` that reads `--tape <cells>`, `--in <file>` and `--out <file>`
+  /// from its arguments, keeping the index in local 1, the tape length in
+  /// local 2 and the streams in locals 3 and 4.
+  fn parse_args(code: &mut impl Sink, size: i32) {
+    lines(code, &["iconst_0", "istore_1"]);
+    push_int(code, size);
+    lines(
+      code,
+      &[
+        "istore_2",
+        "getstatic java/lang/System/in Ljava/io/InputStream;",
+        "astore_3",
+        "getstatic java/lang/System/out Ljava/io/PrintStream;",
+        "checkcast java/io/OutputStream",
+        "astore 4",
+        "argsNext:",
+        "iload_1",
+        "aload_0",
+        "arraylength",
+        "if_icmpge argsDone",
+      ],
+    );
+    match_flag(code, "--tape", "argsNotTape");
+    lines(
+      code,
+      &[
+        "aload_0",
+        "iload_1",
+        "iconst_1",
+        "iadd",
+        "aaload",
+        "invokestatic java/lang/Integer/parseInt(Ljava/lang/String;)I",
+        "istore_2",
+        "iinc 1 2",
+        "goto argsNext",
+        "argsNotTape:",
+      ],
+    );
+    match_flag(code, "--in", "argsNotIn");
+    open_file(code, "java/io/FileInputStream", "java/io/InputStream", 3);
+    lines(code, &["iinc 1 2", "goto argsNext", "argsNotIn:"]);
+    match_flag(code, "--out", "argsUnknown");
+    open_file(code, "java/io/FileOutputStream", "java/io/OutputStream", 4);
+    lines(
+      code,
+      &[
+        "iinc 1 2",
+        "goto argsNext",
+        "argsUnknown:",
+        "new java/lang/IllegalArgumentException",
+        "dup",
+        "ldc \"unknown argument \"",
+        "aload_0",
+        "iload_1",
+        "aaload",
+        "invokevirtual java/lang/String/concat(Ljava/lang/String;)Ljava/lang/String;",
+        "invokespecial java/lang/IllegalArgumentException/<init>(Ljava/lang/String;)V",
+        "athrow",
+        "argsDone:",
+        "aload_3",
+        "aload 4",
+        "iload_2",
+      ],
+    );
+  }
+
+  /// The entry points of an embeddable class: `main` and the two-argument
+  /// `run` both call `run(in, out, tapeSize)`, which holds the program and
+  /// returns the final value of the exit cell.
+  pub fn entry_points(code: &mut impl Sink, size: i32, config: &Config) {
+    lines(code, &[".method public static main([Ljava/lang/String;)V"]);
+    if config.runtime_args {
+      parse_args(code, size);
+    } else {
+      lines(
+        code,
+        &[
+          "getstatic java/lang/System/in Ljava/io/InputStream;",
+          "getstatic java/lang/System/out Ljava/io/PrintStream;",
+        ],
+      );
+      tape_size(code, size, config);
+    }
+    lines(
+      code,
+      &[
+        "invokestatic Main/run(Ljava/io/InputStream;Ljava/io/OutputStream;I)I",
+        if config.exit_cell.is_some() {
+          "invokestatic java/lang/System/exit(I)V"
+        } else {
+          "pop"
+        },
+        "return",
+        ".end method",
+        "",
+        ".method public static run(Ljava/io/InputStream;Ljava/io/OutputStream;)V",
+        "aload_0",
+        "aload_1",
+      ],
+    );
+    push_int(code, size);
+    lines(
+      code,
+      &[
+        "invokestatic Main/run(Ljava/io/InputStream;Ljava/io/OutputStream;I)I",
+        "pop",
+        "return",
+        ".end method",
+        "",
+      ],
+    );
+  }
+
+  /// Pushes the final value of the cell `--exit-from-cell` names, or of the
+  /// current cell by default.
+  pub fn exit_value(code: &mut impl Sink, config: &Config) {
+    lines(
+      code,
+      &[
+        "aload_2",
+        if config.exit_cell == Some(ExitCell::First) {
+          "iconst_0"
+        } else {
+          "iload_1"
+        },
+      ],
+    );
+    load_unsigned(code, config);
+  }
+
+  /// Writes out buffered output, before reading input and at exit.
+  pub fn flush(code: &mut impl Sink, config: &Config) {
+    if config.buffered {
+      lines(
+        code,
+        &["aload 4", "invokevirtual java/io/PrintStream/flush()V"],
+      );
+    }
+  }
+
+  pub fn plus(code: &mut impl Sink, count: i32, config: &Config) {
+    lines(code, &["aload_2", "iload_1", "dup2"]);
+    load(code, config);
+    push_int(code, count);
+    lines(code, &["iadd"]);
+    store(code, config);
+  }
+
+  pub fn set_zero(code: &mut impl Sink, config: &Config) {
+    lines(code, &["aload_2", "iload_1", "iconst_0"]);
+    store_exact(code, config);
+  }
+
+  pub fn add(code: &mut impl Sink, offset: i32, amount: i32, config: &Config) {
+    lines(code, &["aload_2", "iload_1"]);
+    push_int(code, offset);
+    lines(code, &["iadd", "dup2"]);
+    load(code, config);
+    push_int(code, amount);
+    lines(code, &["iadd"]);
+    store(code, config);
+  }
+
+  pub fn set(code: &mut impl Sink, offset: i32, value: i32, config: &Config) {
+    lines(code, &["aload_2", "iload_1"]);
+    push_int(code, offset);
+    lines(code, &["iadd"]);
+    if config.trap_overflow && !(0..=255).contains(&value) {
+      push_int(code, value);
+      store(code, config);
+      return;
+    }
+    push_int(code, if config.wrap { value & 255 } else { value });
+    store_exact(code, config);
+  }
+
+  /// Records the source position of the arithmetic that follows, for the
+  /// error `checkCell` throws.
+  pub fn mark_position(code: &mut impl Sink, position: u32) {
+    push_int(code, position as i32);
+    lines(code, &["putstatic Main/position I"]);
+  }
+
+  /// `checkCell(cell, value)`, which throws an `ArithmeticException` naming
+  /// the cell and the position last marked unless `value` is a byte.
+  pub fn check_cell_method(code: &mut impl Sink) {
+    lines(
+      code,
+      &[
+        ".method private static checkCell(II)V",
+        "iload_1",
+        "iflt cellOverflowed",
+        "iload_1",
+        "sipush 255",
+        "if_icmple cellFits",
+        "cellOverflowed:",
+        "new java/lang/ArithmeticException",
+        "dup",
+        "new java/lang/StringBuilder",
+        "dup",
+        "ldc \"cell \"",
+        "invokespecial java/lang/StringBuilder/<init>(Ljava/lang/String;)V",
+        "iload_0",
+        "invokevirtual java/lang/StringBuilder/append(I)Ljava/lang/StringBuilder;",
+        "ldc \" overflowed at byte \"",
+        "invokevirtual java/lang/StringBuilder/append(Ljava/lang/String;)Ljava/lang/StringBuilder;",
+        "getstatic Main/position I",
+        "invokevirtual java/lang/StringBuilder/append(I)Ljava/lang/StringBuilder;",
+        "invokevirtual java/lang/StringBuilder/toString()Ljava/lang/String;",
+        "invokespecial java/lang/ArithmeticException/<init>(Ljava/lang/String;)V",
+        "athrow",
+        "cellFits:",
+        "return",
+        ".end method",
+      ],
+    );
+  }
+
+  pub fn mov(code: &mut impl Sink, count: i32) {
+    increment(code, 1, count)
+  }
+
+  /// Moves the pointer for `<` and `>`, then loads the cell it lands on
+  /// under `Bounds::Error`, so that moving off the tape throws there rather
+  /// than at the next access. Scans load the cell they land on anyway.
+  pub fn step(code: &mut impl Sink, count: i32, config: &Config) {
+    mov(code, count);
+    if config.bounds == Bounds::Error {
+      lines(code, &["aload_2", "iload_1"]);
+      load(code, config);
+      lines(code, &["pop"]);
+    }
+  }
+
+  /// Adds `count` to the int in `local`.
+  fn increment(code: &mut impl Sink, local: u8, count: i32) {
+    if (-128..=127).contains(&count) {
+      emit!(code, "iinc {} {}", local, count);
+    } else {
+      emit!(code, "iload {}", local);
+      push_int(code, count);
+      lines(code, &["iadd"]);
+      emit!(code, "istore {}", local);
+    }
+  }
+
+  /// Copies the current cell into local 6, which caches it while the
+  /// pointer stays put.
+  pub fn cache_load(code: &mut impl Sink, config: &Config) {
+    lines(code, &["aload_2", "iload_1"]);
+    load(code, config);
+    lines(code, &["istore 6"]);
+  }
+
+  /// Writes the cached cell back to the tape.
+  pub fn cache_store(code: &mut impl Sink, config: &Config) {
+    lines(code, &["aload_2", "iload_1", "iload 6"]);
+    store(code, config);
+  }
+
+  pub fn cache_add(code: &mut impl Sink, count: i32) {
+    increment(code, 6, count)
+  }
+
+  pub fn cache_set(code: &mut impl Sink, value: i32, config: &Config) {
+    let value = if config.wrap { value & 255 } else { value };
+    push_int(code, value);
+    lines(code, &["istore 6"]);
+  }
+
+  /// Prints the cached cell `count` times. The cache is only reduced to a
+  /// byte when written back, so it is masked here.
+  pub fn cache_out(code: &mut impl Sink, count: usize, config: &Config) {
+    for _ in 0..count {
+      lines(code, &["aload 4", "iload 6"]);
+      if config.wrap || config.byte_tape {
+        push_int(code, 255);
+        lines(code, &["iand"]);
+      }
+      put_char(code, config);
+    }
+  }
+
+  pub fn out(code: &mut impl Sink, count: usize, config: &Config) {
+    for _ in 0..count {
+      lines(code, &["aload 4", "aload_2", "iload_1"]);
+      load_unsigned(code, config);
+      put_char(code, config);
+    }
+  }
+
+  /// Prints the cell on the stack to the stream below it.
+  fn put_char(code: &mut impl Sink, config: &Config) {
+    match config.io {
+      Io::Chars => lines(
+        code,
+        &["i2c", "invokevirtual java/io/PrintStream/print(C)V"],
+      ),
+      Io::Unicode => lines(
+        code,
+        &["invokestatic Main/putCodePoint(Ljava/io/PrintStream;I)V"],
+      ),
+      Io::Bytes => {
+        push_int(code, 255);
+        lines(
+          code,
+          &["iand", "i2c", "invokevirtual java/io/PrintStream/print(C)V"],
+        );
+      }
+    }
+  }
+
+  /// `putCodePoint(out, code)`, which prints `code` the way the interpreter
+  /// prints a cell with `--io unicode`: as U+FFFD unless it is a code
+  /// point other than a surrogate.
+  pub fn put_code_point_method(code: &mut impl Sink) {
+    lines(
+      code,
+      &[
+        ".method private static putCodePoint(Ljava/io/PrintStream;I)V",
+        "iload_1",
+        "invokestatic java/lang/Character/isValidCodePoint(I)Z",
+        "ifeq putReplaced",
+        "iload_1",
+        "ldc 55296",
+        "if_icmplt putValid",
+        "iload_1",
+        "ldc 57343",
+        "if_icmpgt putValid",
+        "putReplaced:",
+        "ldc 65533",
+        "istore_1",
+        "putValid:",
+        "aload_0",
+        "iload_1",
+        "invokestatic java/lang/Character/toChars(I)[C",
+        "invokevirtual java/io/PrintStream/print([C)V",
+        "return",
+        ".end method",
+      ],
+    );
+  }
+
+  /// `readCodePoint(in)`, which decodes one UTF-8 code point of `in` the
+  /// way `Eof::read_code_point` does, returning -1 at EOF. Local 1 holds
+  /// the code point, 2 the bytes still to come and 3 the byte read last.
+  pub fn read_code_point_method(code: &mut impl Sink) {
+    const READ: &[&str] = &["aload_0", "invokevirtual java/io/InputStream/read()I"];
+    lines(
+      code,
+      &[
+        ".method private static readCodePoint(Ljava/io/InputStream;)I",
+        "iconst_0",
+        "istore_2",
+        "iconst_0",
+        "istore_3",
+      ],
+    );
+    lines(code, READ);
+    lines(
+      code,
+      &[
+        "dup",
+        "istore_1",
+        "sipush 128",
+        "if_icmpge pointLead",
+        "iload_1",
+        "ireturn",
+        "pointLead:",
+        "iload_1",
+        "sipush 192",
+        "if_icmplt pointBad",
+        "iload_1",
+        "sipush 224",
+        "if_icmpge pointThree",
+        "iload_1",
+        "bipush 31",
+        "iand",
+        "istore_1",
+        "iconst_1",
+        "istore_2",
+        "goto pointMore",
+        "pointThree:",
+        "iload_1",
+        "sipush 240",
+        "if_icmpge pointFour",
+        "iload_1",
+        "bipush 15",
+        "iand",
+        "istore_1",
+        "iconst_2",
+        "istore_2",
+        "goto pointMore",
+        "pointFour:",
+        "iload_1",
+        "sipush 248",
+        "if_icmpge pointBad",
+        "iload_1",
+        "bipush 7",
+        "iand",
+        "istore_1",
+        "iconst_3",
+        "istore_2",
+        "pointMore:",
+        "iload_2",
+        "ifeq pointDone",
+      ],
+    );
+    lines(code, READ);
+    // EOF reads as -1, whose top bits are set like those of a lead byte.
+    lines(
+      code,
+      &[
+        "dup",
+        "istore_3",
+        "sipush 192",
+        "iand",
+        "sipush 128",
+        "if_icmpne pointBad",
+        "iload_1",
+        "bipush 6",
+        "ishl",
+        "iload_3",
+        "bipush 63",
+        "iand",
+        "ior",
+        "istore_1",
+        "iinc 2 -1",
+        "goto pointMore",
+        "pointDone:",
+        "iload_1",
+        "invokestatic java/lang/Character/isValidCodePoint(I)Z",
+        "ifeq pointBad",
+        "iload_1",
+        "ldc 55296",
+        "if_icmplt pointValid",
+        "iload_1",
+        "ldc 57343",
+        "if_icmple pointBad",
+        "pointValid:",
+        "iload_1",
+        "ireturn",
+        "pointBad:",
+        "ldc 65533",
+        "ireturn",
+        ".end method",
+      ],
+    );
+  }
+
+  pub fn out_const(code: &mut impl Sink, value: u8, count: usize) {
+    for _ in 0..count {
+      lines(code, &["aload 4"]);
+      push_int(code, value as i32);
+      lines(code, &["invokevirtual java/io/PrintStream/print(C)V"]);
+    }
+  }
+
+  /// Quotes `text` as a Jasmin string literal.
+  fn quote(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+      match c {
+        '"' => quoted.push_str("\\\""),
+        '\\' => quoted.push_str("\\\\"),
+        '\n' => quoted.push_str("\\n"),
+        '\t' => quoted.push_str("\\t"),
+        ' '..='~' => quoted.push(c),
+        _ => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+      }
+    }
+    quoted.push('"');
+    quoted
+  }
+
+  /// Prints `text`, whose characters are the bytes printed with `--io
+  /// bytes`, which the stream's ISO-8859-1 turns them back into.
+  pub fn print(code: &mut impl Sink, text: &str) {
+    lines(code, &["aload 4"]);
+    emit!(code, "ldc {}", quote(text));
+    lines(
+      code,
+      &["invokevirtual java/io/PrintStream/print(Ljava/lang/String;)V"],
+    );
+  }
+
+  /// Reads `count` bytes, or code points with `--io unicode`, keeping only
+  /// the last one. At EOF `read()` returns -1, which is stored as is for
+  /// `Eof::MinusOne`.
+  pub fn input(code: &mut impl Sink, count: usize, label: usize, config: &Config) {
+    let read: &[&str] = match config.io {
+      Io::Chars | Io::Bytes => &["aload 5", "invokevirtual java/io/InputStream/read()I"],
+      Io::Unicode => &[
+        "aload 5",
+        "invokestatic Main/readCodePoint(Ljava/io/InputStream;)I",
+      ],
+    };
+    let label = code.label(label);
+    for n in 0..count {
+      flush(code, config);
+      match config.eof {
+        Eof::Unchanged => {
+          lines(code, read);
+          lines(code, &["istore_3", "iload_3"]);
+          emit!(code, "iflt read{}_{}", label, n);
+          lines(code, &["aload_2", "iload_1", "iload_3"]);
+          store(code, config);
+          emit!(code, "read{}_{}:", label, n);
+        }
+        Eof::Zero => {
+          lines(code, &["aload_2", "iload_1"]);
+          lines(code, read);
+          lines(code, &["dup"]);
+          emit!(code, "ifge read{}_{}", label, n);
+          lines(code, &["pop", "iconst_0"]);
+          emit!(code, "read{}_{}:", label, n);
+          store(code, config);
+        }
+        Eof::MinusOne => {
+          lines(code, &["aload_2", "iload_1"]);
+          lines(code, read);
+          store(code, config);
+        }
+      }
+    }
+  }
+
+  /// Prints the current cell in decimal.
+  pub fn out_number(code: &mut impl Sink, config: &Config) {
+    lines(code, &["aload 4", "aload_2", "iload_1"]);
+    load_unsigned(code, config);
+    lines(code, &["invokevirtual java/io/PrintStream/print(I)V"]);
+  }
+
+  /// Reads a decimal number into the current cell through `readNumber`.
+  pub fn input_number(code: &mut impl Sink, config: &Config) {
+    flush(code, config);
+    lines(
+      code,
+      &["aload_2", "iload_1", "aload 5", "aload_2", "iload_1"],
+    );
+    load(code, config);
+    lines(
+      code,
+      &["invokestatic Main/readNumber(Ljava/io/InputStream;I)I"],
+    );
+    store(code, config);
+  }
+
+  /// `readNumber(in, cell)`, which reads a decimal number from `in` the way
+  /// `Eof::read_number` does and returns it, or what `--eof` leaves in a
+  /// cell holding `cell` when there are no digits. A `Scanner` would read
+  /// ahead of the bytes `,` takes from the same stream. Locals 2 to 5 hold
+  /// the byte read last, whether the number is negative, its value and
+  /// whether it has any digits.
+  pub fn read_number_method(code: &mut impl Sink, config: &Config) {
+    const READ: &[&str] = &[
+      "aload_0",
+      "invokevirtual java/io/InputStream/read()I",
+      "istore_2",
+    ];
+    lines(
+      code,
+      &[
+        ".method private static readNumber(Ljava/io/InputStream;I)I",
+        "iconst_0",
+        "istore_3",
+        "iconst_0",
+        "istore 4",
+        "iconst_0",
+        "istore 5",
+      ],
+    );
+    lines(code, READ);
+    // Skips spaces and `\t` through `\r`.
+    lines(
+      code,
+      &[
+        "numberSpace:",
+        "iload_2",
+        "bipush 32",
+        "if_icmpeq numberSkip",
+        "iload_2",
+        "bipush 9",
+        "if_icmplt numberSign",
+        "iload_2",
+        "bipush 13",
+        "if_icmpgt numberSign",
+        "numberSkip:",
+      ],
+    );
+    lines(code, READ);
+    lines(
+      code,
+      &[
+        "goto numberSpace",
+        "numberSign:",
+        "iload_2",
+        "bipush 45",
+        "if_icmpne numberPlus",
+        "iconst_1",
+        "istore_3",
+        "goto numberSigned",
+        "numberPlus:",
+        "iload_2",
+        "bipush 43",
+        "if_icmpne numberDigits",
+        "numberSigned:",
+      ],
+    );
+    lines(code, READ);
+    lines(
+      code,
+      &[
+        "numberDigits:",
+        "iload_2",
+        "bipush 48",
+        "if_icmplt numberEnd",
+        "iload_2",
+        "bipush 57",
+        "if_icmpgt numberEnd",
+        "iload 4",
+        "bipush 10",
+        "imul",
+        "iload_2",
+        "iadd",
+        "bipush 48",
+        "isub",
+        "istore 4",
+        "iconst_1",
+        "istore 5",
+      ],
+    );
+    lines(code, READ);
+    lines(
+      code,
+      &[
+        "goto numberDigits",
+        "numberEnd:",
+        "iload 5",
+        "ifne numberFound",
+        match config.eof {
+          Eof::Unchanged => "iload_1",
+          Eof::Zero => "iconst_0",
+          Eof::MinusOne => "iconst_m1",
+        },
+        "ireturn",
+        "numberFound:",
+        "iload_3",
+        "ifeq numberPositive",
+        "iload 4",
+        "ineg",
+        "ireturn",
+        "numberPositive:",
+        "iload 4",
+        "ireturn",
+        ".end method",
+      ],
+    );
+  }
+
+  /// Stores the next byte of `Main.random` in the current cell.
+  pub fn random(code: &mut impl Sink, config: &Config) {
+    lines(
+      code,
+      &[
+        "aload_2",
+        "iload_1",
+        "getstatic Main/random Ljava/util/Random;",
+      ],
+    );
+    push_int(code, 256);
+    lines(code, &["invokevirtual java/util/Random/nextInt(I)I"]);
+    store_exact(code, config);
+  }
+
+  /// The static initializer creating `Main.random`, from `--seed` when it
+  /// is given. The seed goes through `Long.parseLong` for want of `ldc2_w`.
+  pub fn random_init(code: &mut impl Sink, config: &Config) {
+    lines(
+      code,
+      &[".method static <clinit>()V", "new java/util/Random", "dup"],
+    );
+    match config.seed {
+      Some(seed) => {
+        emit!(code, "ldc \"{}\"", seed);
+        lines(
+          code,
+          &[
+            "invokestatic java/lang/Long/parseLong(Ljava/lang/String;)J",
+            "invokespecial java/util/Random/<init>(J)V",
+          ],
+        );
+      }
+      None => lines(code, &["invokespecial java/util/Random/<init>()V"]),
+    }
+    lines(
+      code,
+      &[
+        "putstatic Main/random Ljava/util/Random;",
+        "return",
+        ".end method",
+      ],
+    );
+  }
+
+  /// Straight-line code for a multiplication loop: for every target,
+  /// `cell[ptr + offset] += cell[ptr] * factor`. The current cell is loaded
+  /// once into local 3, and the whole block is skipped when it is zero so
+  /// that the offset cells are never touched in that case.
+  pub fn multiply(code: &mut impl Sink, label: usize, targets: &[(i32, i32)], config: &Config) {
+    let label = code.label(label);
+    lines(code, &["aload_2", "iload_1"]);
+    load(code, config);
+    lines(code, &["dup", "istore_3"]);
+    emit!(code, "ifeq skip{}", label);
+    for &(offset, factor) in targets {
+      lines(code, &["aload_2", "iload_1"]);
+      push_int(code, offset);
+      lines(code, &["iadd", "dup2"]);
+      load(code, config);
+      lines(code, &["iload_3"]);
+      match factor {
+        1 => lines(code, &["iadd"]),
+        -1 => lines(code, &["isub"]),
+        _ if factor > 0 && (factor as u32).is_power_of_two() => {
+          push_int(code, factor.trailing_zeros() as i32);
+          lines(code, &["ishl", "iadd"]);
+        }
+        _ => {
+          push_int(code, factor);
+          lines(code, &["imul", "iadd"]);
+        }
+      }
+      store(code, config);
+    }
+    emit!(code, "skip{}:", label);
+  }
+
+  pub fn scan_zero(code: &mut impl Sink, label: usize, stride: i32, config: &Config) {
+    let label = code.label(label);
+    emit!(code, "scan{}:", label);
+    lines(code, &["aload_2", "iload_1"]);
+    load(code, config);
+    emit!(code, "ifeq scan{}Done", label);
+    mov(code, stride);
+    emit!(code, "goto scan{}", label);
+    emit!(code, "scan{}Done:", label);
+  }
+
+  /// A scan that checks and moves four times between jumps back.
+  pub fn unrolled_scan_zero(code: &mut impl Sink, label: usize, stride: i32, config: &Config) {
+    let label = code.label(label);
+    emit!(code, "scan{}:", label);
+    for _ in 0..4 {
+      lines(code, &["aload_2", "iload_1"]);
+      load(code, config);
+      emit!(code, "ifeq scan{}Done", label);
+      mov(code, stride);
+    }
+    emit!(code, "goto scan{}", label);
+    emit!(code, "scan{}Done:", label);
+  }
+
+  /// Tests the cell once on entry; `rotated_loop_end` tests it again and
+  /// jumps back only while it is nonzero, saving a jump per iteration.
+  pub fn rotated_loop_start(code: &mut impl Sink, pos: usize, config: &Config) {
+    let pos = code.label(pos);
+    lines(code, &["aload_2", "iload_1"]);
+    load(code, config);
+    emit!(code, "ifeq loop{}End", pos);
+    emit!(code, "loop{}Start:", pos);
+  }
+
+  pub fn rotated_loop_end(code: &mut impl Sink, pos: usize, config: &Config) {
+    let pos = code.label(pos);
+    if config.step_budget.is_some() {
+      lines(code, &["invokestatic Main/step()V"]);
+    }
+    lines(code, &["aload_2", "iload_1"]);
+    load(code, config);
+    emit!(code, "ifne loop{}Start", pos);
+    emit!(code, "loop{}End:", pos);
+  }
+
+  pub fn loop_start(code: &mut impl Sink, pos: usize, config: &Config) {
+    let pos = code.label(pos);
+    emit!(code, "loop{}Start:", pos);
+    lines(code, &["aload_2", "iload_1"]);
+    load(code, config);
+    emit!(code, "ifeq loop{}End", pos);
+  }
+
+  pub fn loop_end(code: &mut impl Sink, pos: usize, config: &Config) {
+    let pos = code.label(pos);
+    if config.step_budget.is_some() {
+      lines(code, &["invokestatic Main/step()V"]);
+    }
+    emit!(code, "goto loop{}Start", pos);
+    emit!(code, "loop{}End:", pos);
+  }
+
+  /// Gives the program `budget` more loop iterations.
+  pub fn start_budget(code: &mut impl Sink, budget: i32) {
+    push_int(code, budget);
+    lines(code, &["putstatic Main/steps I"]);
+  }
+
+  /// `step()`, which `--sandbox` classes call on every loop back-edge and
+  /// which throws once the budget `start_budget` gave is spent, so that
+  /// the program stops however it loops. Brainfork threads share the
+  /// budget.
+  pub fn step_method(code: &mut impl Sink, budget: i32) {
+    lines(
+      code,
+      &[
+        ".method private static step()V",
+        "getstatic Main/steps I",
+        "iconst_1",
+        "isub",
+        "dup",
+        "putstatic Main/steps I",
+        "ifge stepTaken",
+        "new java/lang/IllegalStateException",
+        "dup",
+      ],
+    );
+    emit!(code, "ldc \"step budget of {} exceeded\"", budget);
+    lines(
+      code,
+      &[
+        "invokespecial java/lang/IllegalStateException/<init>(Ljava/lang/String;)V",
+        "athrow",
+        "stepTaken:",
+        "return",
+        ".end method",
+      ],
+    );
+  }
+
+  /// Pushes the number of the procedure the current cell names.
+  fn procedure_number(code: &mut impl Sink, config: &Config) {
+    lines(code, &["aload_2", "iload_1"]);
+    load(code, config);
+    push_int(code, 255);
+    lines(code, &["iand"]);
+  }
+
+  /// Allocates the table of defined procedures, which maps a procedure
+  /// number to one more than the index of its definition, or 0.
+  pub fn procedure_table(code: &mut impl Sink) {
+    push_int(code, 256);
+    lines(code, &["newarray int", "putstatic Main/procedures [I"]);
+  }
+
+  /// Defines the procedure at `index` under the number in the current cell.
+  pub fn define(code: &mut impl Sink, index: usize, config: &Config) {
+    lines(code, &["getstatic Main/procedures [I"]);
+    procedure_number(code, config);
+    push_int(code, index as i32 + 1);
+    lines(code, &["iastore"]);
+  }
+
+  /// Calls the procedure the current cell names through `call`, passing
+  /// its table entry in place of local 3.
+  pub fn call(code: &mut impl Sink, config: &Config) {
+    lines(
+      code,
+      &[
+        "aload_0",
+        "iload_1",
+        "aload_2",
+        "getstatic Main/procedures [I",
+      ],
+    );
+    procedure_number(code, config);
+    lines(code, &["iaload", "aload 4", "aload 5"]);
+    emit!(code, "invokestatic Main/call{}", super::descriptor(config));
+    lines(code, &["istore_1"]);
+  }
+
+  /// The method calls jump through: it invokes the method of the procedure
+  /// whose table entry is in local 3, and throws for an undefined one.
+  pub fn dispatch(code: &mut impl Sink, procedures: &[usize], config: &Config) {
+    let descriptor = super::descriptor(config);
+    emit!(code, ".method private static call{}", descriptor);
+    for &index in procedures {
+      lines(code, &["iload_3"]);
+      push_int(code, index as i32 + 1);
+      let label = code.label(index);
+      emit!(code, "if_icmpne notProc{}", label);
+      lines(
+        code,
+        &[
+          "aload_0", "iload_1", "aload_2", "iconst_0", "aload 4", "aload 5",
+        ],
+      );
+      emit!(code, "invokestatic Main/proc{}{}", index, descriptor);
+      lines(code, &["ireturn"]);
+      emit!(code, "notProc{}:", label);
+    }
+    lines(
+      code,
+      &[
+        "new java/lang/IllegalStateException",
+        "dup",
+        "ldc \"call of undefined procedure \"",
+      ],
+    );
+    procedure_number(code, config);
+    lines(
+      code,
+      &[
+        "invokestatic java/lang/String/valueOf(I)Ljava/lang/String;",
+        "invokevirtual java/lang/String/concat(Ljava/lang/String;)Ljava/lang/String;",
+        "invokespecial java/lang/IllegalStateException/<init>(Ljava/lang/String;)V",
+        "athrow",
+        ".end method",
+      ],
+    );
+  }
+
+  /// Starts a thread that resumes at label `forked{index}` and zeroes the
+  /// current cell.
+  pub fn fork(code: &mut impl Sink, index: usize, config: &Config) {
+    let tape = if config.byte_tape { "[B" } else { "[I" };
+    lines(code, &["new Main", "dup", "aload_2", "iload_1"]);
+    push_int(code, index as i32 + 1);
+    emit!(code, "invokespecial Main/<init>({}II)V", tape);
+    lines(code, &["invokevirtual Main/start()V"]);
+    set_zero(code, config);
+    let label = code.label(index);
+    emit!(code, "forked{}:", label);
+  }
+
+  /// Jumps to where the thread with the entry point in local 3 resumes.
+  /// The first thread has entry point 0 and starts at the top.
+  pub fn resume(code: &mut impl Sink, forks: &[usize]) {
+    for &index in forks {
+      lines(code, &["iload_3"]);
+      push_int(code, index as i32 + 1);
+      let label = code.label(index);
+      emit!(code, "if_icmpeq forked{}", label);
+    }
+  }
+
+  /// The fields and methods that make `Main` a thread: the constructor
+  /// copies the tape and moves onto the cell to the right, which it sets to
+  /// 1, and `run` runs `body` from the entry point.
+  pub fn thread_methods(code: &mut impl Sink, config: &Config) {
+    let tape = if config.byte_tape { "[B" } else { "[I" };
+    lines(
+      code,
+      &[
+        ".field private static out Ljava/io/PrintStream;",
+        ".field private static in Ljava/io/InputStream;",
+      ],
+    );
+    emit!(code, ".field private tape {}", tape);
+    lines(
+      code,
+      &[".field private ptr I", ".field private entry I", ""],
+    );
+    emit!(code, ".method private <init>({}II)V", tape);
+    lines(
+      code,
+      &[
+        "aload_0",
+        "invokespecial java/lang/Thread/<init>()V",
+        "aload_0",
+        "aload_1",
+        "aload_1",
+        "arraylength",
+      ],
+    );
+    emit!(
+      code,
+      "invokestatic java/util/Arrays/copyOf({}I){}",
+      tape,
+      tape
+    );
+    emit!(code, "putfield Main/tape {}", tape);
+    lines(
+      code,
+      &[
+        "aload_0",
+        "iload_2",
+        "iconst_1",
+        "iadd",
+        "putfield Main/ptr I",
+        "aload_0",
+        "iload_3",
+        "putfield Main/entry I",
+        "aload_0",
+      ],
+    );
+    emit!(code, "getfield Main/tape {}", tape);
+    lines(code, &["aload_0", "getfield Main/ptr I", "iconst_1"]);
+    store_exact(code, config);
+    lines(
+      code,
+      &[
+        "return",
+        ".end method",
+        "",
+        ".method public run()V",
+        "aconst_null",
+        "aload_0",
+        "getfield Main/ptr I",
+        "aload_0",
+      ],
+    );
+    emit!(code, "getfield Main/tape {}", tape);
+    lines(
+      code,
+      &[
+        "aload_0",
+        "getfield Main/entry I",
+        "getstatic Main/out Ljava/io/PrintStream;",
+        "getstatic Main/in Ljava/io/InputStream;",
+      ],
+    );
+    emit!(code, "invokestatic Main/body{}", super::descriptor(config));
+    lines(
+      code,
+      &[
+        "pop",
+        "getstatic Main/out Ljava/io/PrintStream;",
+        "invokevirtual java/io/PrintStream/flush()V",
+        "return",
+        ".end method",
+        "",
+      ],
+    );
+  }
+
+  /// Runs an Extended Brainfuck command on the current cell and the storage
+  /// cell in the static field `storage`. `@` flushes output and exits the
+  /// JVM, with the value `main` would exit with.
+  pub fn ebf<S: Sink>(code: &mut S, command: Ebf, config: &Config) {
+    // Replaces the current cell with the result of `operation` on it.
+    let update = |code: &mut S, operation: &[&str]| {
+      lines(code, &["aload_2", "iload_1", "dup2"]);
+      load(code, config);
+      lines(code, operation);
+      store(code, config);
+    };
+    match command {
+      Ebf::End => {
+        flush(code, config);
+        if config.exit_cell.is_some() {
+          exit_value(code, config);
+        } else {
+          lines(code, &["iconst_0"]);
+        }
+        lines(code, &["invokestatic java/lang/System/exit(I)V"]);
+      }
+      Ebf::Store => {
+        lines(code, &["aload_2", "iload_1"]);
+        load(code, config);
+        lines(code, &["putstatic Main/storage I"]);
+      }
+      Ebf::Retrieve => {
+        lines(code, &["aload_2", "iload_1", "getstatic Main/storage I"]);
+        store(code, config);
+      }
+      // A byte tape loads the cell sign-extended, which would shift ones in.
+      Ebf::ShiftRight if config.byte_tape => {
+        update(code, &["sipush 255", "iand", "iconst_1", "iushr"])
+      }
+      Ebf::ShiftRight => update(code, &["iconst_1", "iushr"]),
+      Ebf::ShiftLeft => update(code, &["iconst_1", "ishl"]),
+      Ebf::Not => update(code, &["iconst_m1", "ixor"]),
+      Ebf::Xor => update(code, &["getstatic Main/storage I", "ixor"]),
+      Ebf::And => update(code, &["getstatic Main/storage I", "iand"]),
+      Ebf::Or => update(code, &["getstatic Main/storage I", "ior"]),
+    }
+  }
+
+  /// Makes the tape in local 2 the first of the `TAPES` in the static field
+  /// `tapes`, the others as long as it is, whose pointers are kept in
+  /// `pointers` while another is current. The number of the current one is
+  /// in `tape`.
+  pub fn allocate_tapes(code: &mut impl Sink, config: &Config) {
+    let (tape, element) = if config.byte_tape {
+      ("[B", "byte")
+    } else {
+      ("[I", "int")
+    };
+    push_int(code, TAPES as i32);
+    emit!(code, "anewarray {}", tape);
+    lines(code, &["dup", "iconst_0", "aload_2", "aastore"]);
+    for number in 1..TAPES {
+      lines(code, &["dup"]);
+      push_int(code, number as i32);
+      lines(code, &["aload_2", "arraylength"]);
+      emit!(code, "newarray {}", element);
+      lines(code, &["aastore"]);
+    }
+    emit!(code, "putstatic Main/tapes [{}", tape);
+    push_int(code, TAPES as i32);
+    lines(
+      code,
+      &[
+        "newarray int",
+        "putstatic Main/pointers [I",
+        "iconst_0",
+        "putstatic Main/tape I",
+      ],
+    );
+  }
+
+  /// Pushes the number of the tape after the current one.
+  fn next_tape(code: &mut impl Sink) {
+    lines(code, &["getstatic Main/tape I", "iconst_1", "iadd"]);
+    push_int(code, TAPES as i32);
+    lines(code, &["irem"]);
+  }
+
+  /// Loads the current tape into local 2, after a call that may have
+  /// switched it.
+  pub fn reload_tape(code: &mut impl Sink, config: &Config) {
+    let tape = if config.byte_tape { "[B" } else { "[I" };
+    emit!(code, "getstatic Main/tapes [{}", tape);
+    lines(code, &["getstatic Main/tape I", "aaload", "astore_2"]);
+  }
+
+  /// Runs a multi-tape command: `%` leaves the pointer in `pointers` and
+  /// takes the next tape and its pointer, and `=` stores the current cell
+  /// at the pointer of the next tape.
+  pub fn tape(code: &mut impl Sink, command: Tape, config: &Config) {
+    let tape = if config.byte_tape { "[B" } else { "[I" };
+    match command {
+      Tape::Next => {
+        lines(
+          code,
+          &[
+            "getstatic Main/pointers [I",
+            "getstatic Main/tape I",
+            "iload_1",
+            "iastore",
+          ],
+        );
+        next_tape(code);
+        lines(code, &["putstatic Main/tape I"]);
+        reload_tape(code, config);
+        lines(
+          code,
+          &[
+            "getstatic Main/pointers [I",
+            "getstatic Main/tape I",
+            "iaload",
+            "istore_1",
+          ],
+        );
+      }
+      Tape::Copy => {
+        emit!(code, "getstatic Main/tapes [{}", tape);
+        next_tape(code);
+        lines(code, &["aaload", "getstatic Main/pointers [I"]);
+        next_tape(code);
+        lines(code, &["iaload", "aload_2", "iload_1"]);
+        load(code, config);
+        store_exact(code, config);
+      }
+    }
+  }
+
+  /// Prints the pointer and the cells around it to standard error, as the
+  /// interpreter does at `#`, after the output so far.
+  pub fn dump(code: &mut impl Sink, config: &Config) {
+    let tape = if config.byte_tape { "[B" } else { "[I" };
+    flush(code, config);
+    lines(code, &["iload_1", "aload_2"]);
+    emit!(code, "invokestatic Main/dump(I{})V", tape);
+  }
+
+  /// The method `dump` calls, which takes the pointer and the tape. Cells
+  /// from local 2 up to local 3 go into the string builder in local 4.
+  pub fn dump_method<S: Sink>(code: &mut S, config: &Config) {
+    let tape = if config.byte_tape { "[B" } else { "[I" };
+    let append = |code: &mut S, descriptor: &str| {
+      emit!(
+        code,
+        "invokevirtual java/lang/StringBuilder/append({})Ljava/lang/StringBuilder;",
+        descriptor
+      )
+    };
+    emit!(code, ".method private static dump(I{})V", tape);
+    lines(
+      code,
+      &[
+        "new java/lang/StringBuilder",
+        "dup",
+        "invokespecial java/lang/StringBuilder/<init>()V",
+        "astore 4",
+        "iload_0",
+      ],
+    );
+    push_int(code, DUMP_RADIUS as i32);
+    lines(
+      code,
+      &[
+        "isub",
+        "iconst_0",
+        "invokestatic java/lang/Math/max(II)I",
+        "istore_2",
+        "iload_0",
+      ],
+    );
+    push_int(code, DUMP_RADIUS as i32 + 1);
+    lines(
+      code,
+      &[
+        "iadd",
+        "aload_1",
+        "arraylength",
+        "invokestatic java/lang/Math/min(II)I",
+        "istore_3",
+        "aload 4",
+        "ldc \"# ptr \"",
+      ],
+    );
+    append(code, "Ljava/lang/String;");
+    lines(code, &["iload_0"]);
+    append(code, "I");
+    lines(code, &["ldc \", cells \""]);
+    append(code, "Ljava/lang/String;");
+    lines(code, &["iload_2"]);
+    append(code, "I");
+    lines(code, &["ldc \"..\""]);
+    append(code, "Ljava/lang/String;");
+    lines(code, &["iload_3"]);
+    append(code, "I");
+    lines(code, &["bipush 58"]);
+    append(code, "C");
+    lines(
+      code,
+      &[
+        "pop",
+        "dumpLoop:",
+        "iload_2",
+        "iload_3",
+        "if_icmpge dumpDone",
+        "aload 4",
+        "bipush 32",
+      ],
+    );
+    append(code, "C");
+    lines(
+      code,
+      &[
+        "pop",
+        "iload_2",
+        "iload_0",
+        "if_icmpne dumpCell",
+        "aload 4",
+        "bipush 91",
+      ],
+    );
+    append(code, "C");
+    lines(code, &["pop", "dumpCell:", "aload 4", "aload_1", "iload_2"]);
+    load_unsigned(code, config);
+    append(code, "I");
+    lines(
+      code,
+      &[
+        "pop",
+        "iload_2",
+        "iload_0",
+        "if_icmpne dumpNext",
+        "aload 4",
+        "bipush 93",
+      ],
+    );
+    append(code, "C");
+    lines(
+      code,
+      &[
+        "pop",
+        "dumpNext:",
+        "iinc 2 1",
+        "goto dumpLoop",
+        "dumpDone:",
+        "getstatic java/lang/System/err Ljava/io/PrintStream;",
+        "aload 4",
+        "invokevirtual java/io/PrintStream/println(Ljava/lang/Object;)V",
+        "return",
+        ".end method",
+      ],
+    );
+  }
+
+  /// Shares the streams of `main` with the threads it forks.
+  pub fn share_streams(code: &mut impl Sink) {
+    lines(
+      code,
+      &[
+        "aload 4",
+        "putstatic Main/out Ljava/io/PrintStream;",
+        "aload 5",
+        "putstatic Main/in Ljava/io/InputStream;",
+      ],
+    );
+  }
+}
+
+/// The class declaration and default constructor of `Main` extending
+/// `superclass`.
+fn header(superclass: &str) -> String {
+  format!(
+    "
+.class public Main
+.super {0}
+
+.method public <init>()V
+    aload_0
+    invokenonvirtual {0}/<init>()V
+    return
+.end method
+",
+    superclass
+  )
+}
+
+/// Where the program came from, so the class can name its source file and
+/// map its code back to source lines.
+#[derive(Clone, Debug)]
+pub struct DebugInfo {
+  /// Written as the class's `SourceFile`, conventionally without a
+  /// directory.
+  pub file: String,
+  /// Byte offsets at which each line of the source starts.
+  line_starts: Vec<usize>,
+}
+
+impl DebugInfo {
+  pub fn new(file: &str, source: &[u8]) -> DebugInfo {
+    let line_starts = std::iter::once(0)
+      .chain(
+        (0..source.len())
+          .filter(|&at| source[at] == b'\n')
+          .map(|at| at + 1),
+      )
+      .collect();
+    DebugInfo {
+      file: file.to_string(),
+      line_starts,
+    }
+  }
+
+  /// One-based line of a byte offset into the source.
+  fn line(&self, offset: usize) -> usize {
+    self.line_starts.partition_point(|&start| start <= offset)
+  }
+}
+
+/// Starts the comments `Config::source_map` asks for.
+const SPAN_MARKER: &str = "; span";
+
+/// The map of generated Jasmin `code` back to the source `file`: for every
+/// run of lines following a `; span` comment, its method, its first and
+/// last lines and the bytes of the source it came from.
+pub fn source_map(code: &str, file: &str, jasmin: &str) -> Json {
+  let mut regions = Vec::new();
+  let mut method = "";
+  // The span lines belong to, and the first of them.
+  let mut open: Option<((usize, usize), usize)> = None;
+  let mut close = |open: &mut Option<((usize, usize), usize)>, method: &str, end: usize| {
+    if let Some(((start, stop), first)) = open.take().filter(|&(_, first)| first < end) {
+      regions.push(Json::object(vec![
+        ("method", method.into()),
+        ("lines", Json::Array(vec![first.into(), (end - 1).into()])),
+        ("span", Json::Array(vec![start.into(), stop.into()])),
+      ]));
+    }
+  };
+  for (index, text) in code.lines().enumerate() {
+    let number = index + 1;
+    let text = text.trim();
+    if let Some(span) = text.strip_prefix(SPAN_MARKER) {
+      close(&mut open, method, number);
+      let mut bounds = span.split_whitespace().filter_map(|n| n.parse().ok());
+      if let (Some(start), Some(stop)) = (bounds.next(), bounds.next()) {
+        open = Some(((start, stop), number + 1));
+      }
+    } else if let Some(signature) = text.strip_prefix(".method") {
+      close(&mut open, method, number);
+      method = signature.split_whitespace().last().unwrap_or("");
+    } else if text == ".end method" {
+      close(&mut open, method, number);
+    }
+  }
+  close(&mut open, method, code.lines().count() + 1);
+  Json::object(vec![
+    ("source", file.into()),
+    ("jasmin", jasmin.into()),
+    ("regions", Json::Array(regions)),
+  ])
+}
+
+/// Options for the generated class.
+#[derive(Clone, Debug)]
+pub struct Config {
+  /// Number of cells allocated for the tape.
+  pub tape_size: usize,
+  /// Whether the first command-line argument, when given, overrides
+  /// `tape_size` at runtime.
+  pub tape_from_args: bool,
+  /// Whether `,` reads the UTF-8 bytes of the first command-line argument,
+  /// when given, instead of standard input.
+  pub input_from_args: bool,
+  /// Whether cells are stored in a `byte[]`, which always wraps, instead of
+  /// an `int[]`.
+  pub byte_tape: bool,
+  /// Whether cells wrap around at 256 like the interpreter's, rather than
+  /// holding any `int`.
+  pub wrap: bool,
+  /// Whether output is collected in a buffer that is flushed before input
+  /// and at exit, instead of written one character at a time.
+  pub buffered: bool,
+  pub eof: Eof,
+  pub io: Io,
+  /// What moving off either end of the tape does in generated code.
+  pub bounds: Bounds,
+  /// Estimated bytecode size above which code is moved out of `main` into
+  /// separate methods, which the JVM limits to 65535 bytes each.
+  pub method_size: usize,
+  /// Whether the program goes into `run(InputStream, OutputStream)`, which
+  /// `main` calls with the standard streams, so other JVM code can call it.
+  pub embeddable: bool,
+  /// Whether `main` takes the tape length and the files to read and write
+  /// from `--tape`, `--in` and `--out` arguments. Implies `embeddable`.
+  pub runtime_args: bool,
+  /// Whether consecutive operations on the current cell work on a copy in
+  /// a local, instead of loading and storing the tape for each.
+  pub cell_cache: bool,
+  /// The cell whose final value `main` exits with, if any.
+  pub exit_cell: Option<ExitCell>,
+  /// Emits `.source` and `.line` directives when set.
+  pub debug: Option<DebugInfo>,
+  /// Seed of the generator `?` draws from, which is otherwise seeded from
+  /// the clock.
+  pub seed: Option<i64>,
+  /// Loop iterations the class may run before it throws, with `--sandbox`.
+  pub step_budget: Option<usize>,
+  /// Whether the code of each span of the source follows a `; span`
+  /// comment naming it, which `source_map` collects.
+  pub source_map: bool,
+  /// Whether arithmetic that takes a cell out of the range of a byte
+  /// throws, naming the cell and the source position. Cells are not cached
+  /// then, so that each change is checked where it is made.
+  pub trap_overflow: bool,
+  /// Whether labels are numbered 0, 1, 2 ... in the order they are first
+  /// written, rather than after instruction indices, so that the class
+  /// depends only on the code it holds.
+  pub deterministic: bool,
+  /// A profile of a run of the program, from `run --profile`, which picks
+  /// the superinstructions of hot code and keeps it out of the methods
+  /// `main` is split into.
+  pub profile: Option<Arc<Profile>>,
+}
+
+impl Default for Config {
+  fn default() -> Config {
+    Config {
+      tape_size: TAPE_SIZE,
+      tape_from_args: false,
+      input_from_args: false,
+      byte_tape: false,
+      wrap: true,
+      buffered: true,
+      eof: Eof::default(),
+      io: Io::default(),
+      bounds: Bounds::default(),
+      method_size: METHOD_SIZE,
+      embeddable: false,
+      runtime_args: false,
+      cell_cache: true,
+      exit_cell: None,
+      debug: None,
+      seed: None,
+      step_budget: None,
+      source_map: false,
+      trap_overflow: false,
+      deterministic: false,
+      profile: None,
+    }
+  }
+}
+
+impl Config {
+  /// Whether the program is generated into `run` rather than `main`.
+  pub fn has_run(&self) -> bool {
+    self.embeddable || self.runtime_args
+  }
+
+  /// Whether the profile, if any, shows the code in `span` hot.
+  fn is_hot(&self, span: Span) -> bool {
+    self
+      .profile
+      .as_ref()
+      .is_some_and(|profile| profile.is_hot(span))
+  }
+
+  /// How often the profile says the code of `instructions[range]` ran.
+  fn hotness(&self, instructions: &[Inst], range: Range<usize>) -> u64 {
+    let span = Span {
+      start: instructions[range.start].span.start,
+      end: instructions[range.end - 1].span.end,
+    };
+    self
+      .profile
+      .as_ref()
+      .map_or(0, |profile| profile.hotness(span))
+  }
+}
+
+/// Loop iterations a `--sandbox` class runs at most, unless
+/// `--sandbox-steps` says otherwise.
+pub const STEP_BUDGET: usize = 1_000_000_000;
+
+/// Below the 8000 bytes of bytecode HotSpot compiles a method up to, so that
+/// every method the class is cut into gets compiled rather than
+/// interpreted; the estimate only ever errs high.
+pub const METHOD_SIZE: usize = 7500;
+
+const TAIL: &str = "
+    return
+.end method
+";
+
+const RUN_TAIL: &str = "
+    ireturn
+.end method
+";
+
+/// What the generated code knows about local 6 at a point: whether it holds
+/// the current cell, and whether the tape is behind it. The cache is dropped
+/// wherever the pointer moves or control flow joins, and written back
+/// before anything else reads the tape.
+#[derive(Default)]
+struct Cache {
+  valid: bool,
+  dirty: bool,
+}
+
+impl Cache {
+  fn load(&mut self, code: &mut impl Sink, config: &Config) {
+    if !self.valid {
+      bytecode::cache_load(code, config);
+      self.valid = true;
+    }
+  }
+
+  fn write_back(&mut self, code: &mut impl Sink, config: &Config) {
+    if self.dirty {
+      bytecode::cache_store(code, config);
+      self.dirty = false;
+    }
+  }
+}
+
+/// Generates the code for `instructions[range]` in one piece. With `tapes`
+/// the program switches tapes, which procedures it calls may do too.
+fn inline(
+  instructions: &[Inst],
+  range: Range<usize>,
+  config: &Config,
+  tapes: bool,
+  code: &mut impl Sink,
+) {
+  let mut cache = Cache::default();
+  let mut line = None;
+  let mut span = None;
+  let caching = config.cell_cache && !config.trap_overflow;
+  let mut index = range.start;
+  while index < range.end {
+    if let Some(debug) = &config.debug {
+      let here = debug.line(instructions[index].span.start as usize);
+      if line != Some(here) {
+        emit!(code, ".line {}", here);
+        line = Some(here);
+      }
+    }
+    if config.source_map && span != Some(instructions[index].span) {
+      let here = instructions[index].span;
+      emit!(code, "{} {} {}", SPAN_MARKER, here.start, here.end);
+      span = Some(here);
+    }
+    if config.trap_overflow
+      && matches!(
+        instructions[index].op,
+        Op::Plus(_) | Op::Minus(_) | Op::Add { .. } | Op::Set { .. } | Op::AddTo { .. }
+      )
+    {
+      bytecode::mark_position(code, instructions[index].span.start);
+    }
+    // Consecutive `AddTo`s come from one multiplication loop and share a
+    // single load of the current cell.
+    let targets: Vec<(i32, i32)> = instructions[index..range.end]
+      .iter()
+      .map_while(|inst| match inst.op {
+        Op::AddTo { offset, factor } => Some((offset, factor)),
+        _ => None,
+      })
+      .collect();
+    if !targets.is_empty() {
+      cache.write_back(code, config);
+      bytecode::multiply(code, index, &targets, config);
+      index += targets.len();
+      continue;
+    }
+    let inst = &instructions[index];
+    match inst.op {
+      Op::Plus(count) if caching => {
+        cache.load(code, config);
+        bytecode::cache_add(code, count as i32);
+        cache.dirty = true;
+      }
+      Op::Minus(count) if caching => {
+        cache.load(code, config);
+        bytecode::cache_add(code, -(count as i32));
+        cache.dirty = true;
+      }
+      Op::Add { offset: 0, amount } if caching => {
+        cache.load(code, config);
+        bytecode::cache_add(code, amount);
+        cache.dirty = true;
+      }
+      Op::SetZero | Op::Set { offset: 0, .. } if caching => {
+        let value = match inst.op {
+          Op::Set { value, .. } => value,
+          _ => 0,
+        };
+        bytecode::cache_set(code, value, config);
+        cache.valid = true;
+        cache.dirty = true;
+      }
+      Op::PutChar(count) if caching => {
+        cache.load(code, config);
+        bytecode::cache_out(code, count as usize, config);
+      }
+      // Other cells and constant output leave the cache alone.
+      Op::Add { .. } | Op::Set { .. } | Op::PutConst { .. } | Op::Print(_) => {
+        emit(instructions, index, code, config)
+      }
+      // The body becomes a method of its own, leaving only the definition.
+      Op::Procedure(end) => {
+        cache.write_back(code, config);
+        inst.emit_bytecode(code, index, config);
+        index = end as usize + 1;
+        continue;
+      }
+      _ => {
+        cache.write_back(code, config);
+        cache.valid = false;
+        emit(instructions, index, code, config);
+        if tapes && inst.op == Op::Call {
+          bytecode::reload_tape(code, config);
+        }
+      }
+    }
+    index += 1;
+  }
+  cache.write_back(code, config);
+}
+
+/// Feeds the complete lines of `text` to `each`, keeping the end of one
+/// that is cut off in `pending` until the rest of it comes.
+fn complete_lines(pending: &mut String, text: &str, mut each: impl FnMut(&str)) {
+  for piece in text.split_inclusive('\n') {
+    match piece.strip_suffix('\n') {
+      Some(end) if pending.is_empty() => each(end),
+      Some(end) => {
+        pending.push_str(end);
+        each(pending);
+        pending.clear();
+      }
+      None => pending.push_str(piece),
+    }
+  }
+}
+
+/// An upper bound on the bytes code assembles to, counted as it is written
+/// without keeping it: no instruction the backend emits takes more than
+/// three, except a wide `iinc`.
+#[derive(Default)]
+struct Size {
+  bytes: usize,
+  /// The start of a line not yet written in full.
+  pending: String,
+}
+
+impl Size {
+  /// The estimated size of what `generate` writes.
+  fn of(generate: impl FnOnce(&mut Size)) -> usize {
+    let mut size = Size::default();
+    generate(&mut size);
+    size.bytes
+  }
+}
+
+impl fmt::Write for Size {
+  fn write_str(&mut self, text: &str) -> fmt::Result {
+    let bytes = &mut self.bytes;
+    complete_lines(&mut self.pending, text, |line| {
+      let line = line.trim();
+      if line.is_empty() || line.ends_with(':') || line.starts_with('.') || line.starts_with(';') {
+        return;
+      }
+      *bytes += match line.split_whitespace().collect::<Vec<_>>()[..] {
+        ["iinc", _, n] if n.parse::<i8>().is_err() => 6,
+        _ => 3,
+      };
+    });
+    Ok(())
+  }
+}
+
+impl Sink for Size {}
+
+/// The class on its way to `out`. Each method gets the `.limit`s its code
+/// needs, worked out as its lines go by and written before its
+/// `.end method`, so that no more than a line is ever held. With
+/// `Config::deterministic`, labels are numbered in the order they are
+/// first written.
+struct Output<'a> {
+  out: io::BufWriter<&'a mut dyn Write>,
+  /// The start of a line not yet written in full.
+  pending: String,
+  limits: limits::Streaming,
+  /// The number given to the labels of each instruction, when labels are
+  /// renumbered.
+  labels: Option<HashMap<usize, usize>>,
+  /// The first error writing or working out limits, after which nothing
+  /// more is written.
+  error: Option<io::Error>,
+}
+
+impl<'a> Output<'a> {
+  fn new(out: &'a mut dyn Write, config: &Config) -> Output<'a> {
+    Output {
+      out: io::BufWriter::new(out),
+      pending: String::new(),
+      limits: limits::Streaming::default(),
+      labels: if config.deterministic {
+        Some(HashMap::new())
+      } else {
+        None
+      },
+      error: None,
+    }
+  }
+
+  fn write_line(out: &mut dyn Write, limits: &mut limits::Streaming, line: &str) -> io::Result<()> {
+    let limits = limits
+      .line(line)
+      .map_err(|error| io::Error::new(ErrorKind::InvalidData, error))?;
+    if let Some((stack, locals)) = limits {
+      writeln!(out, "    .limit stack {}", stack)?;
+      writeln!(out, "    .limit locals {}", locals)?;
+    }
+    writeln!(out, "{}", line)
+  }
+
+  /// Writes out what is left, or returns the first error.
+  fn finish(mut self) -> io::Result<()> {
+    if !self.pending.is_empty() {
+      fmt::Write::write_str(&mut self, "\n").unwrap();
+    }
+    match self.error {
+      Some(error) => Err(error),
+      None => self.out.flush(),
+    }
+  }
+}
+
+impl fmt::Write for Output<'_> {
+  fn write_str(&mut self, text: &str) -> fmt::Result {
+    let Output {
+      out,
+      pending,
+      limits,
+      error,
+      ..
+    } = self;
+    complete_lines(pending, text, |line| {
+      if error.is_none() {
+        *error = Output::write_line(out, limits, line).err();
+      }
+    });
+    Ok(())
+  }
+}
+
+impl Sink for Output<'_> {
+  fn label(&mut self, index: usize) -> usize {
+    match &mut self.labels {
+      Some(labels) => {
+        let next = labels.len();
+        *labels.entry(index).or_insert(next)
+      }
+      None => index,
+    }
+  }
+}
+
+/// The pieces `instructions[range]` can be cut into without separating a
+/// loop from its body or a multiplication from its other targets.
+pub(super) fn pieces(instructions: &[Inst], range: Range<usize>) -> Vec<Range<usize>> {
+  let mut pieces = Vec::new();
+  let mut index = range.start;
+  while index < range.end {
+    let end = match instructions[index].op {
+      Op::JumpIfZero(end) | Op::Procedure(end) => end as usize + 1,
+      Op::AddTo { .. } => {
+        index
+          + instructions[index..range.end]
+            .iter()
+            .take_while(|inst| matches!(inst.op, Op::AddTo { .. }))
+            .count()
+      }
+      _ => index + 1,
+    };
+    pieces.push(index..end);
+    index = end;
+  }
+  pieces
+}
+
+/// The estimated size of the brackets of the loop from `start` to `end`.
+fn brackets_size(instructions: &[Inst], start: usize, end: usize, config: &Config) -> usize {
+  Size::of(|size| {
+    emit(instructions, start, size, config);
+    emit(instructions, end, size, config);
+  })
+}
+
+/// The estimated size of `inline` for `instructions[range]`, noting that of
+/// each loop in it in `sizes`, at the index of its `[`. Code between loops
+/// is counted once and each loop from the sizes of its pieces, since a
+/// loop leaves nothing cached on either side of it, so that estimating
+/// takes time linear in the length of the program however deep loops nest.
+fn loop_sizes(
+  instructions: &[Inst],
+  range: Range<usize>,
+  config: &Config,
+  tapes: bool,
+  sizes: &mut [usize],
+) -> usize {
+  let mut size = 0;
+  let mut straight = range.start;
+  for piece in pieces(instructions, range.clone()) {
+    if let Op::JumpIfZero(end) = instructions[piece.start].op {
+      let end = end as usize;
+      size += Size::of(|size| inline(instructions, straight..piece.start, config, tapes, size));
+      sizes[piece.start] = brackets_size(instructions, piece.start, end, config)
+        + loop_sizes(instructions, piece.start + 1..end, config, tapes, sizes);
+      size += sizes[piece.start];
+      straight = piece.end;
+    }
+  }
+  size + Size::of(|size| inline(instructions, straight..range.end, config, tapes, size))
+}
+
+/// The descriptor of methods that receive the locals of `main` and return
+/// the pointer.
+fn descriptor(config: &Config) -> String {
+  let tape = if config.byte_tape { "[B" } else { "[I" };
+  format!(
+    "(Ljava/lang/Object;I{}ILjava/io/PrintStream;Ljava/io/InputStream;)I",
+    tape
+  )
+}
+
+/// What `split` leaves of a region of the program.
+enum Part {
+  /// Code generated where it stands.
+  Inline(Range<usize>),
+  /// A loop kept in place around a body that was split in turn.
+  Loop {
+    start: usize,
+    end: usize,
+    body: Vec<Part>,
+  },
+  /// A call to the chunk of that number.
+  Chunk(usize),
+}
+
+/// Generates `parts` of `instructions`, which switch tapes with `tapes`.
+fn write_parts(
+  instructions: &[Inst],
+  parts: &[Part],
+  config: &Config,
+  tapes: bool,
+  code: &mut impl Sink,
+) {
+  for part in parts {
+    match part {
+      Part::Inline(range) => inline(instructions, range.clone(), config, tapes, code),
+      Part::Loop { start, end, body } => {
+        emit(instructions, *start, code, config);
+        write_parts(instructions, body, config, tapes, code);
+        emit(instructions, *end, code, config);
+      }
+      Part::Chunk(number) => call_body(code, &format!("chunk{}", number), config, tapes),
+    }
+  }
+}
+
+/// Where the methods `split` makes go, as it makes them.
+struct Methods<'a> {
+  out: Output<'a>,
+  /// Chunks written so far, which name the next one.
+  chunks: usize,
+  /// Whether the program switches tapes, so that callers reload theirs.
+  tapes: bool,
+}
+
+impl Methods<'_> {
+  /// Writes a method called `name` that runs the code `body` generates on
+  /// the locals of `main` and returns the pointer.
+  fn write_body(&mut self, name: &str, config: &Config, body: impl FnOnce(&mut Output)) {
+    emit!(
+      &mut self.out,
+      ".method private static {}{}",
+      name,
+      descriptor(config)
+    );
+    body(&mut self.out);
+    bytecode::lines(
+      &mut self.out,
+      &["    iload_1", "    ireturn", ".end method"],
+    );
+  }
+
+  /// Writes `parts` as the next chunk, returning the call to it.
+  fn write_chunk(&mut self, instructions: &[Inst], parts: &[Part], config: &Config) -> Part {
+    let number = self.chunks;
+    self.chunks += 1;
+    let tapes = self.tapes;
+    self.write_body(&format!("chunk{}", number), config, |out| {
+      write_parts(instructions, parts, config, tapes, out)
+    });
+    Part::Chunk(number)
+  }
+}
+
+/// Calls a method written by `Methods::write_body`, which updates the
+/// pointer, and the tape with `tapes`.
+fn call_body(code: &mut impl Sink, name: &str, config: &Config, tapes: bool) {
+  bytecode::lines(
+    code,
+    &[
+      "aload_0", "iload_1", "aload_2", "iconst_0", "aload 4", "aload 5",
+    ],
+  );
+  emit!(code, "invokestatic Main/{}{}", name, descriptor(config));
+  bytecode::lines(code, &["istore_1"]);
+  if tapes {
+    bytecode::reload_tape(code, config);
+  }
+}
+
+/// Plans `instructions[range]`, whose estimated size is `size`, moving
+/// parts of it into new static methods written to `methods` while it is
+/// larger than `limit`, and returns what stays for `write_parts`. `sizes`
+/// holds those of its loops, as `loop_sizes` notes them. Each such method
+/// receives the locals of `main` and returns the pointer; local 0 is
+/// passed as an `Object`, since it is `args` in `main` and the input
+/// stream in `run`. With a profile, the coldest pieces move out first and
+/// hot ones stay where they are, so that the calls land in cold code;
+/// otherwise the pieces are moved out in order.
+fn split(
+  instructions: &[Inst],
+  range: Range<usize>,
+  size: usize,
+  limit: usize,
+  sizes: &[usize],
+  config: &Config,
+  methods: &mut Methods,
+) -> Vec<Part> {
+  if size <= limit {
+    return vec![Part::Inline(range)];
+  }
+  let mut parts = Vec::new();
+  for piece in pieces(instructions, range) {
+    let part = match instructions[piece.start].op {
+      // Too big even on its own: keep the brackets and split the body.
+      Op::JumpIfZero(end) if sizes[piece.start] > limit => {
+        let end = end as usize;
+        let brackets = brackets_size(instructions, piece.start, end, config);
+        let body_size = sizes[piece.start] - brackets;
+        let body = piece.start + 1..end;
+        let body = if body_size <= limit {
+          // Inline, the body would leave the brackets over the limit.
+          vec![methods.write_chunk(instructions, &[Part::Inline(body)], config)]
+        } else {
+          let limit = limit.saturating_sub(brackets);
+          split(instructions, body, body_size, limit, sizes, config, methods)
+        };
+        Part::Loop {
+          start: piece.start,
+          end,
+          body,
+        }
+      }
+      _ => Part::Inline(piece.clone()),
+    };
+    parts.push((piece, part));
+  }
+  let tapes = methods.tapes;
+  let sizes: Vec<usize> = parts
+    .iter()
+    .map(|(_, part)| {
+      Size::of(|size| {
+        write_parts(
+          instructions,
+          std::slice::from_ref(part),
+          config,
+          tapes,
+          size,
+        )
+      })
+    })
+    .collect();
+  let mut moved = vec![true; parts.len()];
+  if config.profile.is_some() {
+    let call = Size::of(|size| call_body(size, "chunk", config, tapes));
+    let mut order: Vec<usize> = (0..parts.len()).collect();
+    order.sort_by_key(|&i| config.hotness(instructions, parts[i].0.clone()));
+    // Move the coldest pieces out until what stays and the calls to the
+    // runs of moved pieces fit: one per run, and more for long runs.
+    moved = vec![false; parts.len()];
+    let mut kept_size: usize = sizes.iter().sum();
+    let mut moved_size = 0;
+    let mut runs = 0;
+    for i in order {
+      if kept_size + (runs + moved_size / limit.max(1)) * call <= limit {
+        break;
+      }
+      moved[i] = true;
+      kept_size -= sizes[i];
+      moved_size += sizes[i];
+      let before = i > 0 && moved[i - 1];
+      let after = i + 1 < moved.len() && moved[i + 1];
+      runs = runs + 1 - before as usize - after as usize;
+    }
+  }
+  let mut kept = Vec::new();
+  let mut chunk = Vec::new();
+  let mut chunk_size = 0;
+  for (i, (_, part)) in parts.into_iter().enumerate() {
+    if !chunk.is_empty() && (!moved[i] || chunk_size + sizes[i] > limit) {
+      kept.push(methods.write_chunk(instructions, &chunk, config));
+      chunk.clear();
+      chunk_size = 0;
+    }
+    if moved[i] {
+      chunk.push(part);
+      chunk_size += sizes[i];
+    } else {
+      kept.push(part);
+    }
+  }
+  if !chunk.is_empty() {
+    kept.push(methods.write_chunk(instructions, &chunk, config));
+  }
+  kept
+}
+
+/// Writes the class for `instructions` to `out` as it is generated.
+pub fn write_code(instructions: &[Inst], config: &Config, out: &mut dyn Write) -> io::Result<()> {
+  let invalid = |message: &str| io::Error::new(ErrorKind::InvalidInput, message);
+  let size = i32::try_from(config.tape_size).map_err(|_| {
+    invalid(&format!(
+      "a tape of {} cells does not fit a JVM array",
+      config.tape_size
+    ))
+  })?;
+  let budget = match config.step_budget {
+    Some(budget) => Some(i32::try_from(budget).map_err(|_| {
+      invalid(&format!(
+        "a step budget of {} does not fit a JVM int",
+        budget
+      ))
+    })?),
+    None => None,
+  };
+  if config.runtime_args && config.tape_from_args {
+    return Err(invalid(
+      "--tape-from-args and --runtime-args both read the command line",
+    ));
+  }
+  if config.input_from_args && config.tape_from_args {
+    return Err(invalid(
+      "--input-from-args and --tape-from-args both read args[0]",
+    ));
+  }
+  if config.input_from_args && config.has_run() {
+    return Err(invalid(
+      "--input-from-args reads the arguments of a plain main, without --embeddable or --runtime-args",
+    ));
+  }
+  let forks: Vec<usize> = instructions
+    .iter()
+    .enumerate()
+    .filter(|(_, inst)| inst.op == Op::Fork)
+    .map(|(index, _)| index)
+    .collect();
+  if !forks.is_empty() && (config.has_run() || config.exit_cell.is_some()) {
+    return Err(invalid(
+      "Brainfork threads need a plain main, without --embeddable, --runtime-args or --exit-from-cell",
+    ));
+  }
+  if config.has_run() && instructions.iter().any(|inst| inst.op == Op::Ebf(Ebf::End)) {
+    return Err(invalid(
+      "@ exits the JVM, so it needs a plain main, without --embeddable or --runtime-args",
+    ));
+  }
+  let tapes = has_tapes(instructions);
+  // Threads resume at their fork, so the program stays in one method. It is
+  // measured first, so that nothing is written for a program too big.
+  if !forks.is_empty()
+    && Size::of(|size| inline(instructions, 0..instructions.len(), config, tapes, size))
+      > config.method_size
+  {
+    return Err(invalid(&format!(
+      "Brainfork programs must fit one method of --method-size {} bytes",
+      config.method_size
+    )));
+  }
+  let mut methods = Methods {
+    out: Output::new(out, config),
+    chunks: 0,
+    tapes,
+  };
+  let code = &mut methods.out;
+  if let Some(debug) = &config.debug {
+    emit!(code, ".source {}", debug.file);
+  }
+  if forks.is_empty() {
+    emit!(code, "{}", header("java/lang/Object"));
+  } else {
+    emit!(code, "{}", header("java/lang/Thread"));
+    bytecode::thread_methods(code, config);
+  }
+  let procedures: Vec<usize> = instructions
+    .iter()
+    .enumerate()
+    .filter(|(_, inst)| matches!(inst.op, Op::Procedure(_)))
+    .map(|(index, _)| index)
+    .collect();
+  let has_procedures = has_procedures(instructions);
+  if has_procedures {
+    bytecode::lines(code, &[".field private static procedures [I"]);
+  }
+  if let Some(budget) = budget {
+    bytecode::lines(code, &[".field private static steps I"]);
+    bytecode::step_method(code, budget);
+  }
+  if config.trap_overflow {
+    bytecode::lines(code, &[".field private static position I"]);
+    bytecode::check_cell_method(code);
+  }
+  if has_ebf(instructions) {
+    bytecode::lines(code, &[".field private static storage I"]);
+  }
+  if tapes {
+    let tape = if config.byte_tape { "[B" } else { "[I" };
+    emit!(code, ".field private static tapes [{}", tape);
+    bytecode::lines(
+      code,
+      &[
+        ".field private static pointers [I",
+        ".field private static tape I",
+      ],
+    );
+  }
+  // Public, so that code embedding the class can swap in its own generator.
+  if has_random(instructions) {
+    bytecode::lines(code, &[".field public static random Ljava/util/Random;"]);
+    bytecode::random_init(code, config);
+  }
+  if config.has_run() {
+    bytecode::entry_points(code, size, config);
+  }
+  let mut sizes = vec![0; instructions.len()];
+  // Each procedure is a method like the chunks `split` makes.
+  for &start in &procedures {
+    if let Op::Procedure(end) = instructions[start].op {
+      let range = start + 1..end as usize;
+      let size = loop_sizes(instructions, range.clone(), config, tapes, &mut sizes);
+      let parts = split(
+        instructions,
+        range,
+        size,
+        config.method_size,
+        &sizes,
+        config,
+        &mut methods,
+      );
+      methods.write_body(&format!("proc{}", start), config, |out| {
+        write_parts(instructions, &parts, config, tapes, out)
+      });
+    }
+  }
+  let code = &mut methods.out;
+  if has_procedures {
+    bytecode::dispatch(code, &procedures, config);
+  }
+  if has_debug_dumps(instructions) {
+    bytecode::dump_method(code, config);
+  }
+  if instructions.iter().any(|inst| inst.op == Op::ReadNumber) {
+    bytecode::read_number_method(code, config);
+  }
+  if config.io == Io::Unicode {
+    if instructions
+      .iter()
+      .any(|inst| matches!(inst.op, Op::PutChar(_)))
+    {
+      bytecode::put_code_point_method(code);
+    }
+    if instructions
+      .iter()
+      .any(|inst| matches!(inst.op, Op::ReadChar(_)))
+    {
+      bytecode::read_code_point_method(code);
+    }
+  }
+  // The chunks of the program are written before the method that calls
+  // them.
+  let parts = if forks.is_empty() {
+    let range = 0..instructions.len();
+    let size = loop_sizes(instructions, range.clone(), config, tapes, &mut sizes);
+    split(
+      instructions,
+      range,
+      size,
+      config.method_size,
+      &sizes,
+      config,
+      &mut methods,
+    )
+  } else {
+    methods.write_body("body", config, |out| {
+      bytecode::resume(out, &forks);
+      inline(instructions, 0..instructions.len(), config, tapes, out)
+    });
+    Vec::new()
+  };
+  let code = &mut methods.out;
+  if config.has_run() {
+    // Both streams are wrapped before their locals are reused.
+    bytecode::lines(
+      code,
+      &[".method public static run(Ljava/io/InputStream;Ljava/io/OutputStream;I)I"],
+    );
+    bytecode::open_output(code, config);
+    bytecode::open_input(code, config);
+    bytecode::lines(code, &["iload_2"]);
+    bytecode::allocate(code, config);
+  } else {
+    bytecode::lines(code, &[".method public static main([Ljava/lang/String;)V"]);
+    bytecode::tape_size(code, size, config);
+    bytecode::allocate(code, config);
+    bytecode::open_output(code, config);
+    bytecode::open_input(code, config);
+  }
+  if has_procedures {
+    bytecode::procedure_table(code);
+  }
+  if tapes {
+    bytecode::allocate_tapes(code, config);
+  }
+  if !forks.is_empty() {
+    bytecode::share_streams(code);
+  }
+  bytecode::lines(code, &["iconst_0", "istore_1"]);
+  if let Some(budget) = budget {
+    bytecode::start_budget(code, budget);
+  }
+  if forks.is_empty() {
+    write_parts(instructions, &parts, config, tapes, code);
+  } else {
+    call_body(code, "body", config, tapes);
+  }
+  bytecode::flush(code, config);
+  if config.has_run() {
+    bytecode::exit_value(code, config);
+    emit!(code, "{}", RUN_TAIL);
+  } else {
+    if config.exit_cell.is_some() {
+      bytecode::exit_value(code, config);
+      bytecode::lines(code, &["invokestatic java/lang/System/exit(I)V"]);
+    }
+    emit!(code, "{}", TAIL);
+  }
+  methods.out.finish()
+}
+
+/// The class for `instructions` as one string, for callers that need all of
+/// it at once.
+pub fn produce_code(instructions: Vec<Inst>, config: &Config) -> Result<String, String> {
+  let mut code = Vec::new();
+  write_code(&instructions, config, &mut code).map_err(|error| error.to_string())?;
+  String::from_utf8(code).map_err(|error| error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use super::super::limits;
+  use super::super::profile::Profile;
+  use super::super::{lex_dialect, parse_program, Dialect, Span};
+  use super::{produce_code, Config, Size};
+
+  #[test]
+  fn deep_loops_split_into_methods_within_the_size() {
+    let depth = 300;
+    let code = format!("+{}>+<-.{}", "[".repeat(depth), "]".repeat(depth));
+    let program = lex_dialect(&code, Dialect::Brainfuck)
+      .and_then(parse_program)
+      .unwrap();
+    let config = Config {
+      method_size: 500,
+      ..Config::default()
+    };
+    let class = produce_code(program, &config).unwrap();
+    let chunks: Vec<&str> = class
+      .split(".method private static chunk")
+      .skip(1)
+      .map(|method| &method[..method.find(".end method").unwrap()])
+      .collect();
+    assert!(!chunks.is_empty());
+    for chunk in chunks {
+      // The body, and the return of the pointer.
+      let size = Size::of(|size| std::fmt::Write::write_str(size, chunk).unwrap());
+      assert!(size <= config.method_size + 6);
+    }
+  }
+
+  /// The body of the method `name` in `class`.
+  fn method<'a>(class: &'a str, name: &str) -> &'a str {
+    let start = class.find(&format!(" {}(", name)).unwrap();
+    &class[start..start + class[start..].find(".end method").unwrap()]
+  }
+
+  #[test]
+  fn hot_loops_are_tested_at_their_end() {
+    // The loop around the scan runs a thousand times, the other one once.
+    let code = "+++++[>+<-]>[>[<]<-]";
+    let program = lex_dialect(code, Dialect::Brainfuck)
+      .and_then(parse_program)
+      .unwrap();
+    let config = Config {
+      profile: Some(Arc::new(Profile {
+        counts: vec![
+          (Span::new(0, 11).unwrap(), 1),
+          (Span::new(13, 20).unwrap(), 1000),
+        ],
+      })),
+      ..Config::default()
+    };
+    let class = produce_code(program.clone(), &config).unwrap();
+    let close = program.len() - 1;
+    assert!(class.contains(&format!("ifne loop{}Start", close - 5)));
+    assert!(!class.contains("ifne loop1Start"));
+    assert!(!produce_code(program, &Config::default())
+      .unwrap()
+      .contains("ifne"));
+  }
+
+  #[test]
+  fn hot_code_stays_out_of_split_methods() {
+    let cold = ".>".repeat(100);
+    let code = format!("{}+[>.<-]{}", cold, cold);
+    let program = lex_dialect(&code, Dialect::Brainfuck)
+      .and_then(parse_program)
+      .unwrap();
+    let hot = cold.len() + 1;
+    let config = Config {
+      method_size: 1000,
+      profile: Some(Arc::new(Profile {
+        counts: vec![
+          (Span::new(0, cold.len()).unwrap(), 1),
+          (Span::new(cold.len() + 1, cold.len() + 8).unwrap(), 1000),
+        ],
+      })),
+      ..Config::default()
+    };
+    let class = produce_code(program.clone(), &config).unwrap();
+    assert!(method(&class, "main").contains(&format!("loop{}Start:", hot)));
+    assert!(class.contains("chunk0"));
+    let plain = produce_code(
+      program,
+      &Config {
+        profile: None,
+        ..config
+      },
+    )
+    .unwrap();
+    assert!(!method(&plain, "main").contains(&format!("loop{}Start:", hot)));
+  }
+
+  #[test]
+  fn limits_worked_out_while_streaming_match_the_whole_method_analysis() {
+    let code = "+[>(,[>+<-]*)<[-]>]++[>++++[<+>-]<-]:.#";
+    let program = lex_dialect(code, Dialect::Pbrain)
+      .and_then(parse_program)
+      .unwrap();
+    let config = Config {
+      method_size: 60,
+      ..Config::default()
+    };
+    let class = produce_code(program, &config).unwrap();
+    let stripped: String = class
+      .lines()
+      .filter(|line| !line.trim_start().starts_with(".limit"))
+      .map(|line| format!("{}\n", line))
+      .collect();
+    let mut limited = Vec::new();
+    limits::write_with_limits(&stripped, &mut limited).unwrap();
+    let limits = |class: &str| {
+      let mut limits: Vec<String> = class
+        .lines()
+        .filter(|line| line.trim_start().starts_with(".limit"))
+        .map(|line| line.trim().to_string())
+        .collect();
+      limits.sort();
+      limits
+    };
+    assert!(class.contains("chunk0"));
+    assert_eq!(limits(&class), limits(&String::from_utf8(limited).unwrap()));
+  }
+
+  #[test]
+  fn deterministic_labels_are_numbered_as_they_are_written() {
+    let program = lex_dialect("++>+[-]<[>[-]<-]", Dialect::Brainfuck)
+      .and_then(parse_program)
+      .unwrap();
+    let config = Config {
+      deterministic: true,
+      ..Config::default()
+    };
+    let class = produce_code(program, &config).unwrap();
+    let labels: Vec<&str> = class.lines().filter(|line| line.ends_with(':')).collect();
+    assert_eq!(
+      labels,
+      [
+        "loop0Start:",
+        "loop0End:",
+        "loop1Start:",
+        "loop2Start:",
+        "loop2End:",
+        "loop1End:"
+      ]
+    );
+  }
+}
@@ -0,0 +1,609 @@
+//! Java source for the JVM, as a readable alternative to Jasmin: the same
+//! tape and streams as the generated class, with every loop a `while`.
+//! `out` prints characters in UTF-8, or in ISO-8859-1 with `--io bytes` so
+//! that each character is the byte it stands for. Code that would take
+//! more than `--method-size` bytes moves into further methods, as in the
+//! class, since javac rejects a method of more than 65535.
+
+use std::convert::TryFrom;
+use std::ops::Range;
+
+use super::interpreter::{Bounds, Eof, ExitCell, Io};
+use super::jasmin::{pieces, Config};
+use super::{reject_extensions, Inst, Op};
+
+const INDENT: &str = "    ";
+
+/// The statement following each `<` and `>` under `Bounds::Error`, since
+/// indexing only catches a pointer off the tape at the next access.
+const CHECK: &str = "if (p < 0 || p >= tape.length) \
+                     throw new ArrayIndexOutOfBoundsException(\"pointer moved off the tape\");";
+
+/// An upper bound on the bytecode javac compiles one statement of the
+/// generated source to, by which code is cut into methods of at most
+/// `--method-size` bytes.
+const STATEMENT_SIZE: usize = 32;
+
+/// The tape cell at `offset` from the pointer.
+fn cell(offset: i32) -> String {
+  match offset {
+    0 => "tape[p]".to_string(),
+    _ if offset > 0 => format!("tape[p + {}]", offset),
+    _ => format!("tape[p - {}]", offset.unsigned_abs()),
+  }
+}
+
+/// `+=` or `-=` with a positive operand.
+fn compound(target: &str, amount: i64) -> String {
+  if amount < 0 {
+    format!("{} -= {};", target, amount.unsigned_abs())
+  } else {
+    format!("{} += {};", target, amount)
+  }
+}
+
+/// Adds `amount`, which may start with a minus sign, to the cell at
+/// `offset`. Compound assignment to a `byte` wraps on its own; an `int` cell
+/// is masked when cells wrap.
+fn add(offset: i32, amount: &str, config: &Config) -> String {
+  let target = cell(offset);
+  let (operator, operand) = match amount.strip_prefix('-') {
+    Some(operand) => ('-', operand),
+    None => ('+', amount),
+  };
+  if config.wrap && !config.byte_tape {
+    format!("{} = ({} {} {}) & 255;", target, target, operator, operand)
+  } else {
+    format!("{} {}= {};", target, operator, operand)
+  }
+}
+
+/// Assigns the `int` expression `value` to the cell at `offset`.
+fn store(offset: i32, value: &str, config: &Config) -> String {
+  if config.byte_tape {
+    format!("{} = (byte) {};", cell(offset), value)
+  } else if config.wrap {
+    format!("{} = {} & 255;", cell(offset), value)
+  } else {
+    format!("{} = {};", cell(offset), value)
+  }
+}
+
+/// The current cell as a character code, or as the code of its low byte
+/// when writing bytes.
+fn current(config: &Config) -> String {
+  if config.byte_tape || config.io == Io::Bytes {
+    "(char) (tape[p] & 255)".to_string()
+  } else {
+    "(char) tape[p]".to_string()
+  }
+}
+
+/// Quotes `text` as a Java string literal. Control characters use octal
+/// escapes, since `\u` escapes are replaced before the literal is lexed.
+fn quote(text: &str) -> String {
+  let mut quoted = String::from("\"");
+  for c in text.chars() {
+    match c {
+      '"' => quoted.push_str("\\\""),
+      '\\' => quoted.push_str("\\\\"),
+      '\n' => quoted.push_str("\\n"),
+      '\r' => quoted.push_str("\\r"),
+      '\t' => quoted.push_str("\\t"),
+      ' '..='~' => quoted.push(c),
+      _ if (c as u32) < 0x100 => quoted.push_str(&format!("\\{:03o}", c as u32)),
+      _ => {
+        for unit in c.encode_utf16(&mut [0; 2]) {
+          quoted.push_str(&format!("\\u{:04x}", unit));
+        }
+      }
+    }
+  }
+  quoted.push('"');
+  quoted
+}
+
+/// The final value of the cell `--exit-from-cell` names, or of the current
+/// cell by default.
+fn exit_value(config: &Config) -> String {
+  let cell = if config.exit_cell == Some(ExitCell::First) {
+    "tape[0]"
+  } else {
+    "tape[p]"
+  };
+  if config.byte_tape {
+    format!("{} & 255", cell)
+  } else {
+    cell.to_string()
+  }
+}
+
+/// The statement running `call`, which returns the exit value, and exiting
+/// with its result when there is an exit cell.
+fn exit_with(call: &str, config: &Config) -> String {
+  if config.exit_cell.is_some() {
+    format!("System.exit({});", call)
+  } else {
+    format!("{};", call)
+  }
+}
+
+/// Statements reading one byte into the current cell.
+fn read(config: &Config) -> Vec<String> {
+  match config.eof {
+    Eof::Unchanged => vec![
+      "c = in.read();".to_string(),
+      format!("if (c >= 0) {}", store(0, "c", config)),
+    ],
+    Eof::Zero => vec![
+      "c = in.read();".to_string(),
+      store(0, "(c < 0 ? 0 : c)", config),
+    ],
+    Eof::MinusOne => vec![store(0, "in.read()", config)],
+  }
+}
+
+/// The statements for `instructions[index]`, and how many instructions
+/// they stand for: a multiplication takes all of its targets at once.
+fn statements(instructions: &[Inst], index: usize, config: &Config) -> (Vec<String>, usize) {
+  let mut lines = Vec::new();
+  let mut emit = |line: String| lines.push(line);
+  let mut count = 1;
+  match instructions[index].op {
+    Op::Procedure(_)
+    | Op::Return(_)
+    | Op::Call
+    | Op::Fork
+    | Op::Ebf(_)
+    | Op::Debug
+    | Op::PutNumber
+    | Op::ReadNumber
+    | Op::Random
+    | Op::Tape(_) => {
+      unreachable!("rejected by reject_extensions")
+    }
+    Op::Plus(count) => emit(add(0, &count.to_string(), config)),
+    Op::Minus(count) => emit(add(0, &format!("-{}", count), config)),
+    Op::Right(count) => {
+      emit(compound("p", count as i64));
+      if config.bounds == Bounds::Error {
+        emit(CHECK.to_string());
+      }
+    }
+    Op::Left(count) => {
+      emit(compound("p", -(count as i64)));
+      if config.bounds == Bounds::Error {
+        emit(CHECK.to_string());
+      }
+    }
+    Op::PutChar(count) => {
+      for _ in 0..count {
+        emit(format!("out.print({});", current(config)));
+      }
+    }
+    Op::ReadChar(count) => {
+      if config.buffered {
+        emit("out.flush();".to_string());
+      }
+      for _ in 0..count {
+        read(config).into_iter().for_each(&mut emit);
+      }
+    }
+    Op::JumpIfZero(_) => emit("while (tape[p] != 0) {".to_string()),
+    Op::JumpIfNonZero(_) => emit("}".to_string()),
+    Op::SetZero => emit("tape[p] = 0;".to_string()),
+    Op::AddTo { .. } => {
+      // A multiplication leaves the offset cells alone when the counter
+      // is zero, so they are never indexed out of bounds in that case.
+      let targets: Vec<(i32, i32)> = instructions[index..]
+        .iter()
+        .map_while(|inst| match inst.op {
+          Op::AddTo { offset, factor } => Some((offset, factor)),
+          _ => None,
+        })
+        .collect();
+      emit("if (tape[p] != 0) {".to_string());
+      emit(format!("{}int n = tape[p];", INDENT));
+      for (offset, factor) in &targets {
+        let product = match factor.unsigned_abs() {
+          1 => "n".to_string(),
+          magnitude => format!("n * {}", magnitude),
+        };
+        let product = if *factor < 0 {
+          format!("-{}", product)
+        } else {
+          product
+        };
+        emit(format!("{}{}", INDENT, add(*offset, &product, config)));
+      }
+      emit("}".to_string());
+      count = targets.len();
+    }
+    Op::Add { offset, amount } => emit(add(offset, &amount.to_string(), config)),
+    Op::Set { offset, value } => {
+      let value = if config.wrap { value & 255 } else { value };
+      emit(if config.byte_tape && value > i8::MAX as i32 {
+        format!("{} = (byte) {};", cell(offset), value)
+      } else {
+        format!("{} = {};", cell(offset), value)
+      });
+    }
+    Op::ScanZero { stride } => emit(format!(
+      "while (tape[p] != 0) {}",
+      compound("p", stride as i64)
+    )),
+    Op::PutConst { value, count } => {
+      let text: String = std::iter::repeat_n(value as char, count as usize).collect();
+      emit(format!("out.print({});", quote(&text)));
+    }
+    Op::Print(ref text) => emit(format!("out.print({});", quote(&text.chars()))),
+  }
+  (lines, count)
+}
+
+/// Methods that code moves into when it would make one too large for the
+/// JVM, as `jasmin` cuts up the class it writes. Each takes the tape, the
+/// pointer and the streams, and returns the pointer.
+struct Chunks<'a> {
+  instructions: &'a [Inst],
+  config: &'a Config,
+  /// The type of the cells.
+  kind: &'a str,
+  /// The number of statements before each instruction, from which the
+  /// size of any range of them follows.
+  before: Vec<usize>,
+  /// The lines of the methods written so far.
+  methods: Vec<String>,
+  /// Chunks written so far, which name the next one.
+  chunks: usize,
+}
+
+impl Chunks<'_> {
+  /// An upper bound on the bytecode javac makes of `instructions[range]`.
+  fn size(&self, range: Range<usize>) -> usize {
+    (self.before[range.end] - self.before[range.start]) * STATEMENT_SIZE
+  }
+
+  /// The statements for `instructions[range]`, at their depth in it.
+  fn inline(&self, range: Range<usize>) -> Vec<(usize, String)> {
+    let mut body = Vec::new();
+    let mut depth = 0;
+    let mut index = range.start;
+    while index < range.end {
+      let (statements, count) = statements(self.instructions, index, self.config);
+      match self.instructions[index].op {
+        Op::JumpIfZero(_) => {
+          body.extend(statements.into_iter().map(|line| (depth, line)));
+          depth += 1;
+        }
+        Op::JumpIfNonZero(_) => {
+          depth -= 1;
+          body.extend(statements.into_iter().map(|line| (depth, line)));
+        }
+        _ => body.extend(statements.into_iter().map(|line| (depth, line))),
+      }
+      index += count;
+    }
+    body
+  }
+
+  /// Writes `body`, which runs `instructions[range]`, as the next chunk and
+  /// calls it at the end of `code`.
+  fn write_chunk(
+    &mut self,
+    range: Range<usize>,
+    body: Vec<(usize, String)>,
+    code: &mut Vec<(usize, String)>,
+  ) {
+    let name = format!("chunk{}", self.chunks);
+    self.chunks += 1;
+    self.methods.extend([
+      String::new(),
+      format!(
+        "{}private static int {}({}[] tape, int p, PrintStream out, InputStream in)",
+        INDENT, name, self.kind
+      ),
+      format!("{}throws IOException {{", INDENT.repeat(3)),
+    ]);
+    if self.config.eof != Eof::MinusOne
+      && self.instructions[range]
+        .iter()
+        .any(|inst| matches!(inst.op, Op::ReadChar(_)))
+    {
+      self.methods.push(format!("{}int c;", INDENT.repeat(2)));
+    }
+    self.methods.extend(
+      body
+        .into_iter()
+        .map(|(depth, line)| format!("{}{}", INDENT.repeat(depth + 2), line)),
+    );
+    self.methods.extend([
+      format!("{}return p;", INDENT.repeat(2)),
+      format!("{}}}", INDENT),
+    ]);
+    code.push((0, format!("p = {}(tape, p, out, in);", name)));
+  }
+
+  /// The statements for `instructions[range]`, with parts of it moved into
+  /// chunks while it is larger than `limit`, in the order `jasmin::split`
+  /// moves them without a profile: a loop too large on its own keeps its
+  /// `while` and has its body split, and the rest goes into chunks in runs
+  /// that fit.
+  fn split(&mut self, range: Range<usize>, limit: usize) -> Vec<(usize, String)> {
+    if self.size(range.clone()) <= limit {
+      return self.inline(range);
+    }
+    let mut codes = Vec::new();
+    for piece in pieces(self.instructions, range) {
+      let code = match self.instructions[piece.start].op {
+        Op::JumpIfZero(end) if self.size(piece.clone()) > limit => {
+          let body = piece.start + 1..end as usize;
+          let brackets = 2 * STATEMENT_SIZE;
+          let inner = if self.size(body.clone()) <= limit {
+            // Inline, the body would leave the brackets over the limit.
+            let mut call = Vec::new();
+            let inline = self.inline(body.clone());
+            self.write_chunk(body, inline, &mut call);
+            call
+          } else {
+            self.split(body, limit.saturating_sub(brackets))
+          };
+          let mut code = vec![(0, "while (tape[p] != 0) {".to_string())];
+          code.extend(inner.into_iter().map(|(depth, line)| (depth + 1, line)));
+          code.push((0, "}".to_string()));
+          code
+        }
+        _ => self.inline(piece.clone()),
+      };
+      codes.push((piece, code));
+    }
+    let mut calls = Vec::new();
+    let mut part = Vec::new();
+    let mut part_range = 0..0;
+    for (piece, code) in codes {
+      if !part.is_empty() && (part.len() + code.len()) * STATEMENT_SIZE > limit {
+        self.write_chunk(part_range.clone(), std::mem::take(&mut part), &mut calls);
+      }
+      if part.is_empty() {
+        part_range.start = piece.start;
+      }
+      part_range.end = piece.end;
+      part.extend(code);
+    }
+    if !part.is_empty() {
+      self.write_chunk(part_range, part, &mut calls);
+    }
+    calls
+  }
+}
+
+/// Generates `Main.java` for `instructions`.
+pub fn produce_java(instructions: &[Inst], config: &Config) -> Result<String, String> {
+  reject_extensions(instructions)?;
+  let charset = match config.io {
+    Io::Chars => "UTF-8",
+    Io::Bytes => "ISO-8859-1",
+    Io::Unicode => return Err("--emit java does not support --io unicode".to_string()),
+  };
+  i32::try_from(config.tape_size).map_err(|_| {
+    format!(
+      "a tape of {} cells does not fit a Java array",
+      config.tape_size
+    )
+  })?;
+  if config.runtime_args && config.tape_from_args {
+    return Err("--tape-from-args and --runtime-args both read the command line".to_string());
+  }
+  let kind = if config.byte_tape { "byte" } else { "int" };
+  let size = if config.tape_from_args {
+    format!(
+      "args.length > 0 ? Integer.parseInt(args[0]) : {}",
+      config.tape_size
+    )
+  } else {
+    config.tape_size.to_string()
+  };
+  let mut lines = Vec::new();
+  if let Some(debug) = &config.debug {
+    lines.push(format!("// Compiled from {}", debug.file));
+  }
+  lines.extend([
+    "import java.io.*;".to_string(),
+    String::new(),
+    "public class Main {".to_string(),
+  ]);
+  let (tape, output, input) = if config.has_run() {
+    lines.push(format!(
+      "{}public static void main(String[] args) throws IOException {{",
+      INDENT
+    ));
+    if config.runtime_args {
+      let arguments = [
+        format!("int tapeSize = {};", config.tape_size),
+        "InputStream input = System.in;".to_string(),
+        "OutputStream output = System.out;".to_string(),
+        "for (int i = 0; i < args.length; i += 2) {".to_string(),
+        format!("{}switch (args[i]) {{", INDENT),
+        format!("{}case \"--tape\":", INDENT.repeat(2)),
+        format!(
+          "{}tapeSize = Integer.parseInt(args[i + 1]);",
+          INDENT.repeat(3)
+        ),
+        format!("{}break;", INDENT.repeat(3)),
+        format!("{}case \"--in\":", INDENT.repeat(2)),
+        format!(
+          "{}input = new FileInputStream(args[i + 1]);",
+          INDENT.repeat(3)
+        ),
+        format!("{}break;", INDENT.repeat(3)),
+        format!("{}case \"--out\":", INDENT.repeat(2)),
+        format!(
+          "{}output = new FileOutputStream(args[i + 1]);",
+          INDENT.repeat(3)
+        ),
+        format!("{}break;", INDENT.repeat(3)),
+        format!("{}default:", INDENT.repeat(2)),
+        format!(
+          "{}throw new IllegalArgumentException(\"unknown argument \" + args[i]);",
+          INDENT.repeat(3)
+        ),
+        format!("{}}}", INDENT),
+        "}".to_string(),
+        exit_with("run(input, output, tapeSize)", config),
+      ];
+      lines.extend(
+        arguments
+          .iter()
+          .map(|line| format!("{}{}", INDENT.repeat(2), line)),
+      );
+    } else {
+      lines.push(format!(
+        "{}{}",
+        INDENT.repeat(2),
+        exit_with(&format!("run(System.in, System.out, {})", size), config)
+      ));
+    }
+    lines.extend([
+      format!("{}}}", INDENT),
+      String::new(),
+      format!(
+        "{}public static void run(InputStream input, OutputStream output) throws IOException {{",
+        INDENT
+      ),
+      format!(
+        "{}run(input, output, {});",
+        INDENT.repeat(2),
+        config.tape_size
+      ),
+      format!("{}}}", INDENT),
+      String::new(),
+      format!(
+        "{}public static int run(InputStream input, OutputStream output, int tapeSize)",
+        INDENT
+      ),
+      format!("{}throws IOException {{", INDENT.repeat(3)),
+    ]);
+    ("tapeSize".to_string(), "output", "input")
+  } else {
+    lines.push(format!(
+      "{}public static void main(String[] args) throws IOException {{",
+      INDENT
+    ));
+    (size, "System.out", "System.in")
+  };
+  let mut body = vec![
+    (0, format!("{}[] tape = new {}[{}];", kind, kind, tape)),
+    (0, "int p = 0;".to_string()),
+    (
+      0,
+      if config.buffered {
+        format!(
+          "PrintStream out = new PrintStream(new BufferedOutputStream({}, 65536), false, \"{}\");",
+          output, charset
+        )
+      } else {
+        format!(
+          "PrintStream out = new PrintStream({}, false, \"{}\");",
+          output, charset
+        )
+      },
+    ),
+    (
+      0,
+      format!("InputStream in = new BufferedInputStream({});", input),
+    ),
+  ];
+  if config.eof != Eof::MinusOne
+    && instructions
+      .iter()
+      .any(|inst| matches!(inst.op, Op::ReadChar(_)))
+  {
+    body.push((0, "int c;".to_string()));
+  }
+  let mut chunks = Chunks {
+    instructions,
+    config,
+    kind,
+    before: vec![0],
+    methods: Vec::new(),
+    chunks: 0,
+  };
+  let mut index = 0;
+  while index < instructions.len() {
+    let (statements, count) = statements(instructions, index, config);
+    let before = chunks.before[index] + statements.len();
+    chunks.before.resize(index + count + 1, before);
+    index += count;
+  }
+  body.extend(chunks.split(0..instructions.len(), config.method_size));
+  lines.extend(
+    body
+      .into_iter()
+      .map(|(depth, line)| format!("{}{}", INDENT.repeat(depth + 2), line)),
+  );
+  if config.buffered {
+    lines.push(format!("{}out.flush();", INDENT.repeat(2)));
+  }
+  if config.has_run() {
+    lines.push(format!(
+      "{}return {};",
+      INDENT.repeat(2),
+      exit_value(config)
+    ));
+  } else if config.exit_cell.is_some() {
+    lines.push(format!(
+      "{}System.exit({});",
+      INDENT.repeat(2),
+      exit_value(config)
+    ));
+  }
+  lines.push(format!("{}}}", INDENT));
+  lines.extend(chunks.methods);
+  lines.push("}".to_string());
+  lines.push(String::new());
+  Ok(lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::jasmin::Config;
+  use super::super::{lex_dialect, parse_program, Dialect};
+  use super::{produce_java, STATEMENT_SIZE};
+
+  #[test]
+  fn large_programs_split_into_methods_within_the_size() {
+    let depth = 100;
+    let code = format!(
+      "+{}>+<[-]{}{}",
+      "[".repeat(depth),
+      "]".repeat(depth),
+      ">,.<".repeat(200)
+    );
+    let program = lex_dialect(&code, Dialect::Brainfuck)
+      .and_then(parse_program)
+      .unwrap();
+    let config = Config {
+      method_size: 20 * STATEMENT_SIZE,
+      ..Config::default()
+    };
+    let java = produce_java(&program, &config).unwrap();
+    let chunks: Vec<&str> = java
+      .split("private static int chunk")
+      .skip(1)
+      .map(|method| &method[..method.find("return p;").unwrap()])
+      .collect();
+    assert!(chunks.len() > 10);
+    for chunk in chunks {
+      let statements = chunk
+        .lines()
+        .skip(2)
+        .filter(|line| !matches!(line.trim(), "" | "int c;"))
+        .count();
+      assert!(
+        statements * STATEMENT_SIZE <= config.method_size,
+        "{}",
+        chunk
+      );
+    }
+  }
+}
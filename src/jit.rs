@@ -0,0 +1,130 @@
+//! Native execution of the folded IR through Cranelift, with `.` and `,`
+//! calling back into Rust.
+
+use std::io;
+use std::io::prelude::*;
+
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Linkage};
+
+use super::cranelift::{self, IO_FAILED, OFF_TAPE, OK};
+use super::interpreter::{Eof, Io};
+use super::{reject_extensions, Inst};
+
+struct IoContext<'a> {
+  input: &'a mut dyn Read,
+  output: &'a mut dyn Write,
+  eof: Eof,
+  io: Io,
+  error: Option<io::Error>,
+}
+
+extern "C" fn bf_putchar(ctx: *mut IoContext, byte: u8) -> i32 {
+  let ctx = unsafe { &mut *ctx };
+  let written = match ctx.io {
+    Io::Bytes => ctx.output.write_all(&[byte]),
+    _ => write!(ctx.output, "{}", byte as char),
+  };
+  match written {
+    Ok(()) => OK,
+    Err(e) => {
+      ctx.error = Some(e);
+      IO_FAILED
+    }
+  }
+}
+
+extern "C" fn bf_print(ctx: *mut IoContext, text: *const u8, len: usize) -> i32 {
+  let ctx = unsafe { &mut *ctx };
+  let bytes = unsafe { std::slice::from_raw_parts(text, len) };
+  let written = match ctx.io {
+    Io::Bytes => ctx.output.write_all(bytes),
+    _ => {
+      let text: String = bytes.iter().map(|&byte| byte as char).collect();
+      ctx.output.write_all(text.as_bytes())
+    }
+  };
+  match written {
+    Ok(()) => OK,
+    Err(e) => {
+      ctx.error = Some(e);
+      IO_FAILED
+    }
+  }
+}
+
+extern "C" fn bf_getchar(ctx: *mut IoContext, cell: *mut u8) -> i32 {
+  let ctx = unsafe { &mut *ctx };
+  let cell = unsafe { &mut *cell };
+  let result = ctx
+    .output
+    .flush()
+    .and_then(|_| ctx.eof.read(&mut *ctx.input, cell));
+  match result {
+    Ok(()) => OK,
+    Err(e) => {
+      ctx.error = Some(e);
+      IO_FAILED
+    }
+  }
+}
+
+fn jit_error<E: std::fmt::Display>(error: E) -> io::Error {
+  io::Error::other(format!("JIT compilation failed: {}", error))
+}
+
+type Compiled = unsafe extern "C" fn(*mut u8, *mut IoContext, *mut usize) -> i32;
+
+/// Compiles `program` to native code and runs it against a fresh tape of
+/// `tape_size` bytes, printing bytes or characters as `io` says, returning
+/// the tape and the pointer it finished at.
+pub fn run(
+  program: &[Inst],
+  eof: Eof,
+  io: Io,
+  tape_size: usize,
+  input: &mut dyn Read,
+  output: &mut dyn Write,
+) -> io::Result<(Vec<u8>, usize)> {
+  reject_extensions(program).map_err(io::Error::other)?;
+  let mut flag_builder = settings::builder();
+  flag_builder
+    .set("use_colocated_libcalls", "false")
+    .map_err(jit_error)?;
+  flag_builder.set("is_pic", "false").map_err(jit_error)?;
+  flag_builder.set("opt_level", "speed").map_err(jit_error)?;
+  let isa = cranelift_native::builder()
+    .map_err(jit_error)?
+    .finish(settings::Flags::new(flag_builder))
+    .map_err(jit_error)?;
+  let mut jit_builder = JITBuilder::with_isa(isa, default_libcall_names());
+  jit_builder.symbol("bf_putchar", bf_putchar as *const u8);
+  jit_builder.symbol("bf_getchar", bf_getchar as *const u8);
+  jit_builder.symbol("bf_print", bf_print as *const u8);
+  let mut module = JITModule::new(jit_builder);
+  let runtime = cranelift::declare_runtime(&mut module, Linkage::Import).map_err(jit_error)?;
+  let main =
+    cranelift::define_main(&mut module, &runtime, program, tape_size).map_err(jit_error)?;
+  module.finalize_definitions().map_err(jit_error)?;
+
+  let code = module.get_finalized_function(main);
+  let compiled = unsafe { std::mem::transmute::<*const u8, Compiled>(code) };
+  let mut tape = vec![0u8; tape_size];
+  let mut io_ctx = IoContext {
+    input: &mut *input,
+    output: &mut *output,
+    eof,
+    io,
+    error: None,
+  };
+  let mut ptr = 0;
+  let status = unsafe { compiled(tape.as_mut_ptr(), &mut io_ctx, &mut ptr) };
+  let error = io_ctx.error.take();
+  unsafe { module.free_memory() };
+  match status {
+    OK => output.flush().map(|_| (tape, ptr)),
+    OFF_TAPE => Err(io::Error::other("pointer moved off the tape")),
+    _ => Err(error.unwrap_or_else(|| io::Error::other("I/O failed"))),
+  }
+}
@@ -0,0 +1,326 @@
+//! JavaScript: a Node script reading standard input and writing standard
+//! output, or an ES module exporting `run(input)`, which takes the input as
+//! a string of byte-valued characters and returns the output the same way.
+//! The script writes characters in UTF-8, unless `--io bytes` asks for the
+//! bytes as they are. Typed arrays read `undefined` and ignore writes
+//! outside them, unless `--bounds error` checks every move: the script then
+//! exits with status 1 and the module throws a `RangeError`.
+
+use super::interpreter::{Bounds, Eof, ExitCell};
+use super::jasmin::Config;
+use super::{reject_extensions, writes_bytes, Inst, Op};
+
+const INDENT: &str = "  ";
+
+/// The cell at `offset` from the pointer.
+fn cell(offset: i32) -> String {
+  match offset {
+    0 => "tape[p]".to_string(),
+    _ if offset > 0 => format!("tape[p + {}]", offset),
+    _ => format!("tape[p - {}]", offset.unsigned_abs()),
+  }
+}
+
+/// `+=` or `-=` with a positive operand.
+fn compound(target: &str, amount: i64) -> String {
+  if amount < 0 {
+    format!("{} -= {};", target, amount.unsigned_abs())
+  } else {
+    format!("{} += {};", target, amount)
+  }
+}
+
+/// Quotes `bytes` as a string literal holding one character per byte.
+fn quote(bytes: &[u8]) -> String {
+  let mut quoted = String::from("\"");
+  for &byte in bytes {
+    match byte {
+      b'"' => quoted.push_str("\\\""),
+      b'\\' => quoted.push_str("\\\\"),
+      b'\n' => quoted.push_str("\\n"),
+      b' '..=b'~' => quoted.push(byte as char),
+      _ => quoted.push_str(&format!("\\x{:02x}", byte)),
+    }
+  }
+  quoted.push('"');
+  quoted
+}
+
+/// The statement calling `offTape()` when the cell at `offset` from the
+/// pointer is off the tape, under `Bounds::Error`.
+fn check(offset: i32, config: &Config) -> Option<String> {
+  if config.bounds == Bounds::Unchecked {
+    return None;
+  }
+  let index = match offset {
+    0 => "p".to_string(),
+    _ if offset > 0 => format!("p + {}", offset),
+    _ => format!("p - {}", offset.unsigned_abs()),
+  };
+  Some(format!(
+    "if ({} < 0 || {} >= {}) offTape();",
+    index, index, config.tape_size
+  ))
+}
+
+/// Statements reading one byte into the current cell.
+fn read(config: &Config) -> Vec<String> {
+  match config.eof {
+    Eof::Unchanged => vec![
+      "c = read();".to_string(),
+      "if (c >= 0) tape[p] = c;".to_string(),
+    ],
+    Eof::Zero => vec![
+      "c = read();".to_string(),
+      "tape[p] = c < 0 ? 0 : c;".to_string(),
+    ],
+    Eof::MinusOne => vec!["tape[p] = read();".to_string()],
+  }
+}
+
+/// The statements running `instructions`, with `read`, `write` and `print`
+/// already defined.
+fn body(instructions: &[Inst], config: &Config) -> Vec<String> {
+  let kind = if config.wrap {
+    "Uint8Array"
+  } else {
+    "Int32Array"
+  };
+  let mut body = vec![
+    format!("const tape = new {}({});", kind, config.tape_size),
+    "let p = 0;".to_string(),
+  ];
+  if config.eof != Eof::MinusOne
+    && instructions
+      .iter()
+      .any(|inst| matches!(inst.op, Op::ReadChar(_)))
+  {
+    body.push("let c;".to_string());
+  }
+  let mut depth = 0;
+  let mut index = 0;
+  while index < instructions.len() {
+    let mut emit = |line: String| body.push(format!("{}{}", INDENT.repeat(depth), line));
+    match instructions[index].op {
+      Op::Procedure(_)
+      | Op::Return(_)
+      | Op::Call
+      | Op::Fork
+      | Op::Ebf(_)
+      | Op::Debug
+      | Op::PutNumber
+      | Op::ReadNumber
+      | Op::Random
+      | Op::Tape(_) => {
+        unreachable!("rejected by reject_extensions")
+      }
+      Op::Plus(count) => emit(compound("tape[p]", count as i64)),
+      Op::Minus(count) => emit(compound("tape[p]", -(count as i64))),
+      Op::Right(count) => {
+        emit(compound("p", count as i64));
+        check(0, config).into_iter().for_each(emit);
+      }
+      Op::Left(count) => {
+        emit(compound("p", -(count as i64)));
+        check(0, config).into_iter().for_each(emit);
+      }
+      Op::PutChar(count) => {
+        let byte = if config.wrap {
+          "tape[p]"
+        } else {
+          "tape[p] & 255"
+        };
+        for _ in 0..count {
+          emit(format!("write({});", byte));
+        }
+      }
+      Op::ReadChar(count) => {
+        for _ in 0..count {
+          read(config).into_iter().for_each(&mut emit);
+        }
+      }
+      Op::JumpIfZero(_) => {
+        emit("while (tape[p] !== 0) {".to_string());
+        depth += 1;
+      }
+      Op::JumpIfNonZero(_) => {
+        depth -= 1;
+        body.push(format!("{}}}", INDENT.repeat(depth)));
+      }
+      Op::SetZero => emit("tape[p] = 0;".to_string()),
+      Op::AddTo { .. } => {
+        // Typed arrays ignore writes outside them, but the guard keeps the
+        // offset cells untouched when the loop would not have run anyway.
+        let targets: Vec<(i32, i32)> = instructions[index..]
+          .iter()
+          .map_while(|inst| match inst.op {
+            Op::AddTo { offset, factor } => Some((offset, factor)),
+            _ => None,
+          })
+          .collect();
+        emit("if (tape[p] !== 0) {".to_string());
+        emit(format!("{}const n = tape[p];", INDENT));
+        for (offset, factor) in &targets {
+          let product = match factor.unsigned_abs() {
+            1 => "n".to_string(),
+            magnitude => format!("n * {}", magnitude),
+          };
+          let operator = if *factor < 0 { '-' } else { '+' };
+          if let Some(check) = check(*offset, config) {
+            emit(format!("{}{}", INDENT, check));
+          }
+          emit(format!(
+            "{}{} {}= {};",
+            INDENT,
+            cell(*offset),
+            operator,
+            product
+          ));
+        }
+        emit("}".to_string());
+        index += targets.len() - 1;
+      }
+      Op::Add { offset, amount } => {
+        if offset != 0 {
+          check(offset, config).into_iter().for_each(&mut emit);
+        }
+        emit(compound(&cell(offset), amount as i64))
+      }
+      Op::Set { offset, value } => {
+        if offset != 0 {
+          check(offset, config).into_iter().for_each(&mut emit);
+        }
+        let value = if config.wrap { value & 255 } else { value };
+        emit(format!("{} = {};", cell(offset), value));
+      }
+      Op::ScanZero { stride } => match check(0, config) {
+        Some(check) => emit(format!(
+          "while (tape[p] !== 0) {{ {} {} }}",
+          compound("p", stride as i64),
+          check
+        )),
+        None => emit(format!(
+          "while (tape[p] !== 0) {}",
+          compound("p", stride as i64)
+        )),
+      },
+      Op::PutConst { value, count } => {
+        emit(format!("print({});", quote(&vec![value; count as usize])))
+      }
+      Op::Print(ref text) => emit(format!("print({});", quote(text.as_bytes()))),
+    }
+    index += 1;
+  }
+  body
+}
+
+/// Generates a Node script, or with `module` an ES module, for
+/// `instructions`.
+pub fn produce_js(instructions: &[Inst], config: &Config, module: bool) -> Result<String, String> {
+  reject_extensions(instructions)?;
+  if config.tape_from_args || config.embeddable || config.runtime_args {
+    return Err(
+      "--tape-from-args, --embeddable and --runtime-args only apply to JVM output".to_string(),
+    );
+  }
+  if module && config.exit_cell.is_some() {
+    return Err("--exit-from-cell needs a script, not --js-module".to_string());
+  }
+  let bytes = writes_bytes(config.io)?;
+  let mut lines = Vec::new();
+  if let Some(debug) = &config.debug {
+    lines.push(format!("// Compiled from {}", debug.file));
+  }
+  let body = body(instructions, config);
+  let print = [
+    "const print = (text) => {".to_string(),
+    format!(
+      "{}for (let i = 0; i < text.length; i++) write(text.charCodeAt(i));",
+      INDENT
+    ),
+    "};".to_string(),
+  ];
+  if module {
+    lines.push("export function run(input) {".to_string());
+    let mut run = vec![
+      "let inputAt = 0;".to_string(),
+      "let output = \"\";".to_string(),
+      "const read = () =>".to_string(),
+      format!(
+        "{}inputAt < input.length ? input.charCodeAt(inputAt++) & 255 : -1;",
+        INDENT
+      ),
+      "const write = (byte) => {".to_string(),
+      format!("{}output += String.fromCharCode(byte);", INDENT),
+      "};".to_string(),
+    ];
+    run.extend(print);
+    if config.bounds == Bounds::Error {
+      run.extend([
+        "const offTape = () => {".to_string(),
+        format!(
+          "{}throw new RangeError(\"pointer moved off the tape\");",
+          INDENT
+        ),
+        "};".to_string(),
+      ]);
+    }
+    run.extend(body);
+    run.push("return output;".to_string());
+    lines.extend(run.iter().map(|line| format!("{}{}", INDENT, line)));
+    lines.push("}".to_string());
+  } else {
+    lines.extend([
+      "\"use strict\";".to_string(),
+      "const fs = require(\"fs\");".to_string(),
+      String::new(),
+      "const input = fs.readFileSync(0);".to_string(),
+      "let inputAt = 0;".to_string(),
+      "const read = () => (inputAt < input.length ? input[inputAt++] : -1);".to_string(),
+      "const output = [];".to_string(),
+      "const flush = () => {".to_string(),
+      format!("{}fs.writeSync(1, Uint8Array.from(output));", INDENT),
+      format!("{}output.length = 0;", INDENT),
+      "};".to_string(),
+      "const write = (byte) => {".to_string(),
+    ]);
+    if bytes {
+      lines.push(format!("{}output.push(byte);", INDENT));
+    } else {
+      lines.extend([
+        format!("{}if (byte < 128) output.push(byte);", INDENT),
+        format!(
+          "{}else output.push(0xc0 | (byte >> 6), 0x80 | (byte & 0x3f));",
+          INDENT
+        ),
+      ]);
+    }
+    lines.extend([
+      format!("{}if (output.length >= 65536) flush();", INDENT),
+      "};".to_string(),
+    ]);
+    lines.extend(print);
+    if config.bounds == Bounds::Error {
+      lines.extend([
+        "const offTape = () => {".to_string(),
+        format!("{}flush();", INDENT),
+        format!(
+          "{}process.stderr.write(\"pointer moved off the tape\\n\");",
+          INDENT
+        ),
+        format!("{}process.exit(1);", INDENT),
+        "};".to_string(),
+      ]);
+    }
+    lines.push(String::new());
+    lines.extend(body);
+    lines.push("flush();".to_string());
+    match config.exit_cell {
+      None => (),
+      Some(ExitCell::Current) => lines.push("process.exitCode = tape[p];".to_string()),
+      Some(ExitCell::First) => lines.push("process.exitCode = tape[0];".to_string()),
+    }
+  }
+  lines.push(String::new());
+  Ok(lines.join("\n"))
+}
@@ -0,0 +1,353 @@
+//! The JSON that `lsp` and `dap` speak and `--stats-json` writes: a value
+//! type, a parser for what clients send and rendering for what goes back,
+//! and the framing both protocols put around each message.
+
+use std::fmt;
+use std::io::{self, BufRead, ErrorKind, Write};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+  Null,
+  Bool(bool),
+  Number(f64),
+  String(String),
+  Array(Vec<Json>),
+  /// Members in the order they were written.
+  Object(Vec<(String, Json)>),
+}
+
+impl Json {
+  /// An object with `members`.
+  pub fn object(members: Vec<(&str, Json)>) -> Json {
+    Json::Object(
+      members
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect(),
+    )
+  }
+
+  /// The member `name` of an object, or `Null` when there is none.
+  pub fn get(&self, name: &str) -> &Json {
+    match self {
+      Json::Object(members) => members
+        .iter()
+        .find(|(member, _)| member == name)
+        .map_or(&Json::Null, |(_, value)| value),
+      _ => &Json::Null,
+    }
+  }
+
+  pub fn as_str(&self) -> Option<&str> {
+    match self {
+      Json::String(text) => Some(text),
+      _ => None,
+    }
+  }
+
+  pub fn as_u64(&self) -> Option<u64> {
+    match *self {
+      Json::Number(number) if number >= 0.0 && number.fract() == 0.0 => Some(number as u64),
+      _ => None,
+    }
+  }
+
+  pub fn as_bool(&self) -> Option<bool> {
+    match *self {
+      Json::Bool(value) => Some(value),
+      _ => None,
+    }
+  }
+
+  pub fn as_array(&self) -> &[Json] {
+    match self {
+      Json::Array(items) => items,
+      _ => &[],
+    }
+  }
+}
+
+impl From<&str> for Json {
+  fn from(text: &str) -> Json {
+    Json::String(text.to_string())
+  }
+}
+
+impl From<String> for Json {
+  fn from(text: String) -> Json {
+    Json::String(text)
+  }
+}
+
+impl From<usize> for Json {
+  fn from(number: usize) -> Json {
+    Json::Number(number as f64)
+  }
+}
+
+impl From<bool> for Json {
+  fn from(value: bool) -> Json {
+    Json::Bool(value)
+  }
+}
+
+impl fmt::Display for Json {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Json::Null => write!(f, "null"),
+      Json::Bool(value) => write!(f, "{}", value),
+      Json::Number(number) if number.fract() == 0.0 && number.abs() < 1e15 => {
+        write!(f, "{}", *number as i64)
+      }
+      Json::Number(number) => write!(f, "{}", number),
+      Json::String(text) => write!(f, "{}", quote(text)),
+      Json::Array(items) => {
+        write!(f, "[")?;
+        for (n, item) in items.iter().enumerate() {
+          if n > 0 {
+            write!(f, ",")?;
+          }
+          write!(f, "{}", item)?;
+        }
+        write!(f, "]")
+      }
+      Json::Object(members) => {
+        write!(f, "{{")?;
+        for (n, (name, value)) in members.iter().enumerate() {
+          if n > 0 {
+            write!(f, ",")?;
+          }
+          write!(f, "{}:{}", quote(name), value)?;
+        }
+        write!(f, "}}")
+      }
+    }
+  }
+}
+
+/// Renders `text` as a JSON string literal.
+pub fn quote(text: &str) -> String {
+  let mut quoted = String::from("\"");
+  for c in text.chars() {
+    match c {
+      '"' => quoted.push_str("\\\""),
+      '\\' => quoted.push_str("\\\\"),
+      '\n' => quoted.push_str("\\n"),
+      c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+      c => quoted.push(c),
+    }
+  }
+  quoted.push('"');
+  quoted
+}
+
+struct Parser<'a> {
+  text: &'a str,
+  at: usize,
+}
+
+impl Parser<'_> {
+  fn error<T>(&self, what: &str) -> Result<T, String> {
+    Err(format!("{} at byte {} of JSON", what, self.at))
+  }
+
+  fn skip_space(&mut self) {
+    let rest = &self.text[self.at..];
+    self.at += rest.len() - rest.trim_start_matches([' ', '\t', '\n', '\r']).len();
+  }
+
+  fn peek(&self) -> Option<u8> {
+    self.text.as_bytes().get(self.at).copied()
+  }
+
+  /// Consumes `byte` after any space, or fails.
+  fn expect(&mut self, byte: u8) -> Result<(), String> {
+    self.skip_space();
+    if self.peek() != Some(byte) {
+      return self.error(&format!("expected {}", byte as char));
+    }
+    self.at += 1;
+    Ok(())
+  }
+
+  fn value(&mut self) -> Result<Json, String> {
+    self.skip_space();
+    match self.peek() {
+      Some(b'{') => {
+        self.at += 1;
+        let mut members = Vec::new();
+        self.skip_space();
+        if self.peek() == Some(b'}') {
+          self.at += 1;
+          return Ok(Json::Object(members));
+        }
+        loop {
+          self.skip_space();
+          let name = self.string()?;
+          self.expect(b':')?;
+          members.push((name, self.value()?));
+          self.skip_space();
+          match self.peek() {
+            Some(b',') => self.at += 1,
+            Some(b'}') => {
+              self.at += 1;
+              return Ok(Json::Object(members));
+            }
+            _ => return self.error("expected , or }"),
+          }
+        }
+      }
+      Some(b'[') => {
+        self.at += 1;
+        let mut items = Vec::new();
+        self.skip_space();
+        if self.peek() == Some(b']') {
+          self.at += 1;
+          return Ok(Json::Array(items));
+        }
+        loop {
+          items.push(self.value()?);
+          self.skip_space();
+          match self.peek() {
+            Some(b',') => self.at += 1,
+            Some(b']') => {
+              self.at += 1;
+              return Ok(Json::Array(items));
+            }
+            _ => return self.error("expected , or ]"),
+          }
+        }
+      }
+      Some(b'"') => Ok(Json::String(self.string()?)),
+      Some(b'-' | b'0'..=b'9') => {
+        let start = self.at;
+        while let Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') = self.peek() {
+          self.at += 1;
+        }
+        match self.text[start..self.at].parse() {
+          Ok(number) => Ok(Json::Number(number)),
+          Err(_) => self.error("malformed number"),
+        }
+      }
+      _ => {
+        for (word, value) in [
+          ("null", Json::Null),
+          ("true", Json::Bool(true)),
+          ("false", Json::Bool(false)),
+        ] {
+          if self.text[self.at..].starts_with(word) {
+            self.at += word.len();
+            return Ok(value);
+          }
+        }
+        self.error("expected a value")
+      }
+    }
+  }
+
+  fn string(&mut self) -> Result<String, String> {
+    if self.peek() != Some(b'"') {
+      return self.error("expected a string");
+    }
+    self.at += 1;
+    let mut text = String::new();
+    loop {
+      let Some(c) = self.text[self.at..].chars().next() else {
+        return self.error("unterminated string");
+      };
+      self.at += c.len_utf8();
+      match c {
+        '"' => return Ok(text),
+        '\\' => {
+          let escape = self.peek();
+          self.at += 1;
+          match escape {
+            Some(b'"') => text.push('"'),
+            Some(b'\\') => text.push('\\'),
+            Some(b'/') => text.push('/'),
+            Some(b'b') => text.push('\u{8}'),
+            Some(b'f') => text.push('\u{c}'),
+            Some(b'n') => text.push('\n'),
+            Some(b'r') => text.push('\r'),
+            Some(b't') => text.push('\t'),
+            Some(b'u') => {
+              let unit = self.unit()?;
+              // A surrogate pair spells one character as two escapes.
+              let code =
+                if (0xd800..0xdc00).contains(&unit) && self.text[self.at..].starts_with("\\u") {
+                  self.at += 2;
+                  let low = self.unit()?;
+                  0x10000 + ((unit - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff)
+                } else {
+                  unit
+                };
+              text.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+            }
+            _ => return self.error("unknown escape"),
+          }
+        }
+        c => text.push(c),
+      }
+    }
+  }
+
+  /// The four hex digits of a `\u` escape.
+  fn unit(&mut self) -> Result<u32, String> {
+    let digits = self.text.get(self.at..self.at + 4).unwrap_or("");
+    match u32::from_str_radix(digits, 16) {
+      Ok(unit) => {
+        self.at += 4;
+        Ok(unit)
+      }
+      Err(_) => self.error("malformed \\u escape"),
+    }
+  }
+}
+
+/// Parses `text` as one JSON value.
+pub fn parse(text: &str) -> Result<Json, String> {
+  let mut parser = Parser { text, at: 0 };
+  let value = parser.value()?;
+  parser.skip_space();
+  if parser.at != text.len() {
+    return parser.error("trailing characters");
+  }
+  Ok(value)
+}
+
+/// Reads one message, framed by a `Content-Length` header, or `None` at
+/// the end of `input`.
+pub fn read_message(input: &mut dyn BufRead) -> io::Result<Option<Json>> {
+  let mut length = None;
+  loop {
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+      return Ok(None);
+    }
+    let line = line.trim_end();
+    if line.is_empty() {
+      break;
+    }
+    if let Some((name, value)) = line.split_once(':') {
+      if name.eq_ignore_ascii_case("Content-Length") {
+        length = value.trim().parse().ok();
+      }
+    }
+  }
+  let length: usize = length
+    .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "message without Content-Length"))?;
+  let mut body = vec![0; length];
+  input.read_exact(&mut body)?;
+  let body =
+    String::from_utf8(body).map_err(|error| io::Error::new(ErrorKind::InvalidData, error))?;
+  parse(&body)
+    .map(Some)
+    .map_err(|error| io::Error::new(ErrorKind::InvalidData, error))
+}
+
+/// Writes `message` framed by its `Content-Length`.
+pub fn write_message(output: &mut dyn Write, message: &Json) -> io::Result<()> {
+  let body = message.to_string();
+  write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+  output.flush()
+}
@@ -0,0 +1,223 @@
+//! Krakatau assembler syntax, translated from the Jasmin the JVM backend
+//! generates.
+//!
+//! The two differ mostly in directives: methods are declared as
+//! `name : descriptor` and hold a `.code stack N locals M` block, member
+//! references name the class, member and descriptor separately, labels start
+//! with `L`, and line numbers live in a `.linenumbertable` instead of inline
+//! `.line` directives. The source file is a `.sourcefile` attribute, which
+//! follows `.super` like the members, and the class ends with `.end class`.
+//! Without a `.version` directive Krakatau writes version 49, which needs no
+//! stack map frames.
+
+/// The assembler the `.j` output is written for.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Dialect {
+  Jasmin,
+  Krakatau,
+}
+
+impl Dialect {
+  pub fn parse(text: &str) -> Result<Dialect, String> {
+    match text {
+      "jasmin" => Ok(Dialect::Jasmin),
+      "krakatau" => Ok(Dialect::Krakatau),
+      other => Err(format!("unknown assembler dialect {}", other)),
+    }
+  }
+}
+
+/// Splits `owner/name` into the class and the member name.
+fn member(path: &str) -> Result<(&str, &str), String> {
+  path
+    .rsplit_once('/')
+    .ok_or_else(|| format!("{} has no owning class", path))
+}
+
+/// Rewrites one instruction, prefixing labels and spelling out references.
+fn instruction(word: &str, args: &[&str]) -> Result<String, String> {
+  let word = if word == "invokenonvirtual" {
+    "invokespecial"
+  } else {
+    word
+  };
+  Ok(match (word, args) {
+    ("invokevirtual" | "invokespecial" | "invokestatic", [signature]) => {
+      let split = signature
+        .find('(')
+        .ok_or_else(|| format!("method {} has no descriptor", signature))?;
+      let (class, name) = member(&signature[..split])?;
+      format!("{} Method {} {} {}", word, class, name, &signature[split..])
+    }
+    ("getstatic" | "putstatic" | "getfield" | "putfield", [path, descriptor]) => {
+      let (class, name) = member(path)?;
+      format!("{} Field {} {} {}", word, class, name, descriptor)
+    }
+    _ if word == "goto" || word.starts_with("if") => format!("{} L{}", word, args.join(" ")),
+    _ if args.is_empty() => word.to_string(),
+    _ => format!("{} {}", word, args.join(" ")),
+  })
+}
+
+/// Translates generated Jasmin `source` into Krakatau syntax.
+pub fn from_jasmin(source: &str) -> Result<String, String> {
+  let mut out = Vec::new();
+  let mut source_file = None;
+  let mut lines = source.lines();
+  while let Some(line) = lines.next() {
+    let text = line.trim();
+    let (word, rest) = match text.find(char::is_whitespace) {
+      Some(split) => (&text[..split], text[split..].trim()),
+      None => (text, ""),
+    };
+    match word {
+      ".source" => source_file = Some(format!(".sourcefile \"{}\"", rest)),
+      ".super" => {
+        out.push(line.to_string());
+        out.extend(source_file.take());
+      }
+      ".method" => {
+        let (flags, signature) = rest.rsplit_once(' ').unwrap_or(("", rest));
+        let split = signature
+          .find('(')
+          .ok_or_else(|| format!("method {} has no descriptor", signature))?;
+        out.push(
+          format!(
+            ".method {} {} : {}",
+            flags,
+            &signature[..split],
+            &signature[split..]
+          )
+          .replace("  ", " "),
+        );
+        let mut stack = "1";
+        let mut locals = "1";
+        let mut code = Vec::new();
+        let mut line_numbers = Vec::new();
+        for line in lines.by_ref() {
+          let text = line.trim();
+          if text == ".end method" {
+            break;
+          }
+          let words: Vec<&str> = text.split_whitespace().collect();
+          match words[..] {
+            [] => (),
+            [comment, ..] if comment.starts_with(';') => (),
+            [".limit", "stack", n] => stack = n,
+            [".limit", "locals", n] => locals = n,
+            [".line", n] => {
+              let label = format!("Lline{}", line_numbers.len());
+              code.push(format!("{}:", label));
+              line_numbers.push(format!("{} {}", label, n));
+            }
+            [label] if label.ends_with(':') => code.push(format!("L{}", label)),
+            // String constants keep their spacing.
+            ["ldc", ..] => code.push(format!("    {}", text)),
+            [word, ref args @ ..] => code.push(format!("    {}", instruction(word, args)?)),
+          }
+        }
+        out.push(format!("    .code stack {} locals {}", stack, locals));
+        out.extend(code);
+        if !line_numbers.is_empty() {
+          out.push("    .linenumbertable".to_string());
+          out.extend(
+            line_numbers
+              .iter()
+              .map(|entry| format!("        {}", entry)),
+          );
+          out.push("    .end linenumbertable".to_string());
+        }
+        out.push("    .end code".to_string());
+        out.push(".end method".to_string());
+      }
+      _ => out.push(line.to_string()),
+    }
+  }
+  out.push(".end class".to_string());
+  out.push(String::new());
+  Ok(out.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::from_jasmin;
+
+  /// A class as the JVM backend writes it, with a loop, a line number, a
+  /// field and a string constant.
+  const JASMIN: &str = "\
+.source hello.bf
+.class public Main
+.super java/lang/Object
+.field public static random Ljava/util/Random;
+
+.method public <init>()V
+    .limit stack 1
+    .limit locals 1
+    aload_0
+    invokenonvirtual java/lang/Object/<init>()V
+    return
+.end method
+
+.method  public static main([Ljava/lang/String;)V
+    .limit stack 3
+    .limit locals 2
+    ; the pointer
+    iconst_0
+    istore_1
+    .line 1
+loop1Start:
+    iload_1
+    ifeq loop1End
+    getstatic java/lang/System/out Ljava/io/PrintStream;
+    ldc \"a  b\"
+    invokevirtual java/io/PrintStream/print(Ljava/lang/String;)V
+    iinc 1 -1
+    goto loop1Start
+loop1End:
+    return
+.end method
+";
+
+  /// The same class as the Krakatau assembler takes it.
+  const KRAKATAU: &str = "\
+.class public Main
+.super java/lang/Object
+.sourcefile \"hello.bf\"
+.field public static random Ljava/util/Random;
+
+.method public <init> : ()V
+    .code stack 1 locals 1
+    aload_0
+    invokespecial Method java/lang/Object <init> ()V
+    return
+    .end code
+.end method
+
+.method public static main : ([Ljava/lang/String;)V
+    .code stack 3 locals 2
+    iconst_0
+    istore_1
+Lline0:
+Lloop1Start:
+    iload_1
+    ifeq Lloop1End
+    getstatic Field java/lang/System out Ljava/io/PrintStream;
+    ldc \"a  b\"
+    invokevirtual Method java/io/PrintStream print (Ljava/lang/String;)V
+    iinc 1 -1
+    goto Lloop1Start
+Lloop1End:
+    return
+    .linenumbertable
+        Lline0 1
+    .end linenumbertable
+    .end code
+.end method
+.end class
+";
+
+  #[test]
+  fn jasmin_becomes_krakatau_syntax() {
+    assert_eq!(from_jasmin(JASMIN).unwrap(), KRAKATAU);
+  }
+}
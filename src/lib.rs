@@ -0,0 +1,762 @@
+//! A Brainfuck compiler: lexing and folding into an IR, optimization
+//! passes over it, an interpreter and code generators.
+
+pub mod aarch64;
+pub mod analyze;
+pub mod backend;
+pub mod bench;
+pub mod bf;
+pub mod brainbool;
+pub mod c;
+pub mod cache;
+pub mod cfg;
+pub mod classfile;
+pub mod constants;
+pub mod coverage;
+#[cfg(feature = "cranelift")]
+mod cranelift;
+pub mod dap;
+pub mod dex;
+pub mod evaluate;
+#[cfg(feature = "exe")]
+pub mod exe;
+pub mod format;
+pub mod fuzz;
+pub mod golden;
+pub mod heatmap;
+pub mod inspect;
+pub mod interpreter;
+pub mod jar;
+pub mod jasmin;
+pub mod java;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod js;
+pub mod json;
+pub mod krakatau;
+pub mod limits;
+pub mod link;
+pub mod llvm;
+pub mod lsp;
+pub mod macros;
+pub mod metrics;
+pub mod native;
+pub mod obfuscate;
+pub mod optimizer;
+mod peephole;
+pub mod preset;
+pub mod profile;
+pub mod pseudo;
+pub mod python;
+pub mod replay;
+pub mod report;
+pub mod riscv64;
+pub mod rust;
+pub mod selftest;
+pub mod snippets;
+pub mod source;
+mod stackmap;
+pub mod tally;
+pub mod token_map;
+pub mod trace;
+pub mod wasm;
+pub mod x86_64;
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::Arc;
+
+use interpreter::Io;
+
+/// The language a source file is written in.
+#[derive(PartialEq, Copy, Clone, Debug, Default)]
+pub enum Dialect {
+  #[default]
+  Brainfuck,
+  /// Brainfuck plus pbrain's procedures: `(` starts the definition of a
+  /// procedure numbered by the current cell, `)` ends it and `:` calls the
+  /// procedure numbered by the current cell.
+  Pbrain,
+  /// Brainfuck plus Brainfork's `Y`, which forks a thread.
+  Brainfork,
+  /// Brainfuck plus the commands of Extended Brainfuck Type I, which end
+  /// the program, move values between the current cell and a storage cell
+  /// and combine them bitwise.
+  Ebf1,
+  /// Brainfuck plus `?`, which stores a random byte in the current cell.
+  Extended,
+  /// Brainfuck on `TAPES` tapes, each with a pointer of its own: `%`
+  /// switches to the next tape and `=` copies the current cell onto the
+  /// current cell of the next tape.
+  MultiTape,
+  /// Brainbool, whose cells are bits, as `brainbool` lexes it.
+  Brainbool,
+}
+
+impl Dialect {
+  pub fn parse(text: &str) -> Result<Dialect, String> {
+    match text {
+      "brainfuck" | "bf" => Ok(Dialect::Brainfuck),
+      "pbrain" => Ok(Dialect::Pbrain),
+      "brainfork" => Ok(Dialect::Brainfork),
+      "ebf1" | "ebf" => Ok(Dialect::Ebf1),
+      "extended" => Ok(Dialect::Extended),
+      "multitape" => Ok(Dialect::MultiTape),
+      "brainbool" => Ok(Dialect::Brainbool),
+      other => Err(format!("unknown dialect {}", other)),
+    }
+  }
+}
+
+/// Commands of no dialect, lexed only when asked for and comments
+/// otherwise.
+#[derive(PartialEq, Copy, Clone, Debug, Default)]
+pub struct Commands {
+  /// `#`, printing the pointer and the cells around it to standard error.
+  pub debug_dumps: bool,
+  /// `:` and `;`, printing and reading the current cell as a decimal number.
+  pub decimal_io: bool,
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Token {
+  Plus,
+  Minus,
+  Right,
+  Left,
+  PutChar,
+  ReadChar,
+  JumpIfZero,
+  JumpIfNonZero,
+  ProcStart,
+  ProcEnd,
+  Call,
+  /// `*`, where `--module` files or snippets are linked in: calls the one
+  /// the current cell numbers, as pbrain's `:` calls a procedure.
+  CallModule,
+  Fork,
+  Ebf(Ebf),
+  Debug,
+  PutNumber,
+  ReadNumber,
+  Random,
+  Tape(Tape),
+}
+
+impl Token {
+  /// The character the command is written as, outside `--token-map`.
+  pub fn symbol(self) -> char {
+    match self {
+      Token::Plus => '+',
+      Token::Minus => '-',
+      Token::Right => '>',
+      Token::Left => '<',
+      Token::PutChar => '.',
+      Token::ReadChar => ',',
+      Token::JumpIfZero => '[',
+      Token::JumpIfNonZero => ']',
+      Token::ProcStart => '(',
+      Token::ProcEnd => ')',
+      Token::Call => ':',
+      Token::CallModule => '*',
+      Token::Fork => 'Y',
+      Token::Ebf(command) => command.symbol(),
+      Token::Debug => '#',
+      Token::PutNumber => ':',
+      Token::ReadNumber => ';',
+      Token::Random => '?',
+      Token::Tape(command) => command.symbol(),
+    }
+  }
+}
+
+/// Number of tapes of `Dialect::MultiTape`, which `%` cycles through.
+pub const TAPES: usize = 2;
+
+/// The commands of `Dialect::MultiTape`.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Tape {
+  /// `%`: makes the next tape, or the first after the last, current.
+  Next,
+  /// `=`: copies the current cell onto the current cell of the next tape.
+  Copy,
+}
+
+impl Tape {
+  pub fn symbol(self) -> char {
+    match self {
+      Tape::Next => '%',
+      Tape::Copy => '=',
+    }
+  }
+}
+
+/// The commands Extended Brainfuck Type I adds, which work on the current
+/// cell and a storage cell apart from the tape that starts out as 0.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Ebf {
+  /// `@`: ends the program.
+  End,
+  /// `$`: copies the current cell into storage.
+  Store,
+  /// `!`: copies storage into the current cell.
+  Retrieve,
+  /// `}`: shifts the current cell right by one bit.
+  ShiftRight,
+  /// `{`: shifts the current cell left by one bit.
+  ShiftLeft,
+  /// `~`: inverts the bits of the current cell.
+  Not,
+  /// `^`: the current cell XOR storage.
+  Xor,
+  /// `&`: the current cell AND storage.
+  And,
+  /// `|`: the current cell OR storage.
+  Or,
+}
+
+impl Ebf {
+  pub fn symbol(self) -> char {
+    match self {
+      Ebf::End => '@',
+      Ebf::Store => '$',
+      Ebf::Retrieve => '!',
+      Ebf::ShiftRight => '}',
+      Ebf::ShiftLeft => '{',
+      Ebf::Not => '~',
+      Ebf::Xor => '^',
+      Ebf::And => '&',
+      Ebf::Or => '|',
+    }
+  }
+}
+
+/// Byte range of the source that an instruction was built from.
+#[derive(PartialEq, Copy, Clone, Debug, Default)]
+pub struct Span {
+  pub start: u32,
+  pub end: u32,
+}
+
+impl Span {
+  /// The span of `start..end`, failing for bytes past the first 4 GiB of
+  /// the source, which 32 bits cannot address.
+  pub fn new(start: usize, end: usize) -> Result<Span, String> {
+    Ok(Span {
+      start: to_u32(start, "source byte")?,
+      end: to_u32(end, "source byte")?,
+    })
+  }
+
+  /// The range of source bytes, to index the source with.
+  pub fn range(self) -> std::ops::Range<usize> {
+    self.start as usize..self.end as usize
+  }
+}
+
+/// Bytes computed at compile time, as the cells printing them hold them.
+/// They are shared behind one pointer so that `Op::Print` stays small.
+#[derive(Clone, PartialEq)]
+pub struct Text(Arc<Vec<u8>>);
+
+impl Text {
+  pub fn new(bytes: &[u8]) -> Text {
+    Text(Arc::new(bytes.to_vec()))
+  }
+
+  pub fn as_bytes(&self) -> &[u8] {
+    &self.0
+  }
+
+  /// The bytes as `Io::Chars` prints them, one char each.
+  pub fn chars(&self) -> String {
+    self.0.iter().map(|&byte| byte as char).collect()
+  }
+}
+
+impl fmt::Debug for Text {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "b\"{}\"", self.0.escape_ascii())
+  }
+}
+
+/// Operations of the folded IR, a few bytes each so that programs of
+/// millions of instructions stay small and passes walk them in cache.
+/// Jumps carry the index of their matching bracket; the other payloads are
+/// repeat counts.
+#[derive(PartialEq, Clone, Debug)]
+pub enum Op {
+  Plus(u32),
+  Minus(u32),
+  Right(u32),
+  Left(u32),
+  PutChar(u32),
+  ReadChar(u32),
+  JumpIfZero(u32),
+  JumpIfNonZero(u32),
+  /// A `[-]` or `[+]` loop: store 0 in the current cell.
+  SetZero,
+  /// Adds the current cell times `factor` to the cell at `offset`; emitted
+  /// for balanced multiplication loops and always followed by `SetZero`.
+  AddTo {
+    offset: i32,
+    factor: i32,
+  },
+  /// Adds `amount` to the cell at `offset` from the pointer.
+  Add {
+    offset: i32,
+    amount: i32,
+  },
+  /// Stores `value` in the cell at `offset` from the pointer.
+  Set {
+    offset: i32,
+    value: i32,
+  },
+  /// A `[>]`/`[<]` style loop: moves the pointer by `stride` until it rests
+  /// on a zero cell.
+  ScanZero {
+    stride: i32,
+  },
+  /// Prints `value` `count` times; `.` on a cell with a known value.
+  PutConst {
+    value: u8,
+    count: u32,
+  },
+  /// Prints a string computed at compile time.
+  Print(Text),
+  /// Defines the pbrain procedure numbered by the current cell, whose body
+  /// runs up to the `Return` at the index carried, and skips past it.
+  Procedure(u32),
+  /// Ends the body of the `Procedure` at the index carried, returning to
+  /// the caller.
+  Return(u32),
+  /// Calls the procedure numbered by the current cell.
+  Call,
+  /// Starts a thread on a copy of the tape whose pointer is one cell to the
+  /// right, where the copy holds 1, and zeroes the current cell.
+  Fork,
+  /// An Extended Brainfuck Type I command.
+  Ebf(Ebf),
+  /// `#`: prints the pointer and the cells around it to standard error.
+  Debug,
+  /// `:`: prints the current cell as a decimal number.
+  PutNumber,
+  /// `;`: reads a decimal number into the current cell.
+  ReadNumber,
+  /// `?`: stores a random byte in the current cell.
+  Random,
+  /// A command of `Dialect::MultiTape`.
+  Tape(Tape),
+}
+
+/// An operation and the source it came from, in 24 bytes: every payload
+/// of `Op` fits 32 bits and `Print` text is one shared pointer.
+#[derive(Clone, Debug)]
+pub struct Inst {
+  pub op: Op,
+  pub span: Span,
+}
+
+#[cfg(target_pointer_width = "64")]
+const _: () = assert!(std::mem::size_of::<Inst>() == 24);
+
+/// Whether `instructions` define or call pbrain procedures.
+pub fn has_procedures(instructions: &[Inst]) -> bool {
+  instructions
+    .iter()
+    .any(|inst| matches!(inst.op, Op::Procedure(_) | Op::Call))
+}
+
+/// Whether `instructions` fork Brainfork threads.
+pub fn has_forks(instructions: &[Inst]) -> bool {
+  instructions.iter().any(|inst| inst.op == Op::Fork)
+}
+
+/// Whether `instructions` use Extended Brainfuck Type I commands.
+pub fn has_ebf(instructions: &[Inst]) -> bool {
+  instructions
+    .iter()
+    .any(|inst| matches!(inst.op, Op::Ebf(_)))
+}
+
+/// Whether `instructions` dump the tape at `#`.
+pub fn has_debug_dumps(instructions: &[Inst]) -> bool {
+  instructions.iter().any(|inst| inst.op == Op::Debug)
+}
+
+/// Whether `instructions` print or read decimal numbers.
+pub fn has_decimal_io(instructions: &[Inst]) -> bool {
+  instructions
+    .iter()
+    .any(|inst| matches!(inst.op, Op::PutNumber | Op::ReadNumber))
+}
+
+/// Whether `instructions` draw random bytes.
+pub fn has_random(instructions: &[Inst]) -> bool {
+  instructions.iter().any(|inst| inst.op == Op::Random)
+}
+
+/// Whether `instructions` switch tapes or copy cells between them.
+pub fn has_tapes(instructions: &[Inst]) -> bool {
+  instructions
+    .iter()
+    .any(|inst| matches!(inst.op, Op::Tape(_)))
+}
+
+/// Fails for programs with pbrain procedures, Brainfork threads, Extended
+/// Brainfuck commands, `#` dumps, decimal I/O, random bytes or several
+/// tapes, for code generators without them.
+pub fn reject_extensions(instructions: &[Inst]) -> Result<(), String> {
+  if has_procedures(instructions) {
+    return Err(
+      "pbrain procedures are only supported by the interpreter and JVM output".to_string(),
+    );
+  }
+  if has_forks(instructions) {
+    return Err(
+      "Brainfork threads are only supported by the interpreter and JVM output".to_string(),
+    );
+  }
+  if has_ebf(instructions) {
+    return Err(
+      "Extended Brainfuck commands are only supported by the interpreter and JVM output"
+        .to_string(),
+    );
+  }
+  if has_debug_dumps(instructions) {
+    return Err("--debug-dumps only applies to run and JVM output".to_string());
+  }
+  if has_decimal_io(instructions) {
+    return Err("--decimal-io only applies to run and JVM output".to_string());
+  }
+  if has_random(instructions) {
+    return Err("? is only supported by the interpreter and JVM output".to_string());
+  }
+  if has_tapes(instructions) {
+    return Err("multiple tapes are only supported by the interpreter and JVM output".to_string());
+  }
+  Ok(())
+}
+
+/// Fails for `--io unicode`, which only the interpreter and generated classes
+/// support, and otherwise says whether `.` writes the low byte of a cell as
+/// it is, with `--io bytes`, rather than the character it numbers in UTF-8.
+pub fn writes_bytes(io: Io) -> Result<bool, String> {
+  match io {
+    Io::Chars => Ok(false),
+    Io::Bytes => Ok(true),
+    Io::Unicode => Err("--io unicode is only supported by run and generated classes".to_string()),
+  }
+}
+
+/// What `.` writes for `bytes`: the bytes themselves with `Io::Bytes`,
+/// otherwise the characters they number in UTF-8, two bytes each from 128.
+pub fn output_bytes(bytes: &[u8], io: Io) -> Vec<u8> {
+  match io {
+    Io::Bytes => bytes.to_vec(),
+    Io::Chars | Io::Unicode => bytes
+      .iter()
+      .map(|&byte| byte as char)
+      .collect::<String>()
+      .into_bytes(),
+  }
+}
+
+pub fn lex_program(program: &str) -> Result<Vec<(Token, usize)>, String> {
+  lex_dialect(program, Dialect::Brainfuck)
+}
+
+/// Lexes `program`, recognizing the extra tokens of `dialect`.
+pub fn lex_dialect(program: &str, dialect: Dialect) -> Result<Vec<(Token, usize)>, String> {
+  lex_bytes(program.as_bytes(), dialect, Commands::default())
+}
+
+/// Lexes the bytes of a source, which need not be UTF-8: every command is
+/// ASCII, and no byte of a multi-byte character is. `commands` adds those
+/// of no dialect, and fails where one would take a symbol the dialect has.
+pub fn lex_bytes(
+  program: &[u8],
+  dialect: Dialect,
+  commands: Commands,
+) -> Result<Vec<(Token, usize)>, String> {
+  if commands.decimal_io && dialect == Dialect::Pbrain {
+    return Err("--decimal-io does not apply to pbrain, whose : calls procedures".to_string());
+  }
+  if dialect == Dialect::Brainbool {
+    return Ok(brainbool::lex(program, commands));
+  }
+  let mut tokens = Vec::new();
+  for (pos, &byte) in program.iter().enumerate() {
+    match byte {
+      b'+' => tokens.push((Token::Plus, pos)),
+      b'-' => tokens.push((Token::Minus, pos)),
+      b'>' => tokens.push((Token::Right, pos)),
+      b'<' => tokens.push((Token::Left, pos)),
+      b'.' => tokens.push((Token::PutChar, pos)),
+      b',' => tokens.push((Token::ReadChar, pos)),
+      b'[' => tokens.push((Token::JumpIfZero, pos)),
+      b']' => tokens.push((Token::JumpIfNonZero, pos)),
+      b'#' if commands.debug_dumps => tokens.push((Token::Debug, pos)),
+      b'(' if dialect == Dialect::Pbrain => tokens.push((Token::ProcStart, pos)),
+      b')' if dialect == Dialect::Pbrain => tokens.push((Token::ProcEnd, pos)),
+      b':' if dialect == Dialect::Pbrain => tokens.push((Token::Call, pos)),
+      b':' if commands.decimal_io => tokens.push((Token::PutNumber, pos)),
+      b';' if commands.decimal_io => tokens.push((Token::ReadNumber, pos)),
+      b'Y' if dialect == Dialect::Brainfork => tokens.push((Token::Fork, pos)),
+      b'@' if dialect == Dialect::Ebf1 => tokens.push((Token::Ebf(Ebf::End), pos)),
+      b'$' if dialect == Dialect::Ebf1 => tokens.push((Token::Ebf(Ebf::Store), pos)),
+      b'!' if dialect == Dialect::Ebf1 => tokens.push((Token::Ebf(Ebf::Retrieve), pos)),
+      b'}' if dialect == Dialect::Ebf1 => tokens.push((Token::Ebf(Ebf::ShiftRight), pos)),
+      b'{' if dialect == Dialect::Ebf1 => tokens.push((Token::Ebf(Ebf::ShiftLeft), pos)),
+      b'~' if dialect == Dialect::Ebf1 => tokens.push((Token::Ebf(Ebf::Not), pos)),
+      b'^' if dialect == Dialect::Ebf1 => tokens.push((Token::Ebf(Ebf::Xor), pos)),
+      b'&' if dialect == Dialect::Ebf1 => tokens.push((Token::Ebf(Ebf::And), pos)),
+      b'|' if dialect == Dialect::Ebf1 => tokens.push((Token::Ebf(Ebf::Or), pos)),
+      b'?' if dialect == Dialect::Extended => tokens.push((Token::Random, pos)),
+      b'%' if dialect == Dialect::MultiTape => tokens.push((Token::Tape(Tape::Next), pos)),
+      b'=' if dialect == Dialect::MultiTape => tokens.push((Token::Tape(Tape::Copy), pos)),
+      _ => (), // skip
+    }
+  }
+  Ok(tokens)
+}
+
+/// `value`, a `what` the IR holds in 32 bits, or an error when it does not
+/// fit them.
+fn to_u32(value: usize, what: &str) -> Result<u32, String> {
+  u32::try_from(value).map_err(|_| format!("{} {} does not fit the 32 bits of the IR", what, value))
+}
+
+/// Parses `program`, failing with every bracket that does not pair up, by
+/// position, when any does.
+pub fn parse_program(program: Vec<(Token, usize)>) -> Result<Vec<Inst>, String> {
+  parse(program, true)
+}
+
+/// Parses `program` as `parse_program` does, except that `+` and `-` only
+/// fold with their own kind, so that a run such as `-+` keeps the step
+/// past a byte that `--trap-overflow` stops at.
+pub fn parse_unmixed(program: Vec<(Token, usize)>) -> Result<Vec<Inst>, String> {
+  parse(program, false)
+}
+
+/// Parses `program`, netting `+` against `-` when `mixed`.
+fn parse(program: Vec<(Token, usize)>, mixed: bool) -> Result<Vec<Inst>, String> {
+  let mut mismatches = analyze::brackets(&program);
+  if !mismatches.is_empty() {
+    mismatches.sort_by_key(|mismatch| mismatch.pos);
+    let errors: Vec<String> = mismatches
+      .iter()
+      .map(|mismatch| format!("byte {}: {}", mismatch.pos, mismatch.message))
+      .collect();
+    return Err(errors.join("; "));
+  }
+  let mut pos = 0;
+  let mut instructions = Vec::new();
+  let mut stack = Vec::new();
+  while pos < program.len() {
+    let (curr, start) = program[pos];
+    let span = Span::new(start, start + 1)?;
+    match curr {
+      Token::Plus => instructions.extend(compile_foldable(Token::Plus, &mut pos, &program, mixed)?),
+      Token::Minus => {
+        instructions.extend(compile_foldable(Token::Minus, &mut pos, &program, mixed)?)
+      }
+      Token::Right => {
+        instructions.extend(compile_foldable(Token::Right, &mut pos, &program, mixed)?)
+      }
+      Token::Left => instructions.extend(compile_foldable(Token::Left, &mut pos, &program, mixed)?),
+      Token::PutChar => {
+        instructions.extend(compile_foldable(Token::PutChar, &mut pos, &program, mixed)?)
+      }
+      Token::ReadChar => instructions.extend(compile_foldable(
+        Token::ReadChar,
+        &mut pos,
+        &program,
+        mixed,
+      )?),
+      Token::JumpIfZero => {
+        stack.push(instructions.len());
+        instructions.push(Inst {
+          op: Op::JumpIfZero(0),
+          span,
+        });
+      }
+      Token::JumpIfNonZero => {
+        let open_inst_ptr = stack.pop().unwrap();
+        instructions[open_inst_ptr].op = Op::JumpIfZero(to_u32(instructions.len(), "instruction")?);
+        instructions.push(Inst {
+          op: Op::JumpIfNonZero(to_u32(open_inst_ptr, "instruction")?),
+          span,
+        });
+      }
+      Token::ProcStart => {
+        stack.push(instructions.len());
+        instructions.push(Inst {
+          op: Op::Procedure(0),
+          span,
+        });
+      }
+      Token::ProcEnd => {
+        let open = stack.pop().unwrap();
+        instructions[open].op = Op::Procedure(to_u32(instructions.len(), "instruction")?);
+        instructions.push(Inst {
+          op: Op::Return(to_u32(open, "instruction")?),
+          span,
+        });
+      }
+      Token::Call | Token::CallModule => instructions.push(Inst { op: Op::Call, span }),
+      Token::Fork => instructions.push(Inst { op: Op::Fork, span }),
+      Token::Ebf(command) => instructions.push(Inst {
+        op: Op::Ebf(command),
+        span,
+      }),
+      Token::Debug => instructions.push(Inst {
+        op: Op::Debug,
+        span,
+      }),
+      Token::PutNumber => instructions.push(Inst {
+        op: Op::PutNumber,
+        span,
+      }),
+      Token::ReadNumber => instructions.push(Inst {
+        op: Op::ReadNumber,
+        span,
+      }),
+      Token::Random => instructions.push(Inst {
+        op: Op::Random,
+        span,
+      }),
+      Token::Tape(command) => instructions.push(Inst {
+        op: Op::Tape(command),
+        span,
+      }),
+    }
+    pos += 1;
+  }
+  Ok(instructions)
+}
+
+/// Tokens that fold into the same instruction, and the sign each one
+/// contributes to its net count. `+` and `-` fold together when `mixed`.
+fn fold_sign(group: Token, token: Token, mixed: bool) -> Option<isize> {
+  match (group, token) {
+    (Token::Plus, Token::Plus) => Some(1),
+    (Token::Minus, Token::Minus) => Some(-1),
+    (Token::Minus, Token::Plus) if mixed => Some(1),
+    (Token::Plus, Token::Minus) if mixed => Some(-1),
+    (Token::Right, Token::Right) | (Token::Left, Token::Right) => Some(1),
+    (Token::Right, Token::Left) | (Token::Left, Token::Left) => Some(-1),
+    (Token::PutChar, Token::PutChar) | (Token::ReadChar, Token::ReadChar) => Some(1),
+    _ => None,
+  }
+}
+
+/// Folds the run of tokens starting at `pos` into one instruction, netting
+/// `>`/`<`, and `+`/`-` when `mixed`, against each other. Returns `None`
+/// when the run cancels out completely.
+fn compile_foldable(
+  token: Token,
+  pos: &mut usize,
+  program: &[(Token, usize)],
+  mixed: bool,
+) -> Result<Option<Inst>, String> {
+  let start = program[*pos].1;
+  let mut count = fold_sign(token, token, mixed).unwrap();
+  while *pos < program.len() - 1 {
+    match fold_sign(token, program[*pos + 1].0, mixed) {
+      Some(sign) => count += sign,
+      None => break,
+    }
+    *pos += 1;
+  }
+  let magnitude = to_u32(count.unsigned_abs(), "run of commands")?;
+  let op = match token {
+    _ if count == 0 => return Ok(None),
+    Token::Plus | Token::Minus if count > 0 => Op::Plus(magnitude),
+    Token::Plus | Token::Minus => Op::Minus(magnitude),
+    Token::Right | Token::Left if count > 0 => Op::Right(magnitude),
+    Token::Right | Token::Left => Op::Left(magnitude),
+    Token::PutChar => Op::PutChar(magnitude),
+    Token::ReadChar => Op::ReadChar(magnitude),
+    _ => unreachable!("brackets and procedures are never folded"),
+  };
+  Ok(Some(Inst {
+    op,
+    span: Span::new(start, program[*pos].1 + 1)?,
+  }))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{lex_bytes, lex_dialect, parse_program, parse_unmixed, Commands, Dialect, Op, Token};
+
+  #[test]
+  fn decimal_io_is_a_comment_unless_asked_for() {
+    assert_eq!(
+      lex_dialect("+ note: x; y", Dialect::Brainfuck),
+      Ok(vec![(Token::Plus, 0)])
+    );
+    let commands = Commands {
+      decimal_io: true,
+      ..Commands::default()
+    };
+    assert_eq!(
+      lex_bytes(b":;", Dialect::Brainfuck, commands),
+      Ok(vec![(Token::PutNumber, 0), (Token::ReadNumber, 1)])
+    );
+    assert!(lex_bytes(b":", Dialect::Pbrain, commands).is_err());
+  }
+
+  #[test]
+  fn debug_dumps_are_comments_unless_asked_for() {
+    assert_eq!(lex_dialect("# note", Dialect::Brainfuck), Ok(vec![]));
+    let commands = Commands {
+      debug_dumps: true,
+      ..Commands::default()
+    };
+    assert_eq!(
+      lex_bytes(b"#", Dialect::Brainfuck, commands),
+      Ok(vec![(Token::Debug, 0)])
+    );
+  }
+
+  #[test]
+  fn unmixed_runs_keep_both_directions() {
+    let tokens = lex_dialect("-+>+++", Dialect::Brainfuck).unwrap();
+    let ops =
+      |program: Vec<super::Inst>| program.into_iter().map(|inst| inst.op).collect::<Vec<_>>();
+    assert_eq!(
+      ops(parse_program(tokens.clone()).unwrap()),
+      [Op::Right(1), Op::Plus(3)]
+    );
+    assert_eq!(
+      ops(parse_unmixed(tokens).unwrap()),
+      [Op::Minus(1), Op::Plus(1), Op::Right(1), Op::Plus(3)]
+    );
+  }
+
+  #[test]
+  fn module_calls_keep_their_symbol() {
+    let tokens = [(Token::Call, 0), (Token::CallModule, 1)];
+    let symbols: String = tokens.iter().map(|(token, _)| token.symbol()).collect();
+    assert_eq!(symbols, ":*");
+    let ops: Vec<Op> = parse_program(tokens.to_vec())
+      .unwrap()
+      .into_iter()
+      .map(|inst| inst.op)
+      .collect();
+    assert_eq!(ops, [Op::Call, Op::Call]);
+  }
+
+  #[cfg(target_pointer_width = "64")]
+  #[test]
+  fn positions_past_32_bits_are_errors() {
+    let far = u32::MAX as usize + 1;
+    assert_eq!(
+      parse_program(vec![(Token::Plus, far)]).unwrap_err(),
+      format!("source byte {} does not fit the 32 bits of the IR", far)
+    );
+    assert!(parse_program(vec![(Token::JumpIfZero, 0), (Token::JumpIfNonZero, far)]).is_err());
+  }
+}
@@ -0,0 +1,311 @@
+//! Computes exact `.limit stack` and `.limit locals` values for Jasmin
+//! methods by simulating the operand stack over every path through them.
+
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Write};
+
+/// Stack slots taken by the types in a method descriptor's argument list and
+/// by its return type.
+fn descriptor_slots(descriptor: &str) -> Result<(i32, i32), String> {
+  let malformed = || format!("malformed descriptor {}", descriptor);
+  let inner = descriptor.strip_prefix('(').ok_or_else(malformed)?;
+  let close = inner.find(')').ok_or_else(malformed)?;
+  let mut args = 0;
+  let mut chars = inner[..close].chars();
+  while let Some(c) = chars.next() {
+    match c {
+      'J' | 'D' => args += 2,
+      'L' => {
+        chars.by_ref().find(|&c| c == ';').ok_or_else(malformed)?;
+        args += 1;
+      }
+      '[' => {
+        let mut element = chars.next().ok_or_else(malformed)?;
+        while element == '[' {
+          element = chars.next().ok_or_else(malformed)?;
+        }
+        if element == 'L' {
+          chars.by_ref().find(|&c| c == ';').ok_or_else(malformed)?;
+        }
+        args += 1;
+      }
+      _ => args += 1,
+    }
+  }
+  let returns = match &inner[close + 1..] {
+    "V" => 0,
+    "J" | "D" => 2,
+    _ => 1,
+  };
+  Ok((args, returns))
+}
+
+/// Net stack effect of one instruction, and the local it touches if any.
+fn effect(mnemonic: &str, args: &[&str]) -> Result<(i32, Option<u16>), String> {
+  let local = |text: &str| {
+    text
+      .parse::<u16>()
+      .map_err(|_| format!("bad local variable {}", text))
+  };
+  // `iload_3`, `astore_2`, ...
+  if let Some((kind, n)) = mnemonic.split_once('_') {
+    if let (Some(delta), Ok(n)) = (
+      match kind {
+        "iload" | "aload" => Some(1),
+        "istore" | "astore" => Some(-1),
+        _ => None,
+      },
+      n.parse::<u16>(),
+    ) {
+      return Ok((delta, Some(n)));
+    }
+  }
+  Ok(match mnemonic {
+    "iload" | "aload" => (1, Some(local(args[0])?)),
+    "istore" | "astore" => (-1, Some(local(args[0])?)),
+    "iinc" => (0, Some(local(args[0])?)),
+    "nop" | "i2b" | "i2c" | "i2s" | "ineg" | "newarray" | "anewarray" | "arraylength"
+    | "checkcast" | "getfield" | "goto" | "return" => (0, None),
+    "aconst_null" | "bipush" | "sipush" | "ldc" | "ldc_w" | "dup" | "dup_x1" | "dup_x2" | "new"
+    | "getstatic" => (1, None),
+    "dup2" => (2, None),
+    "swap" => (0, None),
+    "pop" | "ifeq" | "ifne" | "iflt" | "ifge" | "ifgt" | "ifle" | "ifnull" | "ifnonnull"
+    | "ireturn" | "areturn" | "athrow" | "putstatic" | "iaload" | "baload" | "caload"
+    | "aaload" | "iadd" | "isub" | "imul" | "idiv" | "irem" | "ishl" | "ishr" | "iushr"
+    | "iand" | "ior" | "ixor" => (-1, None),
+    "pop2" | "if_icmpeq" | "if_icmpne" | "if_icmplt" | "if_icmpge" | "if_icmpgt" | "if_icmple"
+    | "putfield" => (-2, None),
+    "iastore" | "aastore" | "bastore" | "castore" => (-3, None),
+    "invokevirtual" | "invokespecial" | "invokenonvirtual" | "invokestatic" => {
+      let signature = args.first().ok_or("invoke without a method")?;
+      let split = signature
+        .find('(')
+        .ok_or_else(|| format!("method {} has no descriptor", signature))?;
+      let (params, returns) = descriptor_slots(&signature[split..])?;
+      let receiver = if mnemonic == "invokestatic" { 0 } else { 1 };
+      (returns - params - receiver, None)
+    }
+    _ if mnemonic.starts_with("iconst_") => (1, None),
+    other => return Err(format!("no stack effect known for {}", other)),
+  })
+}
+
+fn is_branch(mnemonic: &str) -> bool {
+  mnemonic == "goto" || mnemonic.starts_with("if")
+}
+
+fn ends_flow(mnemonic: &str) -> bool {
+  matches!(
+    mnemonic,
+    "goto" | "return" | "ireturn" | "areturn" | "athrow"
+  )
+}
+
+/// Maximum stack depth and number of locals of a method body, given with
+/// the number of each of its lines.
+fn method_limits(
+  is_static: bool,
+  descriptor: &str,
+  body: &[(usize, &str)],
+) -> Result<(i32, u16), String> {
+  let (params, _) = descriptor_slots(descriptor)?;
+  let mut locals = params as u16 + if is_static { 0 } else { 1 };
+  let lines: Vec<(&str, Vec<&str>)> = body
+    .iter()
+    .map(|(_, line)| {
+      let mut words = line.split_whitespace();
+      (words.next().unwrap_or(""), words.collect())
+    })
+    .collect();
+  let mut labels: HashMap<&str, usize> = HashMap::new();
+  for (index, (word, args)) in lines.iter().enumerate() {
+    if let Some(label) = word.strip_suffix(':').filter(|_| args.is_empty()) {
+      if labels.insert(label, index).is_some() {
+        return Err(format!(
+          "line {}: label {} is already defined",
+          body[index].0, label
+        ));
+      }
+    }
+  }
+  let mut depth: Vec<Option<i32>> = vec![None; lines.len()];
+  let mut max = 0;
+  let mut work = vec![(0, 0)];
+  while let Some((index, entry)) = work.pop() {
+    if index >= lines.len() {
+      continue;
+    }
+    match depth[index] {
+      Some(known) if known == entry => continue,
+      Some(known) => {
+        return Err(format!(
+          "stack depth {} and {} meet at `{}`",
+          known, entry, body[index].1
+        ))
+      }
+      None => depth[index] = Some(entry),
+    }
+    let (word, args) = &lines[index];
+    if word.is_empty() || word.ends_with(':') || word.starts_with('.') || word.starts_with(';') {
+      work.push((index + 1, entry));
+      continue;
+    }
+    let (delta, local) = effect(word, args)?;
+    if let Some(local) = local {
+      locals = locals.max(local + 1);
+    }
+    // Instructions that push first and pop afterwards, such as `invoke`s
+    // returning a value, never exceed the larger of the two depths.
+    let after = entry + delta;
+    if after < 0 {
+      return Err(format!("stack underflow at `{}`", body[index].1));
+    }
+    max = max.max(after).max(entry);
+    if is_branch(word) {
+      let target = labels
+        .get(args[0])
+        .ok_or_else(|| format!("undefined label {}", args[0]))?;
+      work.push((*target, after));
+    }
+    if !ends_flow(word) {
+      work.push((index + 1, after));
+    }
+  }
+  Ok((max, locals))
+}
+
+/// Writes `source` to `out` with the `.limit` directives of every method
+/// replaced by the values its code actually needs.
+pub fn write_with_limits(source: &str, out: &mut dyn Write) -> io::Result<()> {
+  let invalid = |message: String| io::Error::new(ErrorKind::InvalidData, message);
+  let mut lines = source
+    .lines()
+    .enumerate()
+    .map(|(number, line)| (number + 1, line));
+  while let Some((_, line)) = lines.next() {
+    writeln!(out, "{}", line)?;
+    let words: Vec<&str> = line.split_whitespace().collect();
+    if words.first() != Some(&".method") {
+      continue;
+    }
+    let signature = words.last().unwrap();
+    let open = signature
+      .find('(')
+      .ok_or_else(|| invalid("method without descriptor".to_string()))?;
+    let body: Vec<(usize, &str)> = lines
+      .by_ref()
+      .take_while(|(_, line)| line.trim() != ".end method")
+      .filter(|(_, line)| !line.trim().starts_with(".limit"))
+      .collect();
+    let (stack, locals) =
+      method_limits(words.contains(&"static"), &signature[open..], &body).map_err(invalid)?;
+    writeln!(out, "    .limit stack {}", stack)?;
+    writeln!(out, "    .limit locals {}", locals)?;
+    for (_, line) in body {
+      writeln!(out, "{}", line)?;
+    }
+    writeln!(out, ".end method")?;
+  }
+  Ok(())
+}
+
+/// `.limit`s worked out a line at a time, for code that is written out as
+/// it is generated. A label takes the depth of a branch to it seen before,
+/// or of the code falling into it; one reached only by branches back is
+/// taken to start on an empty stack, which those branches must confirm.
+#[derive(Default)]
+pub struct Streaming {
+  /// Whether a method is open, so that lines between methods are skipped.
+  open: bool,
+  /// The stack depth after the lines so far, or `None` where they end in
+  /// a jump or return and nothing falls through.
+  depth: Option<i32>,
+  max: i32,
+  locals: u16,
+  /// The depth at each label met so far, and whether it is defined yet.
+  labels: HashMap<String, (i32, bool)>,
+}
+
+impl Streaming {
+  /// Accounts for the next line of the class, returning the stack and
+  /// locals of the method a `.end method` line closes.
+  pub fn line(&mut self, line: &str) -> Result<Option<(i32, u16)>, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let (word, args) = match words.split_first() {
+      Some((word, args)) => (*word, args),
+      None => return Ok(None),
+    };
+    if word == ".method" {
+      let signature = words.last().unwrap();
+      let open = signature
+        .find('(')
+        .ok_or_else(|| "method without descriptor".to_string())?;
+      let (params, _) = descriptor_slots(&signature[open..])?;
+      *self = Streaming {
+        open: true,
+        depth: Some(0),
+        locals: params as u16 + if words.contains(&"static") { 0 } else { 1 },
+        ..Streaming::default()
+      };
+      return Ok(None);
+    }
+    if !self.open {
+      return Ok(None);
+    }
+    if word == ".end" && args == ["method"] {
+      self.open = false;
+      return Ok(Some((self.max, self.locals)));
+    }
+    if word.starts_with('.') || word.starts_with(';') {
+      return Ok(None);
+    }
+    if let Some(label) = word.strip_suffix(':').filter(|_| args.is_empty()) {
+      let depth = match (self.labels.get(label), self.depth) {
+        (Some(&(_, true)), _) => return Err(format!("label {} is already defined", label)),
+        (Some(&(known, _)), Some(depth)) if known != depth => {
+          return Err(format!(
+            "stack depth {} and {} meet at `{}`",
+            known, depth, line
+          ))
+        }
+        (Some(&(known, _)), _) => known,
+        (None, depth) => depth.unwrap_or(0),
+      };
+      self.labels.insert(label.to_string(), (depth, true));
+      self.depth = Some(depth);
+      return Ok(None);
+    }
+    // Nothing reaches code after a jump until the next label.
+    let entry = match self.depth {
+      Some(entry) => entry,
+      None => return Ok(None),
+    };
+    let (delta, local) = effect(word, args)?;
+    if let Some(local) = local {
+      self.locals = self.locals.max(local + 1);
+    }
+    let after = entry + delta;
+    if after < 0 {
+      return Err(format!("stack underflow at `{}`", line));
+    }
+    self.max = self.max.max(after).max(entry);
+    if is_branch(word) {
+      let target = args[0];
+      match self.labels.get(target) {
+        Some(&(known, _)) if known != after => {
+          return Err(format!(
+            "stack depth {} and {} meet at label {}",
+            known, after, target
+          ))
+        }
+        Some(_) => (),
+        None => {
+          self.labels.insert(target.to_string(), (after, false));
+        }
+      }
+    }
+    self.depth = if ends_flow(word) { None } else { Some(after) };
+    Ok(None)
+  }
+}
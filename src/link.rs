@@ -0,0 +1,89 @@
+//! Linking of `--module` files into one program. Each module becomes a
+//! procedure, numbered by its place among the modules and defined before
+//! the program starts, so `*` calls the module the current cell names on
+//! the tape as it is, and every backend with procedures runs it: the JVM
+//! as a static method of the class.
+//!
+//! Bytes `--init-tape` or `--init-hex` load onto the tape are set by
+//! instructions ahead of the program too, so that every backend starts
+//! with them.
+
+use super::optimizer::link_jumps;
+use super::{Inst, Op, Span};
+
+/// Procedure numbers are cells, so this many modules can be told apart.
+pub const MAX_MODULES: usize = 256;
+
+/// Parses `--init-hex` text, two digits to a byte, ignoring whitespace.
+pub fn parse_hex(text: &str) -> Result<Vec<u8>, String> {
+  let digits: Vec<char> = text.chars().filter(|c| !c.is_whitespace()).collect();
+  if !digits.len().is_multiple_of(2) {
+    return Err(format!("{} has an odd number of hex digits", text));
+  }
+  digits
+    .chunks(2)
+    .map(|pair| {
+      let pair: String = pair.iter().collect();
+      u8::from_str_radix(&pair, 16).map_err(|_| format!("{} is not a hex byte", pair))
+    })
+    .collect()
+}
+
+/// Instructions setting the first cells of the tape to `data`, leaving the
+/// pointer on the first.
+pub fn initialize(data: &[u8]) -> Vec<Inst> {
+  data
+    .iter()
+    .enumerate()
+    .filter(|&(_, &byte)| byte != 0)
+    .map(|(offset, &byte)| Inst {
+      op: Op::Set {
+        offset: offset as i32,
+        value: byte as i32,
+      },
+      span: Span::default(),
+    })
+    .collect()
+}
+
+/// `program` preceded by the definitions of `modules`, which leave the
+/// first cell as zero as it was.
+pub fn link(modules: Vec<Vec<Inst>>, program: Vec<Inst>) -> Result<Vec<Inst>, String> {
+  if modules.len() > MAX_MODULES {
+    return Err(format!(
+      "{} modules, but a cell names at most {}",
+      modules.len(),
+      MAX_MODULES
+    ));
+  }
+  let span = Span::default();
+  let mut linked = Vec::new();
+  let linking = !modules.is_empty();
+  for (number, module) in modules.into_iter().enumerate() {
+    linked.push(Inst {
+      op: Op::Set {
+        offset: 0,
+        value: number as i32,
+      },
+      span,
+    });
+    linked.push(Inst {
+      op: Op::Procedure(0),
+      span,
+    });
+    linked.extend(module);
+    linked.push(Inst {
+      op: Op::Return(0),
+      span,
+    });
+  }
+  if linking {
+    linked.push(Inst {
+      op: Op::SetZero,
+      span,
+    });
+  }
+  linked.extend(program);
+  link_jumps(&mut linked);
+  Ok(linked)
+}
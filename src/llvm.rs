@@ -0,0 +1,453 @@
+//! Textual LLVM IR, for compiling programs to native executables with
+//! `clang`. The tape is a zeroed global and the pointer lives in a stack
+//! slot, so loops need no phi nodes: every block loads what it uses.
+//!
+//! Pointers are written opaque (`ptr`), which LLVM 15 and later expect.
+//! `.` writes characters in UTF-8, unless `--io bytes` asks for the bytes
+//! as they are.
+
+use std::convert::TryFrom;
+
+use super::interpreter::{Bounds, Eof, ExitCell};
+use super::jasmin::Config;
+use super::{output_bytes, reject_extensions, writes_bytes, Inst, Op};
+
+/// Writes `@putchar` out for `text.as_bytes().len()` bytes starting at `text`.
+const PRINT: &str = "define internal void @print(ptr %text, i64 %length) {
+entry:
+  %i = alloca i64
+  store i64 0, ptr %i
+  br label %test
+test:
+  %n = load i64, ptr %i
+  %more = icmp ult i64 %n, %length
+  br i1 %more, label %body, label %done
+body:
+  %at = getelementptr inbounds i8, ptr %text, i64 %n
+  %byte = load i8, ptr %at
+  %code = zext i8 %byte to i32
+  call i32 @putchar(i32 %code)
+  %next = add i64 %n, 1
+  store i64 %next, ptr %i
+  br label %test
+done:
+  ret void
+}";
+
+/// Writes the low byte of `%code` as a character in UTF-8 through
+/// `@putchar`, the way the interpreter prints a cell.
+const PUT: &str = "define internal void @put(i32 %code) {
+entry:
+  %byte = and i32 %code, 255
+  %ascii = icmp ult i32 %byte, 128
+  br i1 %ascii, label %one, label %two
+one:
+  call i32 @putchar(i32 %byte)
+  ret void
+two:
+  %high = lshr i32 %byte, 6
+  %lead = or i32 %high, 192
+  call i32 @putchar(i32 %lead)
+  %low = and i32 %byte, 63
+  %trail = or i32 %low, 128
+  call i32 @putchar(i32 %trail)
+  ret void
+}";
+
+/// The message `@off_tape` writes to standard error.
+const OFF_TAPE: &str = "pointer moved off the tape\n";
+
+/// Escapes `bytes` for a `c"..."` constant.
+fn quote(bytes: &[u8]) -> String {
+  bytes
+    .iter()
+    .map(|&byte| match byte {
+      b' '..=b'~' if byte != b'"' && byte != b'\\' => (byte as char).to_string(),
+      _ => format!("\\{:02X}", byte),
+    })
+    .collect()
+}
+
+/// The function body as it is built, with a counter for fresh temporaries.
+struct Function<'a> {
+  lines: Vec<String>,
+  temps: usize,
+  config: &'a Config,
+  /// `i8` when cells wrap, `i32` otherwise.
+  cell: &'static str,
+  tape: String,
+  /// Whether a check branched to the `%off_tape` block, which is then
+  /// written after the others.
+  checked: bool,
+}
+
+impl<'a> Function<'a> {
+  fn temp(&mut self) -> String {
+    self.temps += 1;
+    format!("%t{}", self.temps)
+  }
+
+  fn emit(&mut self, line: String) {
+    self.lines.push(format!("  {}", line));
+  }
+
+  fn label(&mut self, name: String) {
+    self.lines.push(format!("{}:", name));
+  }
+
+  /// Branches to `name` and starts it, since every block needs a terminator.
+  fn enter(&mut self, name: String) {
+    self.emit(format!("br label %{}", name));
+    self.label(name);
+  }
+
+  fn pointer(&mut self) -> String {
+    let ptr = self.temp();
+    self.emit(format!("{} = load i64, ptr %ptr", ptr));
+    ptr
+  }
+
+  /// Stops the program in `%off_tape` unless `index` is the index of a
+  /// cell, under `Bounds::Error`. An index below zero compares as a large
+  /// unsigned one.
+  fn check(&mut self, index: &str) {
+    if self.config.bounds == Bounds::Unchecked {
+      return;
+    }
+    let outside = self.temp();
+    self.emit(format!(
+      "{} = icmp uge i64 {}, {}",
+      outside, index, self.config.tape_size
+    ));
+    let inside = format!("inside{}", self.temps);
+    self.emit(format!(
+      "br i1 {}, label %off_tape, label %{}",
+      outside, inside
+    ));
+    self.label(inside);
+    self.checked = true;
+  }
+
+  fn move_by(&mut self, amount: isize) {
+    let ptr = self.pointer();
+    let moved = self.temp();
+    self.emit(format!("{} = add i64 {}, {}", moved, ptr, amount));
+    self.check(&moved);
+    self.emit(format!("store i64 {}, ptr %ptr", moved));
+  }
+
+  /// The address of the cell at `offset` from the pointer.
+  fn address(&mut self, offset: i32) -> String {
+    let mut index = self.pointer();
+    if offset != 0 {
+      let shifted = self.temp();
+      self.emit(format!("{} = add i64 {}, {}", shifted, index, offset));
+      self.check(&shifted);
+      index = shifted;
+    }
+    let address = self.temp();
+    self.emit(format!(
+      "{} = getelementptr inbounds {}, ptr @tape, i64 0, i64 {}",
+      address, self.tape, index
+    ));
+    address
+  }
+
+  fn load(&mut self, address: &str) -> String {
+    let value = self.temp();
+    self.emit(format!("{} = load {}, ptr {}", value, self.cell, address));
+    value
+  }
+
+  fn store(&mut self, address: &str, value: &str) {
+    self.emit(format!("store {} {}, ptr {}", self.cell, value, address));
+  }
+
+  /// `value` as a constant of the cell type.
+  fn constant(&self, value: i64) -> i64 {
+    if self.cell == "i8" {
+      value as i8 as i64
+    } else {
+      value as i32 as i64
+    }
+  }
+
+  fn add(&mut self, offset: i32, amount: i64) {
+    let address = self.address(offset);
+    let value = self.load(&address);
+    let sum = self.temp();
+    let amount = self.constant(amount);
+    self.emit(format!("{} = add {} {}, {}", sum, self.cell, value, amount));
+    self.store(&address, &sum);
+  }
+
+  /// Converts an `i32` to a cell.
+  fn as_cell(&mut self, value: &str) -> String {
+    if self.cell == "i32" {
+      return value.to_string();
+    }
+    let cell = self.temp();
+    self.emit(format!("{} = trunc i32 {} to i8", cell, value));
+    cell
+  }
+
+  /// Converts a cell to an `i32` character code.
+  fn as_code(&mut self, value: &str) -> String {
+    if self.cell == "i32" {
+      return value.to_string();
+    }
+    let code = self.temp();
+    self.emit(format!("{} = zext i8 {} to i32", code, value));
+    code
+  }
+
+  fn is_zero(&mut self, value: &str) -> String {
+    let zero = self.temp();
+    self.emit(format!("{} = icmp eq {} {}, 0", zero, self.cell, value));
+    zero
+  }
+
+  fn read(&mut self) {
+    let address = self.address(0);
+    let read = self.temp();
+    self.emit(format!("{} = call i32 @getchar()", read));
+    let value = match self.config.eof {
+      Eof::MinusOne => read,
+      Eof::Zero | Eof::Unchanged => {
+        let eof = self.temp();
+        self.emit(format!("{} = icmp slt i32 {}, 0", eof, read));
+        let old = self.load(&address);
+        let old = self.as_code(&old);
+        let kept = if self.config.eof == Eof::Zero {
+          "0".to_string()
+        } else {
+          old
+        };
+        let value = self.temp();
+        self.emit(format!(
+          "{} = select i1 {}, i32 {}, i32 {}",
+          value, eof, kept, read
+        ));
+        value
+      }
+    };
+    let value = self.as_cell(&value);
+    self.store(&address, &value);
+  }
+}
+
+/// Generates an LLVM module whose `main` runs `instructions`.
+pub fn produce_llvm(instructions: &[Inst], config: &Config) -> Result<String, String> {
+  reject_extensions(instructions)?;
+  if config.tape_from_args || config.embeddable || config.runtime_args {
+    return Err(
+      "--tape-from-args, --embeddable and --runtime-args only apply to JVM output".to_string(),
+    );
+  }
+  i64::try_from(config.tape_size)
+    .map_err(|_| format!("a tape of {} cells is too large", config.tape_size))?;
+  let cell = if config.wrap { "i8" } else { "i32" };
+  let tape = format!("[{} x {}]", config.tape_size, cell);
+  let mut module = Vec::new();
+  if let Some(debug) = &config.debug {
+    module.push(format!("source_filename = \"{}\"", debug.file));
+    module.push(String::new());
+  }
+  module.push(format!("@tape = internal global {} zeroinitializer", tape));
+  let bytes = writes_bytes(config.io)?;
+  let mut texts = Vec::new();
+  let mut f = Function {
+    lines: Vec::new(),
+    temps: 0,
+    config,
+    cell,
+    tape,
+    checked: false,
+  };
+  f.label("entry".to_string());
+  f.emit("%ptr = alloca i64".to_string());
+  f.emit("store i64 0, ptr %ptr".to_string());
+  let mut index = 0;
+  while index < instructions.len() {
+    match instructions[index].op {
+      Op::Procedure(_)
+      | Op::Return(_)
+      | Op::Call
+      | Op::Fork
+      | Op::Ebf(_)
+      | Op::Debug
+      | Op::PutNumber
+      | Op::ReadNumber
+      | Op::Random
+      | Op::Tape(_) => {
+        unreachable!("rejected by reject_extensions")
+      }
+      Op::Plus(count) => f.add(0, count as i64),
+      Op::Minus(count) => f.add(0, -(count as i64)),
+      Op::Right(count) => f.move_by(count as isize),
+      Op::Left(count) => f.move_by(-(count as isize)),
+      Op::PutChar(count) => {
+        let address = f.address(0);
+        let value = f.load(&address);
+        let code = f.as_code(&value);
+        for _ in 0..count {
+          f.emit(if bytes {
+            format!("call i32 @putchar(i32 {})", code)
+          } else {
+            format!("call void @put(i32 {})", code)
+          });
+        }
+      }
+      Op::ReadChar(count) => {
+        for _ in 0..count {
+          f.read();
+        }
+      }
+      Op::JumpIfZero(end) => {
+        f.enter(format!("loop{}", index));
+        let address = f.address(0);
+        let value = f.load(&address);
+        let zero = f.is_zero(&value);
+        f.emit(format!(
+          "br i1 {}, label %end{}, label %body{}",
+          zero, end, index
+        ));
+        f.label(format!("body{}", index));
+      }
+      Op::JumpIfNonZero(start) => {
+        f.emit(format!("br label %loop{}", start));
+        f.label(format!("end{}", index));
+      }
+      Op::SetZero => {
+        let address = f.address(0);
+        f.store(&address, "0");
+      }
+      Op::AddTo { .. } => {
+        // The offset cells may be outside the tape when the counter is
+        // zero, so they are only touched when it is not.
+        let targets: Vec<(i32, i32)> = instructions[index..]
+          .iter()
+          .map_while(|inst| match inst.op {
+            Op::AddTo { offset, factor } => Some((offset, factor)),
+            _ => None,
+          })
+          .collect();
+        let address = f.address(0);
+        let counter = f.load(&address);
+        let zero = f.is_zero(&counter);
+        f.emit(format!(
+          "br i1 {}, label %multiplied{}, label %multiply{}",
+          zero, index, index
+        ));
+        f.label(format!("multiply{}", index));
+        for (offset, factor) in &targets {
+          let product = f.temp();
+          let factor = f.constant(*factor as i64);
+          f.emit(format!(
+            "{} = mul {} {}, {}",
+            product, cell, counter, factor
+          ));
+          let target = f.address(*offset);
+          let value = f.load(&target);
+          let sum = f.temp();
+          f.emit(format!("{} = add {} {}, {}", sum, cell, value, product));
+          f.store(&target, &sum);
+        }
+        f.enter(format!("multiplied{}", index));
+        index += targets.len() - 1;
+      }
+      Op::Add { offset, amount } => f.add(offset, amount as i64),
+      Op::Set { offset, value } => {
+        let address = f.address(offset);
+        let value = f.constant(value as i64);
+        f.store(&address, &value.to_string());
+      }
+      Op::ScanZero { stride } => {
+        f.enter(format!("scan{}", index));
+        let address = f.address(0);
+        let value = f.load(&address);
+        let zero = f.is_zero(&value);
+        f.emit(format!(
+          "br i1 {}, label %scanned{}, label %step{}",
+          zero, index, index
+        ));
+        f.label(format!("step{}", index));
+        f.move_by(stride as isize);
+        f.emit(format!("br label %scan{}", index));
+        f.label(format!("scanned{}", index));
+      }
+      Op::PutConst { value, count } => {
+        for byte in output_bytes(&vec![value; count as usize], config.io) {
+          f.emit(format!("call i32 @putchar(i32 {})", byte));
+        }
+      }
+      Op::Print(ref text) => {
+        let text = output_bytes(text.as_bytes(), config.io);
+        f.emit(format!(
+          "call void @print(ptr @text{}, i64 {})",
+          texts.len(),
+          text.len()
+        ));
+        texts.push(text);
+      }
+    }
+    index += 1;
+  }
+  let status = match config.exit_cell {
+    None => "0".to_string(),
+    Some(cell) => {
+      if cell == ExitCell::First {
+        f.emit("store i64 0, ptr %ptr".to_string());
+      }
+      let address = f.address(0);
+      let value = f.load(&address);
+      f.as_code(&value)
+    }
+  };
+  f.emit(format!("ret i32 {}", status));
+  if f.checked {
+    f.label("off_tape".to_string());
+    f.emit(format!(
+      "call i64 @write(i32 2, ptr @off_tape_message, i64 {})",
+      OFF_TAPE.len()
+    ));
+    f.emit("call void @exit(i32 1)".to_string());
+    f.emit("unreachable".to_string());
+    module.push(format!(
+      "@off_tape_message = private unnamed_addr constant [{} x i8] c\"{}\"",
+      OFF_TAPE.len(),
+      quote(OFF_TAPE.as_bytes())
+    ));
+  }
+  for (index, text) in texts.iter().enumerate() {
+    module.push(format!(
+      "@text{} = private unnamed_addr constant [{} x i8] c\"{}\"",
+      index,
+      text.len(),
+      quote(text)
+    ));
+  }
+  module.extend([
+    String::new(),
+    "declare i32 @getchar()".to_string(),
+    "declare i32 @putchar(i32)".to_string(),
+  ]);
+  if f.checked {
+    module.extend([
+      "declare i64 @write(i32, ptr, i64)".to_string(),
+      "declare void @exit(i32)".to_string(),
+    ]);
+  }
+  module.extend([
+    String::new(),
+    PRINT.to_string(),
+    String::new(),
+    PUT.to_string(),
+    String::new(),
+    "define i32 @main() {".to_string(),
+  ]);
+  module.extend(f.lines);
+  module.push("}".to_string());
+  module.push(String::new());
+  Ok(module.join("\n"))
+}
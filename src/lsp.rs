@@ -0,0 +1,321 @@
+//! The language server behind `lsp`, speaking the Language Server Protocol
+//! over standard input and output.
+//!
+//! Open documents are checked on every change, with a diagnostic for each
+//! bracket without its partner. Going to the definition of a bracket jumps
+//! to its partner, hovering inside a loop shows the loop folded into IR,
+//! and formatting reprints the document the way `fmt` does. Documents are
+//! synced whole, so each change carries the full text.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+use super::json::{read_message, write_message, Json};
+use super::optimizer::{self, Level};
+use super::{analyze, format, lex_bytes, parse_program, Commands, Dialect, Token};
+
+/// JSON-RPC's code for a method the server does not know.
+const METHOD_NOT_FOUND: i32 = -32601;
+/// The protocol's code for a request that was understood but failed.
+const REQUEST_FAILED: i32 = -32803;
+
+/// How documents are lexed.
+pub struct Config {
+  pub dialect: Dialect,
+  pub commands: Commands,
+}
+
+/// The protocol's position of byte `offset` of `text`: a line and a
+/// column counted in UTF-16 code units.
+fn position(text: &str, offset: usize) -> Json {
+  let before = &text[..offset];
+  let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+  let character: usize = before[line_start..].chars().map(char::len_utf16).sum();
+  Json::object(vec![
+    ("line", before.matches('\n').count().into()),
+    ("character", character.into()),
+  ])
+}
+
+fn range(text: &str, start: usize, end: usize) -> Json {
+  Json::object(vec![
+    ("start", position(text, start)),
+    ("end", position(text, end)),
+  ])
+}
+
+/// The byte offset of the protocol's `position` in `text`, past the end of
+/// a line going to its end.
+fn offset(text: &str, position: &Json) -> Option<usize> {
+  let line = position.get("line").as_u64()? as usize;
+  let character = position.get("character").as_u64()? as usize;
+  let mut start = 0;
+  for _ in 0..line {
+    start += text[start..].find('\n')? + 1;
+  }
+  let mut units = 0;
+  for (at, c) in text[start..].char_indices() {
+    if units >= character || c == '\n' {
+      return Some(start + at);
+    }
+    units += c.len_utf16();
+  }
+  Some(text.len())
+}
+
+/// The positions of each pair of brackets of `tokens` that match, opening
+/// first, skipping those that do not.
+fn pairs(tokens: &[(Token, usize)]) -> Vec<(usize, usize)> {
+  let mut pairs = Vec::new();
+  let mut open: Vec<(Token, usize)> = Vec::new();
+  for &(token, pos) in tokens {
+    match token {
+      Token::JumpIfZero | Token::ProcStart => open.push((token, pos)),
+      Token::JumpIfNonZero | Token::ProcEnd => {
+        let opener = if token == Token::JumpIfNonZero {
+          Token::JumpIfZero
+        } else {
+          Token::ProcStart
+        };
+        if let Some(&(_, at)) = open.last().filter(|&&(kind, _)| kind == opener) {
+          open.pop();
+          pairs.push((at, pos));
+        }
+      }
+      _ => (),
+    }
+  }
+  pairs
+}
+
+struct Server<'a> {
+  config: &'a Config,
+  output: &'a mut dyn Write,
+  /// The text of each open document by URI.
+  documents: HashMap<String, String>,
+}
+
+impl Server<'_> {
+  fn tokens(&self, text: &str) -> Vec<(Token, usize)> {
+    lex_bytes(text.as_bytes(), self.config.dialect, self.config.commands).unwrap_or_default()
+  }
+
+  fn send(&mut self, message: Json) -> io::Result<()> {
+    write_message(self.output, &message)
+  }
+
+  fn respond(&mut self, id: Json, result: Result<Json, (i32, String)>) -> io::Result<()> {
+    let outcome = match result {
+      Ok(result) => ("result", result),
+      Err((code, message)) => (
+        "error",
+        Json::object(vec![
+          ("code", Json::Number(code.into())),
+          ("message", message.into()),
+        ]),
+      ),
+    };
+    self.send(Json::object(vec![
+      ("jsonrpc", "2.0".into()),
+      ("id", id),
+      outcome,
+    ]))
+  }
+
+  /// Publishes the diagnostics of the document at `uri`, none once it is
+  /// closed.
+  fn diagnose(&mut self, uri: &str) -> io::Result<()> {
+    let diagnostics = match self.documents.get(uri) {
+      Some(text) => analyze::brackets(&self.tokens(text))
+        .into_iter()
+        .map(|warning| {
+          Json::object(vec![
+            ("range", range(text, warning.pos, warning.pos + 1)),
+            ("severity", Json::Number(1.0)),
+            ("code", warning.code.into()),
+            ("source", "brainfuck".into()),
+            ("message", warning.message.into()),
+          ])
+        })
+        .collect(),
+      None => Vec::new(),
+    };
+    self.send(Json::object(vec![
+      ("jsonrpc", "2.0".into()),
+      ("method", "textDocument/publishDiagnostics".into()),
+      (
+        "params",
+        Json::object(vec![
+          ("uri", uri.into()),
+          ("diagnostics", Json::Array(diagnostics)),
+        ]),
+      ),
+    ]))
+  }
+
+  /// The partner of the bracket at or just before `params`' position.
+  fn definition(&self, uri: &str, text: &str, params: &Json) -> Json {
+    let Some(at) = offset(text, params.get("position")) else {
+      return Json::Null;
+    };
+    let pairs = pairs(&self.tokens(text));
+    let partner = |pos: usize| {
+      pairs.iter().find_map(|&(open, close)| match pos {
+        _ if pos == open => Some(close),
+        _ if pos == close => Some(open),
+        _ => None,
+      })
+    };
+    let found = partner(at).or_else(|| at.checked_sub(1).and_then(partner));
+    match found {
+      Some(pos) => Json::object(vec![
+        ("uri", uri.into()),
+        ("range", range(text, pos, pos + 1)),
+      ]),
+      None => Json::Null,
+    }
+  }
+
+  /// The innermost loop around `params`' position, folded at -O1. The
+  /// passes of -O2 assume the tape the program starts with, which a loop
+  /// in the middle of it does not see.
+  fn hover(&self, text: &str, params: &Json) -> Json {
+    let Some(at) = offset(text, params.get("position")) else {
+      return Json::Null;
+    };
+    let tokens = self.tokens(text);
+    let innermost = pairs(&tokens)
+      .into_iter()
+      .filter(|&(open, close)| open <= at && at <= close)
+      .max_by_key(|&(open, _)| open);
+    let Some((open, close)) = innermost else {
+      return Json::Null;
+    };
+    let region: Vec<(Token, usize)> = tokens
+      .into_iter()
+      .filter(|&(_, pos)| open <= pos && pos <= close)
+      .collect();
+    let Ok(program) = parse_program(region) else {
+      return Json::Null;
+    };
+    let (program, _) = optimizer::optimize(program, &optimizer::preset(Level::O1, true), 0, 0);
+    let mut value = String::from("```\n");
+    for (index, inst) in program.iter().enumerate() {
+      value.push_str(&format!("{:>4}  {:?}\n", index, inst.op));
+    }
+    value.push_str("```");
+    Json::object(vec![
+      (
+        "contents",
+        Json::object(vec![("kind", "markdown".into()), ("value", value.into())]),
+      ),
+      ("range", range(text, open, close + 1)),
+    ])
+  }
+
+  /// The edit reprinting the document as `fmt` would.
+  fn formatting(&self, text: &str) -> Result<Json, (i32, String)> {
+    let tokens = self.tokens(text);
+    parse_program(tokens.clone()).map_err(|error| (REQUEST_FAILED, error))?;
+    let formatted = format::format(text, &tokens, format::DEFAULT_WIDTH);
+    if formatted == text {
+      return Ok(Json::Array(Vec::new()));
+    }
+    Ok(Json::Array(vec![Json::object(vec![
+      ("range", range(text, 0, text.len())),
+      ("newText", formatted.into()),
+    ])]))
+  }
+
+  /// Handles one message, returning whether the client asked to exit.
+  fn handle(&mut self, message: &Json) -> io::Result<bool> {
+    let id = message.get("id").clone();
+    let params = message.get("params");
+    let uri = params.get("textDocument").get("uri").as_str().unwrap_or("");
+    let text = self.documents.get(uri).cloned();
+    match message.get("method").as_str() {
+      Some("initialize") => {
+        let capabilities = Json::object(vec![
+          ("textDocumentSync", Json::Number(1.0)),
+          ("definitionProvider", true.into()),
+          ("hoverProvider", true.into()),
+          ("documentFormattingProvider", true.into()),
+        ]);
+        let result = Json::object(vec![
+          ("capabilities", capabilities),
+          (
+            "serverInfo",
+            Json::object(vec![("name", env!("CARGO_PKG_NAME").into())]),
+          ),
+        ]);
+        self.respond(id, Ok(result))?;
+      }
+      Some("shutdown") => self.respond(id, Ok(Json::Null))?,
+      Some("exit") => return Ok(true),
+      Some("textDocument/didOpen") => {
+        let text = params
+          .get("textDocument")
+          .get("text")
+          .as_str()
+          .unwrap_or("");
+        self.documents.insert(uri.to_string(), text.to_string());
+        self.diagnose(uri)?;
+      }
+      Some("textDocument/didChange") => {
+        let changes = params.get("contentChanges").as_array();
+        if let Some(text) = changes
+          .last()
+          .and_then(|change| change.get("text").as_str())
+        {
+          self.documents.insert(uri.to_string(), text.to_string());
+        }
+        self.diagnose(uri)?;
+      }
+      Some("textDocument/didClose") => {
+        self.documents.remove(uri);
+        self.diagnose(uri)?;
+      }
+      Some("textDocument/definition") => {
+        let result = text.map_or(Json::Null, |text| self.definition(uri, &text, params));
+        self.respond(id, Ok(result))?;
+      }
+      Some("textDocument/hover") => {
+        let result = text.map_or(Json::Null, |text| self.hover(&text, params));
+        self.respond(id, Ok(result))?;
+      }
+      Some("textDocument/formatting") => {
+        let result = match text {
+          Some(text) => self.formatting(&text),
+          None => Err((REQUEST_FAILED, format!("{} is not open", uri))),
+        };
+        self.respond(id, result)?;
+      }
+      // Other notifications, and responses to requests never sent.
+      _ if id == Json::Null => (),
+      method => {
+        let method = method.unwrap_or("").to_string();
+        self.respond(
+          id,
+          Err((METHOD_NOT_FOUND, format!("unknown method {}", method))),
+        )?;
+      }
+    }
+    Ok(false)
+  }
+}
+
+/// Serves the client writing to `input` and reading from `output` until
+/// it exits or closes `input`.
+pub fn serve(config: &Config, input: &mut dyn BufRead, output: &mut dyn Write) -> io::Result<()> {
+  let mut server = Server {
+    config,
+    output,
+    documents: HashMap::new(),
+  };
+  while let Some(message) = read_message(input)? {
+    if server.handle(&message)? {
+      break;
+    }
+  }
+  Ok(())
+}
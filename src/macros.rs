@@ -0,0 +1,338 @@
+//! The preprocessor `--macros` runs before lexing.
+//!
+//! `@include "lib/digits.bf"` is replaced by the file named, relative to
+//! the file that includes it. `@define print_nl { ++++++++++ . [-] }`
+//! defines a macro anywhere in the program, and `@print_nl` elsewhere,
+//! including in the bodies of other macros, expands to its body. Braces
+//! inside a body must balance. An `@` not followed by a name is left alone,
+//! but one followed by a name no macro has is an error, unless it names
+//! one of the `snippets`.
+//!
+//! Every byte of the expansion remembers where it was written, so
+//! instructions built from a macro point into its definition. Positions
+//! count through every file read, one after another with the main file
+//! first, so those in the main file are plain offsets into it.
+
+use std::collections::HashMap;
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use super::snippets::{self, Snippet};
+use super::Token;
+
+/// Default number of levels macro expansions may nest.
+pub const DEFAULT_DEPTH: usize = 64;
+
+/// A file read into `Expansion::source`.
+#[derive(Debug)]
+struct File {
+  path: PathBuf,
+  /// The canonical path, which identifies the file.
+  key: PathBuf,
+  range: Range<usize>,
+}
+
+/// Source text with its includes and macros expanded.
+#[derive(Debug)]
+pub struct Expansion {
+  pub text: String,
+  /// Position in `source` of each byte of `text`.
+  origins: Vec<usize>,
+  /// Every file read, one after another with the main file first.
+  source: String,
+  files: Vec<File>,
+  /// The snippets called, numbered from `Snippets::Call::first` in order.
+  pub snippets: Vec<&'static Snippet>,
+  /// Offsets in `text` of the `*` of each call of a snippet.
+  pub calls: Vec<usize>,
+}
+
+/// What `@name` for one of the `snippets` expands to.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Snippets {
+  /// Its code.
+  Inline,
+  /// A call of it as the procedure numbered `first` plus the number of
+  /// other snippets called before it first was.
+  Call { first: usize },
+}
+
+impl Expansion {
+  /// Maps the positions of `tokens`, lexed from `text`, back to the source.
+  pub fn map(&self, tokens: Vec<(Token, usize)>) -> Vec<(Token, usize)> {
+    tokens
+      .into_iter()
+      .map(|(token, pos)| (token, self.origins[pos]))
+      .collect()
+  }
+
+  /// The file a position in `source` falls in and the offset within it.
+  pub fn locate(&self, pos: usize) -> (&Path, usize) {
+    let file = self
+      .files
+      .iter()
+      .find(|file| file.range.contains(&pos))
+      .unwrap_or(&self.files[0]);
+    (&file.path, pos - file.range.start.min(pos))
+  }
+
+  /// Describes a position in `source` for an error message.
+  fn describe(&self, pos: usize) -> String {
+    match self.locate(pos) {
+      (_, offset) if self.files[0].range.contains(&pos) => format!("byte {}", offset),
+      (path, offset) => format!("byte {} of {}", offset, path.display()),
+    }
+  }
+
+  /// Appends `path`, whose contents are `text`, with the files it includes
+  /// in place of its `@include`s. `including` lists the keys and paths of
+  /// the files whose `@include`s are being followed.
+  fn include(
+    &mut self,
+    path: &Path,
+    text: &str,
+    including: &mut Vec<(PathBuf, PathBuf)>,
+  ) -> Result<(), String> {
+    let key = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    if let Some(first) = including.iter().position(|(file, _)| *file == key) {
+      let cycle: Vec<String> = including[first..]
+        .iter()
+        .map(|(_, path)| path.as_path())
+        .chain([path])
+        .map(|path| path.display().to_string())
+        .collect();
+      return Err(format!("files include each other: {}", cycle.join(" -> ")));
+    }
+    // A file included again keeps the positions it was first read at.
+    let start = match self.files.iter().find(|file| file.key == key) {
+      Some(file) => file.range.start,
+      None => {
+        let start = self.source.len();
+        self.source.push_str(text);
+        self.files.push(File {
+          path: path.to_path_buf(),
+          key: key.clone(),
+          range: start..self.source.len(),
+        });
+        start
+      }
+    };
+    including.push((key, path.to_path_buf()));
+    let mut copied = 0;
+    let mut search = 0;
+    while let Some(found) = text[search..].find("@include") {
+      let at = search + found;
+      search = at + "@include".len();
+      if name_at(text, at + 1) != "include" {
+        continue;
+      }
+      let open = skip_space(text, search);
+      let name = text[open..]
+        .strip_prefix('"')
+        .and_then(|rest| rest.split_once('"'))
+        .map(|(name, _)| name)
+        .ok_or_else(|| {
+          format!(
+            "@include at {} expects a quoted file name",
+            self.describe(start + at)
+          )
+        })?;
+      self.copy(text, start, copied..at);
+      let target = path.parent().unwrap_or(Path::new("")).join(name);
+      let contents = fs::read_to_string(&target).map_err(|e| {
+        format!(
+          "@include at {} cannot read {}: {}",
+          self.describe(start + at),
+          target.display(),
+          e
+        )
+      })?;
+      self.include(&target, &contents, including)?;
+      copied = open + name.len() + 2;
+      search = copied;
+    }
+    self.copy(text, start, copied..text.len());
+    including.pop();
+    Ok(())
+  }
+
+  /// Appends `text[range]`, from the file at `start` in `source`.
+  fn copy(&mut self, text: &str, start: usize, range: Range<usize>) {
+    self.origins.extend(range.clone().map(|pos| start + pos));
+    self.text.push_str(&text[range]);
+  }
+
+  /// Collects the definitions in `text`, which `origins` maps to the
+  /// source, returning the body of each macro and the ranges of `text`
+  /// outside any definition.
+  fn definitions<'t>(
+    &self,
+    text: &'t str,
+    origins: &[usize],
+  ) -> Result<(Macros<'t>, Vec<Range<usize>>), String> {
+    let mut macros = Macros::new();
+    let mut rest = Vec::new();
+    let mut start = 0;
+    let mut search = 0;
+    while let Some(found) = text[search..].find("@define") {
+      let at = search + found;
+      search = at + "@define".len();
+      if name_at(text, at + 1) != "define" {
+        continue;
+      }
+      let place = self.describe(origins[at]);
+      let name_start = skip_space(text, search);
+      let name = name_at(text, name_start);
+      if name.is_empty() {
+        return Err(format!("@define at {} has no macro name", place));
+      }
+      let open = skip_space(text, name_start + name.len());
+      if !text[open..].starts_with('{') {
+        return Err(format!("@define {} at {} expects {{", name, place));
+      }
+      let mut depth = 0;
+      let close = text[open..]
+        .char_indices()
+        .find(|&(_, c)| {
+          match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            _ => (),
+          }
+          depth == 0
+        })
+        .map(|(pos, _)| open + pos)
+        .ok_or_else(|| format!("@define {} at {} is never closed", name, place))?;
+      let body = open + 1..close;
+      // A file included twice defines its macros twice, but alike.
+      let previous = macros.insert(name, Macro { at, body });
+      if let Some(previous) = previous.filter(|previous| origins[previous.at] != origins[at]) {
+        return Err(format!(
+          "macro {} is defined twice, at {} and {}",
+          name,
+          self.describe(origins[previous.at]),
+          place
+        ));
+      }
+      rest.push(start..at);
+      start = close + 1;
+      search = start;
+    }
+    rest.push(start..text.len());
+    Ok((macros, rest))
+  }
+
+  /// Appends `text[range]`, expanding at most `depth` levels of macros.
+  fn expand_range(
+    &mut self,
+    text: &str,
+    origins: &[usize],
+    range: Range<usize>,
+    macros: &Macros,
+    depth: usize,
+    mode: Snippets,
+  ) -> Result<(), String> {
+    let mut pos = range.start;
+    while pos < range.end {
+      let c = text[pos..].chars().next().unwrap();
+      let name = if c == '@' { name_at(text, pos + 1) } else { "" };
+      if name.is_empty() {
+        self.text.push(c);
+        self.origins.extend(&origins[pos..pos + c.len_utf8()]);
+        pos += c.len_utf8();
+        continue;
+      }
+      let place = self.describe(origins[pos]);
+      let body = match macros.get(name) {
+        Some(definition) => definition.body.clone(),
+        None if name == "define" => return Err(format!("@define at {} is inside a macro", place)),
+        None => match snippets::lookup(name) {
+          Some(snippet) => {
+            self.snippet(snippet, origins[pos], mode);
+            pos += 1 + name.len();
+            continue;
+          }
+          None => return Err(format!("@{} at {} names no macro", name, place)),
+        },
+      };
+      if depth == 0 {
+        return Err(format!(
+          "@{} at {} nests macros too deeply; see --macro-depth",
+          name, place
+        ));
+      }
+      self.expand_range(text, origins, body, macros, depth - 1, mode)?;
+      pos += 1 + name.len();
+    }
+    Ok(())
+  }
+
+  /// Appends the expansion of `snippet`, all of which is from `origin`.
+  fn snippet(&mut self, snippet: &'static Snippet, origin: usize, mode: Snippets) {
+    let code = match mode {
+      Snippets::Inline => snippet.code.to_string(),
+      Snippets::Call { first } => {
+        let number = match self
+          .snippets
+          .iter()
+          .position(|&used| used.name == snippet.name)
+        {
+          Some(number) => number,
+          None => {
+            self.snippets.push(snippet);
+            self.snippets.len() - 1
+          }
+        };
+        let call = snippet.call(first + number);
+        self.calls.push(self.text.len() + call.find('*').unwrap());
+        call
+      }
+    };
+    self.origins.extend(std::iter::repeat_n(origin, code.len()));
+    self.text.push_str(&code);
+  }
+}
+
+struct Macro {
+  /// Offset of the `@define`.
+  at: usize,
+  body: Range<usize>,
+}
+
+/// The macros of a program by name.
+type Macros<'t> = HashMap<&'t str, Macro>;
+
+/// The name starting at `at`, if any.
+fn name_at(text: &str, at: usize) -> &str {
+  let len = text[at..]
+    .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+    .unwrap_or(text.len() - at);
+  &text[at..at + len]
+}
+
+/// Offset of the first character at or after `at` that is not whitespace.
+fn skip_space(text: &str, at: usize) -> usize {
+  at + (text.len() - at - text[at..].trim_start().len())
+}
+
+/// Expands the includes and macros of `source`, read from `path`, nesting at
+/// most `depth` macros.
+pub fn expand(path: &str, source: &str, depth: usize, mode: Snippets) -> Result<Expansion, String> {
+  let mut expansion = Expansion {
+    text: String::new(),
+    origins: Vec::new(),
+    source: String::new(),
+    files: Vec::new(),
+    snippets: Vec::new(),
+    calls: Vec::new(),
+  };
+  expansion.include(Path::new(path), source, &mut Vec::new())?;
+  let text = std::mem::take(&mut expansion.text);
+  let origins = std::mem::take(&mut expansion.origins);
+  let (macros, rest) = expansion.definitions(&text, &origins)?;
+  for range in rest {
+    expansion.expand_range(&text, &origins, range, &macros, depth, mode)?;
+  }
+  Ok(expansion)
+}
@@ -2,292 +2,1836 @@ use std::env;
 use std::error::Error;
 use std::fs::File;
 use std::io::prelude::*;
-use std::io::ErrorKind;
-
-#[derive(PartialEq, Copy, Clone, Debug)]
-enum Token {
-  Plus,
-  Minus,
-  Right,
-  Left,
-  PutChar,
-  ReadChar,
-  JumpIfZero,
-  JumpIfNonZero,
+use std::io::{BufWriter, ErrorKind};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use brainfuck::interpreter::cell::{Cell, CellType};
+
+#[cfg(feature = "jit")]
+use brainfuck::jit;
+use brainfuck::{
+  aarch64, analyze, backend, bench, bf, cache, cfg, classfile, constants, coverage, dap, evaluate,
+  format, fuzz, golden, has_forks, heatmap, inspect, interpreter, jasmin, krakatau, lex_bytes,
+  link, lsp, macros, metrics, obfuscate, optimizer, parse_program, parse_unmixed, preset, profile,
+  replay, report, riscv64, selftest, source::Source, tally, token_map, trace, x86_64, Commands,
+  Dialect, Inst, Token,
+};
+
+#[derive(Debug)]
+enum Command {
+  Compile,
+  Run,
+  /// Prints a program printing the text given in place of a file.
+  GenText,
+  /// Formats the file in place.
+  Fmt,
+  /// Prints the file without comments.
+  Minify,
+  /// Prints a longer program doing the same as the file.
+  Obfuscate,
+  /// Prints the control-flow graph of the optimized program in DOT.
+  Graph,
+  /// Reports likely mistakes in the file.
+  Analyze,
+  /// Times the optimized program under each way of running it.
+  Bench,
+  /// Compares random programs in the interpreter and a backend.
+  Fuzz,
+  /// Runs the programs of the directory given in place of a file that
+  /// have expected output.
+  Test,
+  /// Serves the Language Server Protocol over standard input and output.
+  Lsp,
+  /// Serves the Debug Adapter Protocol over standard input and output.
+  Dap,
+  /// Prints the file shaded by how often a profile says each command ran.
+  Heatmap,
+  /// Prints figures about the file, such as its command counts.
+  Stats,
+  /// Scrubs through the binary trace given in place of a file.
+  TraceView,
+  /// Assembles the Jasmin file given in place of a source into a class.
+  Asm,
+  /// Runs the file under several backends and shows where their output
+  /// parts.
+  DiffBackends,
+  /// Runs the sample programs built in through the interpreter and every
+  /// backend that can be run.
+  SelfTest,
 }
 
-#[derive(Copy, Clone, Debug)]
-struct Inst {
-  typ: Token,
-  argument: usize,
+struct Options {
+  command: Command,
+  filename: String,
+  /// Files compiled along with `filename`, which make a batch.
+  files: Vec<String>,
+  /// Threads a batch is compiled on.
+  jobs: usize,
+  language: Dialect,
+  token_map: Option<String>,
+  /// Files linked into the program, which `*` calls by number.
+  modules: Vec<String>,
+  /// Bytes the tape starts with.
+  init_tape: Vec<u8>,
+  /// Commands of no dialect the lexer recognizes.
+  commands: Commands,
+  /// Line width of `fmt`.
+  width: usize,
+  /// Whether `fmt` only checks that the file is formatted.
+  check: bool,
+  /// Whether `minify` also drops what cancels out and loops never entered.
+  shorten: bool,
+  /// Warning codes `analyze` leaves out.
+  allow: Vec<&'static str>,
+  /// The backend `fuzz` compares with the interpreter.
+  against: fuzz::Target,
+  /// Programs `fuzz` tries.
+  runs: usize,
+  /// The file `diff-backends` gives the program as input.
+  input: Option<String>,
+  /// What `diff-backends` runs the program on, the first giving the output
+  /// the others are compared with.
+  backends: Vec<fuzz::Target>,
+  /// Whether `heatmap` writes HTML rather than colored text.
+  html: bool,
+  /// How many levels macros may nest, when `--macros` expands them.
+  macros: Option<usize>,
+  /// Whether snippets the macros name are called as procedures.
+  inject_snippets: bool,
+  /// Whether the source file is mapped rather than read.
+  mmap: bool,
+  /// Whether `run` and `compile` reuse what they built before from
+  /// `.brainrust-cache`.
+  cache: bool,
+  emit: &'static dyn backend::Backend,
+  /// The name `emit` is registered under, however `--emit` spelled it.
+  emit_name: &'static str,
+  jit: bool,
+  passes: Vec<&'static str>,
+  opt_stats: bool,
+  explain_opts: Option<report::Format>,
+  eval_budget: usize,
+  unroll_limit: usize,
+  profile: Option<String>,
+  /// Where `run` writes the lcov tracefile.
+  coverage: Option<String>,
+  stats_json: Option<String>,
+  eof: interpreter::Eof,
+  io: interpreter::Io,
+  /// What the cells of `run` hold.
+  cell_type: CellType,
+  /// Seed of the generator `?` draws from.
+  seed: Option<i64>,
+  trap_overflow: bool,
+  exit_cell: Option<interpreter::ExitCell>,
+  /// Whether `run` prints the cells it left set to stderr.
+  dump_tape: bool,
+  /// Where `run` writes the tape it left.
+  dump_tape_out: Option<String>,
+  /// Whether `run` answers queries about the tape it left.
+  inspect: bool,
+  jvm: jasmin::Config,
+  class_version: u16,
+  d8: String,
+  linker: String,
+  dialect: krakatau::Dialect,
+  syntax: x86_64::Syntax,
+  os: aarch64::Os,
+  riscv_io: riscv64::Io,
+  js_module: bool,
+  debug_info: bool,
+  trace: trace::TraceOptions,
 }
 
-type LabelStack = Vec<usize>;
+const USAGE: &str = "usage: brainfuck [compile] <file>... [options]
+       brainfuck run <file> [options]
+       brainfuck gen-text <text>
+       brainfuck fmt <file> [--width <n>] [--check]
+       brainfuck minify <file> [--shorten]
+       brainfuck obfuscate <file> [--seed <n>]
+       brainfuck graph <file> [options]
+       brainfuck analyze <file> [--allow <codes>]
+       brainfuck bench <file> [options]
+       brainfuck test <dir> [options]
+       brainfuck fuzz [--against <class|c|python|js|jit>] [--runs <n>] [options]
+       brainfuck lsp [--dialect <dialect>] [--debug-dumps] [--decimal-io]
+       brainfuck dap [options]
+       brainfuck heatmap <file> --profile <file> [--html]
+       brainfuck stats <file> [options]
+       brainfuck trace-view <trace>
+       brainfuck asm <file.j> [--class-version <n>]
+       brainfuck diff-backends <file> [--input <file>] [--backends <name,...>]
+       brainfuck selftest [options]
+
+options:
+  --dialect <brainfuck|pbrain|brainfork|ebf1|extended|multitape|brainbool>
+                            source language (default brainfuck); pbrain adds
+                            procedures, brainfork adds threads, ebf1 adds
+                            Extended Brainfuck Type I's @ $ ! } { ~ ^ & |,
+                            which only run and JVM output support, extended
+                            adds ?, storing a random byte, and multitape a
+                            second tape, % switching to the other one and =
+                            copying the current cell onto it, which only
+                            they support too; brainbool has cells of one
+                            bit, which + flips, printed and read as 0 or 1
+  --token-map <file>        lex the commands as spelled in <file>, with lines
+                            such as plus = \"Ook. Ook.\" naming plus, minus,
+                            right, left, output, input, open and close
+  --module <file>           link <file> into the program, where * calls the
+                            module the current cell numbers, from 0 in the
+                            order given, on the tape as it is; JVM output
+                            makes each a static method
+  --init-tape <file>        start the tape with the bytes of <file>, in run
+                            and in every backend's output
+  --init-hex <hex>          start the tape with these bytes, such as
+                            48656c6c6f
+  --debug-dumps             make # print the pointer and the cells around it
+                            to stderr with run and in JVM output, instead of
+                            ignoring it
+  --decimal-io              make : print the current cell as a decimal number
+                            and ; read one into it, with run and in JVM
+                            output; not with pbrain, whose : calls
+  --width <n>               line width fmt fills with commands (default 72)
+  --check                   make fmt fail when the file is not formatted,
+                            instead of formatting it in place
+  --shorten                 make minify also drop commands that cancel out and
+                            loops that are never entered
+  --against <target>        backend fuzz runs against the interpreter: class on
+                            java (default), c built by cc, python on python3,
+                            js on node, jit, or interp, the interpreter on
+                            the optimized program
+  --runs <n>                random programs fuzz tries (default 100)
+  --input <file>            what diff-backends gives the program to read
+  --backends <name,...>     the targets diff-backends runs, named as for
+                            --against, each compared with the first (default
+                            interp and those selftest tries)
+  --html                    make heatmap write an HTML page instead of text
+                            colored for a terminal
+  --allow <code,...>        leave these warnings out of analyze: W001 brackets
+                            without a partner, W002 loops that never run,
+                            W003 loops that never end once entered, W004
+                            moves off the tape, W005 unbounded pointers
+  --macros                  before lexing, replace @include \"file\" with the
+                            file, relative to the including one, and expand
+                            @define name { ... } definitions at @name uses;
+                            @print_decimal, @read_line and @memset expand
+                            to routines of the library unless defined
+  --macro-depth <n>         levels --macros expansions may nest (default 64)
+  --inject-snippets         with --macros, link each library routine used
+                            into the program once, as a procedure numbered
+                            after the modules, and call it at each use; JVM
+                            output makes each a static method
+  --mmap                    map the source file instead of reading it, which
+                            lexes sources of many megabytes in place (mmap
+                            feature)
+  --cache                   keep the optimized program and compiled output in
+                            .brainrust-cache, keyed by a hash of the source
+                            and the options, in whatever order they were
+                            given, and reuse them when neither changed
+  --jobs <n>                compile several files on n threads, by default one
+                            per core; each file's output goes into a directory
+                            named after it
+  -O0, -O1, -O2             optimization level (default -O1; -O2 adds dead
+                            loop elimination, constant-cell analysis and
+                            compile-time evaluation of input-free code)
+  --emit, --backend <jasmin|class|jar|java|dex|llvm|wasm|wat|exe|x86_64|aarch64|riscv64|c|rust|js|python|ir|brir|bf|pseudo>
+                            write Jasmin to main.j (default), a runnable
+                            Main.class or <file>.jar, Java source to
+                            Main.java, a native executable <file> (exe
+                            feature), a Main.class for Android that D8
+                            turns into classes.dex when it is installed,
+                            LLVM IR for clang to main.ll, a WebAssembly
+                            module importing env.read_byte and
+                            env.write_byte to main.wasm or main.wat,
+                            x86-64, AArch64 or RISC-V assembly to main.s,
+                            C to main.c, Rust to main.rs, a Node script to
+                            main.js, or a Python script to main.py;
+                            print the optimized IR with the facts proven
+                            about it, the optimized program as Brainfuck,
+                            or C-like pseudocode of it for reading; or
+                            write the optimized IR to <file>.brir, which
+                            run and compile take in place of a source
+  --no-loop-opts            keep clear and multiplication loops as loops
+  --passes <a,b,...>        run exactly these passes in order instead of the
+                            -O preset: fold, clear-loop, scan-loop, multiply,
+                            offset, peephole, dce, partial-eval, constants
+  --opt-stats               summarize on stderr what each pass did
+  --explain-opts <text|json>
+                            report on stderr every rewrite each pass made,
+                            with its source position
+  --eval-budget <steps>     instructions -O2 may evaluate at compile time
+                            (default 1000000)
+  --unroll-limit <n>        instructions -O2 may unroll a loop with a known
+                            trip count into (default 64, 0 disables)
+  --tape-size <cells>       tape length of run and generated code (default
+                            30000)
+  --tape-from-args          let the generated class take its tape length from
+                            its first command-line argument
+  --input-from-args         make , in the generated class read its first
+                            command-line argument, when given, rather than
+                            standard input: java Main \"some input\"
+  --no-wrap                 let cells of the generated class hold any int
+                            instead of wrapping at 256
+  --trap-overflow           stop with an error naming the cell and the source
+                            position when + goes past 255 or - below 0, in
+                            run and in generated classes; -O1 and up may
+                            merge the + and - of a cell into one change, so
+                            use -O0 to check every step
+  --byte-tape               store the generated class's cells in a byte[]
+  --eof <unchanged|zero|minus-one>
+                            what `,` and `;` store at end of input (default
+                            unchanged)
+  --io <chars|unicode|bytes>
+                            whether . prints the character numbered by the
+                            low byte of the cell (default chars) or the cell
+                            as a code point, with , decoding one, in UTF-8,
+                            or the low byte itself, for binary output;
+                            characters are UTF-8 in every backend, and
+                            unicode only works in run and generated
+                            classes, where it calls for --no-wrap
+  --seed <n>                seed the random bytes of ?, both in run and in
+                            generated classes, whose public static field
+                            Main.random also takes another java.util.Random,
+                            the rewrites obfuscate picks and the programs
+                            fuzz generates
+  --bounds <unchecked|error>
+                            what generated code does when the pointer moves
+                            off the tape: whatever the target does (default
+                            unchecked), or stop with an error and status 1
+                            as run always does
+  --preset <classic|dbfi|extended>
+                            set the cells, what , stores at end of input,
+                            the tape length and --bounds error as a
+                            well-known interpreter has them, in run and
+                            generated code alike: classic for 30000
+                            wrapping bytes leaving the cell at end of input,
+                            dbfi for the same storing 0, extended for 65536
+                            32-bit cells storing -1; options after it
+                            override it
+  --cell-type <u8|u16|u32|i64|big>
+                            what the cells of run hold (default u8); big
+                            cells never wrap and need the bigint feature.
+                            Other than u8 rules out the passes that evaluate
+                            the program while compiling it; pbrain still
+                            numbers procedures 0 to 255 on wider cells
+  --unbuffered              make the generated class write each character
+                            as it is produced
+  --no-cell-cache           load and store the tape for every operation of
+                            the generated class instead of keeping the
+                            current cell in a local
+  --embeddable              put the program in a public static
+                            run(InputStream, OutputStream) that main calls
+  --runtime-args            let the generated main take --tape <cells>,
+                            --in <file> and --out <file> (implies
+                            --embeddable)
+  --sandbox                 make the generated class count loop iterations
+                            and throw once they exceed a budget, so that an
+                            untrusted program runs for a bounded time
+  --sandbox-steps <n>       the budget of --sandbox (default 1000000000)
+  --exit-from-cell <current|first>
+                            exit with the final value of the current or the
+                            first cell, both in run and in generated classes
+  --dump-tape               after run, print the cells the program left set to
+                            stderr in hex and ASCII, with their addresses
+  --dump-tape-out <file>    after run, write the whole tape to <file>, one
+                            byte per cell
+  --inspect                 after run, answer queries about the tape from the
+                            terminal: p <addr>, range <a> <b>, find <value>
+  --method-size <bytes>     move code into further methods once main would
+                            exceed this size (default 7500, under the
+                            8000 bytes HotSpot compiles a method up to)
+  --asm-dialect <jasmin|krakatau>
+                            assembler syntax of main.j (default jasmin)
+  --asm-syntax <att|intel>  syntax of x86-64 assembly (default att)
+  --target-os <linux|macos> platform AArch64 assembly is written for
+                            (default the host's)
+  --riscv-io <libc|ecall>   whether RISC-V assembly is a main calling libc
+                            (default) or a _start making Linux system calls
+  --js-module               make --emit js write an ES module exporting
+                            run(input), returning the output, to main.mjs
+  --no-debug-info           leave out the source file name and line numbers
+                            that map generated code back to <file>
+  --source-map              with --emit jasmin, also write main.j.map, JSON
+                            giving the method, lines and source bytes of
+                            each region of the code
+  --deterministic           output that depends only on the source and the
+                            options: labels numbered in order rather than by
+                            position in the optimized program, and ? seeded
+                            with 0 unless --seed is given
+  --class-version <49-65>   major version of --emit class and jar output and
+                            of asm (default 52); 50 and later carry stack map
+                            frames
+  --d8 <path>               D8 executable for --emit dex (default d8)
+  --linker <path>           C compiler that links --emit exe (default cc)
+  --profile <file>          with run, record how often each instruction
+                            executes; with compile, read such a profile,
+                            show it in --emit ir, give hot loops and scans
+                            of JVM output faster, larger code and keep them
+                            in the methods chunks are called from; with
+                            heatmap, shade the source by it
+  --coverage <file>         with run, write which commands ran to <file> as
+                            lcov and to <file's name>.cov as annotated
+                            source; runs unoptimized so each is counted
+  --stats-json <file>       with run, write to <file> a JSON report of the
+                            steps, wall time, highest cell reached, bytes
+                            read and written and steps of each operation
+  --jit                     run natively through Cranelift (jit feature)
+  --trace                   log each executed instruction to stderr
+  --trace-out <file>        write a binary execution trace to <file>, which
+                            trace-view scrubs through
+  --trace-io                only trace `.` and `,`
+  --trace-range <start>:<end>
+                            only trace instructions from this source byte range";
+
+fn invalid_input(message: String) -> Box<dyn Error> {
+  Box::new(std::io::Error::new(ErrorKind::InvalidInput, message))
+}
 
-fn label_push(stack: &mut LabelStack) -> usize {
-  let last = match stack.last() {
-    Some(&last) => last,
-    None => 0,
+fn parse_args(args: Vec<String>) -> Result<Options, Box<dyn Error>> {
+  let mut command = None;
+  let mut filename = None;
+  let mut files = Vec::new();
+  let mut jobs = None;
+  let mut language = Dialect::Brainfuck;
+  let mut token_map = None;
+  let mut modules = Vec::new();
+  let mut init_tape = Vec::new();
+  let mut width = format::DEFAULT_WIDTH;
+  let mut check = false;
+  let mut shorten = false;
+  let mut allow = Vec::new();
+  let mut against = fuzz::Target::Class;
+  let mut runs = 100;
+  let mut input = None;
+  let mut backends = None;
+  let mut html = false;
+  let mut commands = Commands::default();
+  let mut macros = false;
+  let mut macro_depth = macros::DEFAULT_DEPTH;
+  let mut inject_snippets = false;
+  let mut mmap = false;
+  let mut cache = false;
+  let mut emit = backend::lookup("jasmin").unwrap();
+  let mut emit_name = "jasmin";
+  let mut jit = false;
+  let mut opt_level = optimizer::Level::O1;
+  let mut loop_opts = true;
+  let mut passes = None;
+  let mut opt_stats = false;
+  let mut explain_opts = None;
+  let mut eval_budget = evaluate::DEFAULT_BUDGET;
+  let mut unroll_limit = constants::DEFAULT_UNROLL_LIMIT;
+  let mut profile = None;
+  let mut coverage = None;
+  let mut stats_json = None;
+  let mut jvm = jasmin::Config::default();
+  let mut class_version = classfile::DEFAULT_VERSION;
+  let mut d8 = "d8".to_string();
+  let mut linker = "cc".to_string();
+  let mut dialect = krakatau::Dialect::Jasmin;
+  let mut syntax = x86_64::Syntax::Att;
+  let mut os = aarch64::Os::host();
+  let mut riscv_io = riscv64::Io::Libc;
+  let mut js_module = false;
+  let mut debug_info = true;
+  let mut eof = interpreter::Eof::default();
+  let mut io = interpreter::Io::default();
+  let mut sandbox = false;
+  let mut sandbox_steps = jasmin::STEP_BUDGET;
+  let mut cell_type = CellType::default();
+  let mut seed = None;
+  let mut exit_cell = None;
+  let mut dump_tape = false;
+  let mut dump_tape_out = None;
+  let mut inspect = false;
+  let mut trace = trace::TraceOptions::default();
+  let mut args = args.into_iter();
+  while let Some(arg) = args.next() {
+    let mut value = |flag: &str| {
+      args
+        .next()
+        .ok_or_else(|| invalid_input(format!("{} expects a value", flag)))
+    };
+    match arg.as_str() {
+      "--emit" | "--backend" => {
+        let name = value(&arg)?;
+        emit = backend::lookup(&name)
+          .ok_or_else(|| invalid_input(format!("unknown output kind {}", name)))?;
+        emit_name = backend::canonical(&name).unwrap();
+      }
+      "--dialect" => language = Dialect::parse(&value("--dialect")?).map_err(invalid_input)?,
+      "--token-map" => token_map = Some(value("--token-map")?),
+      "--module" => modules.push(value("--module")?),
+      "--init-tape" => init_tape = std::fs::read(value("--init-tape")?)?,
+      "--init-hex" => init_tape = link::parse_hex(&value("--init-hex")?).map_err(invalid_input)?,
+      "--debug-dumps" => commands.debug_dumps = true,
+      "--decimal-io" => commands.decimal_io = true,
+      "--macros" => macros = true,
+      "--macro-depth" => macro_depth = value("--macro-depth")?.parse()?,
+      "--inject-snippets" => inject_snippets = true,
+      "--mmap" => mmap = true,
+      "--cache" => cache = true,
+      "--jobs" => jobs = Some(value("--jobs")?.parse()?),
+      "--jit" => jit = true,
+      "-O0" => opt_level = optimizer::Level::O0,
+      "-O1" => opt_level = optimizer::Level::O1,
+      "-O2" => opt_level = optimizer::Level::O2,
+      "--no-loop-opts" => loop_opts = false,
+      "--passes" => {
+        passes = Some(optimizer::parse_passes(&value("--passes")?).map_err(invalid_input)?)
+      }
+      "--opt-stats" => opt_stats = true,
+      "--explain-opts" => {
+        explain_opts = Some(report::parse_format(&value("--explain-opts")?).map_err(invalid_input)?)
+      }
+      "--eval-budget" => eval_budget = value("--eval-budget")?.parse()?,
+      "--unroll-limit" => unroll_limit = value("--unroll-limit")?.parse()?,
+      "--tape-size" => jvm.tape_size = value("--tape-size")?.parse()?,
+      "--tape-from-args" => jvm.tape_from_args = true,
+      "--input-from-args" => jvm.input_from_args = true,
+      "--no-wrap" => jvm.wrap = false,
+      "--trap-overflow" => jvm.trap_overflow = true,
+      "--byte-tape" => jvm.byte_tape = true,
+      "--unbuffered" => jvm.buffered = false,
+      "--no-cell-cache" => jvm.cell_cache = false,
+      "--embeddable" => jvm.embeddable = true,
+      "--runtime-args" => jvm.runtime_args = true,
+      "--sandbox" => sandbox = true,
+      "--sandbox-steps" => sandbox_steps = value("--sandbox-steps")?.parse()?,
+      "--exit-from-cell" => {
+        exit_cell =
+          Some(interpreter::ExitCell::parse(&value("--exit-from-cell")?).map_err(invalid_input)?)
+      }
+      "--dump-tape" => dump_tape = true,
+      "--dump-tape-out" => dump_tape_out = Some(value("--dump-tape-out")?),
+      "--inspect" => inspect = true,
+      "--method-size" => jvm.method_size = value("--method-size")?.parse()?,
+      "--d8" => d8 = value("--d8")?,
+      "--linker" => linker = value("--linker")?,
+      "--asm-dialect" => {
+        dialect = krakatau::Dialect::parse(&value("--asm-dialect")?).map_err(invalid_input)?
+      }
+      "--asm-syntax" => {
+        syntax = x86_64::Syntax::parse(&value("--asm-syntax")?).map_err(invalid_input)?
+      }
+      "--target-os" => os = aarch64::Os::parse(&value("--target-os")?).map_err(invalid_input)?,
+      "--riscv-io" => {
+        riscv_io = riscv64::Io::parse(&value("--riscv-io")?).map_err(invalid_input)?
+      }
+      "--js-module" => js_module = true,
+      "--no-debug-info" => debug_info = false,
+      "--source-map" => jvm.source_map = true,
+      "--deterministic" => jvm.deterministic = true,
+      "--class-version" => class_version = value("--class-version")?.parse()?,
+      "--eof" => eof = interpreter::Eof::parse(&value("--eof")?).map_err(invalid_input)?,
+      "--seed" => seed = Some(value("--seed")?.parse()?),
+      "--io" => io = interpreter::Io::parse(&value("--io")?).map_err(invalid_input)?,
+      "--bounds" => {
+        jvm.bounds = interpreter::Bounds::parse(&value("--bounds")?).map_err(invalid_input)?
+      }
+      "--cell-type" => {
+        cell_type = CellType::parse(&value("--cell-type")?).map_err(invalid_input)?
+      }
+      "--preset" => {
+        let preset = preset::lookup(&value("--preset")?).map_err(invalid_input)?;
+        cell_type = preset.cell_type;
+        jvm = preset.configure(jvm);
+        // `eof` is merged into the configuration at the end, with `--eof`.
+        eof = jvm.eof;
+      }
+      "--profile" => profile = Some(value("--profile")?),
+      "--coverage" => coverage = Some(value("--coverage")?),
+      "--stats-json" => stats_json = Some(value("--stats-json")?),
+      "--trace" => trace.to_stderr = true,
+      "--trace-out" => trace.out_file = Some(value("--trace-out")?),
+      "--trace-io" => trace.only_io = true,
+      "--trace-range" => trace.range = Some(trace::parse_range(&value("--trace-range")?)?),
+      "compile" if command.is_none() && filename.is_none() => command = Some(Command::Compile),
+      "run" if command.is_none() && filename.is_none() => command = Some(Command::Run),
+      "gen-text" if command.is_none() && filename.is_none() => command = Some(Command::GenText),
+      "fmt" if command.is_none() && filename.is_none() => command = Some(Command::Fmt),
+      "minify" if command.is_none() && filename.is_none() => command = Some(Command::Minify),
+      "obfuscate" if command.is_none() && filename.is_none() => command = Some(Command::Obfuscate),
+      "graph" if command.is_none() && filename.is_none() => command = Some(Command::Graph),
+      "analyze" if command.is_none() && filename.is_none() => command = Some(Command::Analyze),
+      "bench" if command.is_none() && filename.is_none() => command = Some(Command::Bench),
+      "fuzz" if command.is_none() && filename.is_none() => command = Some(Command::Fuzz),
+      "test" if command.is_none() && filename.is_none() => command = Some(Command::Test),
+      "lsp" if command.is_none() && filename.is_none() => command = Some(Command::Lsp),
+      "dap" if command.is_none() && filename.is_none() => command = Some(Command::Dap),
+      "heatmap" if command.is_none() && filename.is_none() => command = Some(Command::Heatmap),
+      "stats" if command.is_none() && filename.is_none() => command = Some(Command::Stats),
+      "trace-view" if command.is_none() && filename.is_none() => command = Some(Command::TraceView),
+      "selftest" if command.is_none() && filename.is_none() => command = Some(Command::SelfTest),
+      "asm" if command.is_none() && filename.is_none() => command = Some(Command::Asm),
+      "diff-backends" if command.is_none() && filename.is_none() => {
+        command = Some(Command::DiffBackends)
+      }
+      "--html" => html = true,
+      "--against" => against = fuzz::Target::parse(&value("--against")?).map_err(invalid_input)?,
+      "--runs" => runs = value("--runs")?.parse()?,
+      "--input" => input = Some(value("--input")?),
+      "--backends" => {
+        backends = Some(
+          value("--backends")?
+            .split(',')
+            .map(fuzz::Target::parse)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(invalid_input)?,
+        )
+      }
+      "--allow" => allow = analyze::parse_codes(&value("--allow")?).map_err(invalid_input)?,
+      "--shorten" => shorten = true,
+      "--width" => width = value("--width")?.parse()?,
+      "--check" => check = true,
+      _ if arg.starts_with("--") => return Err(invalid_input(format!("unknown option {}", arg))),
+      _ if filename.is_none() => filename = Some(arg),
+      _ if matches!(command, None | Some(Command::Compile)) => files.push(arg),
+      _ => return Err(invalid_input(format!("unexpected argument {}", arg))),
+    }
+  }
+  // Without a seed, `?` would draw from the clock.
+  if jvm.deterministic && seed.is_none() {
+    seed = Some(0);
+  }
+  if jobs == Some(0) {
+    return Err(invalid_input("--jobs must be at least 1".to_string()));
+  }
+  if jvm.tape_size == 0 {
+    return Err(invalid_input("--tape-size must be at least 1".to_string()));
+  }
+  // `fuzz` makes up its programs and `lsp` and `dap` are sent them.
+  let filename = match command {
+    Some(Command::Fuzz | Command::Lsp | Command::Dap | Command::SelfTest) if filename.is_none() => {
+      Some(String::new())
+    }
+    _ => filename,
   };
-  stack.push(last + 1);
-  last + 1
+  match filename {
+    Some(filename) => Ok(Options {
+      command: command.unwrap_or(Command::Compile),
+      filename,
+      files,
+      jobs: jobs.unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get())),
+      language,
+      token_map,
+      modules,
+      init_tape,
+      commands,
+      width,
+      check,
+      shorten,
+      allow,
+      against,
+      runs,
+      input,
+      backends: backends.unwrap_or_else(|| {
+        let mut backends = vec![fuzz::Target::Interpreter];
+        backends.extend(selftest::targets());
+        backends
+      }),
+      html,
+      macros: Some(macro_depth).filter(|_| macros),
+      inject_snippets,
+      mmap,
+      cache,
+      emit,
+      emit_name,
+      jit,
+      passes: match coverage {
+        Some(_) => Vec::new(),
+        None => optimizer::for_cells(
+          passes.unwrap_or_else(|| optimizer::preset(opt_level, loop_opts)),
+          cell_type == CellType::U8 && jvm.wrap && !jvm.trap_overflow,
+        ),
+      },
+      opt_stats,
+      explain_opts,
+      eval_budget,
+      unroll_limit,
+      profile,
+      coverage,
+      stats_json,
+      eof,
+      io,
+      cell_type,
+      seed,
+      trap_overflow: jvm.trap_overflow,
+      exit_cell,
+      dump_tape,
+      dump_tape_out,
+      inspect,
+      jvm: jasmin::Config {
+        eof,
+        io,
+        step_budget: Some(sandbox_steps).filter(|_| sandbox),
+        exit_cell,
+        seed,
+        ..jvm
+      },
+      class_version,
+      d8,
+      linker,
+      dialect,
+      syntax,
+      os,
+      riscv_io,
+      js_module,
+      debug_info,
+      trace,
+    }),
+    None => Err(invalid_input(format!("No input file!\n{}", USAGE))),
+  }
 }
 
-impl Inst {
-  fn to_bytecode(&self, loop_stack: &mut LabelStack) -> String {
-    let arg = self.argument as i32;
-    match self.typ {
-      Token::Plus => bytecode::plus(arg),
-      Token::Minus => bytecode::plus(-arg),
-      Token::Left => bytecode::mov(-arg),
-      Token::Right => bytecode::mov(arg),
-      Token::PutChar => bytecode::out(),
-      Token::ReadChar => bytecode::input(),
-      Token::JumpIfZero => bytecode::loop_start(loop_stack),
-      Token::JumpIfNonZero => bytecode::loop_end(loop_stack),
+/// The commands of a program with their positions, and the expansion those
+/// point into when `--macros` is given.
+type Lexed = (Option<macros::Expansion>, Vec<(Token, usize)>);
+
+/// Expands and lexes `program`, read from `path`, into its commands. Only
+/// macros need it to be UTF-8.
+fn lex_source(options: &Options, path: &str, program: &[u8]) -> Result<Lexed, Box<dyn Error>> {
+  let text = || std::str::from_utf8(program).map_err(|error| format!("{}: {}", path, error));
+  // Modules and the files of a batch, not linked like the program, expand
+  // snippets in place.
+  let snippets = if options.inject_snippets && path == options.filename {
+    macros::Snippets::Call {
+      first: options.modules.len(),
+    }
+  } else {
+    macros::Snippets::Inline
+  };
+  let expansion = match options.macros {
+    Some(depth) => Some(macros::expand(path, text()?, depth, snippets).map_err(invalid_input)?),
+    None => None,
+  };
+  let bytes = expansion
+    .as_ref()
+    .map_or(program, |expansion| expansion.text.as_bytes());
+  let tokens = match &options.token_map {
+    Some(path) => token_map::TokenMap::load(path)?.lex(bytes),
+    None => {
+      let mut tokens =
+        lex_bytes(bytes, options.language, options.commands).map_err(invalid_input)?;
+      // `*` only calls where there are modules to call, or snippets.
+      if !options.modules.is_empty() {
+        let calls = bytes.iter().enumerate().filter(|&(_, &byte)| byte == b'*');
+        tokens.extend(calls.map(|(pos, _)| (Token::CallModule, pos)));
+        tokens.sort_by_key(|&(_, pos)| pos);
+      } else if let Some(expansion) = &expansion {
+        tokens.extend(expansion.calls.iter().map(|&pos| (Token::CallModule, pos)));
+        tokens.sort_by_key(|&(_, pos)| pos);
+      }
+      tokens
     }
+  };
+  // Positions in the expansion are mapped back to where they were written.
+  let tokens = match &expansion {
+    Some(expansion) => expansion.map(tokens),
+    None => tokens,
+  };
+  Ok((expansion, tokens))
+}
+
+/// Parses `tokens`, linking in the `--module` files and the snippets the
+/// expansion calls. With `--trap-overflow`, `+` and `-` are not netted
+/// against each other, so that even -O0 checks each step.
+fn parse_linked(
+  options: &Options,
+  expansion: Option<&macros::Expansion>,
+  tokens: Vec<(Token, usize)>,
+) -> Result<Vec<Inst>, Box<dyn Error>> {
+  let parse_program = if options.jvm.trap_overflow {
+    parse_unmixed
+  } else {
+    parse_program
+  };
+  let mut modules = Vec::new();
+  for path in &options.modules {
+    let (_, tokens) = lex_source(options, path, &std::fs::read(path)?)?;
+    let module = parse_program(tokens).map_err(|error| format!("{}: {}", path, error));
+    modules.push(module.map_err(invalid_input)?);
+  }
+  for snippet in expansion.map_or(&[][..], |expansion| &expansion.snippets) {
+    modules.push(snippet.procedure().map_err(invalid_input)?);
   }
+  if options.init_tape.len() > options.jvm.tape_size {
+    return Err(invalid_input(format!(
+      "the tape starts with {} bytes but has {} cells",
+      options.init_tape.len(),
+      options.jvm.tape_size
+    )));
+  }
+  let mut program = link::initialize(&options.init_tape);
+  program.extend(parse_program(tokens).map_err(invalid_input)?);
+  link::link(modules, program).map_err(invalid_input)
 }
 
-mod bytecode {
-
-  pub fn plus(count: i32) -> String {
-    vec![
-      "aload_2".to_string(),
-      "iload_1".to_string(),
-      "dup2".to_string(),
-      "iaload".to_string(),
-      format!("bipush {}", count),
-      "iadd".to_string(),
-      "iastore".to_string(),
-    ]
-    .join("\n")
-  }
-
-  pub fn mov(count: i32) -> String {
-    format!("iinc 1 {}", count)
-  }
-
-  pub fn out() -> String {
-    vec![
-      "getstatic java/lang/System/out Ljava/io/PrintStream;".to_string(),
-      "aload_2".to_string(),
-      "iload_1".to_string(),
-      "iaload".to_string(),
-      "i2c".to_string(),
-      "invokevirtual java/io/PrintStream/print(C)V".to_string(),
-    ]
-    .join("\n")
-  }
-
-  pub fn input() -> String {
-    vec![
-      "aload_2".to_string(),
-      "iload_1".to_string(),
-      "getstatic java/lang/System/in Ljava/io/InputStream;".to_string(),
-      "invokevirtual java/io/InputStream/read()I".to_string(),
-      "iastore".to_string(),
-    ]
-    .join("\n")
-  }
-
-  pub fn loop_start(stack: &mut super::LabelStack) -> String {
-    let pos = super::label_push(stack);
-    vec![
-      format!("loop{}Start:", pos),
-      "aload_2".to_string(),
-      "iload_1".to_string(),
-      "iaload".to_string(),
-      format!("ifeq loop{}End", pos),
-    ]
-    .join("\n")
-  }
-
-  pub fn loop_end(stack: &mut super::LabelStack) -> String {
-    let pos = stack.pop().unwrap();
-    vec![format!("goto loop{}Start", pos), format!("loop{}End:", pos)].join("\n")
+/// The entries `--cache` keeps for this run or compilation, if it applies:
+/// not to other commands, nor to reports on what the passes did, which a
+/// cached program skips.
+fn cache_for(options: &Options, source: &Source) -> Result<Option<cache::Cache>, Box<dyn Error>> {
+  let applies = matches!(options.command, Command::Run | Command::Compile)
+    && options.coverage.is_none()
+    && !options.opt_stats
+    && options.explain_opts.is_none();
+  if !options.cache || !applies {
+    return Ok(None);
   }
+  // Included files are part of the source.
+  let expansion = match options.macros {
+    Some(depth) => Some(
+      macros::expand(
+        &options.filename,
+        source.text()?,
+        depth,
+        macros::Snippets::Inline,
+      )
+      .map_err(invalid_input)?
+      .text,
+    ),
+    None => None,
+  };
+  let token_map = match &options.token_map {
+    Some(path) => std::fs::read(path)?,
+    None => Vec::new(),
+  };
+  // `run` writes its profile rather than reading it.
+  let profile = match (&options.command, &options.profile) {
+    (Command::Compile, Some(path)) => std::fs::read(path)?,
+    _ => Vec::new(),
+  };
+  let modules = options
+    .modules
+    .iter()
+    .map(std::fs::read)
+    .collect::<Result<Vec<_>, _>>()?;
+  // The options as they resolved, so that the order and spelling of the
+  // arguments do not matter but every setting the artifacts depend on does.
+  let resolved = [
+    format!("{:?}", options.command),
+    format!("{:?}", options.filename),
+    format!("{:?}", options.language),
+    format!("{:?}", options.commands),
+    format!("{:?}", options.macros),
+    format!("{:?}", options.inject_snippets),
+    options.emit_name.to_string(),
+    format!("{:?}", options.jit),
+    format!("{:?}", options.passes),
+    format!("{:?}", options.eval_budget),
+    format!("{:?}", options.unroll_limit),
+    format!("{:?}", options.eof),
+    format!("{:?}", options.io),
+    format!("{:?}", options.cell_type),
+    format!("{:?}", options.seed),
+    format!("{:?}", options.trap_overflow),
+    format!("{:?}", options.exit_cell),
+    format!("{:?}", options.jvm),
+    format!("{:?}", options.class_version),
+    format!("{:?}", options.d8),
+    format!("{:?}", options.linker),
+    format!("{:?}", options.dialect),
+    format!("{:?}", options.syntax),
+    format!("{:?}", options.os),
+    format!("{:?}", options.riscv_io),
+    format!("{:?}", options.js_module),
+    format!("{:?}", options.debug_info),
+  ]
+  .join("\0");
+  let mut parts = vec![
+    cache::FORMAT.as_bytes(),
+    env!("CARGO_PKG_VERSION").as_bytes(),
+    resolved.as_bytes(),
+    expansion
+      .as_ref()
+      .map_or(source.bytes(), |text| text.as_bytes()),
+    &token_map,
+    &profile,
+  ];
+  parts.extend(modules.iter().map(Vec::as_slice));
+  parts.push(&options.init_tape);
+  Ok(Some(cache::Cache::new(&parts)))
 }
-fn lex_program(program: String) -> Result<Vec<Token>, String> {
-  let mut tokens = Vec::new();
-  for c in program.chars() {
-    match c {
-      '+' => tokens.push(Token::Plus),
-      '-' => tokens.push(Token::Minus),
-      '>' => tokens.push(Token::Right),
-      '<' => tokens.push(Token::Left),
-      '.' => tokens.push(Token::PutChar),
-      ',' => tokens.push(Token::ReadChar),
-      '[' => tokens.push(Token::JumpIfZero),
-      ']' => tokens.push(Token::JumpIfNonZero),
-      _ => (), // skip
+
+/// Formats the file `program` was read from, or with `--check` fails if
+/// that would change it.
+fn format_file(options: &Options, program: &str) -> Result<(), Box<dyn Error>> {
+  if options.token_map.is_some() {
+    return Err(invalid_input(
+      "fmt only formats commands of one character, not --token-map ones".to_string(),
+    ));
+  }
+  let tokens =
+    lex_bytes(program.as_bytes(), options.language, options.commands).map_err(invalid_input)?;
+  parse_program(tokens.clone()).map_err(invalid_input)?;
+  let formatted = format::format(program, &tokens, options.width);
+  if formatted == program {
+    return Ok(());
+  }
+  if options.check {
+    return Err(invalid_input(format!(
+      "{} is not formatted",
+      options.filename
+    )));
+  }
+  Ok(std::fs::write(&options.filename, formatted)?)
+}
+
+/// Prints the commands of `tokens` alone, or with `--shorten` the program
+/// they parse into once runs that cancel out and dead loops are gone.
+fn minify(options: &Options, tokens: Vec<(Token, usize)>) -> Result<(), Box<dyn Error>> {
+  let code: String = if options.shorten {
+    let program = parse_program(tokens).map_err(invalid_input)?;
+    let (instructions, _) = optimizer::optimize(program, &["dce", "fold"], 0, 0);
+    // Calls come from `*` unless pbrain's `:` makes them.
+    let call = match options.language {
+      Dialect::Pbrain => ':',
+      _ => '*',
+    };
+    bf::serialize(&instructions, call).map_err(invalid_input)?
+  } else {
+    tokens.iter().map(|&(token, _)| token.symbol()).collect()
+  };
+  println!("{}", code);
+  Ok(())
+}
+
+/// Prints what `analyze` finds in `tokens`, exiting with status 1 if it
+/// warns about anything `--allow` does not leave out.
+fn analyze(
+  options: &Options,
+  tokens: &[(Token, usize)],
+  expansion: Option<&macros::Expansion>,
+) -> Result<(), Box<dyn Error>> {
+  let report = analyze::analyze(tokens, options.jvm.tape_size).map_err(invalid_input)?;
+  let warnings: Vec<_> = report
+    .warnings
+    .iter()
+    .filter(|warning| !options.allow.contains(&warning.code))
+    .collect();
+  for warning in &warnings {
+    let place = match expansion.map(|expansion| expansion.locate(warning.pos)) {
+      Some((path, offset)) => format!("{}: byte {}", path.display(), offset),
+      None => format!("{}: byte {}", options.filename, warning.pos),
+    };
+    println!("{}: {}: {}", place, warning.code, warning.message);
+  }
+  if let Some((lo, hi)) = report.range {
+    println!(
+      "{}: the pointer stays within cells {}..={}",
+      options.filename, lo, hi
+    );
+  }
+  if !warnings.is_empty() {
+    std::process::exit(1);
+  }
+  Ok(())
+}
+
+/// Times `instructions` under the interpreter, the JIT and the JVM on all
+/// of standard input, printing a line for each.
+fn run_bench(options: &Options, instructions: &[brainfuck::Inst]) -> Result<(), Box<dyn Error>> {
+  let mut input = Vec::new();
+  std::io::stdin().read_to_end(&mut input)?;
+  let steps = bench::count_steps(instructions, options.eof, &input)?;
+  let reference = bench::interpreter(instructions, options.eof, &input)?;
+  let report = |name: &str, timing: &bench::Timing| {
+    let seconds = timing.time.as_secs_f64();
+    let mut line = format!(
+      "{:<12} {:>10.3} s {:>14.0} instructions/s",
+      name,
+      seconds,
+      steps as f64 / seconds
+    );
+    if timing.output != reference.output {
+      line.push_str(", but prints something else");
     }
+    println!("{}", line);
+  };
+  println!("{} instructions executed", steps);
+  report("interpreter", &reference);
+  #[cfg(feature = "jit")]
+  match bench::jit(instructions, options.eof, &input) {
+    Ok(timing) => report("jit", &timing),
+    Err(e) => println!("{:<12} skipped: {}", "jit", e),
   }
-  Ok(tokens)
+  #[cfg(not(feature = "jit"))]
+  println!("{:<12} skipped: built without the `jit` feature", "jit");
+  match bench::jvm(
+    instructions,
+    &options.jvm,
+    options.class_version,
+    "java",
+    &input,
+  ) {
+    Ok(Some(timing)) => report("jvm", &timing),
+    Ok(None) => println!("{:<12} skipped: java was not found", "jvm"),
+    Err(e) => println!("{:<12} skipped: {}", "jvm", e),
+  }
+  Ok(())
 }
 
-fn parse_program(program: Vec<Token>) -> Result<Vec<Inst>, String> {
-  let mut pos = 0;
-  let mut instructions = Vec::new();
-  let mut stack = Vec::new();
-  while pos < program.len() {
-    let curr = program[pos];
-    match curr {
-      Token::Plus => instructions.push(compile_foldable(Token::Plus, &mut pos, &program)),
-      Token::Minus => instructions.push(compile_foldable(Token::Minus, &mut pos, &program)),
-      Token::Right => instructions.push(compile_foldable(Token::Right, &mut pos, &program)),
-      Token::Left => instructions.push(compile_foldable(Token::Left, &mut pos, &program)),
-      Token::PutChar => instructions.push(compile_foldable(Token::PutChar, &mut pos, &program)),
-      Token::ReadChar => instructions.push(compile_foldable(Token::ReadChar, &mut pos, &program)),
-      Token::JumpIfZero => {
-        stack.push(instructions.len());
-        instructions.push(Inst {
-          typ: Token::JumpIfZero,
-          argument: 0,
-        });
-      }
-      Token::JumpIfNonZero => {
-        let open_inst_ptr = stack.pop().unwrap();
-        let mut open_inst = instructions[open_inst_ptr];
-        open_inst.argument = instructions.len();
-        instructions.push(Inst {
-          typ: Token::JumpIfNonZero,
-          argument: open_inst_ptr,
-        });
-        instructions[open_inst_ptr] = open_inst;
+/// Compares random programs in the interpreter and the `--against`
+/// backend, exiting with status 1 after printing the smallest program they
+/// disagree on.
+fn run_fuzz(options: &Options) -> Result<(), Box<dyn Error>> {
+  let mut random = options
+    .seed
+    .map_or_else(interpreter::Random::from_time, interpreter::Random::new);
+  let dir = std::env::temp_dir().join(format!("brainfuck-fuzz-{}", std::process::id()));
+  std::fs::create_dir_all(&dir)?;
+  let fuzz_options = fuzz::Options {
+    target: options.against,
+    passes: &options.passes,
+    eval_budget: options.eval_budget,
+    unroll_limit: options.unroll_limit,
+    config: &options.jvm,
+    class_version: options.class_version,
+    dir: &dir,
+  };
+  let result = fuzz::fuzz(options.runs, &mut random, &fuzz_options);
+  std::fs::remove_dir_all(&dir)?;
+  match result? {
+    None => {
+      println!("{} programs agree", options.runs);
+      Ok(())
+    }
+    Some(divergence) => {
+      println!("program: {}", divergence.code);
+      println!("input: {:?}", String::from_utf8_lossy(&divergence.input));
+      println!(
+        "interpreter: {:?}",
+        String::from_utf8_lossy(&divergence.expected)
+      );
+      match &divergence.actual {
+        Ok(output) => println!(
+          "{:?}: {:?}",
+          options.against,
+          String::from_utf8_lossy(output)
+        ),
+        Err(e) => println!("{:?}: {}", options.against, e),
       }
+      std::process::exit(1);
     }
-    pos += 1;
   }
-  Ok(instructions)
 }
 
-fn compile_foldable(token: Token, pos: &mut usize, program: &Vec<Token>) -> Inst {
-  let mut count = 1;
-  while *pos < program.len() - 1 && program[*pos + 1] == token {
-    count += 1;
-    *pos += 1;
+/// Runs the program of `case` in the interpreter as `run` would, linked
+/// and configured the same way, returning what it printed.
+fn run_case(options: &Options, case: &golden::Case) -> Result<Vec<u8>, Box<dyn Error>> {
+  let path = case.program.to_string_lossy();
+  let program = std::fs::read(&case.program)?;
+  let (expansion, tokens) = lex_source(options, &path, &program)?;
+  let (instructions, _) =
+    optimizer::Compiler::new(&options.passes, options.eval_budget, options.unroll_limit)
+      .with_tape_size(options.jvm.tape_size)
+      .optimize(parse_linked(options, expansion.as_ref(), tokens)?);
+  let input = match &case.input {
+    Some(input) => std::fs::read(input)?,
+    None => Vec::new(),
+  };
+  let mut output = Vec::new();
+  interpreter_for::<u8>(options, &instructions).run(&mut &input[..], &mut output, None)?;
+  Ok(output)
+}
+
+/// Runs every test under the directory named in place of a file, printing
+/// a line for each and a summary, and exits with status 1 if any failed.
+fn run_tests(options: &Options) -> Result<(), Box<dyn Error>> {
+  let cases = golden::discover(Path::new(&options.filename))?;
+  let mut failed = 0;
+  for case in &cases {
+    let problem = match run_case(options, case) {
+      Ok(output) => golden::difference(&std::fs::read(&case.expected)?, &output),
+      Err(e) => Some(e.to_string()),
+    };
+    match problem {
+      None => println!("test {} ... ok", case.program.display()),
+      Some(problem) => {
+        failed += 1;
+        println!("test {} ... FAILED: {}", case.program.display(), problem);
+      }
+    }
   }
-  Inst {
-    typ: token,
-    argument: count,
+  println!(
+    "test result: {}. {} passed; {} failed",
+    if failed == 0 { "ok" } else { "FAILED" },
+    cases.len() - failed,
+    failed
+  );
+  if failed > 0 {
+    std::process::exit(1);
   }
+  Ok(())
 }
 
-const HEADER: &str = "
-.class public Main
-.super java/lang/Object
-
-.method public <init>()V
-    aload_0
-    invokenonvirtual java/lang/Object/<init>()V
-    return
-.end method
-
-.method public static main([Ljava/lang/String;)V
-    .limit stack 10
-    .limit locals 3
-
-    iconst_0
-    istore_1
-
-    bipush 100
-    newarray int
-    astore_2
-";
-
-const TAIL: &str = "
-    return
-.end method
-";
-
-fn produce_code(instructions: Vec<Inst>) -> String {
-  let mut code = vec![HEADER.to_string()];
-  let mut stack: LabelStack = Vec::new();
-  for inst in instructions {
-    code.push(inst.to_bytecode(&mut stack));
-  }
-  code.push(TAIL.to_string());
-  code.join("\n")
+/// Assembles the Jasmin file `asm` was given into a class file named after
+/// the class, in the directories of its package.
+fn assemble(options: &Options) -> Result<(), Box<dyn Error>> {
+  let source = std::fs::read_to_string(&options.filename)?;
+  let (name, class) =
+    classfile::assemble_file(&source, options.class_version).map_err(invalid_input)?;
+  let path = format!("{}.class", name);
+  if let Some(dir) = Path::new(&path).parent() {
+    std::fs::create_dir_all(dir)?;
+  }
+  std::fs::write(&path, class)?;
+  println!("Assembled {} into {}", options.filename, path);
+  Ok(())
 }
 
-fn interpret(program: String) {
-  let mut tape: Vec<u8> = vec![0; 100];
-  let mut ptr = 0;
-  let mut stack = Vec::new();
-  let mut is_looping = false;
-  let mut inner_loops = 0;
-  let mut i = 0;
-  let mut output = String::new();
-
-  while i < program.len() {
-    let c = program.chars().nth(i).unwrap();
-    if is_looping {
-      if c == '[' {
-        inner_loops += 1;
-      }
-      if c == ']' {
-        if inner_loops == 0 {
-          is_looping = false;
-        } else {
-          inner_loops -= 1;
-        }
+/// Runs the file on each of `--backends` with the `--input` bytes and shows
+/// where any prints something else than the first, exiting with status 1
+/// if one does or fails.
+fn diff_backends(options: &Options) -> Result<(), Box<dyn Error>> {
+  let code = std::fs::read_to_string(&options.filename)?;
+  let input = match &options.input {
+    Some(path) => std::fs::read(path)?,
+    None => Vec::new(),
+  };
+  let dir = std::env::temp_dir().join(format!("brainfuck-diff-{}", std::process::id()));
+  std::fs::create_dir_all(&dir)?;
+  let mut reference: Option<(String, Vec<u8>)> = None;
+  let mut differ = false;
+  for &target in &options.backends {
+    let name = format!("{:?}", target).to_lowercase();
+    let fuzz_options = fuzz::Options {
+      target,
+      passes: &options.passes,
+      eval_budget: options.eval_budget,
+      unroll_limit: options.unroll_limit,
+      config: &options.jvm,
+      class_version: options.class_version,
+      dir: &dir,
+    };
+    let outcome = fuzz::run_target(&code, &input, &fuzz_options)
+      .unwrap_or_else(|error| Some(Err(error.to_string())));
+    let output = match outcome {
+      None => {
+        println!("{}: skipped, its tools were not found", name);
+        continue;
       }
-      continue;
+      Some(Err(error)) => {
+        differ = true;
+        println!("{}: failed: {}", name, error);
+        continue;
+      }
+      Some(Ok(output)) => output,
+    };
+    match &reference {
+      None => {
+        println!("{}: {} bytes", name, output.len());
+        reference = Some((name, output));
+      }
+      Some((first, expected)) => match golden::divergence(expected, &output) {
+        None => println!("{}: the same {} bytes as {}", name, output.len(), first),
+        Some(offset) => {
+          differ = true;
+          println!("{}: differs from {} at byte {}", name, first, offset);
+          println!("  {}: {}", first, golden::context(expected, offset));
+          println!("  {}: {}", name, golden::context(&output, offset));
+        }
+      },
     }
-    match c {
-      '+' => tape[ptr] += 1,
-      '-' => tape[ptr] -= 1,
-      '>' => ptr += 1,
-      '<' => ptr -= 1,
-      '[' => {
-        if tape[ptr] == 0 {
-          is_looping = true;
-        } else {
-          stack.push(i);
+  }
+  std::fs::remove_dir_all(&dir)?;
+  if differ {
+    std::process::exit(1);
+  }
+  Ok(())
+}
+
+/// Runs the built-in samples in the interpreter and through every backend
+/// whose tools are found, printing a line for each, and exits with status 1
+/// if any printed the wrong thing.
+fn run_selftest(options: &Options) -> Result<(), Box<dyn Error>> {
+  let dir = std::env::temp_dir().join(format!("brainfuck-selftest-{}", std::process::id()));
+  std::fs::create_dir_all(&dir)?;
+  // The samples are written for the default configuration.
+  let config = jasmin::Config::default();
+  let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+  for sample in selftest::SAMPLES {
+    let mut outcomes = vec![("interpreter".to_string(), Some(selftest::interpret(sample)))];
+    for target in selftest::targets() {
+      let fuzz_options = fuzz::Options {
+        target,
+        passes: &options.passes,
+        eval_budget: options.eval_budget,
+        unroll_limit: options.unroll_limit,
+        config: &config,
+        class_version: options.class_version,
+        dir: &dir,
+      };
+      let outcome = fuzz::run_target(sample.code, sample.input, &fuzz_options)
+        .unwrap_or_else(|error| Some(Err(error.to_string())));
+      outcomes.push((format!("{:?}", target).to_lowercase(), outcome));
+    }
+    for (name, outcome) in outcomes {
+      let problem = match outcome {
+        None => {
+          skipped += 1;
+          println!("selftest {} on {} ... skipped", sample.name, name);
+          continue;
         }
-      }
-      ']' => {
-        if tape[ptr] != 0 {
-          i = *stack.last().unwrap();
-        } else {
-          stack.pop();
+        Some(Ok(output)) => golden::difference(sample.expected, &output),
+        Some(Err(error)) => Some(error),
+      };
+      match problem {
+        None => {
+          passed += 1;
+          println!("selftest {} on {} ... ok", sample.name, name);
+        }
+        Some(problem) => {
+          failed += 1;
+          println!(
+            "selftest {} on {} ... FAILED: {}",
+            sample.name, name, problem
+          );
         }
       }
-      '.' => output.push(tape[ptr] as char),
-      ',' => {
-        let mut line = String::new();
-        match std::io::stdin().read_line(&mut line) {
-          Ok(_) => {
-            let c = line.chars().nth(0).unwrap();
-            tape[ptr] = c as u8;
+    }
+  }
+  std::fs::remove_dir_all(&dir)?;
+  println!(
+    "selftest result: {}. {} passed; {} failed; {} skipped",
+    if failed == 0 { "ok" } else { "FAILED" },
+    passed,
+    failed,
+    skipped
+  );
+  if failed > 0 {
+    std::process::exit(1);
+  }
+  Ok(())
+}
+
+/// Serves editors over standard input and output until they exit.
+fn serve_lsp(options: &Options) -> Result<(), Box<dyn Error>> {
+  if options.token_map.is_some() {
+    return Err(invalid_input(
+      "lsp only reads commands of one character, not --token-map ones".to_string(),
+    ));
+  }
+  let config = lsp::Config {
+    dialect: options.language,
+    commands: options.commands,
+  };
+  let stdin = std::io::stdin();
+  lsp::serve(&config, &mut stdin.lock(), &mut std::io::stdout())?;
+  Ok(())
+}
+
+/// Debugs the programs editors launch over standard input and output.
+fn serve_dap(options: &Options) -> Result<(), Box<dyn Error>> {
+  let load = |path: &str| -> Result<_, String> {
+    let program = std::fs::read_to_string(path).map_err(|error| format!("{}: {}", path, error))?;
+    let (_, tokens) =
+      lex_source(options, path, program.as_bytes()).map_err(|error| error.to_string())?;
+    let instructions = parse_program(tokens)?;
+    Ok((program, instructions))
+  };
+  let config = dap::Config {
+    load: &load,
+    eof: options.eof,
+  };
+  dap::serve(
+    &config,
+    std::io::BufReader::new(std::io::stdin()),
+    &mut std::io::stdout(),
+  )?;
+  Ok(())
+}
+
+/// An interpreter on a tape of `C` with the tape size, end of input, I/O,
+/// overflow checks and seed the options ask for.
+fn interpreter_for<'a, C: Cell>(
+  options: &Options,
+  instructions: &'a [Inst],
+) -> interpreter::Interpreter<'a, C> {
+  let interpreter = interpreter::Interpreter::<C>::with_cells(instructions)
+    .with_tape_size(options.jvm.tape_size)
+    .with_eof(options.eof)
+    .with_io(options.io)
+    .with_trap_overflow(options.trap_overflow);
+  match options.seed {
+    Some(seed) => interpreter.with_seed(seed),
+    None => interpreter,
+  }
+}
+
+/// Runs the program on a tape of `C`.
+fn run_on<C: Cell>(options: &Options, instructions: &[Inst]) -> Result<(), Box<dyn Error>> {
+  interpreter_for::<C>(options, instructions).run(
+    &mut std::io::stdin().lock(),
+    &mut std::io::stdout().lock(),
+    None,
+  )?;
+  Ok(())
+}
+
+/// Runs the program on the cells `--cell-type` picked, which only the
+/// interpreter holds and nothing that reads the tape as bytes looks at.
+fn run_cells(options: &Options, instructions: &[Inst]) -> Result<(), Box<dyn Error>> {
+  let trace = options.trace.to_stderr || options.trace.out_file.is_some();
+  let tape = options.dump_tape || options.dump_tape_out.is_some() || options.inspect;
+  if options.jit
+    || options.profile.is_some()
+    || options.coverage.is_some()
+    || options.stats_json.is_some()
+    || options.exit_cell.is_some()
+    || trace
+    || tape
+  {
+    return Err(invalid_input(
+      "--cell-type cannot be combined with --jit, --profile, --coverage, --stats-json, --trace, --exit-from-cell, --dump-tape or --inspect".to_string(),
+    ));
+  }
+  match options.cell_type {
+    CellType::U8 => run_on::<u8>(options, instructions),
+    CellType::U16 => run_on::<u16>(options, instructions),
+    CellType::U32 => run_on::<u32>(options, instructions),
+    CellType::I64 => run_on::<i64>(options, instructions),
+    #[cfg(feature = "bigint")]
+    CellType::Big => run_on::<num_bigint::BigUint>(options, instructions),
+    #[cfg(not(feature = "bigint"))]
+    CellType::Big => Err(invalid_input(
+      "big cells need the `bigint` feature".to_string(),
+    )),
+  }
+}
+
+/// Shows, writes or inspects the tape a run left, as `--dump-tape`,
+/// `--dump-tape-out` and `--inspect` ask.
+fn examine_tape(options: &Options, tape: &[u8], ptr: usize) -> std::io::Result<()> {
+  if options.dump_tape {
+    eprint!("{}", interpreter::dump_table(tape, ptr));
+  }
+  if let Some(path) = &options.dump_tape_out {
+    std::fs::write(path, tape)?;
+  }
+  if options.inspect {
+    // The program may have read standard input to its end, so queries come
+    // from the terminal when there is one.
+    let stderr = &mut std::io::stderr();
+    match File::open("/dev/tty") {
+      Ok(tty) => inspect::inspect(tape, ptr, &mut std::io::BufReader::new(tty), stderr)?,
+      Err(_) => inspect::inspect(tape, ptr, &mut std::io::stdin().lock(), stderr)?,
+    }
+  }
+  Ok(())
+}
+
+/// The JVM settings for `filename`, whose line numbers point into `source`
+/// unless `--no-debug-info` leaves them out or it is a compiled program,
+/// whose source is not at hand.
+fn jvm_config(options: &Options, filename: &str, source: &[u8]) -> jasmin::Config {
+  let mut jvm = options.jvm.clone();
+  if options.debug_info && !cache::is_encoded(source) {
+    let file = Path::new(filename)
+      .file_name()
+      .and_then(|name| name.to_str())
+      .unwrap_or(filename);
+    jvm.debug = Some(jasmin::DebugInfo::new(file, source));
+  }
+  jvm
+}
+
+fn backend_options<'a>(
+  options: &'a Options,
+  filename: &'a str,
+  dir: &'a str,
+  jvm: &'a jasmin::Config,
+  notes: &'a [optimizer::Note],
+  profile: Option<&'a profile::Profile>,
+) -> backend::Options<'a> {
+  backend::Options {
+    filename,
+    dir,
+    config: jvm,
+    class_version: options.class_version,
+    d8: &options.d8,
+    linker: &options.linker,
+    dialect: options.dialect,
+    syntax: options.syntax,
+    os: options.os,
+    riscv_io: options.riscv_io,
+    js_module: options.js_module,
+    notes,
+    profile,
+  }
+}
+
+/// Writes the code for `instructions` to `path`. It is written beside the
+/// file and moved into place once complete, so a failure leaves no partial
+/// file.
+fn write_output(
+  options: &Options,
+  instructions: &[Inst],
+  opts: &backend::Options,
+  path: &str,
+) -> std::io::Result<()> {
+  let partial = format!("{}.partial", path);
+  let written = File::create(&partial)
+    .map(BufWriter::new)
+    .and_then(|mut file| {
+      options.emit.emit(instructions, opts, &mut file)?;
+      file.flush()
+    });
+  if let Err(error) = written {
+    let _ = std::fs::remove_file(&partial);
+    return Err(error);
+  }
+  std::fs::rename(&partial, path)
+}
+
+/// What compiling one file of a batch made.
+enum Compiled {
+  /// The message of a backend that writes files.
+  Written(String),
+  /// The code of a backend that prints it.
+  Printed(Vec<u8>),
+}
+
+/// Lexes, parses, optimizes and emits `filename`, writing any files into
+/// `dir`.
+fn compile_one(options: &Options, filename: &str, dir: &str) -> Result<Compiled, Box<dyn Error>> {
+  let source = Source::open(filename, options.mmap)?;
+  let (instructions, stats) = match load_ir(options, filename, source.bytes())? {
+    Some(instructions) => (instructions, optimizer::Stats::default()),
+    None => {
+      let (expansion, tokens) = lex_source(options, filename, source.bytes())?;
+      optimizer::Compiler::new(&options.passes, options.eval_budget, options.unroll_limit)
+        .with_tape_size(options.jvm.tape_size)
+        .optimize(parse_linked(options, expansion.as_ref(), tokens)?)
+    }
+  };
+  let jvm = jvm_config(options, filename, source.bytes());
+  let opts = backend_options(options, filename, dir, &jvm, &stats.notes, None);
+  match options.emit.path(&opts) {
+    Some(path) => {
+      std::fs::create_dir_all(dir)?;
+      let path = backend::in_dir(&opts, &path);
+      write_output(options, &instructions, &opts, &path)?;
+      Ok(Compiled::Written(options.emit.finish(&path, &opts)?))
+    }
+    None => {
+      let mut code = Vec::new();
+      options.emit.emit(&instructions, &opts, &mut code)?;
+      Ok(Compiled::Printed(code))
+    }
+  }
+}
+
+/// Compiles every file given on `options.jobs` threads. Each one's files go
+/// into a directory named after it, since backends name their output the
+/// same for every program, and what each made is reported in the order the
+/// files were given, followed by how many failed.
+fn compile_batch(options: &Options) -> Result<(), Box<dyn Error>> {
+  for (flag, given) in [
+    ("--profile", options.profile.is_some()),
+    ("--opt-stats", options.opt_stats),
+    ("--explain-opts", options.explain_opts.is_some()),
+    ("--cache", options.cache),
+  ] {
+    if given {
+      return Err(invalid_input(format!(
+        "{} applies to one file, not a batch",
+        flag
+      )));
+    }
+  }
+  let files: Vec<&str> = std::iter::once(&options.filename)
+    .chain(&options.files)
+    .map(String::as_str)
+    .collect();
+  let dirs: Vec<&str> = files
+    .iter()
+    .map(|file| {
+      Path::new(file)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or(file)
+    })
+    .collect();
+  for (index, dir) in dirs.iter().enumerate() {
+    if let Some(other) = dirs[..index].iter().position(|other| other == dir) {
+      return Err(invalid_input(format!(
+        "{} and {} would both be compiled into {}",
+        files[other], files[index], dir
+      )));
+    }
+  }
+  // Workers take the next file until none are left; errors become text,
+  // since those of backends are not all `Send`.
+  let next = AtomicUsize::new(0);
+  let mut results: Vec<(usize, Result<Compiled, String>)> = thread::scope(|scope| {
+    let workers: Vec<_> = (0..options.jobs.min(files.len()))
+      .map(|_| {
+        scope.spawn(|| {
+          let mut done = Vec::new();
+          loop {
+            let index = next.fetch_add(1, Ordering::Relaxed);
+            if index >= files.len() {
+              return done;
+            }
+            let result = compile_one(options, files[index], dirs[index]);
+            done.push((index, result.map_err(|error| error.to_string())));
           }
-          Err(_) => (),
-        }
+        })
+      })
+      .collect();
+    workers
+      .into_iter()
+      .flat_map(|worker| worker.join().unwrap())
+      .collect()
+  });
+  results.sort_by_key(|&(index, _)| index);
+  let mut failed = 0;
+  let stdout = std::io::stdout();
+  let mut stdout = stdout.lock();
+  for (index, result) in results {
+    match result {
+      Ok(Compiled::Written(message)) => writeln!(stdout, "{}: {}", files[index], message)?,
+      Ok(Compiled::Printed(code)) => {
+        writeln!(stdout, "==> {} <==", files[index])?;
+        stdout.write_all(&code)?;
+      }
+      Err(error) => {
+        failed += 1;
+        stdout.flush()?;
+        eprintln!("{}: error: {}", files[index], error);
       }
-      _ => (),
     }
-    i += 1;
   }
-  println!("{}", output);
+  if failed > 0 {
+    eprintln!("{} of {} files failed to compile", failed, files.len());
+    std::process::exit(1);
+  }
+  Ok(())
+}
+
+/// The program in `bytes` if `--emit brir` wrote them, which is already
+/// lexed, linked and optimized.
+fn load_ir(
+  options: &Options,
+  filename: &str,
+  bytes: &[u8],
+) -> Result<Option<Vec<Inst>>, Box<dyn Error>> {
+  if !cache::is_encoded(bytes) {
+    return Ok(None);
+  }
+  if !matches!(options.command, Command::Run | Command::Compile) {
+    return Err(invalid_input(format!(
+      "{} holds a compiled program, which can only be run or compiled",
+      filename
+    )));
+  }
+  Ok(Some(cache::decode(bytes).map_err(invalid_input)?))
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-  if let Some(filename) = env::args().nth(1) {
-    let mut file = File::open(filename)?;
-    let mut program = String::new();
-    file.read_to_string(&mut program)?;
-    let tokens = lex_program(program).unwrap();
-    let instructions = parse_program(tokens).unwrap();
-    let code = produce_code(instructions);
-    let mut outfile = File::create("main.j")?;
-    write!(outfile, "{}", code)?;
-    println!("Compiled code to main.j");
-    Ok(())
-  } else {
-    Err(Box::new(std::io::Error::new(
-      ErrorKind::InvalidInput,
-      "No input file!",
-    )))
+  let options = parse_args(env::args().skip(1).collect())?;
+  if let Command::GenText = options.command {
+    print!(
+      "{}",
+      bf::produce_text(&options.filename).map_err(invalid_input)?
+    );
+    return Ok(());
+  }
+  if let Command::Fuzz = options.command {
+    return run_fuzz(&options);
+  }
+  if let Command::Test = options.command {
+    return run_tests(&options);
+  }
+  if let Command::SelfTest = options.command {
+    return run_selftest(&options);
+  }
+  if let Command::Lsp = options.command {
+    return serve_lsp(&options);
+  }
+  if let Command::Dap = options.command {
+    return serve_dap(&options);
+  }
+  if let Command::TraceView = options.command {
+    let records = trace::load(&options.filename)?;
+    replay::view(
+      &records,
+      &mut std::io::stdin().lock(),
+      &mut std::io::stdout(),
+    )?;
+    return Ok(());
+  }
+  if let Command::Asm = options.command {
+    return assemble(&options);
+  }
+  if let Command::DiffBackends = options.command {
+    return diff_backends(&options);
+  }
+  if !options.files.is_empty() {
+    return compile_batch(&options);
+  }
+  let source = Source::open(&options.filename, options.mmap)?;
+  let loaded = load_ir(&options, &options.filename, source.bytes())?;
+  if let Command::Fmt = options.command {
+    return format_file(&options, source.text()?);
+  }
+  let cache = cache_for(&options, &source)?;
+  let cached = loaded.or_else(|| cache.as_ref().and_then(cache::Cache::load_instructions));
+  let (expansion, tokens) = match cached {
+    Some(_) => (None, Vec::new()),
+    None => lex_source(&options, &options.filename, source.bytes())?,
+  };
+  if let Command::Minify = options.command {
+    return minify(&options, tokens);
+  }
+  if let Command::Analyze = options.command {
+    return analyze(&options, &tokens, expansion.as_ref());
+  }
+  if let Command::Stats = options.command {
+    let metrics = metrics::collect(
+      &tokens,
+      &options.passes,
+      options.eval_budget,
+      options.unroll_limit,
+    )
+    .map_err(invalid_input)?;
+    print!("{}", metrics.report());
+    return Ok(());
+  }
+  if let Command::Heatmap = options.command {
+    let path = options.profile.as_ref().ok_or_else(|| {
+      invalid_input("heatmap needs the --profile of a run of the file".to_string())
+    })?;
+    let profile = profile::Profile::load(path)?;
+    let positions: Vec<usize> = tokens.iter().map(|&(_, pos)| pos).collect();
+    let style = if options.html {
+      heatmap::Style::Html
+    } else {
+      heatmap::Style::Ansi
+    };
+    print!(
+      "{}",
+      heatmap::render(
+        source.text()?,
+        &positions,
+        &profile,
+        style,
+        &options.filename
+      )
+    );
+    return Ok(());
+  }
+  if let Command::Obfuscate = options.command {
+    let mut random = options
+      .seed
+      .map_or_else(interpreter::Random::from_time, interpreter::Random::new);
+    let tokens: Vec<Token> = tokens.into_iter().map(|(token, _)| token).collect();
+    print!(
+      "{}",
+      obfuscate::obfuscate(&tokens, options.language, &mut random).map_err(invalid_input)?
+    );
+    return Ok(());
+  }
+  let positions: Vec<usize> = tokens.iter().map(|&(_, pos)| pos).collect();
+  let (instructions, stats) = match cached {
+    Some(instructions) => (instructions, optimizer::Stats::default()),
+    None => {
+      let (instructions, stats) =
+        optimizer::Compiler::new(&options.passes, options.eval_budget, options.unroll_limit)
+          .with_tape_size(options.jvm.tape_size)
+          .optimize(parse_linked(&options, expansion.as_ref(), tokens)?);
+      if let Some(cache) = &cache {
+        cache.store_instructions(&instructions)?;
+      }
+      (instructions, stats)
+    }
+  };
+  if let Some(format) = options.explain_opts {
+    eprint!(
+      "{}",
+      report::explain(&stats, source.text()?, &options.filename, format)
+    );
+  } else if options.opt_stats {
+    eprint!("{}", report::summary(&stats));
+  }
+  if let Command::Graph = options.command {
+    print!("{}", cfg::Cfg::build(&instructions).to_dot(&instructions));
+    return Ok(());
+  }
+  if let Command::Bench = options.command {
+    return run_bench(&options, &instructions);
+  }
+  let profile = match (&options.command, &options.profile) {
+    (Command::Compile, Some(path)) => Some(Arc::new(profile::Profile::load(path)?)),
+    _ => None,
+  };
+  let mut jvm = jvm_config(&options, &options.filename, source.bytes());
+  jvm.profile = profile.clone();
+  match options.command {
+    Command::Compile => {
+      let opts = backend_options(
+        &options,
+        &options.filename,
+        "",
+        &jvm,
+        &stats.notes,
+        profile.as_deref(),
+      );
+      match options.emit.path(&opts) {
+        Some(path) => {
+          match cache.as_ref().and_then(|cache| cache.load(&path)) {
+            Some(code) => std::fs::write(&path, code)?,
+            None => {
+              write_output(&options, &instructions, &opts, &path)?;
+              if let Some(cache) = &cache {
+                cache.store(&path, &std::fs::read(&path)?)?;
+              }
+            }
+          }
+          println!("{}", options.emit.finish(&path, &opts)?);
+        }
+        None => match &cache {
+          Some(cache) => {
+            let code = match cache.load("out") {
+              Some(code) => code,
+              None => {
+                let mut code = Vec::new();
+                options.emit.emit(&instructions, &opts, &mut code)?;
+                cache.store("out", &code)?;
+                code
+              }
+            };
+            std::io::stdout().write_all(&code)?;
+          }
+          None => options
+            .emit
+            .emit(&instructions, &opts, &mut std::io::stdout().lock())?,
+        },
+      }
+    }
+    Command::Run if options.cell_type != CellType::U8 => run_cells(&options, &instructions)?,
+    #[cfg(feature = "jit")]
+    Command::Run if options.jit => {
+      if options.trace.to_stderr || options.trace.out_file.is_some() {
+        return Err(invalid_input(
+          "--trace cannot be combined with --jit".to_string(),
+        ));
+      }
+      if options.profile.is_some() || options.coverage.is_some() || options.stats_json.is_some() {
+        return Err(invalid_input(
+          "--profile, --coverage and --stats-json cannot be combined with --jit".to_string(),
+        ));
+      }
+      if options.io == interpreter::Io::Unicode {
+        return Err(invalid_input(
+          "--io unicode cannot be combined with --jit".to_string(),
+        ));
+      }
+      if options.trap_overflow {
+        return Err(invalid_input(
+          "--trap-overflow cannot be combined with --jit".to_string(),
+        ));
+      }
+      let stdin = std::io::stdin();
+      let stdout = std::io::stdout();
+      let (tape, ptr) = jit::run(
+        &instructions,
+        options.eof,
+        options.io,
+        options.jvm.tape_size,
+        &mut stdin.lock(),
+        &mut stdout.lock(),
+      )?;
+      examine_tape(&options, &tape, ptr)?;
+      if let Some(cell) = options.exit_cell {
+        std::process::exit(cell.value(&tape, ptr) as i32);
+      }
+    }
+    Command::Run => {
+      if options.jit {
+        eprintln!("warning: built without the `jit` feature, falling back to the interpreter");
+      }
+      let observed =
+        options.profile.is_some() || options.coverage.is_some() || options.stats_json.is_some();
+      if observed && has_forks(&instructions) {
+        return Err(invalid_input(
+          "--profile, --coverage and --stats-json cannot follow Brainfork threads".to_string(),
+        ));
+      }
+      let mut tracer = trace::Tracer::new(&options.trace)?;
+      let stdin = std::io::stdin();
+      let stdout = std::io::stdout();
+      let mut tally = tally::Tally::default();
+      let mut input = tally::Counted {
+        input: &mut stdin.lock(),
+        read: 0,
+      };
+      let mut interpreter = interpreter_for::<u8>(&options, &instructions);
+      if options.stats_json.is_some() {
+        interpreter = interpreter.with_observer(&mut tally);
+      }
+      let started = Instant::now();
+      if options.profile.is_some() || options.coverage.is_some() {
+        let profile = profile::Profile::collect(
+          &mut interpreter,
+          &mut input,
+          &mut stdout.lock(),
+          tracer.as_mut(),
+        )?;
+        if let Some(path) = &options.profile {
+          profile.save(path)?;
+        }
+        if let Some(path) = &options.coverage {
+          let coverage =
+            coverage::Coverage::new(source.text()?, &positions, &instructions, &profile);
+          std::fs::write(path, coverage.lcov(&options.filename))?;
+          let name = Path::new(&options.filename)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(&options.filename);
+          std::fs::write(format!("{}.cov", name), coverage.annotate())?;
+        }
+      } else {
+        interpreter.run(&mut input, &mut stdout.lock(), tracer.as_mut())?;
+      }
+      let wall = started.elapsed();
+      examine_tape(&options, interpreter.tape(), interpreter.ptr())?;
+      let exit = options
+        .exit_cell
+        .map(|cell| cell.value(interpreter.tape(), interpreter.ptr()));
+      if let Some(path) = &options.stats_json {
+        std::fs::write(path, format!("{}\n", tally.json(wall, input.read)))?;
+      }
+      if let Some(code) = exit {
+        // `exit` skips destructors, so the trace file is flushed first.
+        drop(tracer);
+        std::process::exit(code as i32);
+      }
+    }
+    Command::GenText
+    | Command::Fmt
+    | Command::Minify
+    | Command::Obfuscate
+    | Command::Graph
+    | Command::Analyze
+    | Command::Bench
+    | Command::Fuzz
+    | Command::Test
+    | Command::SelfTest
+    | Command::Lsp
+    | Command::Dap
+    | Command::Heatmap
+    | Command::Stats
+    | Command::TraceView
+    | Command::Asm
+    | Command::DiffBackends => {
+      unreachable!("handled before compiling")
+    }
   }
+  Ok(())
 }
@@ -0,0 +1,93 @@
+//! The figures `stats` reports about a program without running it, for
+//! code golf and for comparing programs of a corpus.
+
+use super::analyze;
+use super::optimizer;
+use super::{parse_program, Op, Token};
+
+pub struct Metrics {
+  /// How often each command is written, most frequent first.
+  pub histogram: Vec<(Token, usize)>,
+  pub commands: usize,
+  /// Instructions once runs of commands are folded.
+  pub folded: usize,
+  /// Instructions after the optimization passes.
+  pub optimized: usize,
+  pub loops: usize,
+  /// How deeply loops and procedures nest.
+  pub depth: usize,
+  /// The lowest and highest cells the pointer reaches, when they are
+  /// bounded.
+  pub range: Option<(isize, isize)>,
+}
+
+/// The metrics of the program made of `tokens`, optimized by `passes` as
+/// `optimizer::optimize` does.
+pub fn collect(
+  tokens: &[(Token, usize)],
+  passes: &[&'static str],
+  eval_budget: usize,
+  unroll_limit: usize,
+) -> Result<Metrics, String> {
+  let mut histogram: Vec<(Token, usize)> = Vec::new();
+  let mut depth: usize = 0;
+  let mut deepest = 0;
+  for &(token, _) in tokens {
+    match histogram.iter_mut().find(|(seen, _)| *seen == token) {
+      Some((_, count)) => *count += 1,
+      None => histogram.push((token, 1)),
+    }
+    match token {
+      Token::JumpIfZero | Token::ProcStart => {
+        depth += 1;
+        deepest = deepest.max(depth);
+      }
+      Token::JumpIfNonZero | Token::ProcEnd => depth = depth.saturating_sub(1),
+      _ => (),
+    }
+  }
+  // Stable, so commands written as often keep the order they first appear in.
+  histogram.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+  let program = parse_program(tokens.to_vec())?;
+  let loops = program
+    .iter()
+    .filter(|inst| matches!(inst.op, Op::JumpIfZero(_)))
+    .count();
+  let range = analyze::pointer_range(&program);
+  let folded = program.len();
+  let (optimized, _) = optimizer::optimize(program, passes, eval_budget, unroll_limit);
+  Ok(Metrics {
+    histogram,
+    commands: tokens.len(),
+    folded,
+    optimized: optimized.len(),
+    loops,
+    depth: deepest,
+    range,
+  })
+}
+
+impl Metrics {
+  pub fn report(&self) -> String {
+    let mut lines = vec![format!("{:<14} {}", "commands", self.commands)];
+    for &(token, count) in &self.histogram {
+      lines.push(format!("  {:<12} {}", token.symbol(), count));
+    }
+    lines.push(format!("{:<14} {}", "folded", self.folded));
+    lines.push(format!("{:<14} {}", "optimized", self.optimized));
+    lines.push(format!("{:<14} {}", "loops", self.loops));
+    lines.push(format!("{:<14} {}", "nesting", self.depth));
+    lines.push(match self.range {
+      Some((lo, hi)) => format!(
+        "{:<14} at least {} cells, {}..={} from the start",
+        "tape",
+        hi - lo + 1,
+        lo,
+        hi
+      ),
+      None => format!("{:<14} unbounded", "tape"),
+    });
+    lines.push(String::new());
+    lines.join("\n")
+  }
+}
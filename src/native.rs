@@ -0,0 +1,262 @@
+//! Assembly for native targets. The IR is first lowered into steps that each
+//! map to a few machine instructions, which every target then spells out in
+//! its own syntax; the targets share everything else.
+//!
+//! The generated `main` keeps the pointer in a callee-saved register, does
+//! I/O through libc's `putchar` and `getchar`, and reaches the tape, a `.bss`
+//! array, relative to that register. `.` writes characters in UTF-8, or bytes
+//! with `--io bytes`. With `--bounds error`, a pointer or cell off the tape
+//! jumps to `off_tape`, which writes `OFF_TAPE` to standard error and exits
+//! with status 1.
+
+use super::interpreter::{Bounds, Io};
+use super::jasmin::Config;
+use super::{output_bytes, reject_extensions, writes_bytes, Inst, Op, Text};
+
+/// One lowered operation. Offsets and strides count cells, which targets
+/// scale by the cell width.
+#[derive(PartialEq, Clone, Debug)]
+pub enum Step {
+  Add {
+    offset: isize,
+    amount: i32,
+  },
+  Set {
+    offset: isize,
+    value: i32,
+  },
+  Move(isize),
+  /// Stops the program unless the cell at `offset` is on the tape.
+  Check {
+    offset: isize,
+  },
+  /// Writes the current cell as a byte.
+  Put,
+  /// Writes the current cell as a character in UTF-8, which takes a second
+  /// byte from 128 up; `label` skips the lead byte below that.
+  PutUtf8 {
+    label: usize,
+  },
+  PutConst(u8),
+  /// Reads into the current cell, using `label` for the end-of-input check.
+  Read {
+    label: usize,
+  },
+  /// Tests the current cell, leaving the loop `label` when it is zero.
+  LoopStart {
+    label: usize,
+  },
+  LoopEnd {
+    label: usize,
+  },
+  /// Adds the current cell times each factor to the cell at each offset,
+  /// skipping them all when it is zero. Under `Bounds::Error` each offset
+  /// is checked before it is touched.
+  Multiply {
+    label: usize,
+    targets: Vec<(isize, i32)>,
+  },
+  /// Moves by `stride` until the current cell is zero, checking each move
+  /// under `Bounds::Error`.
+  ScanZero {
+    label: usize,
+    stride: isize,
+  },
+  /// Writes the program's `text`th constant string, `length` bytes long.
+  Print {
+    text: usize,
+    length: usize,
+  },
+}
+
+/// What `off_tape` writes to standard error.
+pub const OFF_TAPE: &str = "pointer moved off the tape\n";
+
+/// A target's spelling of each step.
+pub trait Target {
+  /// Directives and the start of `main`, with the pointer at the tape start.
+  fn prologue(&self, out: &mut Vec<String>, config: &Config);
+  fn step(&self, out: &mut Vec<String>, step: &Step, config: &Config);
+  /// Returns from `main` with the exit cell's final value, or 0.
+  fn epilogue(&self, out: &mut Vec<String>, config: &Config);
+  /// The `print` helper, the constant strings and the tape.
+  fn data(&self, out: &mut Vec<String>, texts: &[Text], config: &Config);
+}
+
+/// Bytes in a cell: wrapping cells are bytes, the others 32-bit words.
+pub fn cell_width(config: &Config) -> isize {
+  if config.wrap {
+    1
+  } else {
+    4
+  }
+}
+
+/// Lowers `instructions`, returning the steps and the constant strings the
+/// `Print` steps refer to, both writing characters unless `io` is bytes.
+/// Under `Bounds::Error`, each move and each access off the current cell
+/// is checked.
+pub fn lower(instructions: &[Inst], io: Io, bounds: Bounds) -> (Vec<Step>, Vec<Text>) {
+  let checked = bounds == Bounds::Error;
+  let mut steps = Vec::new();
+  let mut texts = Vec::new();
+  let mut labels = 0;
+  let mut label = || {
+    labels += 1;
+    labels
+  };
+  let mut loops = Vec::new();
+  let mut index = 0;
+  while index < instructions.len() {
+    match instructions[index].op {
+      Op::Procedure(_)
+      | Op::Return(_)
+      | Op::Call
+      | Op::Fork
+      | Op::Ebf(_)
+      | Op::Debug
+      | Op::PutNumber
+      | Op::ReadNumber
+      | Op::Random
+      | Op::Tape(_) => {
+        unreachable!("rejected by reject_extensions")
+      }
+      Op::Plus(count) => steps.push(Step::Add {
+        offset: 0,
+        amount: count as i32,
+      }),
+      Op::Minus(count) => steps.push(Step::Add {
+        offset: 0,
+        amount: -(count as i32),
+      }),
+      Op::Right(count) => {
+        steps.push(Step::Move(count as isize));
+        if checked {
+          steps.push(Step::Check { offset: 0 });
+        }
+      }
+      Op::Left(count) => {
+        steps.push(Step::Move(-(count as isize)));
+        if checked {
+          steps.push(Step::Check { offset: 0 });
+        }
+      }
+      Op::PutChar(count) => {
+        for _ in 0..count {
+          steps.push(match io {
+            Io::Bytes => Step::Put,
+            _ => Step::PutUtf8 { label: label() },
+          });
+        }
+      }
+      Op::ReadChar(count) => {
+        for _ in 0..count {
+          steps.push(Step::Read { label: label() });
+        }
+      }
+      Op::JumpIfZero(_) => {
+        let label = label();
+        loops.push(label);
+        steps.push(Step::LoopStart { label });
+      }
+      Op::JumpIfNonZero(_) => steps.push(Step::LoopEnd {
+        label: loops.pop().expect("unbalanced loop"),
+      }),
+      Op::SetZero => steps.push(Step::Set {
+        offset: 0,
+        value: 0,
+      }),
+      Op::AddTo { .. } => {
+        let targets: Vec<(isize, i32)> = instructions[index..]
+          .iter()
+          .map_while(|inst| match inst.op {
+            Op::AddTo { offset, factor } => Some((offset as isize, factor)),
+            _ => None,
+          })
+          .collect();
+        index += targets.len() - 1;
+        steps.push(Step::Multiply {
+          label: label(),
+          targets,
+        });
+      }
+      Op::Add { offset, amount } => {
+        if checked && offset != 0 {
+          steps.push(Step::Check {
+            offset: offset as isize,
+          });
+        }
+        steps.push(Step::Add {
+          offset: offset as isize,
+          amount,
+        })
+      }
+      Op::Set { offset, value } => {
+        if checked && offset != 0 {
+          steps.push(Step::Check {
+            offset: offset as isize,
+          });
+        }
+        steps.push(Step::Set {
+          offset: offset as isize,
+          value,
+        })
+      }
+      Op::ScanZero { stride } => steps.push(Step::ScanZero {
+        label: label(),
+        stride: stride as isize,
+      }),
+      Op::PutConst { value, count } => steps.extend(
+        output_bytes(&vec![value; count as usize], io)
+          .into_iter()
+          .map(Step::PutConst),
+      ),
+      Op::Print(ref text) => {
+        let text = Text::new(&output_bytes(text.as_bytes(), io));
+        steps.push(Step::Print {
+          text: texts.len(),
+          length: text.as_bytes().len(),
+        });
+        texts.push(text);
+      }
+    }
+    index += 1;
+  }
+  (steps, texts)
+}
+
+/// Generates assembly for `instructions` in the syntax of `target`.
+pub fn produce_asm(
+  instructions: &[Inst],
+  config: &Config,
+  target: &dyn Target,
+) -> Result<String, String> {
+  reject_extensions(instructions)?;
+  if config.tape_from_args || config.embeddable || config.runtime_args {
+    return Err(
+      "--tape-from-args, --embeddable and --runtime-args only apply to JVM output".to_string(),
+    );
+  }
+  writes_bytes(config.io)?;
+  let (steps, texts) = lower(instructions, config.io, config.bounds);
+  let mut out = Vec::new();
+  target.prologue(&mut out, config);
+  for step in &steps {
+    target.step(&mut out, step, config);
+  }
+  target.epilogue(&mut out, config);
+  target.data(&mut out, &texts, config);
+  out.push(String::new());
+  Ok(out.join("\n"))
+}
+
+/// Escapes `text` for an `.ascii` directive.
+pub fn quote(text: &[u8]) -> String {
+  text
+    .iter()
+    .map(|&byte| match byte {
+      b' '..=b'~' if byte != b'"' && byte != b'\\' => (byte as char).to_string(),
+      _ => format!("\\{:03o}", byte),
+    })
+    .collect()
+}
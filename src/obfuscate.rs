@@ -0,0 +1,161 @@
+//! The rewriting behind `obfuscate`, which makes a program longer and
+//! harder to read without changing what it does.
+//!
+//! A run of `+` and `-` becomes a longer shuffle of both adding up to the
+//! same amount, and a run of `>` and `<` overshoots and comes back, always
+//! to the right so that it never passes the left end of the tape. Pairs
+//! that cancel out are scattered between commands, and a loop that is never
+//! entered follows some `]`, after which the cell is always zero. The
+//! result is checked against the original by running both.
+
+use std::io;
+
+use super::interpreter::{Interpreter, Random};
+use super::{bf, has_forks, lex_dialect, parse_program, Dialect, Inst, Token};
+
+/// Steps the original program runs for when it is compared with the
+/// rewritten one, which may take a few times as many.
+pub const VERIFY_STEPS: usize = 10_000_000;
+
+/// Pairs of commands that undo each other, wherever they are inserted.
+const NO_OPS: [&str; 3] = ["+-", "-+", "><"];
+
+/// Commands the body of a dead loop is made of.
+const DEAD_CODE: &[u8] = b"+-<>.";
+
+/// A number below `bound`.
+fn below(random: &mut Random, bound: usize) -> usize {
+  random.next_byte() as usize % bound
+}
+
+/// Writes `ups` of `up` and `downs` of `down` in a random order.
+fn shuffle(out: &mut String, random: &mut Random, up: char, ups: usize, down: char, downs: usize) {
+  let (mut ups, mut downs) = (ups, downs);
+  while ups + downs > 0 {
+    if below(random, ups + downs) < ups {
+      out.push(up);
+      ups -= 1;
+    } else {
+      out.push(down);
+      downs -= 1;
+    }
+  }
+}
+
+/// The sum of the run of `up` and `down` tokens starting `tokens`, and its
+/// length.
+fn run(tokens: &[Token], up: Token, down: Token) -> (i64, usize) {
+  let len = tokens
+    .iter()
+    .take_while(|&&token| token == up || token == down)
+    .count();
+  let sum = tokens[..len]
+    .iter()
+    .map(|&token| if token == up { 1 } else { -1 })
+    .sum();
+  (sum, len)
+}
+
+/// Writes a longer program than `tokens` that does the same.
+fn scramble(tokens: &[Token], random: &mut Random) -> String {
+  let mut out = String::new();
+  let mut index = 0;
+  while index < tokens.len() {
+    match tokens[index] {
+      Token::Plus | Token::Minus => {
+        let (sum, len) = run(&tokens[index..], Token::Plus, Token::Minus);
+        let extra = below(random, 4);
+        let (ups, downs) = if sum < 0 {
+          (extra, extra + sum.unsigned_abs() as usize)
+        } else {
+          (extra + sum as usize, extra)
+        };
+        shuffle(&mut out, random, '+', ups, '-', downs);
+        index += len;
+      }
+      Token::Right | Token::Left => {
+        let (sum, len) = run(&tokens[index..], Token::Right, Token::Left);
+        let extra = below(random, 3);
+        let (rights, lefts) = if sum < 0 {
+          (extra, extra + sum.unsigned_abs() as usize)
+        } else {
+          (extra + sum as usize, extra)
+        };
+        out.push_str(&">".repeat(rights));
+        out.push_str(&"<".repeat(lefts));
+        index += len;
+      }
+      token => {
+        out.push(token.symbol());
+        if token == Token::JumpIfNonZero && below(random, 3) == 0 {
+          out.push('[');
+          for _ in 0..2 + below(random, 6) {
+            out.push(DEAD_CODE[below(random, DEAD_CODE.len())] as char);
+          }
+          out.push(']');
+        }
+        index += 1;
+      }
+    }
+    if below(random, 3) == 0 {
+      out.push_str(NO_OPS[below(random, NO_OPS.len())]);
+    }
+  }
+  out
+}
+
+/// Runs `program` on empty input for at most `steps` steps, returning its
+/// output and whether it halted, or the error it stopped with.
+fn execute(program: &[Inst], steps: usize) -> (Vec<u8>, Result<bool, String>) {
+  let mut output = Vec::new();
+  let mut interpreter = Interpreter::new(program).with_seed(0);
+  // Threads are only run to completion.
+  if has_forks(program) {
+    let result = interpreter.run(&mut io::empty(), &mut output, None);
+    return (output, result.map(|()| true).map_err(|e| e.to_string()));
+  }
+  for _ in 0..steps {
+    match interpreter.step(&mut io::empty(), &mut output) {
+      Ok(Some(_)) => (),
+      Ok(None) => return (output, Ok(true)),
+      Err(e) => return (output, Err(e.to_string())),
+    }
+  }
+  (output, Ok(false))
+}
+
+/// Fails unless `obfuscated` behaves as `original` does on empty input, as
+/// far as `VERIFY_STEPS` steps of the original show.
+pub fn verify(original: &[Inst], obfuscated: &[Inst]) -> Result<(), String> {
+  let (expected, ended) = execute(original, VERIFY_STEPS);
+  let (actual, result) = execute(obfuscated, 4 * VERIFY_STEPS);
+  let same = match ended {
+    Ok(false) => expected.starts_with(&actual) || actual.starts_with(&expected),
+    ended => actual == expected && result == ended,
+  };
+  if same {
+    Ok(())
+  } else {
+    Err("the obfuscated program behaves differently".to_string())
+  }
+}
+
+/// Rewrites the commands `tokens` of a `dialect` program with choices drawn
+/// from `random`, into lines of checked code.
+pub fn obfuscate(
+  tokens: &[Token],
+  dialect: Dialect,
+  random: &mut Random,
+) -> Result<String, String> {
+  let code = scramble(tokens, random);
+  let original = parse_program(
+    tokens
+      .iter()
+      .enumerate()
+      .map(|(pos, &token)| (token, pos))
+      .collect(),
+  )?;
+  let obfuscated = parse_program(lex_dialect(&code, dialect)?)?;
+  verify(&original, &obfuscated)?;
+  Ok(bf::lines(&code))
+}
@@ -0,0 +1,844 @@
+//! Rewrite passes over the folded IR.
+
+use super::constants;
+use super::evaluate;
+use super::interpreter::TAPE_SIZE;
+use super::peephole;
+use super::{Inst, Op, Span, Tape};
+
+#[derive(PartialEq, PartialOrd, Copy, Clone, Debug)]
+pub enum Level {
+  /// No rewriting at all.
+  O0,
+  /// Loop lowering, offset addressing and peephole rules.
+  O1,
+  /// Additionally removes loops that can never run, evaluates input-free
+  /// code at compile time and folds cells with values known statically.
+  O2,
+}
+
+/// Something a pass changed or established about a region of the source.
+#[derive(Debug)]
+pub struct Note {
+  pub pass: &'static str,
+  pub span: Span,
+  pub message: String,
+}
+
+/// What one pass did to the program as a whole.
+#[derive(Debug)]
+pub struct PassStats {
+  pub name: &'static str,
+  /// Number of notes the pass recorded.
+  pub fired: usize,
+  pub before: usize,
+  pub after: usize,
+}
+
+#[derive(Default, Debug)]
+pub struct Stats {
+  pub passes: Vec<PassStats>,
+  pub notes: Vec<Note>,
+  current: &'static str,
+}
+
+impl Stats {
+  /// Records a note on behalf of the pass that is currently running.
+  pub fn note(&mut self, span: Span, message: String) {
+    self.notes.push(Note {
+      pass: self.current,
+      span,
+      message,
+    });
+  }
+
+  fn run<F>(&mut self, name: &'static str, program: Vec<Inst>, pass: F) -> Vec<Inst>
+  where
+    F: FnOnce(Vec<Inst>, &mut Stats) -> Vec<Inst>,
+  {
+    let before = program.len();
+    let notes = self.notes.len();
+    self.current = name;
+    let instructions = pass(program, self);
+    self.passes.push(PassStats {
+      name,
+      fired: self.notes.len() - notes,
+      before,
+      after: instructions.len(),
+    });
+    instructions
+  }
+}
+
+/// Names accepted by `--passes`, in the order `-O2` runs them.
+pub const PASSES: &[&str] = &[
+  "fold",
+  "clear-loop",
+  "scan-loop",
+  "multiply",
+  "offset",
+  "peephole",
+  "dce",
+  "partial-eval",
+  "constants",
+];
+
+/// The passes run for `level`. `loop_opts` disables clear and
+/// multiplication loop lowering so their effect can be verified.
+pub fn preset(level: Level, loop_opts: bool) -> Vec<&'static str> {
+  let mut passes = Vec::new();
+  if level >= Level::O1 {
+    if loop_opts {
+      passes.extend(["clear-loop", "scan-loop", "multiply"]);
+    }
+    passes.extend(["offset", "peephole"]);
+  }
+  if level >= Level::O2 {
+    passes.extend(["dce", "partial-eval", "constants"]);
+  }
+  passes
+}
+
+/// Parses a comma-separated pass list such as `fold,clear-loop,offset,dce`.
+pub fn parse_passes(text: &str) -> Result<Vec<&'static str>, String> {
+  text
+    .split(',')
+    .filter(|name| !name.trim().is_empty())
+    .map(|name| {
+      let name = name.trim();
+      PASSES
+        .iter()
+        .find(|&&known| known == name)
+        .copied()
+        .ok_or_else(|| format!("unknown pass {} (known: {})", name, PASSES.join(", ")))
+    })
+    .collect()
+}
+
+/// `passes` less the two evaluating the program at compile time, which fold
+/// cells as bytes wrapping at 256, unless `wrapping_bytes` says the cells
+/// the program runs on are such.
+pub fn for_cells(passes: Vec<&'static str>, wrapping_bytes: bool) -> Vec<&'static str> {
+  passes
+    .into_iter()
+    .filter(|&pass| wrapping_bytes || !matches!(pass, "partial-eval" | "constants"))
+    .collect()
+}
+
+/// A transformation of the IR from outside this crate, run among the
+/// built-in passes by `Compiler`.
+pub trait Pass: Send + Sync {
+  /// The name its notes and statistics go under.
+  fn name(&self) -> &'static str;
+
+  /// Where the pass runs: before every built-in pass of a higher
+  /// `priority` and after the rest, or after all of them by default.
+  /// Passes of the same priority run in the order they were registered.
+  fn priority(&self) -> i32 {
+    i32::MAX
+  }
+
+  /// Rewrites `program`, recording what it did in `stats`. Jumps must be
+  /// linked again afterwards, which `link_jumps` does.
+  fn run(&self, program: Vec<Inst>, stats: &mut Stats) -> Vec<Inst>;
+}
+
+/// The priority of a built-in pass, spaced by its place in `PASSES` so
+/// that a `Pass` can run between two of them.
+pub fn priority(name: &str) -> Option<i32> {
+  PASSES
+    .iter()
+    .position(|&known| known == name)
+    .map(|position| position as i32 * 100)
+}
+
+/// The built-in passes to run and the `Pass`es registered besides them.
+pub struct Compiler {
+  passes: Vec<&'static str>,
+  plugins: Vec<Box<dyn Pass>>,
+  eval_budget: usize,
+  unroll_limit: usize,
+  tape_size: usize,
+}
+
+impl Compiler {
+  /// Runs `passes` in order. `eval_budget` bounds how much of the program
+  /// is evaluated at compile time and `unroll_limit` how large a loop with
+  /// a known trip count may grow when unrolled.
+  pub fn new(passes: &[&'static str], eval_budget: usize, unroll_limit: usize) -> Compiler {
+    Compiler {
+      passes: passes.to_vec(),
+      plugins: Vec::new(),
+      eval_budget,
+      unroll_limit,
+      tape_size: TAPE_SIZE,
+    }
+  }
+
+  /// Evaluates at compile time on a tape of `size` cells in place of
+  /// `TAPE_SIZE`, as the program will run on.
+  pub fn with_tape_size(mut self, size: usize) -> Compiler {
+    self.tape_size = size;
+    self
+  }
+
+  pub fn register_pass(&mut self, pass: Box<dyn Pass>) {
+    self.plugins.push(pass);
+  }
+
+  fn run_builtin(&self, name: &'static str, program: Vec<Inst>, stats: &mut Stats) -> Vec<Inst> {
+    match name {
+      "fold" => stats.run(name, program, fold),
+      "clear-loop" => stats.run(name, program, clear_loops),
+      "scan-loop" => stats.run(name, program, scan_loops),
+      "multiply" => stats.run(name, program, multiply_loops),
+      "offset" => stats.run(name, program, offset_ops),
+      "peephole" => stats.run(name, program, |program, stats| {
+        peephole::rewrite(program, peephole::RULES, stats)
+      }),
+      "dce" => stats.run(name, program, dead_loops),
+      "partial-eval" => stats.run(name, program, |program, stats| {
+        evaluate::partial_eval(program, self.eval_budget, self.tape_size, stats)
+      }),
+      "constants" => stats.run(name, program, |program, stats| {
+        constants::constant_cells(program, self.unroll_limit, stats)
+      }),
+      _ => unreachable!("pass names are validated by parse_passes"),
+    }
+  }
+
+  pub fn optimize(&self, program: Vec<Inst>) -> (Vec<Inst>, Stats) {
+    let mut stats = Stats::default();
+    let mut instructions = program;
+    let mut plugins: Vec<&dyn Pass> = self.plugins.iter().map(|pass| &**pass).collect();
+    plugins.sort_by_key(|pass| pass.priority());
+    let mut plugins = plugins.into_iter().peekable();
+    for &name in &self.passes {
+      let priority = priority(name).unwrap_or(i32::MAX);
+      while let Some(pass) = plugins.next_if(|pass| pass.priority() < priority) {
+        instructions = stats.run(pass.name(), instructions, |program, stats| {
+          pass.run(program, stats)
+        });
+      }
+      instructions = self.run_builtin(name, instructions, &mut stats);
+    }
+    for pass in plugins {
+      instructions = stats.run(pass.name(), instructions, |program, stats| {
+        pass.run(program, stats)
+      });
+    }
+    (instructions, stats)
+  }
+}
+
+/// Runs `passes` in order, with nothing registered besides them.
+pub fn optimize(
+  program: Vec<Inst>,
+  passes: &[&'static str],
+  eval_budget: usize,
+  unroll_limit: usize,
+) -> (Vec<Inst>, Stats) {
+  Compiler::new(passes, eval_budget, unroll_limit).optimize(program)
+}
+
+/// Recomputes the targets of every jump after a pass has moved instructions.
+pub fn link_jumps(instructions: &mut [Inst]) {
+  let mut stack = Vec::new();
+  for pos in 0..instructions.len() {
+    match instructions[pos].op {
+      Op::JumpIfZero(_) => stack.push(pos),
+      Op::JumpIfNonZero(_) => {
+        let open = stack.pop().unwrap();
+        instructions[open].op = Op::JumpIfZero(pos as u32);
+        instructions[pos].op = Op::JumpIfNonZero(open as u32);
+      }
+      Op::Procedure(_) => stack.push(pos),
+      Op::Return(_) => {
+        let open = stack.pop().unwrap();
+        instructions[open].op = Op::Procedure(pos as u32);
+        instructions[pos].op = Op::Return(open as u32);
+      }
+      _ => (),
+    }
+  }
+}
+
+/// Net cell (`0`) or pointer (`1`) change of a `+`/`-`/`>`/`<` instruction.
+fn fold_delta(op: &Op) -> Option<(u8, i64)> {
+  match *op {
+    Op::Plus(count) => Some((0, count as i64)),
+    Op::Minus(count) => Some((0, -(count as i64))),
+    Op::Right(count) => Some((1, count as i64)),
+    Op::Left(count) => Some((1, -(count as i64))),
+    _ => None,
+  }
+}
+
+/// Merges adjacent `+`/`-` and `>`/`<` instructions left behind by other
+/// passes, dropping runs that cancel out.
+pub fn fold(program: Vec<Inst>, stats: &mut Stats) -> Vec<Inst> {
+  let mut instructions: Vec<Inst> = Vec::with_capacity(program.len());
+  for inst in program {
+    let previous = instructions.last().and_then(|last| fold_delta(&last.op));
+    match (previous, fold_delta(&inst.op)) {
+      (Some((kind, a)), Some((other, b))) if kind == other => {
+        let last = instructions.pop().unwrap();
+        let span = Span {
+          start: last.span.start,
+          end: inst.span.end,
+        };
+        let net = a + b;
+        let op = match kind {
+          _ if net == 0 => None,
+          0 if net > 0 => Some(Op::Plus(net as u32)),
+          0 => Some(Op::Minus(-net as u32)),
+          _ if net > 0 => Some(Op::Right(net as u32)),
+          _ => Some(Op::Left(-net as u32)),
+        };
+        stats.note(span, format!("merged into {:?}", op));
+        if let Some(op) = op {
+          instructions.push(Inst { op, span });
+        }
+      }
+      _ => instructions.push(inst),
+    }
+  }
+  link_jumps(&mut instructions);
+  instructions
+}
+
+/// Replaces `[-]` and `[+]` loops with `SetZero`.
+pub fn clear_loops(program: Vec<Inst>, stats: &mut Stats) -> Vec<Inst> {
+  let mut instructions = Vec::with_capacity(program.len());
+  let mut pos = 0;
+  while pos < program.len() {
+    match program[pos..] {
+      [Inst {
+        op: Op::JumpIfZero(_),
+        span: open,
+      }, Inst {
+        op: Op::Plus(1), ..
+      }
+      | Inst {
+        op: Op::Minus(1), ..
+      }, Inst {
+        op: Op::JumpIfNonZero(_),
+        span: close,
+      }, ..] => {
+        let span = Span {
+          start: open.start,
+          end: close.end,
+        };
+        instructions.push(Inst {
+          op: Op::SetZero,
+          span,
+        });
+        stats.note(span, "clear loop replaced by SetZero".to_string());
+        pos += 3;
+      }
+      _ => {
+        instructions.push(program[pos].clone());
+        pos += 1;
+      }
+    }
+  }
+  link_jumps(&mut instructions);
+  instructions
+}
+
+/// Replaces loops whose body is a single pointer move, such as `[>]` or
+/// `[<<]`, with `ScanZero`.
+pub fn scan_loops(program: Vec<Inst>, stats: &mut Stats) -> Vec<Inst> {
+  let mut instructions = Vec::with_capacity(program.len());
+  let mut pos = 0;
+  while pos < program.len() {
+    if let [Inst {
+      op: Op::JumpIfZero(_),
+      span: open,
+    }, Inst { op: ref body, .. }, Inst {
+      op: Op::JumpIfNonZero(_),
+      span: close,
+    }, ..] = program[pos..]
+    {
+      let stride = match *body {
+        Op::Right(count) => Some(count as i32),
+        Op::Left(count) => Some(-(count as i32)),
+        _ => None,
+      };
+      if let Some(stride) = stride {
+        let span = Span {
+          start: open.start,
+          end: close.end,
+        };
+        instructions.push(Inst {
+          op: Op::ScanZero { stride },
+          span,
+        });
+        stats.note(
+          span,
+          format!("scan loop replaced by ScanZero with stride {}", stride),
+        );
+        pos += 3;
+        continue;
+      }
+    }
+    instructions.push(program[pos].clone());
+    pos += 1;
+  }
+  link_jumps(&mut instructions);
+  instructions
+}
+
+/// Lowers balanced loops that only add to cells and step the current one by
+/// one (e.g. `[->>+++<<]`) into `AddTo` operations and a `SetZero`.
+pub fn multiply_loops(program: Vec<Inst>, stats: &mut Stats) -> Vec<Inst> {
+  let mut instructions = Vec::with_capacity(program.len());
+  let mut pos = 0;
+  while pos < program.len() {
+    if let Op::JumpIfZero(end) = program[pos].op {
+      let end = end as usize;
+      if let Some(targets) = multiplication(&program[pos + 1..end]) {
+        let span = Span {
+          start: program[pos].span.start,
+          end: program[end].span.end,
+        };
+        stats.note(
+          span,
+          format!(
+            "multiplication loop lowered to {} AddTo operations",
+            targets.len()
+          ),
+        );
+        for (offset, factor) in targets {
+          instructions.push(Inst {
+            op: Op::AddTo { offset, factor },
+            span,
+          });
+        }
+        instructions.push(Inst {
+          op: Op::SetZero,
+          span,
+        });
+        pos = end + 1;
+        continue;
+      }
+    }
+    instructions.push(program[pos].clone());
+    pos += 1;
+  }
+  link_jumps(&mut instructions);
+  instructions
+}
+
+/// Returns the `(offset, factor)` pairs of a multiplication loop body, in
+/// order of first appearance, or `None` if the body is not one.
+fn multiplication(body: &[Inst]) -> Option<Vec<(i32, i32)>> {
+  let mut offset = 0;
+  let mut deltas: Vec<(i32, i32)> = Vec::new();
+  for inst in body {
+    let delta = match inst.op {
+      Op::Plus(count) => count as i32,
+      Op::Minus(count) => -(count as i32),
+      Op::Right(count) => {
+        offset += count as i32;
+        continue;
+      }
+      Op::Left(count) => {
+        offset -= count as i32;
+        continue;
+      }
+      _ => return None,
+    };
+    match deltas.iter_mut().find(|(o, _)| *o == offset) {
+      Some((_, total)) => *total += delta,
+      None => deltas.push((offset, delta)),
+    }
+  }
+  let counter = deltas.iter().position(|&(o, _)| o == 0)?;
+  let step = deltas[counter].1;
+  if offset != 0 || (step != -1 && step != 1) {
+    return None;
+  }
+  deltas.remove(counter);
+  deltas.retain(|&(_, factor)| factor != 0);
+  // Counting up from `v` runs `-v` times modulo the cell width, both for
+  // wrapping bytes and for 32-bit ints, so the factors just flip sign.
+  if step == 1 {
+    for (_, factor) in deltas.iter_mut() {
+      *factor = -*factor;
+    }
+  }
+  Some(deltas)
+}
+
+#[derive(Copy, Clone)]
+enum Effect {
+  Add(i32),
+  Set(i32),
+}
+
+fn is_straight_line(op: &Op) -> bool {
+  matches!(
+    op,
+    Op::Plus(_) | Op::Minus(_) | Op::Right(_) | Op::Left(_) | Op::SetZero
+  )
+}
+
+/// Rewrites runs of cell updates and pointer moves such as `>+>++<<` into
+/// offset-addressed `Add`/`Set` operations followed by one net move.
+pub fn offset_ops(program: Vec<Inst>, stats: &mut Stats) -> Vec<Inst> {
+  let mut instructions = Vec::with_capacity(program.len());
+  let mut pos = 0;
+  while pos < program.len() {
+    let len = program[pos..]
+      .iter()
+      .take_while(|inst| is_straight_line(&inst.op))
+      .count();
+    if len == 0 {
+      instructions.push(program[pos].clone());
+      pos += 1;
+      continue;
+    }
+    let run = &program[pos..pos + len];
+    let moves = run
+      .iter()
+      .filter(|inst| matches!(inst.op, Op::Right(_) | Op::Left(_)))
+      .count();
+    if len > 1 && moves > 0 {
+      let emitted = instructions.len();
+      lower_run(run, &mut instructions);
+      let span = Span {
+        start: run[0].span.start,
+        end: run[len - 1].span.end,
+      };
+      stats.note(
+        span,
+        format!(
+          "{} instructions fused into {} offset operations",
+          len,
+          instructions.len() - emitted
+        ),
+      );
+    } else {
+      instructions.extend_from_slice(run);
+    }
+    pos += len;
+  }
+  link_jumps(&mut instructions);
+  instructions
+}
+
+fn lower_run(run: &[Inst], instructions: &mut Vec<Inst>) {
+  let mut offset = 0;
+  let mut effects: Vec<(i32, Effect, Span)> = Vec::new();
+  for inst in run {
+    let effect = match inst.op {
+      Op::Plus(count) => Effect::Add(count as i32),
+      Op::Minus(count) => Effect::Add(-(count as i32)),
+      Op::SetZero => Effect::Set(0),
+      Op::Right(count) => {
+        offset += count as i32;
+        continue;
+      }
+      Op::Left(count) => {
+        offset -= count as i32;
+        continue;
+      }
+      _ => unreachable!("not a straight-line operation"),
+    };
+    match effects.iter_mut().find(|(o, _, _)| *o == offset) {
+      Some((_, previous, span)) => {
+        *previous = match (*previous, effect) {
+          (Effect::Add(a), Effect::Add(b)) => Effect::Add(a + b),
+          (Effect::Set(a), Effect::Add(b)) => Effect::Set(a + b),
+          (_, set) => set,
+        };
+        span.end = inst.span.end;
+      }
+      None => effects.push((offset, effect, inst.span)),
+    }
+  }
+  for (offset, effect, span) in effects {
+    let op = match effect {
+      Effect::Add(0) => continue,
+      Effect::Add(amount) => Op::Add { offset, amount },
+      Effect::Set(value) => Op::Set { offset, value },
+    };
+    instructions.push(Inst { op, span });
+  }
+  let span = Span {
+    start: run[0].span.start,
+    end: run[run.len() - 1].span.end,
+  };
+  if offset > 0 {
+    instructions.push(Inst {
+      op: Op::Right(offset as u32),
+      span,
+    });
+  } else if offset < 0 {
+    instructions.push(Inst {
+      op: Op::Left(-offset as u32),
+      span,
+    });
+  }
+}
+
+/// Removes loops entered while the current cell is known to be zero: loops
+/// before anything has been written to the tape, and loops directly after
+/// another loop or a clear (also when the clear was folded into an offset
+/// `Set` and the pointer then moved onto it).
+pub fn dead_loops(program: Vec<Inst>, stats: &mut Stats) -> Vec<Inst> {
+  let mut instructions = Vec::with_capacity(program.len());
+  // No cell has been written yet, so the whole tape is zero.
+  let mut pristine = true;
+  // Offsets from the pointer of cells known to hold zero.
+  let mut zeros: Vec<i32> = Vec::new();
+  let mut pos = 0;
+  while pos < program.len() {
+    let inst = &program[pos];
+    match inst.op {
+      Op::JumpIfZero(end) if pristine || zeros.contains(&0) => {
+        let end = end as usize;
+        let span = Span {
+          start: inst.span.start,
+          end: program[end].span.end,
+        };
+        stats.note(
+          span,
+          "loop entered on a cell known to be zero, removed".to_string(),
+        );
+        pos = end + 1;
+        continue;
+      }
+      Op::JumpIfZero(_) => zeros.clear(),
+      Op::JumpIfNonZero(_) | Op::SetZero | Op::ScanZero { .. } => zeros = vec![0],
+      Op::Right(count) => zeros.iter_mut().for_each(|o| *o -= count as i32),
+      Op::Left(count) => zeros.iter_mut().for_each(|o| *o += count as i32),
+      Op::PutChar(_)
+      | Op::PutConst { .. }
+      | Op::Print(_)
+      | Op::Debug
+      | Op::PutNumber
+      | Op::Tape(Tape::Copy) => (),
+      Op::Set { offset, value: 0 } => {
+        pristine = false;
+        zeros.push(offset);
+      }
+      Op::Plus(_) | Op::Minus(_) | Op::ReadChar(_) | Op::ReadNumber | Op::Random | Op::Ebf(_) => {
+        pristine = false;
+        zeros.retain(|&o| o != 0);
+      }
+      Op::AddTo { offset, .. } | Op::Add { offset, .. } | Op::Set { offset, .. } => {
+        pristine = false;
+        zeros.retain(|&o| o != offset);
+      }
+      // A procedure body runs on whatever tape its callers leave, a call
+      // can change any cell and a forked thread continues on another one.
+      // The other tapes too, as the next one may have been written.
+      Op::Procedure(_) | Op::Return(_) | Op::Call | Op::Fork | Op::Tape(Tape::Next) => {
+        pristine = false;
+        zeros.clear();
+      }
+    }
+    instructions.push(inst.clone());
+    pos += 1;
+  }
+  link_jumps(&mut instructions);
+  instructions
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::interpreter::Interpreter;
+  use super::super::{lex_dialect, parse_program, Dialect, Inst, Op};
+  use super::{constants, evaluate, for_cells, optimize, preset, Level, PASSES};
+
+  /// Programs the passes all have something to rewrite in, with their input.
+  const PROGRAMS: &[(&str, &[u8])] = &[
+    (
+      "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.",
+      b"",
+    ),
+    ("+++[>+++++<-]>[>+>+<<-]>>[-]<.>++[<+>-]<.", b""),
+    ("-[>+<---]>.[-]>>+<<[>]>.[.]", b""),
+    (",[.[-],]>,[->+>++<<]>>.<.", b"ab\x80"),
+    ("+>+++++<[>]<[-]+[+]-.", b""),
+  ];
+
+  fn parsed(code: &str) -> Vec<Inst> {
+    lex_dialect(code, Dialect::Brainfuck)
+      .and_then(parse_program)
+      .unwrap()
+  }
+
+  /// The operations `passes` leave of `code`.
+  fn ops(code: &str, passes: &[&'static str]) -> Vec<Op> {
+    let (instructions, _) = optimize(
+      parsed(code),
+      passes,
+      evaluate::DEFAULT_BUDGET,
+      constants::DEFAULT_UNROLL_LIMIT,
+    );
+    instructions.into_iter().map(|inst| inst.op).collect()
+  }
+
+  /// What `instructions` print reading `input`.
+  fn run(instructions: &[Inst], input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    Interpreter::new(instructions)
+      .run(&mut &input[..], &mut output, None)
+      .unwrap();
+    output
+  }
+
+  fn optimized(code: &str, wrapping_bytes: bool) -> Vec<Inst> {
+    let program = lex_dialect(code, Dialect::Brainfuck)
+      .and_then(parse_program)
+      .unwrap();
+    let passes = for_cells(preset(Level::O2, true), wrapping_bytes);
+    optimize(
+      program,
+      &passes,
+      evaluate::DEFAULT_BUDGET,
+      constants::DEFAULT_UNROLL_LIMIT,
+    )
+    .0
+  }
+
+  #[test]
+  fn clear_loops_become_set_zero() {
+    assert_eq!(
+      ops("+[-]>[+]", &["clear-loop"]),
+      [Op::Plus(1), Op::SetZero, Op::Right(1), Op::SetZero]
+    );
+    assert!(ops("+[--]", &["clear-loop"]).contains(&Op::Minus(2)));
+  }
+
+  #[test]
+  fn scan_loops_become_scan_zero() {
+    assert_eq!(
+      ops("+[>]<[<<]", &["scan-loop"]),
+      [
+        Op::Plus(1),
+        Op::ScanZero { stride: 1 },
+        Op::Left(1),
+        Op::ScanZero { stride: -2 }
+      ]
+    );
+  }
+
+  #[test]
+  fn multiplication_loops_become_add_to() {
+    assert_eq!(
+      ops("+[->>+++<<]", &["multiply"]),
+      [
+        Op::Plus(1),
+        Op::AddTo {
+          offset: 2,
+          factor: 3
+        },
+        Op::SetZero
+      ]
+    );
+    // Counting up runs as many times as counting down from the negation.
+    assert_eq!(
+      ops("-[+>-<]", &["multiply"])[1],
+      Op::AddTo {
+        offset: 1,
+        factor: 1
+      }
+    );
+    // Unbalanced bodies and bodies stepping the counter by two are loops.
+    for code in &["+[->+<<]", "+[-->+<]", "+[->.<]"] {
+      assert!(
+        ops(code, &["multiply"])
+          .iter()
+          .any(|op| matches!(op, Op::JumpIfNonZero(_))),
+        "{}",
+        code
+      );
+    }
+  }
+
+  #[test]
+  fn pointer_moves_fuse_into_offsets() {
+    assert_eq!(
+      ops(">+>++<<", &["offset"]),
+      [
+        Op::Add {
+          offset: 1,
+          amount: 1
+        },
+        Op::Add {
+          offset: 2,
+          amount: 2
+        }
+      ]
+    );
+    assert_eq!(ops(">+>-", &["offset"]).last(), Some(&Op::Right(2)));
+  }
+
+  #[test]
+  fn loops_on_zero_cells_are_dead() {
+    assert_eq!(ops("[.]+", &["dce"]), [Op::Plus(1)]);
+    assert_eq!(ops("+[-][.]", &["dce"]).len(), 4);
+    assert_eq!(ops("+[-],[.]", &["dce"]).len(), 8);
+  }
+
+  #[test]
+  fn folding_cancels_what_passes_leave_side_by_side() {
+    assert!(ops(">[]<", &["dce", "fold"]).is_empty());
+    assert_eq!(ops(">[]>>", &["dce", "fold"]), [Op::Right(3)]);
+  }
+
+  #[test]
+  fn every_pass_keeps_what_programs_print() {
+    let presets = [preset(Level::O1, true), preset(Level::O2, true)];
+    let passes = PASSES
+      .iter()
+      .map(|&pass| vec![pass])
+      .chain(presets.iter().cloned());
+    for passes in passes {
+      for &(code, input) in PROGRAMS {
+        let (instructions, _) = optimize(
+          parsed(code),
+          &passes,
+          evaluate::DEFAULT_BUDGET,
+          constants::DEFAULT_UNROLL_LIMIT,
+        );
+        assert_eq!(
+          run(&instructions, input),
+          run(&parsed(code), input),
+          "{} with {:?}",
+          code,
+          passes
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn cells_that_do_not_wrap_are_left_to_run_time() {
+    // 256 is zero only in a byte, where the loop never runs.
+    let code = "+".repeat(256) + "[.]";
+    assert!(optimized(&code, true).is_empty());
+    assert!(optimized(&code, false)
+      .iter()
+      .any(|inst| inst.op == Op::PutChar(1)));
+  }
+
+  #[test]
+  fn overflow_traps_at_every_level() {
+    let trap = |instructions: &[Inst]| {
+      Interpreter::new(instructions)
+        .with_trap_overflow(true)
+        .run(&mut &b""[..], &mut Vec::new(), None)
+        .map_err(|error| error.to_string())
+    };
+    let o0 = lex_dialect("-.", Dialect::Brainfuck)
+      .and_then(parse_program)
+      .unwrap();
+    assert!(trap(&o0).is_err());
+    assert_eq!(trap(&optimized("-.", false)), trap(&o0));
+  }
+}
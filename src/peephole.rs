@@ -0,0 +1,214 @@
+//! Peephole rewriting over the IR.
+//!
+//! Each rule matches a fixed-width window of operations and produces its
+//! replacement. Rules are applied repeatedly until none of them fires, so
+//! new optimizations can be added to `RULES` without writing another pass.
+
+use super::optimizer::{link_jumps, Stats};
+use super::{Inst, Op, Span};
+
+pub struct Rule {
+  pub name: &'static str,
+  pub width: usize,
+  pub rewrite: fn(&[Op]) -> Option<Vec<Op>>,
+}
+
+pub const RULES: &[Rule] = &[
+  Rule {
+    name: "clear-then-add",
+    width: 2,
+    rewrite: |ops| match *ops {
+      [Op::SetZero, Op::Plus(count)] => Some(vec![Op::Set {
+        offset: 0,
+        value: count as i32,
+      }]),
+      [Op::SetZero, Op::Minus(count)] => Some(vec![Op::Set {
+        offset: 0,
+        value: -(count as i32),
+      }]),
+      [Op::SetZero, Op::Add { offset: 0, amount }] => Some(vec![Op::Set {
+        offset: 0,
+        value: amount,
+      }]),
+      _ => None,
+    },
+  },
+  Rule {
+    name: "set-then-add",
+    width: 2,
+    rewrite: |ops| match *ops {
+      [Op::Set { offset, value }, Op::Add { offset: o, amount }] if o == offset => {
+        Some(vec![Op::Set {
+          offset,
+          value: value + amount,
+        }])
+      }
+      [Op::Set { offset: 0, value }, Op::Plus(count)] => Some(vec![Op::Set {
+        offset: 0,
+        value: value + count as i32,
+      }]),
+      [Op::Set { offset: 0, value }, Op::Minus(count)] => Some(vec![Op::Set {
+        offset: 0,
+        value: value - count as i32,
+      }]),
+      _ => None,
+    },
+  },
+  Rule {
+    name: "overwritten-store",
+    width: 2,
+    rewrite: |ops| match *ops {
+      [Op::SetZero, Op::SetZero] | [Op::Set { offset: 0, .. }, Op::SetZero] => {
+        Some(vec![Op::SetZero])
+      }
+      [Op::Set { offset, .. }, Op::Set { offset: o, value }] if o == offset => {
+        Some(vec![Op::Set { offset, value }])
+      }
+      [Op::Add { offset, .. }, Op::Set { offset: o, value }] if o == offset => {
+        Some(vec![Op::Set { offset, value }])
+      }
+      _ => None,
+    },
+  },
+  Rule {
+    name: "merge-adds",
+    width: 2,
+    rewrite: |ops| match *ops {
+      [Op::Plus(a), Op::Plus(b)] => Some(vec![Op::Plus(a + b)]),
+      [Op::Minus(a), Op::Minus(b)] => Some(vec![Op::Minus(a + b)]),
+      [Op::Add { offset, amount: a }, Op::Add {
+        offset: o,
+        amount: b,
+      }] if o == offset => Some(vec![Op::Add {
+        offset,
+        amount: a + b,
+      }]),
+      _ => None,
+    },
+  },
+  Rule {
+    name: "merge-moves",
+    width: 2,
+    rewrite: |ops| {
+      let net = |op: &Op| match *op {
+        Op::Right(count) => Some(count as i64),
+        Op::Left(count) => Some(-(count as i64)),
+        _ => None,
+      };
+      let total = net(&ops[0])? + net(&ops[1])?;
+      Some(match total {
+        0 => vec![],
+        _ if total > 0 => vec![Op::Right(total as u32)],
+        _ => vec![Op::Left(total.unsigned_abs() as u32)],
+      })
+    },
+  },
+  Rule {
+    name: "zero-add",
+    width: 1,
+    rewrite: |ops| match *ops {
+      [Op::Add { amount: 0, .. }] => Some(vec![]),
+      _ => None,
+    },
+  },
+];
+
+/// Applies `rules` until none of them matches anywhere in the program.
+pub fn rewrite(program: Vec<Inst>, rules: &[Rule], stats: &mut Stats) -> Vec<Inst> {
+  let mut instructions = program;
+  let mut changed = true;
+  while changed {
+    changed = false;
+    let mut out = Vec::with_capacity(instructions.len());
+    let mut pos = 0;
+    'scan: while pos < instructions.len() {
+      for rule in rules {
+        let window = match instructions.get(pos..pos + rule.width) {
+          Some(window) => window,
+          None => continue,
+        };
+        let ops: Vec<Op> = window.iter().map(|inst| inst.op.clone()).collect();
+        if let Some(replacement) = (rule.rewrite)(&ops) {
+          let span = Span {
+            start: window[0].span.start,
+            end: window[rule.width - 1].span.end,
+          };
+          out.extend(replacement.into_iter().map(|op| Inst { op, span }));
+          pos += rule.width;
+          changed = true;
+          stats.note(span, format!("rule {} fired", rule.name));
+          continue 'scan;
+        }
+      }
+      out.push(instructions[pos].clone());
+      pos += 1;
+    }
+    instructions = out;
+  }
+  link_jumps(&mut instructions);
+  instructions
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::optimizer::{self, Stats};
+  use super::super::{lex_dialect, parse_program, Dialect, Op};
+  use super::{rewrite, RULES};
+
+  /// The operations of `code` after clear loops and dead loops are lowered
+  /// and the rules rewrote what they left.
+  fn rewritten(code: &str) -> Vec<Op> {
+    let program = lex_dialect(code, Dialect::Brainfuck)
+      .and_then(parse_program)
+      .unwrap();
+    let stats = &mut Stats::default();
+    let program = optimizer::dead_loops(optimizer::clear_loops(program, stats), stats);
+    rewrite(program, RULES, stats)
+      .into_iter()
+      .map(|inst| inst.op)
+      .collect()
+  }
+
+  #[test]
+  fn clears_followed_by_changes_become_sets() {
+    assert_eq!(
+      rewritten("+[-]++"),
+      [
+        Op::Plus(1),
+        Op::Set {
+          offset: 0,
+          value: 2
+        }
+      ]
+    );
+    assert_eq!(
+      rewritten("+[-]-")[1],
+      Op::Set {
+        offset: 0,
+        value: -1
+      }
+    );
+  }
+
+  #[test]
+  fn rules_fire_until_none_matches() {
+    // The two sets only meet once both clears have become sets.
+    assert_eq!(
+      rewritten("+[-]+[-]+"),
+      [
+        Op::Plus(1),
+        Op::Set {
+          offset: 0,
+          value: 1
+        }
+      ]
+    );
+    assert_eq!(rewritten("+[-][-]"), [Op::Plus(1), Op::SetZero]);
+  }
+
+  #[test]
+  fn moves_that_cancel_are_dropped() {
+    assert!(rewritten(">[]<").is_empty());
+    assert_eq!(rewritten(">[]<<<"), [Op::Left(2)]);
+  }
+}
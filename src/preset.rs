@@ -0,0 +1,157 @@
+//! The semantics `--preset` sets at once, after interpreters programs are
+//! often written against: the cells of run and of generated code, what
+//! `,` stores at the end of input, how long the tape is and what moving
+//! off it does, so that a program behaves the same whether it is run or
+//! compiled.
+
+use super::interpreter::cell::CellType;
+use super::interpreter::{Bounds, Eof, TAPE_SIZE};
+use super::jasmin::Config;
+
+pub struct Preset {
+  pub name: &'static str,
+  /// The cells of run.
+  pub cell_type: CellType,
+  /// Whether generated code wraps cells at 256, rather than at 2^32 as the
+  /// `u32` cells of run do.
+  pub wrap: bool,
+  pub eof: Eof,
+  pub tape_size: usize,
+  /// What generated code does off the tape, where run always stops.
+  pub bounds: Bounds,
+}
+
+impl Preset {
+  /// `config` with the semantics of the preset for generated code.
+  pub fn configure(&self, config: Config) -> Config {
+    Config {
+      wrap: self.wrap,
+      eof: self.eof,
+      tape_size: self.tape_size,
+      bounds: self.bounds,
+      ..config
+    }
+  }
+}
+
+pub const PRESETS: &[Preset] = &[
+  // The original interpreter: 30000 byte cells that wrap, leaving a cell
+  // as it was at the end of input.
+  Preset {
+    name: "classic",
+    cell_type: CellType::U8,
+    wrap: true,
+    eof: Eof::Unchanged,
+    tape_size: TAPE_SIZE,
+    bounds: Bounds::Error,
+  },
+  // What Daniel Cristofani's dbfi and the programs written alongside it
+  // take for granted: byte cells that wrap, and 0 at the end of input.
+  Preset {
+    name: "dbfi",
+    cell_type: CellType::U8,
+    wrap: true,
+    eof: Eof::Zero,
+    tape_size: TAPE_SIZE,
+    bounds: Bounds::Error,
+  },
+  // Interpreters with 32-bit cells and a longer tape, storing -1 at the
+  // end of input.
+  Preset {
+    name: "extended",
+    cell_type: CellType::U32,
+    wrap: false,
+    eof: Eof::MinusOne,
+    tape_size: 65536,
+    bounds: Bounds::Error,
+  },
+];
+
+pub fn lookup(name: &str) -> Result<&'static Preset, String> {
+  PRESETS
+    .iter()
+    .find(|preset| preset.name == name)
+    .ok_or_else(|| {
+      let known: Vec<&str> = PRESETS.iter().map(|preset| preset.name).collect();
+      format!("unknown preset {} (known: {})", name, known.join(", "))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::fuzz::{run_target, Options, Target};
+  use super::super::interpreter::cell::{Cell, CellType};
+  use super::super::interpreter::Interpreter;
+  use super::super::{classfile, constants, evaluate, lex_dialect, optimizer, parse_program};
+  use super::super::{Dialect, Inst};
+  use super::{Preset, PRESETS};
+
+  /// Prints `A` when a cell holds 256, then what `,` leaves in a cell
+  /// holding 1 at the end of input, plus one.
+  const CELLS: &str = "++++++++++++++++[>++++++++++++++++<-]>\
+                       [[-]>++++++++[<++++++++>-]<+.[-]]+,+.";
+
+  /// Which of these programs move off the tape depends on its length.
+  fn programs() -> Vec<String> {
+    vec![
+      CELLS.to_string(),
+      "+.<".to_string(),
+      "+[<+>-]".to_string(),
+      format!("{}+.", ">".repeat(40000)),
+    ]
+  }
+
+  /// What `program` prints under `preset` in run, or `None` if it fails.
+  fn run<C: Cell>(program: &[Inst], preset: &Preset) -> Option<Vec<u8>> {
+    let mut output = Vec::new();
+    Interpreter::<C>::with_cells(program)
+      .with_eof(preset.eof)
+      .with_tape_size(preset.tape_size)
+      .run(&mut &b""[..], &mut output, None)
+      .ok()
+      .map(|_| output)
+  }
+
+  #[test]
+  fn a_preset_behaves_the_same_in_run_and_in_generated_code() {
+    let dir = std::env::temp_dir().join(format!("brainfuck-preset-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let passes = optimizer::preset(optimizer::Level::O1, true);
+    for preset in PRESETS {
+      let config = preset.configure(Default::default());
+      for code in programs() {
+        let program = lex_dialect(&code, Dialect::Brainfuck)
+          .and_then(parse_program)
+          .unwrap();
+        let expected = match preset.cell_type {
+          CellType::U8 => run::<u8>(&program, preset),
+          CellType::U32 => run::<u32>(&program, preset),
+          other => panic!("no preset has {:?} cells", other),
+        };
+        for target in [Target::Class, Target::C, Target::Python, Target::Js] {
+          let options = Options {
+            target,
+            passes: &passes,
+            eval_budget: evaluate::DEFAULT_BUDGET,
+            unroll_limit: constants::DEFAULT_UNROLL_LIMIT,
+            config: &config,
+            class_version: classfile::DEFAULT_VERSION,
+            dir: &dir,
+          };
+          // Backends whose tools are missing are skipped.
+          if let Some(outcome) = run_target(&code, b"", &options).unwrap() {
+            assert_eq!(
+              outcome.ok(),
+              expected,
+              "{:?} under {} on {:.20}",
+              target,
+              preset.name,
+              code
+            );
+          }
+        }
+      }
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+}
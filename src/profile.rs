@@ -0,0 +1,111 @@
+//! Execution profiles recorded by `run --profile`.
+//!
+//! Counts are stored per source span rather than per IR index, so a profile
+//! taken with one set of passes still applies when compiling with another.
+
+use std::error::Error;
+use std::fs;
+use std::io::{self, Read, Write};
+
+use super::interpreter::Interpreter;
+use super::trace::Tracer;
+use super::Span;
+
+const HEADER: &str = "# brainfuck profile v1";
+
+/// Code is hot when it runs at least this fraction of the times the hottest
+/// code of the profile runs.
+const HOT_SHARE: u64 = 100;
+
+#[derive(Default, Debug)]
+pub struct Profile {
+  /// Execution counts of the instructions built from each span.
+  pub counts: Vec<(Span, u64)>,
+}
+
+impl Profile {
+  /// Runs `interpreter` to completion, counting how often each instruction
+  /// executes.
+  pub fn collect(
+    interpreter: &mut Interpreter,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+    mut tracer: Option<&mut Tracer>,
+  ) -> io::Result<Profile> {
+    let mut hits: Vec<(Span, u64)> = Vec::new();
+    while let Some(step) = interpreter.step(input, output)? {
+      if let Some(tracer) = tracer.as_mut() {
+        tracer.record(&step)?;
+      }
+      if hits.len() <= step.index {
+        hits.resize(step.index + 1, (step.inst.span, 0));
+      }
+      hits[step.index] = (step.inst.span, hits[step.index].1 + 1);
+    }
+    output.flush()?;
+    let mut counts: Vec<(Span, u64)> = Vec::new();
+    for (span, count) in hits.into_iter().filter(|&(_, count)| count > 0) {
+      // Instructions lowered from one loop share its span; keep the hottest.
+      match counts.iter_mut().find(|(s, _)| *s == span) {
+        Some((_, total)) => *total = (*total).max(count),
+        None => counts.push((span, count)),
+      }
+    }
+    counts.sort_by_key(|&(span, _)| (span.start, span.end));
+    Ok(Profile { counts })
+  }
+
+  pub fn load(path: &str) -> Result<Profile, Box<dyn Error>> {
+    let text = fs::read_to_string(path)?;
+    let mut lines = text.lines();
+    if lines.next() != Some(HEADER) {
+      return Err(format!("{} is not a brainfuck profile", path).into());
+    }
+    let mut counts = Vec::new();
+    for line in lines.filter(|line| !line.trim().is_empty()) {
+      let fields: Vec<&str> = line.split_whitespace().collect();
+      match fields[..] {
+        [start, end, count] => counts.push((
+          Span {
+            start: start.parse()?,
+            end: end.parse()?,
+          },
+          count.parse()?,
+        )),
+        _ => return Err(format!("malformed profile line: {}", line).into()),
+      }
+    }
+    Ok(Profile { counts })
+  }
+
+  pub fn save(&self, path: &str) -> io::Result<()> {
+    let mut text = format!("{}\n", HEADER);
+    for (span, count) in &self.counts {
+      text.push_str(&format!("{} {} {}\n", span.start, span.end, count));
+    }
+    fs::write(path, text)
+  }
+
+  /// How often the hottest recorded instruction overlapping `span` ran.
+  pub fn hotness(&self, span: Span) -> u64 {
+    self
+      .counts
+      .iter()
+      .filter(|(s, _)| s.start < span.end && span.start < s.end)
+      .map(|&(_, count)| count)
+      .max()
+      .unwrap_or(0)
+  }
+
+  /// Whether code in `span` is hot: it runs at least a hundredth as often as
+  /// the hottest recorded instruction.
+  pub fn is_hot(&self, span: Span) -> bool {
+    let hottest = self
+      .counts
+      .iter()
+      .map(|&(_, count)| count)
+      .max()
+      .unwrap_or(0);
+    hottest > 0 && self.hotness(span).saturating_mul(HOT_SHARE) >= hottest
+  }
+}
@@ -0,0 +1,109 @@
+//! C-like pseudocode of the optimized IR, for reading what a program does
+//! rather than for compiling: `tape[p] += 5;`, `while (tape[p]) { ... }`,
+//! and a call such as `getchar()` or `random()` for each command that has
+//! no operator. Every extension has a spelling, so nothing is rejected.
+
+use super::{Ebf, Inst, Op, Tape};
+
+const INDENT: &str = "    ";
+
+/// The cell at `offset` from the pointer.
+fn cell(offset: i32) -> String {
+  match offset {
+    0 => "tape[p]".to_string(),
+    _ if offset > 0 => format!("tape[p + {}]", offset),
+    _ => format!("tape[p - {}]", offset.unsigned_abs()),
+  }
+}
+
+/// `+=` or `-=` with a positive operand.
+fn compound(target: &str, amount: i64) -> String {
+  if amount < 0 {
+    format!("{} -= {};", target, amount.unsigned_abs())
+  } else {
+    format!("{} += {};", target, amount)
+  }
+}
+
+/// The statement an Extended Brainfuck Type I command amounts to.
+fn ebf(command: Ebf) -> &'static str {
+  match command {
+    Ebf::End => "exit();",
+    Ebf::Store => "storage = tape[p];",
+    Ebf::Retrieve => "tape[p] = storage;",
+    Ebf::ShiftRight => "tape[p] >>= 1;",
+    Ebf::ShiftLeft => "tape[p] <<= 1;",
+    Ebf::Not => "tape[p] = ~tape[p];",
+    Ebf::Xor => "tape[p] ^= storage;",
+    Ebf::And => "tape[p] &= storage;",
+    Ebf::Or => "tape[p] |= storage;",
+  }
+}
+
+/// Renders `instructions` as pseudocode, one statement per line.
+pub fn produce_pseudo(instructions: &[Inst]) -> String {
+  let mut lines = Vec::new();
+  let mut depth = 0;
+  for inst in instructions {
+    let line = match inst.op {
+      Op::Plus(count) => compound("tape[p]", count as i64),
+      Op::Minus(count) => compound("tape[p]", -(count as i64)),
+      Op::Right(count) => compound("p", count as i64),
+      Op::Left(count) => compound("p", -(count as i64)),
+      Op::PutChar(count) | Op::ReadChar(count) => {
+        let line = if let Op::PutChar(_) = inst.op {
+          "putchar(tape[p]);"
+        } else {
+          "tape[p] = getchar();"
+        };
+        // All but the last of the repeats.
+        lines.extend((1..count).map(|_| format!("{}{}", INDENT.repeat(depth), line)));
+        line.to_string()
+      }
+      Op::JumpIfZero(_) => {
+        depth += 1;
+        lines.push(format!("{}while (tape[p]) {{", INDENT.repeat(depth - 1)));
+        continue;
+      }
+      Op::Procedure(_) => {
+        depth += 1;
+        lines.push(format!(
+          "{}procedure[tape[p]] = {{",
+          INDENT.repeat(depth - 1)
+        ));
+        continue;
+      }
+      Op::JumpIfNonZero(_) | Op::Return(_) => {
+        depth -= 1;
+        "}".to_string()
+      }
+      Op::SetZero => "tape[p] = 0;".to_string(),
+      Op::AddTo { offset, factor } => match factor {
+        1 => format!("{} += tape[p];", cell(offset)),
+        -1 => format!("{} -= tape[p];", cell(offset)),
+        _ if factor < 0 => format!("{} -= tape[p] * {};", cell(offset), factor.unsigned_abs()),
+        _ => format!("{} += tape[p] * {};", cell(offset), factor),
+      },
+      Op::Add { offset, amount } => compound(&cell(offset), amount as i64),
+      Op::Set { offset, value } => format!("{} = {};", cell(offset), value),
+      Op::ScanZero { stride } => format!("while (tape[p]) {}", compound("p", stride as i64)),
+      Op::PutConst { value, count } => {
+        let text: String = std::iter::repeat_n(value as char, count as usize).collect();
+        format!("print({:?});", text)
+      }
+      Op::Print(ref text) => format!("print({:?});", text),
+      Op::Call => "call(procedure[tape[p]]);".to_string(),
+      Op::Fork => "fork();".to_string(),
+      Op::Ebf(command) => ebf(command).to_string(),
+      Op::Debug => "dump(p, tape);".to_string(),
+      Op::PutNumber => "print_number(tape[p]);".to_string(),
+      Op::ReadNumber => "tape[p] = read_number();".to_string(),
+      Op::Random => "tape[p] = random();".to_string(),
+      Op::Tape(Tape::Next) => "next_tape();".to_string(),
+      Op::Tape(Tape::Copy) => "next_tape[next_p] = tape[p];".to_string(),
+    };
+    lines.push(format!("{}{}", INDENT.repeat(depth), line));
+  }
+  lines.push(String::new());
+  lines.join("\n")
+}
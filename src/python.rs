@@ -0,0 +1,287 @@
+//! An executable Python 3 script with no dependencies: a `bytearray` tape,
+//! or a list of ints masked to 32 bits with `--no-wrap`, and byte I/O
+//! through `sys.stdin.buffer` and `sys.stdout.buffer`, where `.` writes
+//! characters in UTF-8 unless `--io bytes` asks for the bytes as they are.
+//! Indexing past the end of the tape raises `IndexError`, but a negative
+//! pointer counts from the end, as Python indexing does, unless
+//! `--bounds error` checks every move.
+
+use super::interpreter::{Bounds, Eof, ExitCell};
+use super::jasmin::Config;
+use super::{output_bytes, reject_extensions, writes_bytes, Inst, Op};
+
+const INDENT: &str = "    ";
+
+/// The cell at `offset` from the pointer.
+fn cell(offset: i32) -> String {
+  match offset {
+    0 => "tape[p]".to_string(),
+    _ if offset > 0 => format!("tape[p + {}]", offset),
+    _ => format!("tape[p - {}]", offset.unsigned_abs()),
+  }
+}
+
+/// `+=` or `-=` with a positive operand.
+fn compound(target: &str, amount: i64) -> String {
+  if amount < 0 {
+    format!("{} -= {}", target, amount.unsigned_abs())
+  } else {
+    format!("{} += {}", target, amount)
+  }
+}
+
+/// Adds the expression `term` to `target`, masking it back into a byte
+/// since a `bytearray` rejects anything else, or into 32 bits as the `u32`
+/// cells of run wrap.
+fn add(target: &str, term: &str, config: &Config) -> String {
+  if config.wrap {
+    format!("{} = ({} {}) & 255", target, target, term)
+  } else {
+    format!("{} = ({} {}) & 0xffffffff", target, target, term)
+  }
+}
+
+/// `value` as a cell.
+fn literal(value: i32, config: &Config) -> i64 {
+  if config.wrap {
+    (value & 255) as i64
+  } else {
+    value as u32 as i64
+  }
+}
+
+/// The statement stopping the program when the cell at `offset` from the
+/// pointer is off the tape, under `Bounds::Error`.
+fn check(offset: i32, config: &Config) -> Option<String> {
+  if config.bounds == Bounds::Unchecked {
+    return None;
+  }
+  let index = match offset {
+    0 => "p".to_string(),
+    _ if offset > 0 => format!("p + {}", offset),
+    _ => format!("p - {}", offset.unsigned_abs()),
+  };
+  Some(format!(
+    "if not 0 <= {} < {}: sys.exit(\"pointer moved off the tape\")",
+    index, config.tape_size
+  ))
+}
+
+/// The signed term adding `amount` times `factor`, such as `+ 3` or `- n`.
+fn term(amount: i64, factor: Option<&str>) -> String {
+  let sign = if amount < 0 { '-' } else { '+' };
+  let magnitude = amount.unsigned_abs();
+  match factor {
+    None => format!("{} {}", sign, magnitude),
+    Some(factor) if magnitude == 1 => format!("{} {}", sign, factor),
+    Some(factor) => format!("{} {} * {}", sign, factor, magnitude),
+  }
+}
+
+/// Quotes `bytes` as a bytes literal.
+fn quote(bytes: &[u8]) -> String {
+  let mut quoted = String::from("b\"");
+  for &byte in bytes {
+    match byte {
+      b'"' => quoted.push_str("\\\""),
+      b'\\' => quoted.push_str("\\\\"),
+      b'\n' => quoted.push_str("\\n"),
+      b' '..=b'~' => quoted.push(byte as char),
+      _ => quoted.push_str(&format!("\\x{:02x}", byte)),
+    }
+  }
+  quoted.push('"');
+  quoted
+}
+
+/// Statements reading one byte into the current cell, each with how much
+/// deeper than the read it is nested.
+fn read(config: &Config) -> Vec<(usize, String)> {
+  let minus_one = literal(-1, config);
+  let mut lines = vec![
+    (0, "out.flush()".to_string()),
+    (0, "c = read(1)".to_string()),
+  ];
+  match config.eof {
+    Eof::Unchanged => {
+      lines.push((0, "if c:".to_string()));
+      lines.push((1, "tape[p] = c[0]".to_string()));
+    }
+    Eof::Zero => lines.push((0, "tape[p] = c[0] if c else 0".to_string())),
+    Eof::MinusOne => lines.push((0, format!("tape[p] = c[0] if c else {}", minus_one))),
+  }
+  lines
+}
+
+/// Generates `main.py` for `instructions`.
+pub fn produce_python(instructions: &[Inst], config: &Config) -> Result<String, String> {
+  reject_extensions(instructions)?;
+  if config.tape_from_args || config.embeddable || config.runtime_args {
+    return Err(
+      "--tape-from-args, --embeddable and --runtime-args only apply to JVM output".to_string(),
+    );
+  }
+  let bytes = writes_bytes(config.io)?;
+  let reads = instructions
+    .iter()
+    .any(|inst| matches!(inst.op, Op::ReadChar(_)));
+  let mut lines = vec!["#!/usr/bin/env python3".to_string()];
+  if let Some(debug) = &config.debug {
+    lines.push(format!("# Compiled from {}", debug.file));
+  }
+  lines.extend([
+    "import sys".to_string(),
+    String::new(),
+    String::new(),
+    "def main():".to_string(),
+  ]);
+  let mut body = vec![
+    if config.wrap {
+      format!("tape = bytearray({})", config.tape_size)
+    } else {
+      format!("tape = [0] * {}", config.tape_size)
+    },
+    "p = 0".to_string(),
+  ];
+  if reads {
+    body.push("read = sys.stdin.buffer.read".to_string());
+  }
+  body.push("out = sys.stdout.buffer".to_string());
+  body.push("write = out.write".to_string());
+  let mut body: Vec<(usize, String)> = body.into_iter().map(|line| (0, line)).collect();
+  let mut depth = 0;
+  let mut index = 0;
+  while index < instructions.len() {
+    let mut emit = |line: String| body.push((depth, line));
+    match instructions[index].op {
+      Op::Procedure(_)
+      | Op::Return(_)
+      | Op::Call
+      | Op::Fork
+      | Op::Ebf(_)
+      | Op::Debug
+      | Op::PutNumber
+      | Op::ReadNumber
+      | Op::Random
+      | Op::Tape(_) => {
+        unreachable!("rejected by reject_extensions")
+      }
+      Op::Plus(count) => emit(add("tape[p]", &term(count as i64, None), config)),
+      Op::Minus(count) => emit(add("tape[p]", &term(-(count as i64), None), config)),
+      Op::Right(count) => {
+        emit(compound("p", count as i64));
+        check(0, config).into_iter().for_each(emit);
+      }
+      Op::Left(count) => {
+        emit(compound("p", -(count as i64)));
+        check(0, config).into_iter().for_each(emit);
+      }
+      Op::PutChar(count) => {
+        let byte = if config.wrap {
+          "tape[p]"
+        } else {
+          "tape[p] & 255"
+        };
+        for _ in 0..count {
+          if bytes {
+            emit(format!("write(bytes(({},)))", byte));
+          } else {
+            emit(format!("write(chr({}).encode())", byte));
+          }
+        }
+      }
+      Op::ReadChar(count) => {
+        for _ in 0..count {
+          for (nested, line) in read(config) {
+            body.push((depth + nested, line));
+          }
+        }
+      }
+      Op::JumpIfZero(_) => {
+        emit("while tape[p]:".to_string());
+        depth += 1;
+      }
+      Op::JumpIfNonZero(_) => {
+        // A loop with nothing left in its body still needs a statement.
+        if body.last().map(|(_, line)| line.as_str()) == Some("while tape[p]:") {
+          body.push((depth, "pass".to_string()));
+        }
+        depth -= 1;
+      }
+      Op::SetZero => emit("tape[p] = 0".to_string()),
+      Op::AddTo { .. } => {
+        let targets: Vec<(i32, i32)> = instructions[index..]
+          .iter()
+          .map_while(|inst| match inst.op {
+            Op::AddTo { offset, factor } => Some((offset, factor)),
+            _ => None,
+          })
+          .collect();
+        emit("if tape[p]:".to_string());
+        emit(format!("{}n = tape[p]", INDENT));
+        for (offset, factor) in &targets {
+          if let Some(check) = check(*offset, config) {
+            emit(format!("{}{}", INDENT, check));
+          }
+          emit(format!(
+            "{}{}",
+            INDENT,
+            add(&cell(*offset), &term(*factor as i64, Some("n")), config)
+          ));
+        }
+        index += targets.len() - 1;
+      }
+      Op::Add { offset, amount } => {
+        if offset != 0 {
+          check(offset, config).into_iter().for_each(&mut emit);
+        }
+        emit(add(&cell(offset), &term(amount as i64, None), config))
+      }
+      Op::Set { offset, value } => {
+        if offset != 0 {
+          check(offset, config).into_iter().for_each(&mut emit);
+        }
+        emit(format!("{} = {}", cell(offset), literal(value, config)));
+      }
+      Op::ScanZero { stride } => {
+        emit("while tape[p]:".to_string());
+        emit(format!("{}{}", INDENT, compound("p", stride as i64)));
+        if let Some(check) = check(0, config) {
+          emit(format!("{}{}", INDENT, check));
+        }
+      }
+      Op::PutConst { value, count } => {
+        let text = output_bytes(&vec![value; count as usize], config.io);
+        emit(format!("write({})", quote(&text)))
+      }
+      Op::Print(ref text) => emit(format!(
+        "write({})",
+        quote(&output_bytes(text.as_bytes(), config.io))
+      )),
+    }
+    index += 1;
+  }
+  body.push((0, "out.flush()".to_string()));
+  match config.exit_cell {
+    None => (),
+    Some(ExitCell::Current) => body.push((0, "return tape[p] & 255".to_string())),
+    Some(ExitCell::First) => body.push((0, "return tape[0] & 255".to_string())),
+  }
+  lines.extend(
+    body
+      .into_iter()
+      .map(|(depth, line)| format!("{}{}", INDENT.repeat(depth + 1), line)),
+  );
+  lines.extend([
+    String::new(),
+    String::new(),
+    "if __name__ == \"__main__\":".to_string(),
+    if config.exit_cell.is_some() {
+      format!("{}sys.exit(main())", INDENT)
+    } else {
+      format!("{}main()", INDENT)
+    },
+    String::new(),
+  ]);
+  Ok(lines.join("\n"))
+}
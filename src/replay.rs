@@ -0,0 +1,191 @@
+//! The viewer behind `trace-view`, which scrubs through a binary trace
+//! written by `run --trace-out`:
+//!
+//! ```text
+//! <n>               go to step n and show it
+//! n, p              go to the next or previous step
+//! tape              the cells set before the current step
+//! diff <a> <b>      the cells that change from step a to step b
+//! diverge <file>    the first step where another trace differs
+//! q                 leave
+//! ```
+//!
+//! Steps are numbered from 0. The tape at a step is rebuilt by replaying
+//! every step before it, which needs a trace recorded without
+//! `--trace-io` or `--trace-range`.
+
+use std::io::{self, BufRead, Write};
+
+use super::interpreter::dump_table;
+use super::trace::{self, Record};
+use super::Op;
+
+const HELP: &str = "<n>               go to step n and show it
+n, p              go to the next or previous step
+tape              show the cells set before the current step
+diff <a> <b>      show the cells that change from step a to step b
+diverge <file>    find the first step where another trace differs
+q                 quit
+";
+
+/// Applies what `record` did to `tape`: its current cell is the value the
+/// record ends with, and the operations that reach other cells write
+/// them too.
+fn apply(tape: &mut Vec<u8>, record: &Record) {
+  let mut write = |cell: isize, value: &dyn Fn(u8) -> u8| {
+    if cell < 0 {
+      return;
+    }
+    let cell = cell as usize;
+    if tape.len() <= cell {
+      tape.resize(cell + 1, 0);
+    }
+    tape[cell] = value(tape[cell]);
+  };
+  let ptr = record.ptr as isize;
+  match trace::decode(record.opcode, record.argument) {
+    Some(Op::AddTo { offset, factor }) => write(ptr + offset as isize, &|cell| {
+      cell.wrapping_add((record.before as i32).wrapping_mul(factor) as u8)
+    }),
+    Some(Op::Add { offset, amount }) if offset != 0 => write(ptr + offset as isize, &|cell| {
+      cell.wrapping_add(amount as u8)
+    }),
+    Some(Op::Set { offset, value }) if offset != 0 => {
+      write(ptr + offset as isize, &|_| value as u8)
+    }
+    _ => (),
+  }
+  write(ptr, &|_| record.after);
+}
+
+/// The tape before step `step` of `records`.
+fn tape_at(records: &[Record], step: usize) -> Vec<u8> {
+  let mut tape = Vec::new();
+  for record in &records[..step] {
+    apply(&mut tape, record);
+  }
+  tape
+}
+
+fn describe(step: usize, record: &Record) -> String {
+  format!(
+    "step {}: instruction {} {} at cell {}, {} -> {}",
+    step,
+    record.index,
+    record.describe(),
+    record.ptr,
+    record.before,
+    record.after
+  )
+}
+
+fn parse_step(text: &str, records: &[Record]) -> Result<usize, String> {
+  let step: usize = text
+    .parse()
+    .map_err(|_| format!("{} is not a step number", text))?;
+  if step >= records.len() {
+    return Err(format!("the trace ends at step {}", records.len() - 1));
+  }
+  Ok(step)
+}
+
+/// The cells that differ between the tapes before steps `a` and `b`.
+fn diff(records: &[Record], a: usize, b: usize) -> String {
+  let before = tape_at(records, a);
+  let after = tape_at(records, b);
+  let cell = |tape: &[u8], at: usize| tape.get(at).copied().unwrap_or(0);
+  let lines: Vec<String> = (0..before.len().max(after.len()))
+    .filter(|&at| cell(&before, at) != cell(&after, at))
+    .map(|at| format!("cell {}: {} -> {}", at, cell(&before, at), cell(&after, at)))
+    .collect();
+  if lines.is_empty() {
+    return format!("no cell changes from step {} to step {}", a, b);
+  }
+  lines.join("\n")
+}
+
+/// The first step where `records` and `other` differ.
+fn diverge(records: &[Record], other: &[Record]) -> String {
+  let first = records.iter().zip(other).position(|(a, b)| a != b);
+  match first {
+    Some(step) => format!(
+      "the traces differ from step {}:\n  this:  {}\n  other: {}",
+      step,
+      describe(step, &records[step]),
+      describe(step, &other[step])
+    ),
+    None if records.len() == other.len() => "the traces are the same".to_string(),
+    None => {
+      let shorter = records.len().min(other.len());
+      let (which, longer) = if records.len() < other.len() {
+        ("this", other)
+      } else {
+        ("the other", records)
+      };
+      let ends = match shorter {
+        0 => format!("{} trace is empty", which),
+        _ => format!("{} trace ends after step {}", which, shorter - 1),
+      };
+      format!(
+        "{}, where the other goes on with\n  {}",
+        ends,
+        describe(shorter, &longer[shorter])
+      )
+    }
+  }
+}
+
+/// Answers queries about the trace `records`, read from `input` until it
+/// ends or asks to quit.
+pub fn view(records: &[Record], input: &mut dyn BufRead, output: &mut dyn Write) -> io::Result<()> {
+  writeln!(output, "{} steps recorded", records.len())?;
+  if records.is_empty() {
+    return Ok(());
+  }
+  let mut step = 0;
+  writeln!(output, "{}", describe(step, &records[step]))?;
+  loop {
+    write!(output, "trace> ")?;
+    output.flush()?;
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+      writeln!(output)?;
+      return Ok(());
+    }
+    let query: Vec<&str> = line.split_whitespace().collect();
+    let answer = match query[..] {
+      [] => continue,
+      ["q" | "quit"] => return Ok(()),
+      ["help"] => Ok(HELP.trim_end().to_string()),
+      ["n"] if step + 1 < records.len() => {
+        step += 1;
+        Ok(describe(step, &records[step]))
+      }
+      ["n"] => Err("this is the last step".to_string()),
+      ["p"] if step > 0 => {
+        step -= 1;
+        Ok(describe(step, &records[step]))
+      }
+      ["p"] => Err("this is the first step".to_string()),
+      ["tape"] => {
+        let tape = tape_at(records, step);
+        Ok(dump_table(&tape, records[step].ptr).trim_end().to_string())
+      }
+      ["diff", a, b] => parse_step(a, records)
+        .and_then(|a| Ok((a, parse_step(b, records)?)))
+        .map(|(a, b)| diff(records, a, b)),
+      ["diverge", path] => trace::load(path)
+        .map(|other| diverge(records, &other))
+        .map_err(|error| error.to_string()),
+      [number] => parse_step(number, records).map(|to| {
+        step = to;
+        describe(step, &records[step])
+      }),
+      _ => Err("unknown query; help lists them".to_string()),
+    };
+    match answer {
+      Ok(text) => writeln!(output, "{}", text)?,
+      Err(error) => writeln!(output, "error: {}", error)?,
+    }
+  }
+}
@@ -0,0 +1,97 @@
+//! Human and machine readable reports of what the optimizer did.
+
+use super::json::quote;
+use super::optimizer::Stats;
+
+#[derive(Copy, Clone, Debug)]
+pub enum Format {
+  Text,
+  Json,
+}
+
+pub fn parse_format(text: &str) -> Result<Format, String> {
+  match text {
+    "text" => Ok(Format::Text),
+    "json" => Ok(Format::Json),
+    other => Err(format!("unknown report format {}", other)),
+  }
+}
+
+/// One-based line and column of a byte offset into `source`.
+pub(crate) fn position(source: &str, offset: usize) -> (usize, usize) {
+  let before = &source[..offset.min(source.len())];
+  let line = before.matches('\n').count() + 1;
+  let column = match before.rfind('\n') {
+    Some(newline) => before[newline + 1..].chars().count() + 1,
+    None => before.chars().count() + 1,
+  };
+  (line, column)
+}
+
+/// One line per pass with how often it fired and how it changed the
+/// instruction count.
+pub fn summary(stats: &Stats) -> String {
+  let mut lines = vec![format!("{:<14} {:>6}  instructions", "pass", "fired")];
+  for pass in &stats.passes {
+    lines.push(format!(
+      "{:<14} {:>6}  {} -> {}",
+      pass.name, pass.fired, pass.before, pass.after
+    ));
+  }
+  lines.push(String::new());
+  lines.join("\n")
+}
+
+/// The summary followed by every note, located in `filename`.
+pub fn explain(stats: &Stats, source: &str, filename: &str, format: Format) -> String {
+  match format {
+    Format::Text => {
+      let mut report = summary(stats);
+      for note in &stats.notes {
+        let (line, column) = position(source, note.span.start as usize);
+        report.push_str(&format!(
+          "{}:{}:{}: {}: {}\n",
+          filename, line, column, note.pass, note.message
+        ));
+      }
+      report
+    }
+    Format::Json => {
+      let passes: Vec<String> = stats
+        .passes
+        .iter()
+        .map(|pass| {
+          format!(
+            "    {{\"name\": {}, \"fired\": {}, \"before\": {}, \"after\": {}}}",
+            quote(pass.name),
+            pass.fired,
+            pass.before,
+            pass.after
+          )
+        })
+        .collect();
+      let notes: Vec<String> = stats
+        .notes
+        .iter()
+        .map(|note| {
+          let (line, column) = position(source, note.span.start as usize);
+          format!(
+            "    {{\"pass\": {}, \"start\": {}, \"end\": {}, \"line\": {}, \"column\": {}, \"message\": {}}}",
+            quote(note.pass),
+            note.span.start,
+            note.span.end,
+            line,
+            column,
+            quote(&note.message)
+          )
+        })
+        .collect();
+      format!(
+        "{{\n  \"file\": {},\n  \"passes\": [\n{}\n  ],\n  \"notes\": [\n{}\n  ]\n}}\n",
+        quote(filename),
+        passes.join(",\n"),
+        notes.join(",\n")
+      )
+    }
+  }
+}
@@ -0,0 +1,415 @@
+//! RV64GC assembly for the GNU assembler. I/O goes through libc, glibc on
+//! Linux or newlib on boards, or straight to Linux system calls with
+//! `ecall` for static binaries linked with `-nostdlib`. The pointer lives in
+//! the callee-saved `s1`; `t0` to `t3` are scratch, and `t4` to `t6` hold
+//! the bounds checks.
+
+use super::interpreter::{Bounds, Eof, ExitCell};
+use super::jasmin::Config;
+use super::native::{self, Step, Target};
+use super::Text;
+
+/// How the program reaches the outside world.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Io {
+  /// `main` calling `putchar` and `getchar`.
+  Libc,
+  /// `_start` calling Linux's `read`, `write` and `exit`.
+  Ecall,
+}
+
+impl Io {
+  pub fn parse(text: &str) -> Result<Io, String> {
+    match text {
+      "libc" => Ok(Io::Libc),
+      "ecall" => Ok(Io::Ecall),
+      other => Err(format!("unknown RISC-V I/O {}", other)),
+    }
+  }
+}
+
+/// Linux system call numbers, shared by every RISC-V ABI.
+const READ: i32 = 63;
+const WRITE: i32 = 64;
+const EXIT: i32 = 93;
+
+pub struct RiscV64 {
+  pub io: Io,
+}
+
+impl RiscV64 {
+  fn emit(out: &mut Vec<String>, line: String) {
+    out.push(format!("  {}", line));
+  }
+
+  /// The memory operand of the cell at `offset`, forming its address in
+  /// `t3` when the offset does not fit the 12-bit immediate.
+  fn cell(out: &mut Vec<String>, offset: isize, config: &Config) -> String {
+    let bytes = offset * native::cell_width(config);
+    if (-2048..=2047).contains(&bytes) {
+      format!("{}(s1)", bytes)
+    } else {
+      RiscV64::emit(out, format!("li t3, {}", bytes));
+      RiscV64::emit(out, "add t3, s1, t3".to_string());
+      "0(t3)".to_string()
+    }
+  }
+
+  fn load(out: &mut Vec<String>, register: &str, offset: isize, config: &Config) -> String {
+    let cell = RiscV64::cell(out, offset, config);
+    let op = if config.wrap { "lbu" } else { "lw" };
+    RiscV64::emit(out, format!("{} {}, {}", op, register, cell));
+    cell
+  }
+
+  /// Stores `register` to `cell`, a memory operand from `cell`.
+  fn store(out: &mut Vec<String>, register: &str, cell: &str, config: &Config) {
+    let op = if config.wrap { "sb" } else { "sw" };
+    RiscV64::emit(out, format!("{} {}, {}", op, register, cell));
+  }
+
+  /// `target = source + value`, through `t1` when `value` does not fit an
+  /// immediate.
+  fn add_constant(out: &mut Vec<String>, target: &str, source: &str, value: i64) {
+    if (-2048..=2047).contains(&value) {
+      RiscV64::emit(out, format!("addi {}, {}, {}", target, source, value));
+    } else {
+      RiscV64::emit(out, format!("li t1, {}", value));
+      RiscV64::emit(out, format!("add {}, {}, t1", target, source));
+    }
+  }
+
+  /// `write(1, buffer, length)` with the buffer already in `a1`.
+  fn write(out: &mut Vec<String>, length: usize) {
+    RiscV64::emit(out, "li a0, 1".to_string());
+    RiscV64::emit(out, format!("li a2, {}", length));
+    RiscV64::emit(out, format!("li a7, {}", WRITE));
+    RiscV64::emit(out, "ecall".to_string());
+  }
+
+  /// Writes the low byte of `register` to standard output.
+  fn put(&self, out: &mut Vec<String>, register: &str) {
+    match self.io {
+      Io::Libc => {
+        if register != "a0" {
+          RiscV64::emit(out, format!("mv a0, {}", register));
+        }
+        RiscV64::emit(out, "call putchar".to_string());
+      }
+      Io::Ecall => {
+        // The stack slot is the one-byte buffer for both directions.
+        RiscV64::emit(out, format!("sb {}, 0(sp)", register));
+        RiscV64::emit(out, "mv a1, sp".to_string());
+        RiscV64::write(out, 1);
+      }
+    }
+  }
+
+  /// Reads one byte into the current cell.
+  fn read(&self, out: &mut Vec<String>, label: usize, config: &Config) {
+    let done = format!(".Lread{}", label);
+    match self.io {
+      Io::Libc => {
+        RiscV64::emit(out, "call getchar".to_string());
+        match config.eof {
+          Eof::MinusOne => RiscV64::store(out, "a0", "0(s1)", config),
+          Eof::Zero => {
+            RiscV64::emit(out, format!("bgez a0, {}", done));
+            RiscV64::emit(out, "li a0, 0".to_string());
+            out.push(format!("{}:", done));
+            RiscV64::store(out, "a0", "0(s1)", config);
+          }
+          Eof::Unchanged => {
+            RiscV64::emit(out, format!("bltz a0, {}", done));
+            RiscV64::store(out, "a0", "0(s1)", config);
+            out.push(format!("{}:", done));
+          }
+        }
+      }
+      Io::Ecall => {
+        let eof = format!(".Leof{}", label);
+        RiscV64::emit(out, "li a0, 0".to_string());
+        RiscV64::emit(out, "mv a1, sp".to_string());
+        RiscV64::emit(out, "li a2, 1".to_string());
+        RiscV64::emit(out, format!("li a7, {}", READ));
+        RiscV64::emit(out, "ecall".to_string());
+        RiscV64::emit(out, format!("blez a0, {}", eof));
+        RiscV64::emit(out, "lbu t0, 0(sp)".to_string());
+        RiscV64::store(out, "t0", "0(s1)", config);
+        RiscV64::emit(out, format!("j {}", done));
+        out.push(format!("{}:", eof));
+        match config.eof {
+          Eof::Unchanged => (),
+          Eof::Zero => RiscV64::store(out, "zero", "0(s1)", config),
+          Eof::MinusOne => {
+            RiscV64::emit(out, "li t0, -1".to_string());
+            RiscV64::store(out, "t0", "0(s1)", config);
+          }
+        }
+        out.push(format!("{}:", done));
+      }
+    }
+  }
+
+  /// Jumps to `.Loff_tape` unless the cell at `offset` lies within the
+  /// tape. The branches skip over the jumps, since `.Loff_tape` may be more
+  /// than 4 KiB away.
+  fn check(out: &mut Vec<String>, offset: isize, config: &Config) {
+    let width = native::cell_width(config);
+    let bytes = offset * width;
+    if (-2048..=2047).contains(&bytes) {
+      RiscV64::emit(out, format!("addi t4, s1, {}", bytes));
+    } else {
+      RiscV64::emit(out, format!("li t4, {}", bytes));
+      RiscV64::emit(out, "add t4, s1, t4".to_string());
+    }
+    RiscV64::emit(out, "lla t5, tape".to_string());
+    RiscV64::emit(out, "bgeu t4, t5, 1f".to_string());
+    RiscV64::emit(out, "j .Loff_tape".to_string());
+    out.push("1:".to_string());
+    RiscV64::emit(out, format!("li t6, {}", config.tape_size as isize * width));
+    RiscV64::emit(out, "add t5, t5, t6".to_string());
+    RiscV64::emit(out, "bltu t4, t5, 1f".to_string());
+    RiscV64::emit(out, "j .Loff_tape".to_string());
+    out.push("1:".to_string());
+  }
+
+  /// `.Loff_tape`, which writes `native::OFF_TAPE` to standard error and
+  /// exits with status 1.
+  fn off_tape(&self, out: &mut Vec<String>) {
+    out.push(".Loff_tape:".to_string());
+    RiscV64::emit(out, "li a0, 2".to_string());
+    RiscV64::emit(out, "lla a1, .Loff_tape_message".to_string());
+    RiscV64::emit(out, format!("li a2, {}", native::OFF_TAPE.len()));
+    match self.io {
+      Io::Libc => {
+        RiscV64::emit(out, "call write".to_string());
+        RiscV64::emit(out, "li a0, 1".to_string());
+        RiscV64::emit(out, "call exit".to_string());
+      }
+      Io::Ecall => {
+        RiscV64::emit(out, format!("li a7, {}", WRITE));
+        RiscV64::emit(out, "ecall".to_string());
+        RiscV64::emit(out, "li a0, 1".to_string());
+        RiscV64::emit(out, format!("li a7, {}", EXIT));
+        RiscV64::emit(out, "ecall".to_string());
+      }
+    }
+  }
+
+  /// `print(text, length)`, writing `length` bytes through `putchar`.
+  fn print_helper(out: &mut Vec<String>) {
+    out.push("print:".to_string());
+    let lines = [
+      "addi sp, sp, -32",
+      "sd ra, 24(sp)",
+      "sd s2, 16(sp)",
+      "sd s3, 8(sp)",
+      "mv s2, a0",
+      "mv s3, a1",
+    ];
+    for line in lines {
+      RiscV64::emit(out, line.to_string());
+    }
+    out.push(".Lprint:".to_string());
+    let lines = [
+      "beqz s3, .Lprinted",
+      "lbu a0, 0(s2)",
+      "call putchar",
+      "addi s2, s2, 1",
+      "addi s3, s3, -1",
+      "j .Lprint",
+    ];
+    for line in lines {
+      RiscV64::emit(out, line.to_string());
+    }
+    out.push(".Lprinted:".to_string());
+    let lines = [
+      "ld s3, 8(sp)",
+      "ld s2, 16(sp)",
+      "ld ra, 24(sp)",
+      "addi sp, sp, 32",
+      "ret",
+    ];
+    for line in lines {
+      RiscV64::emit(out, line.to_string());
+    }
+  }
+}
+
+impl Target for RiscV64 {
+  fn prologue(&self, out: &mut Vec<String>, config: &Config) {
+    if let Some(debug) = &config.debug {
+      out.push(format!("  .file \"{}\"", debug.file));
+    }
+    let entry = match self.io {
+      Io::Libc => "main",
+      Io::Ecall => "_start",
+    };
+    out.push("  .text".to_string());
+    out.push(format!("  .globl {}", entry));
+    out.push(format!("  .type {}, @function", entry));
+    out.push(format!("{}:", entry));
+    RiscV64::emit(out, "addi sp, sp, -16".to_string());
+    if self.io == Io::Libc {
+      RiscV64::emit(out, "sd ra, 8(sp)".to_string());
+      RiscV64::emit(out, "sd s1, 0(sp)".to_string());
+    }
+    RiscV64::emit(out, "lla s1, tape".to_string());
+  }
+
+  fn step(&self, out: &mut Vec<String>, step: &Step, config: &Config) {
+    let width = native::cell_width(config) as i64;
+    match step {
+      Step::Add { offset, amount } => {
+        let cell = RiscV64::load(out, "t0", *offset, config);
+        RiscV64::add_constant(out, "t0", "t0", *amount as i64);
+        RiscV64::store(out, "t0", &cell, config);
+      }
+      Step::Set { offset, value } => {
+        let cell = RiscV64::cell(out, *offset, config);
+        if *value == 0 {
+          RiscV64::store(out, "zero", &cell, config);
+        } else {
+          RiscV64::emit(out, format!("li t0, {}", value));
+          RiscV64::store(out, "t0", &cell, config);
+        }
+      }
+      Step::Move(cells) => RiscV64::add_constant(out, "s1", "s1", *cells as i64 * width),
+      Step::Check { offset } => RiscV64::check(out, *offset, config),
+      Step::Put => {
+        RiscV64::load(out, "a0", 0, config);
+        self.put(out, "a0");
+      }
+      Step::PutUtf8 { label } => {
+        // `s1` survives the first write, so the trail byte reloads the cell.
+        let done = format!(".Lput{}", label);
+        RiscV64::load(out, "a0", 0, config);
+        if !config.wrap {
+          RiscV64::emit(out, "andi a0, a0, 255".to_string());
+        }
+        RiscV64::emit(out, "li t0, 128".to_string());
+        RiscV64::emit(out, format!("bltu a0, t0, {}", done));
+        RiscV64::emit(out, "srli a0, a0, 6".to_string());
+        RiscV64::emit(out, "ori a0, a0, 192".to_string());
+        self.put(out, "a0");
+        RiscV64::load(out, "a0", 0, config);
+        RiscV64::emit(out, "andi a0, a0, 63".to_string());
+        RiscV64::emit(out, "ori a0, a0, 128".to_string());
+        out.push(format!("{}:", done));
+        self.put(out, "a0");
+      }
+      Step::PutConst(value) => {
+        RiscV64::emit(out, format!("li a0, {}", value));
+        self.put(out, "a0");
+      }
+      Step::Read { label } => self.read(out, *label, config),
+      Step::LoopStart { label } => {
+        // Conditional branches only reach 4 KiB, so the exit from a loop of
+        // any size is a jump.
+        out.push(format!(".Lloop{}:", label));
+        RiscV64::load(out, "t0", 0, config);
+        RiscV64::emit(out, format!("bnez t0, .Lbody{}", label));
+        RiscV64::emit(out, format!("j .Lend{}", label));
+        out.push(format!(".Lbody{}:", label));
+      }
+      Step::LoopEnd { label } => {
+        RiscV64::emit(out, format!("j .Lloop{}", label));
+        out.push(format!(".Lend{}:", label));
+      }
+      Step::Multiply { label, targets } => {
+        let done = format!(".Lmultiplied{}", label);
+        RiscV64::load(out, "t0", 0, config);
+        RiscV64::emit(out, format!("beqz t0, {}", done));
+        for (offset, factor) in targets {
+          if config.bounds == Bounds::Error {
+            RiscV64::check(out, *offset, config);
+          }
+          RiscV64::emit(out, format!("li t1, {}", factor));
+          RiscV64::emit(out, "mul t1, t0, t1".to_string());
+          let cell = RiscV64::load(out, "t2", *offset, config);
+          RiscV64::emit(out, "add t2, t2, t1".to_string());
+          RiscV64::store(out, "t2", &cell, config);
+        }
+        out.push(format!("{}:", done));
+      }
+      Step::ScanZero { label, stride } => {
+        out.push(format!(".Lscan{}:", label));
+        RiscV64::load(out, "t0", 0, config);
+        RiscV64::emit(out, format!("beqz t0, .Lscanned{}", label));
+        RiscV64::add_constant(out, "s1", "s1", *stride as i64 * width);
+        if config.bounds == Bounds::Error {
+          RiscV64::check(out, 0, config);
+        }
+        RiscV64::emit(out, format!("j .Lscan{}", label));
+        out.push(format!(".Lscanned{}:", label));
+      }
+      Step::Print { text, length } => match self.io {
+        Io::Libc => {
+          RiscV64::emit(out, format!("lla a0, .Ltext{}", text));
+          RiscV64::emit(out, format!("li a1, {}", length));
+          RiscV64::emit(out, "call print".to_string());
+        }
+        Io::Ecall => {
+          RiscV64::emit(out, format!("lla a1, .Ltext{}", text));
+          RiscV64::write(out, *length);
+        }
+      },
+    }
+  }
+
+  fn epilogue(&self, out: &mut Vec<String>, config: &Config) {
+    match config.exit_cell {
+      None => RiscV64::emit(out, "li a0, 0".to_string()),
+      Some(ExitCell::Current) => {
+        RiscV64::load(out, "a0", 0, config);
+      }
+      Some(ExitCell::First) => {
+        RiscV64::emit(out, "lla s1, tape".to_string());
+        RiscV64::load(out, "a0", 0, config);
+      }
+    }
+    match self.io {
+      Io::Libc => {
+        RiscV64::emit(out, "ld s1, 0(sp)".to_string());
+        RiscV64::emit(out, "ld ra, 8(sp)".to_string());
+        RiscV64::emit(out, "addi sp, sp, 16".to_string());
+        RiscV64::emit(out, "ret".to_string());
+      }
+      Io::Ecall => {
+        RiscV64::emit(out, format!("li a7, {}", EXIT));
+        RiscV64::emit(out, "ecall".to_string());
+      }
+    }
+  }
+
+  fn data(&self, out: &mut Vec<String>, texts: &[Text], config: &Config) {
+    let checked = config.bounds == Bounds::Error;
+    if !texts.is_empty() && self.io == Io::Libc {
+      RiscV64::print_helper(out);
+    }
+    if checked {
+      self.off_tape(out);
+    }
+    if !texts.is_empty() || checked {
+      out.push("  .section .rodata".to_string());
+    }
+    for (index, text) in texts.iter().enumerate() {
+      out.push(format!(".Ltext{}:", index));
+      out.push(format!("  .ascii \"{}\"", native::quote(text.as_bytes())));
+    }
+    if checked {
+      out.push(".Loff_tape_message:".to_string());
+      out.push(format!(
+        "  .ascii \"{}\"",
+        native::quote(native::OFF_TAPE.as_bytes())
+      ));
+    }
+    out.push("  .bss".to_string());
+    out.push(format!(
+      "  .lcomm tape, {}",
+      config.tape_size as isize * native::cell_width(config)
+    ));
+    out.push("  .section .note.GNU-stack,\"\",@progbits".to_string());
+  }
+}
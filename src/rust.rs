@@ -0,0 +1,364 @@
+//! A standalone `main.rs` in safe Rust, for embedding a program in a Rust
+//! project or building it with `rustc` alone. Cells use wrapping arithmetic
+//! and indexing is bounds-checked, so touching a cell off the tape panics,
+//! as does any move off it with `--bounds error`. `.` writes
+//! characters in UTF-8, unless `--io bytes` asks for the bytes as they are.
+//!
+//! `produce_function` writes the same code as a function from the input to
+//! the output, which is what the `bf!` macro of `brainfuck-macro` expands
+//! to.
+
+use super::interpreter::{Bounds, Eof, ExitCell, Io};
+use super::jasmin::Config;
+use super::{output_bytes, reject_extensions, writes_bytes, Inst, Op};
+
+const INDENT: &str = "    ";
+
+/// The statement following each `<` and `>` under `Bounds::Error`. A move
+/// below zero wraps around to a huge `usize` in release builds, and panics
+/// on the overflow itself in debug ones.
+const CHECK: &str = "assert!(p < tape.len(), \"pointer moved off the tape\");";
+
+/// The cell at `offset` from the pointer.
+fn cell(offset: i32) -> String {
+  match offset {
+    0 => "tape[p]".to_string(),
+    _ if offset > 0 => format!("tape[p + {}]", offset),
+    _ => format!("tape[p - {}]", offset.unsigned_abs()),
+  }
+}
+
+/// `+=` or `-=` with a positive operand.
+fn compound(target: &str, amount: i64) -> String {
+  if amount < 0 {
+    format!("{} -= {};", target, amount.unsigned_abs())
+  } else {
+    format!("{} += {};", target, amount)
+  }
+}
+
+/// The literal for `value` as a cell, which wraps for `u8` cells.
+fn literal(value: i64, config: &Config) -> i64 {
+  if config.wrap {
+    value & 255
+  } else {
+    value as i32 as i64
+  }
+}
+
+/// Adds `amount` times the expression `term`, or just `amount` without one,
+/// to the cell at `offset`.
+fn add(offset: i32, amount: i64, term: Option<&str>, config: &Config) -> String {
+  let target = cell(offset);
+  let (method, magnitude) = if amount < 0 {
+    ("wrapping_sub", literal(-amount, config))
+  } else {
+    ("wrapping_add", literal(amount, config))
+  };
+  let operand = match term {
+    None => magnitude.to_string(),
+    Some(term) if magnitude == 1 => term.to_string(),
+    Some(term) => format!("{}.wrapping_mul({})", term, magnitude),
+  };
+  format!("{} = {}.{}({});", target, target, method, operand)
+}
+
+/// Quotes `bytes` as a byte string literal.
+fn quote(bytes: &[u8]) -> String {
+  let mut quoted = String::from("b\"");
+  for &byte in bytes {
+    match byte {
+      b'"' => quoted.push_str("\\\""),
+      b'\\' => quoted.push_str("\\\\"),
+      b'\n' => quoted.push_str("\\n"),
+      b' '..=b'~' => quoted.push(byte as char),
+      _ => quoted.push_str(&format!("\\x{:02x}", byte)),
+    }
+  }
+  quoted.push('"');
+  quoted
+}
+
+/// Statements reading one byte into the current cell.
+fn read(config: &Config) -> Vec<String> {
+  let byte = if config.wrap {
+    "c.unwrap()"
+  } else {
+    "c.unwrap() as i32"
+  };
+  let read = match config.eof {
+    Eof::Unchanged => format!("if let Some(c) = input.next() {{ tape[p] = {}; }}", byte),
+    Eof::Zero => format!("tape[p] = input.next().map_or(0, |c| {});", byte),
+    Eof::MinusOne => format!(
+      "tape[p] = input.next().map_or({}, |c| {});",
+      literal(-1, config),
+      byte
+    ),
+  };
+  vec!["out.flush().unwrap();".to_string(), read]
+}
+
+/// What the code around the statements of a program declares for them.
+struct Needs {
+  reads: bool,
+  moves: bool,
+  writes: bool,
+  /// Programs -O2 reduced to constant output need no tape, and leave
+  /// every cell zero.
+  tape: bool,
+}
+
+fn needs(instructions: &[Inst]) -> Needs {
+  let uses = |test: fn(&Op) -> bool| instructions.iter().any(|inst| test(&inst.op));
+  Needs {
+    reads: uses(|op| matches!(op, Op::ReadChar(_))),
+    moves: uses(|op| matches!(op, Op::Right(_) | Op::Left(_) | Op::ScanZero { .. })),
+    writes: uses(|op| {
+      !matches!(
+        op,
+        Op::Right(_)
+          | Op::Left(_)
+          | Op::PutChar(_)
+          | Op::JumpIfZero(_)
+          | Op::JumpIfNonZero(_)
+          | Op::ScanZero { .. }
+          | Op::PutConst { .. }
+          | Op::Print(_)
+      )
+    }),
+    tape: uses(|op| !matches!(op, Op::PutConst { .. } | Op::Print(_))),
+  }
+}
+
+fn check(instructions: &[Inst], config: &Config) -> Result<(), String> {
+  reject_extensions(instructions)?;
+  writes_bytes(config.io)?;
+  if config.tape_from_args || config.embeddable || config.runtime_args {
+    return Err(
+      "--tape-from-args, --embeddable and --runtime-args only apply to JVM output".to_string(),
+    );
+  }
+  Ok(())
+}
+
+/// Generates `main.rs` for `instructions`.
+pub fn produce_rust(instructions: &[Inst], config: &Config) -> Result<String, String> {
+  check(instructions, config)?;
+  let needs = needs(instructions);
+  let mut lines = Vec::new();
+  if let Some(debug) = &config.debug {
+    lines.push(format!("// Compiled from {}", debug.file));
+  }
+  if needs.moves {
+    // Moves at the end of the program are never read back.
+    lines.push("#![allow(unused_assignments)]".to_string());
+    lines.push(String::new());
+  }
+  lines.extend([
+    if needs.reads {
+      "use std::io::{self, Read, Write};".to_string()
+    } else {
+      "use std::io::{self, Write};".to_string()
+    },
+    String::new(),
+    "fn main() {".to_string(),
+  ]);
+  let mut body = tape(config, &needs);
+  if needs.reads {
+    body.push((0, "let mut input = io::stdin().lock().bytes();".to_string()));
+  }
+  body.push((
+    0,
+    "let mut out = io::BufWriter::new(io::stdout().lock());".to_string(),
+  ));
+  body.extend(statements(instructions, config));
+  body.push((0, "out.flush().unwrap();".to_string()));
+  let status = match config.exit_cell {
+    None => None,
+    Some(_) if !needs.tape => Some("0"),
+    Some(ExitCell::Current) => Some("tape[p] as i32"),
+    Some(ExitCell::First) => Some("tape[0] as i32"),
+  };
+  if let Some(status) = status {
+    body.push((0, format!("std::process::exit({});", status)));
+  }
+  lines.extend(indent(body));
+  lines.push("}".to_string());
+  lines.push(String::new());
+  Ok(lines.join("\n"))
+}
+
+/// Generates a function `name` taking the input as `&[u8]` and returning
+/// the output as `Vec<u8>`, running `instructions`.
+pub fn produce_function(
+  instructions: &[Inst],
+  config: &Config,
+  name: &str,
+) -> Result<String, String> {
+  check(instructions, config)?;
+  let needs = needs(instructions);
+  let mut lines = vec![
+    "#[allow(unused_assignments, unused_mut, unused_variables)]".to_string(),
+    format!("fn {}(input: &[u8]) -> Vec<u8> {{", name),
+  ];
+  let mut body = vec![(
+    0,
+    if needs.reads {
+      "use std::io::{Read, Write};".to_string()
+    } else {
+      "use std::io::Write;".to_string()
+    },
+  )];
+  body.extend(tape(config, &needs));
+  if needs.reads {
+    body.push((0, "let mut input = input.bytes();".to_string()));
+  }
+  body.push((0, "let mut out = Vec::new();".to_string()));
+  body.extend(statements(instructions, config));
+  body.push((0, "out".to_string()));
+  lines.extend(indent(body));
+  lines.push("}".to_string());
+  lines.push(String::new());
+  Ok(lines.join("\n"))
+}
+
+fn indent(body: Vec<(usize, String)>) -> impl Iterator<Item = String> {
+  body
+    .into_iter()
+    .map(|(depth, line)| format!("{}{}", INDENT.repeat(depth + 1), line))
+}
+
+/// The declarations of the tape and the pointer, if the program has a
+/// tape.
+fn tape(config: &Config, needs: &Needs) -> Vec<(usize, String)> {
+  let kind = if config.wrap { "u8" } else { "i32" };
+  let mut body = Vec::new();
+  if needs.tape {
+    body.push((
+      0,
+      format!(
+        "let {}tape = vec![0{}; {}];",
+        if needs.writes { "mut " } else { "" },
+        kind,
+        config.tape_size
+      ),
+    ));
+    body.push((
+      0,
+      if needs.moves {
+        "let mut p: usize = 0;".to_string()
+      } else {
+        "let p: usize = 0;".to_string()
+      },
+    ));
+  }
+  body
+}
+
+/// The statements running `instructions`, each with its depth of nesting.
+fn statements(instructions: &[Inst], config: &Config) -> Vec<(usize, String)> {
+  let mut body = Vec::new();
+  let mut depth = 0;
+  let mut index = 0;
+  while index < instructions.len() {
+    let mut emit = |line: String| body.push((depth, line));
+    match instructions[index].op {
+      Op::Procedure(_)
+      | Op::Return(_)
+      | Op::Call
+      | Op::Fork
+      | Op::Ebf(_)
+      | Op::Debug
+      | Op::PutNumber
+      | Op::ReadNumber
+      | Op::Random
+      | Op::Tape(_) => {
+        unreachable!("rejected by reject_extensions")
+      }
+      Op::Plus(count) => emit(add(0, count as i64, None, config)),
+      Op::Minus(count) => emit(add(0, -(count as i64), None, config)),
+      Op::Right(count) => {
+        emit(compound("p", count as i64));
+        if config.bounds == Bounds::Error {
+          emit(CHECK.to_string());
+        }
+      }
+      Op::Left(count) => {
+        emit(compound("p", -(count as i64)));
+        if config.bounds == Bounds::Error {
+          emit(CHECK.to_string());
+        }
+      }
+      Op::PutChar(count) => {
+        let byte = if config.wrap {
+          "tape[p]"
+        } else {
+          "tape[p] as u8"
+        };
+        for _ in 0..count {
+          emit(if config.io == Io::Bytes {
+            format!("out.write_all(&[{}]).unwrap();", byte)
+          } else {
+            format!("write!(out, \"{{}}\", {} as char).unwrap();", byte)
+          });
+        }
+      }
+      Op::ReadChar(count) => {
+        for _ in 0..count {
+          read(config).into_iter().for_each(&mut emit);
+        }
+      }
+      Op::JumpIfZero(_) => {
+        emit("while tape[p] != 0 {".to_string());
+        depth += 1;
+      }
+      Op::JumpIfNonZero(_) => {
+        depth -= 1;
+        body.push((depth, "}".to_string()));
+      }
+      Op::SetZero => emit("tape[p] = 0;".to_string()),
+      Op::AddTo { .. } => {
+        // Indexing panics outside the tape, so the offset cells are only
+        // touched when the loop would have run.
+        let targets: Vec<(i32, i32)> = instructions[index..]
+          .iter()
+          .map_while(|inst| match inst.op {
+            Op::AddTo { offset, factor } => Some((offset, factor)),
+            _ => None,
+          })
+          .collect();
+        emit("if tape[p] != 0 {".to_string());
+        emit(format!("{}let n = tape[p];", INDENT));
+        for (offset, factor) in &targets {
+          emit(format!(
+            "{}{}",
+            INDENT,
+            add(*offset, *factor as i64, Some("n"), config)
+          ));
+        }
+        emit("}".to_string());
+        index += targets.len() - 1;
+      }
+      Op::Add { offset, amount } => emit(add(offset, amount as i64, None, config)),
+      Op::Set { offset, value } => emit(format!(
+        "{} = {};",
+        cell(offset),
+        literal(value as i64, config)
+      )),
+      Op::ScanZero { stride } => emit(format!(
+        "while tape[p] != 0 {{ {} }}",
+        compound("p", stride as i64)
+      )),
+      Op::PutConst { value, count } => emit(format!(
+        "out.write_all({}).unwrap();",
+        quote(&output_bytes(&vec![value; count as usize], config.io))
+      )),
+      Op::Print(ref text) => emit(format!(
+        "out.write_all({}).unwrap();",
+        quote(&output_bytes(text.as_bytes(), config.io))
+      )),
+    }
+    index += 1;
+  }
+  body
+}
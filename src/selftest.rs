@@ -0,0 +1,72 @@
+//! The programs `selftest` runs to check an install: a few classic ones,
+//! built into the binary with the output they must print, run through the
+//! interpreter and through every backend whose tools are found.
+
+use super::backend;
+use super::fuzz::{self, Target};
+use super::interpreter::Interpreter;
+use super::{lex_dialect, parse_program, Dialect};
+
+pub struct Sample {
+  pub name: &'static str,
+  pub code: &'static str,
+  pub input: &'static [u8],
+  pub expected: &'static [u8],
+}
+
+pub const SAMPLES: &[Sample] = &[
+  Sample {
+    name: "hello",
+    code: "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.\
+           ------.--------.>>+.>++.",
+    input: b"",
+    expected: b"Hello World!\n",
+  },
+  // Divides each character by 32 and then by 13 to find its letter, which
+  // needs cells that wrap; ends at EOF whether it leaves the cell or stores
+  // -1.
+  Sample {
+    name: "rot13",
+    code: "-,+[-[>>++++[>++++++++<-]<+<-[>+>+>-[>>>]<[[>+<-]>>+>]<<<<<-]]>>>[-]+>--[-[<->+++[-]]]\
+           <[++++++++++++<[>-[>+>>]>[+[<+>-]>+>>]<<<<<-]>>[<+>-]>[-[-<<[-]>>]<<[<<->>-]>>]<<\
+           [<<+>>-]]<[-]<.[-]<-,+]",
+    input: b"Hello, World!\n",
+    expected: b"Uryyb, Jbeyq!\n",
+  },
+  // Ten loops nested in each other, each entered once and printing a digit,
+  // followed at every level by loops that are skipped.
+  Sample {
+    name: "nesting",
+    code: "++++++[>++++++++<-]>>+[<.+>>+[<<.+>>>+[<<<.+>>>>+[<<<<.+>>>>>+[<<<<<.+>>>>>>+[<<<<<<.+\
+           >>>>>>>+[<<<<<<<.+>>>>>>>>+[<<<<<<<<.+>>>>>>>>>+[<<<<<<<<<.+>>>>>>>>>>+[<<<<<<<<<<.+\
+           >>>>>>>>>>-][[][[]]]<-][[][[]]]<-][[][[]]]<-][[][[]]]<-][[][[]]]<-][[][[]]]<-][[][[]]\
+           ]<-][[][[]]]<-][[][[]]]<-][[][[]]]<>>[[[[[[[[[[]]]]]]]]]]++++++++++.",
+    input: b"",
+    expected: b"0123456789\n",
+  },
+];
+
+/// The backends `selftest` tries besides the interpreter: those of the
+/// registry that `fuzz` can run, and the JIT in builds with it. Any whose
+/// tools are not found is skipped when run.
+pub fn targets() -> Vec<Target> {
+  let mut targets: Vec<Target> = backend::REGISTRY
+    .iter()
+    .filter_map(|(names, _)| Target::parse(names[0]).ok())
+    .collect();
+  if cfg!(feature = "jit") {
+    targets.push(Target::Jit);
+  }
+  targets
+}
+
+/// What the interpreter prints for `sample`, running it as parsed.
+pub fn interpret(sample: &Sample) -> fuzz::Outcome {
+  let tokens = lex_dialect(sample.code, Dialect::Brainfuck)?;
+  let program = parse_program(tokens)?;
+  let mut output = Vec::new();
+  Interpreter::new(&program)
+    .run(&mut &sample.input[..], &mut output, None)
+    .map_err(|error| error.to_string())?;
+  Ok(output)
+}
@@ -0,0 +1,86 @@
+//! Routines programs tend to write by hand, in plain Brainfuck, which
+//! `--macros` makes `@print_decimal`, `@read_line` and `@memset` expand
+//! to unless the program defines macros of those names. Each starts and
+//! ends on a cell it documents and clears the scratch cells it uses, all
+//! to the right of it.
+//!
+//! With `--inject-snippets` each routine used is instead linked into the
+//! program once, as a procedure the way `--module` files are, which the
+//! JVM output makes a helper method, and each use calls it: the number of
+//! the procedure goes into the scratch cell `slot` cells right of the
+//! start, which the routine leaves clear at the same distance from where
+//! it ends.
+
+use super::{lex_dialect, parse_program, Dialect, Inst, Span};
+
+#[derive(Debug)]
+pub struct Snippet {
+  pub name: &'static str,
+  pub code: &'static str,
+  /// The offset of the cell a call passes the procedure number in.
+  pub slot: usize,
+}
+
+pub const SNIPPETS: &[Snippet] = &[
+  // Prints the current byte in decimal and leaves it as it was, using the
+  // nine cells after it: two divisions by ten, then the hundreds and the
+  // tens if they are not leading zeros, then the ones.
+  Snippet {
+    name: "print_decimal",
+    code: ">[-]>[-]>[-]>[-]>[-]>[-]>[-]>[-]>[-]<<<<<<<<<[>+>+<<-]>>[<<+>>-]++++++++++<\
+           [->-[>+>>]>[+[-<+>]>+>>]<<<<<]>[-]>>>++++++++++<[->-[>+>>]>[+[-<+>]>+>>]<<<<<]\
+           >[-]>>[>++++++[<++++++++>-]<.[-]<<+>>]<[>>+<<<[-]+>-]<\
+           [->>>>++++++[<++++++++>-]<.[-]<<<]<<>++++++[<++++++++>-]<.[-]<<<",
+    slot: 1,
+  },
+  // Reads characters into the current cell and those after it up to a
+  // newline or the end of input, neither of which is stored, and ends on
+  // the zero after the last one. Input that stores -1 at its end never
+  // ends the line.
+  Snippet {
+    name: "read_line",
+    code: "[-]+[[-],>[-]>[-]>[-]<<<[>>+>+<<<-]>>>[<<<+>>>-]<[----------[>+<[-]]]>[<<+>>-]<<]<[-]",
+    slot: 1,
+  },
+  // Fills as many cells as the current one holds, starting with it, with
+  // the value of the next, and ends on the cell after them. The three
+  // cells after the region are cleared.
+  Snippet {
+    name: "memset",
+    code: "[>>[-]>[-]<<<-[>>>+<<<-]>[<+>>+<-]>>[<<+>>-]<<]>[-]<",
+    slot: 2,
+  },
+];
+
+pub fn lookup(name: &str) -> Option<&'static Snippet> {
+  SNIPPETS.iter().find(|snippet| snippet.name == name)
+}
+
+impl Snippet {
+  /// The code calling the snippet as procedure `number`.
+  pub fn call(&self, number: usize) -> String {
+    format!(
+      "{}[-]{}*[-]{}",
+      ">".repeat(self.slot),
+      "+".repeat(number),
+      "<".repeat(self.slot)
+    )
+  }
+
+  /// The body of the procedure `call` calls, which runs the snippet from
+  /// the cell the call started on. Its instructions have no place in the
+  /// source.
+  pub fn procedure(&self) -> Result<Vec<Inst>, String> {
+    let code = format!(
+      "{}{}{}",
+      "<".repeat(self.slot),
+      self.code,
+      ">".repeat(self.slot)
+    );
+    let mut body = parse_program(lex_dialect(&code, Dialect::Brainfuck)?)?;
+    for inst in &mut body {
+      inst.span = Span::default();
+    }
+    Ok(body)
+  }
+}
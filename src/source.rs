@@ -0,0 +1,52 @@
+//! The contents of a source file: read into memory or, with the `mmap`
+//! feature, mapped from the file, so that sources of tens of megabytes are
+//! lexed in place without a copy. Either way the bytes are lexed as they
+//! are, and only checked to be UTF-8 by what needs the text.
+
+use std::fs::File;
+use std::io::{self, Read};
+
+pub enum Source {
+  Read(Vec<u8>),
+  #[cfg(feature = "mmap")]
+  Mapped(memmap2::Mmap),
+}
+
+impl Source {
+  /// Reads the file at `path`, or maps it when `map` is set and the
+  /// feature is built in.
+  pub fn open(path: &str, map: bool) -> io::Result<Source> {
+    let mut file = File::open(path)?;
+    #[cfg(feature = "mmap")]
+    if map {
+      // Safe as long as nothing truncates the file while the program runs,
+      // which would fault rather than read stale bytes.
+      return Ok(Source::Mapped(unsafe { memmap2::Mmap::map(&file)? }));
+    }
+    #[cfg(not(feature = "mmap"))]
+    if map {
+      eprintln!("warning: built without the `mmap` feature, reading the file instead");
+    }
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+    Ok(Source::Read(bytes))
+  }
+
+  pub fn bytes(&self) -> &[u8] {
+    match self {
+      Source::Read(bytes) => bytes,
+      #[cfg(feature = "mmap")]
+      Source::Mapped(map) => map,
+    }
+  }
+
+  /// The source as text, for what needs more of it than its commands.
+  pub fn text(&self) -> io::Result<&str> {
+    std::str::from_utf8(self.bytes()).map_err(|error| {
+      io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("source is not UTF-8: {}", error),
+      )
+    })
+  }
+}
@@ -0,0 +1,381 @@
+//! Verification types at the branch targets of a method, which class files
+//! from version 50 on must declare in a `StackMapTable`.
+//!
+//! Types are inferred the way the old inference verifier did: by flowing
+//! locals and stack through every path and merging where paths meet. Locals
+//! that disagree become `Top`; stacks must agree exactly.
+
+use std::collections::HashMap;
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Type {
+  Top,
+  Integer,
+  /// A `long`, which only ever passes through the stack.
+  Long,
+  Null,
+  UninitializedThis,
+  /// A class or array type, named as in a `Class` constant.
+  Object(String),
+  /// The result of the `new` at this offset, before its constructor ran.
+  Uninitialized(usize, String),
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Frame {
+  pub locals: Vec<Type>,
+  pub stack: Vec<Type>,
+}
+
+/// One assembled instruction: its offset in the code and its source form.
+pub struct Insn {
+  pub offset: usize,
+  pub mnemonic: String,
+  pub args: Vec<String>,
+}
+
+/// The type of a field or return descriptor.
+fn field_type(descriptor: &str) -> Result<Option<Type>, String> {
+  Ok(match descriptor {
+    "V" => None,
+    "I" | "B" | "C" | "S" | "Z" => Some(Type::Integer),
+    "J" => Some(Type::Long),
+    _ if descriptor.starts_with('[') => Some(Type::Object(descriptor.to_string())),
+    _ => match descriptor
+      .strip_prefix('L')
+      .and_then(|d| d.strip_suffix(';'))
+    {
+      Some(class) => Some(Type::Object(class.to_string())),
+      None => return Err(format!("unsupported type {}", descriptor)),
+    },
+  })
+}
+
+/// Argument types and return type of a method descriptor.
+fn method_types(descriptor: &str) -> Result<(Vec<Type>, Option<Type>), String> {
+  let malformed = || format!("malformed descriptor {}", descriptor);
+  let inner = descriptor.strip_prefix('(').ok_or_else(malformed)?;
+  let close = inner.find(')').ok_or_else(malformed)?;
+  let params = &inner[..close];
+  let mut args = Vec::new();
+  let mut start = 0;
+  while start < params.len() {
+    let rest = &params[start..];
+    let dims = rest.len() - rest.trim_start_matches('[').len();
+    let end = match rest[dims..].chars().next() {
+      Some('L') => dims + rest[dims..].find(';').ok_or_else(malformed)? + 1,
+      Some(_) => dims + 1,
+      None => return Err(malformed()),
+    };
+    args.push(field_type(&rest[..end])?.ok_or_else(malformed)?);
+    start += end;
+  }
+  Ok((args, field_type(&inner[close + 1..])?))
+}
+
+/// Locals on entry to a method of `class` with the given `descriptor`.
+pub fn entry(class: &str, name: &str, is_static: bool, descriptor: &str) -> Result<Frame, String> {
+  let mut locals = Vec::new();
+  if !is_static {
+    locals.push(if name == "<init>" {
+      Type::UninitializedThis
+    } else {
+      Type::Object(class.to_string())
+    });
+  }
+  locals.extend(method_types(descriptor)?.0);
+  Ok(Frame {
+    locals,
+    stack: Vec::new(),
+  })
+}
+
+impl Frame {
+  fn pop(&mut self, insn: &Insn) -> Result<Type, String> {
+    self
+      .stack
+      .pop()
+      .ok_or_else(|| format!("stack underflow at {}", insn.mnemonic))
+  }
+
+  fn pop_n(&mut self, n: usize, insn: &Insn) -> Result<(), String> {
+    for _ in 0..n {
+      self.pop(insn)?;
+    }
+    Ok(())
+  }
+
+  fn local(&self, n: usize) -> Type {
+    self.locals.get(n).cloned().unwrap_or(Type::Top)
+  }
+
+  fn set_local(&mut self, n: usize, value: Type) {
+    if self.locals.len() <= n {
+      self.locals.resize(n + 1, Type::Top);
+    }
+    self.locals[n] = value;
+  }
+
+  /// Applies `insn` to the frame.
+  fn step(&mut self, insn: &Insn, class: &str) -> Result<(), String> {
+    let local = |text: &str| {
+      text
+        .parse::<usize>()
+        .map_err(|_| format!("bad local variable {}", text))
+    };
+    let arg = |n: usize| {
+      insn
+        .args
+        .get(n)
+        .map(String::as_str)
+        .ok_or_else(|| format!("{} is missing an operand", insn.mnemonic))
+    };
+    let mnemonic = insn.mnemonic.as_str();
+    // `iload_3`, `astore_2`, ...
+    let (base, index) = match mnemonic.rsplit_once('_') {
+      Some((base, n)) if n.parse::<usize>().is_ok() => (base, Some(local(n)?)),
+      _ => (mnemonic, None),
+    };
+    match base {
+      "iload" => {
+        index.map_or_else(|| local(arg(0)?), Ok)?;
+        self.stack.push(Type::Integer);
+      }
+      "aload" => {
+        let n = index.map_or_else(|| local(arg(0)?), Ok)?;
+        self.stack.push(self.local(n));
+      }
+      "istore" | "astore" => {
+        let n = index.map_or_else(|| local(arg(0)?), Ok)?;
+        let value = self.pop(insn)?;
+        self.set_local(n, value);
+      }
+      "iinc" | "nop" | "goto" | "return" | "i2b" | "i2c" | "i2s" | "ineg" => (),
+      "aconst_null" => self.stack.push(Type::Null),
+      "bipush" | "sipush" => self.stack.push(Type::Integer),
+      "ldc" | "ldc_w" => self.stack.push(if arg(0)?.starts_with('"') {
+        Type::Object("java/lang/String".to_string())
+      } else {
+        Type::Integer
+      }),
+      "dup" => {
+        let top = self.pop(insn)?;
+        self.stack.extend([top.clone(), top]);
+      }
+      "dup_x1" => {
+        let (a, b) = (self.pop(insn)?, self.pop(insn)?);
+        self.stack.extend([a.clone(), b, a]);
+      }
+      "dup_x2" => {
+        let (a, b, c) = (self.pop(insn)?, self.pop(insn)?, self.pop(insn)?);
+        self.stack.extend([a.clone(), c, b, a]);
+      }
+      "dup2" => {
+        let (a, b) = (self.pop(insn)?, self.pop(insn)?);
+        self.stack.extend([b.clone(), a.clone(), b, a]);
+      }
+      "swap" => {
+        let (a, b) = (self.pop(insn)?, self.pop(insn)?);
+        self.stack.extend([a, b]);
+      }
+      "pop" | "ifeq" | "ifne" | "iflt" | "ifge" | "ifgt" | "ifle" | "ifnull" | "ifnonnull"
+      | "ireturn" | "areturn" | "athrow" | "putstatic" => self.pop_n(1, insn)?,
+      "pop2" | "if_icmpeq" | "if_icmpne" | "if_icmplt" | "if_icmpge" | "if_icmpgt"
+      | "if_icmple" | "putfield" => self.pop_n(2, insn)?,
+      "iastore" | "aastore" | "bastore" | "castore" => self.pop_n(3, insn)?,
+      "iadd" | "isub" | "imul" | "idiv" | "irem" | "ishl" | "ishr" | "iushr" | "iand" | "ior"
+      | "ixor" | "iaload" | "baload" | "caload" => {
+        self.pop_n(2, insn)?;
+        self.stack.push(Type::Integer);
+      }
+      "aaload" => {
+        self.pop(insn)?;
+        let element = match self.pop(insn)? {
+          Type::Object(array) => array
+            .strip_prefix('[')
+            .map(field_type)
+            .transpose()?
+            .flatten(),
+          _ => None,
+        };
+        self.stack.push(element.unwrap_or(Type::Null));
+      }
+      "arraylength" => {
+        self.pop(insn)?;
+        self.stack.push(Type::Integer);
+      }
+      "getstatic" | "getfield" => {
+        if base == "getfield" {
+          self.pop(insn)?;
+        }
+        if let Some(value) = field_type(arg(1)?)? {
+          self.stack.push(value);
+        }
+      }
+      "invokevirtual" | "invokespecial" | "invokenonvirtual" | "invokestatic" => {
+        let signature = arg(0)?;
+        let split = signature
+          .find('(')
+          .ok_or_else(|| format!("method {} has no descriptor", signature))?;
+        let (params, returns) = method_types(&signature[split..])?;
+        self.pop_n(params.len(), insn)?;
+        if base != "invokestatic" {
+          let receiver = self.pop(insn)?;
+          if signature[..split].ends_with("/<init>") {
+            let initialized = match &receiver {
+              Type::Uninitialized(_, owner) => Type::Object(owner.clone()),
+              Type::UninitializedThis => Type::Object(class.to_string()),
+              _ => receiver.clone(),
+            };
+            for slot in self.locals.iter_mut().chain(self.stack.iter_mut()) {
+              if *slot == receiver {
+                *slot = initialized.clone();
+              }
+            }
+          }
+        }
+        self.stack.extend(returns);
+      }
+      "new" => self
+        .stack
+        .push(Type::Uninitialized(insn.offset, arg(0)?.to_string())),
+      "newarray" => {
+        self.pop(insn)?;
+        let element = match arg(0)? {
+          "boolean" => "Z",
+          "char" => "C",
+          "byte" => "B",
+          "short" => "S",
+          "int" => "I",
+          other => return Err(format!("unsupported array type {}", other)),
+        };
+        self.stack.push(Type::Object(format!("[{}", element)));
+      }
+      "anewarray" => {
+        self.pop(insn)?;
+        let element = arg(0)?;
+        self.stack.push(Type::Object(if element.starts_with('[') {
+          format!("[{}", element)
+        } else {
+          format!("[L{};", element)
+        }));
+      }
+      "checkcast" => {
+        self.pop(insn)?;
+        self.stack.push(Type::Object(arg(0)?.to_string()));
+      }
+      _ if base.starts_with("iconst") => self.stack.push(Type::Integer),
+      other => return Err(format!("no verification types known for {}", other)),
+    }
+    Ok(())
+  }
+
+  /// Merges `other` into the frame, returning whether it changed.
+  fn merge(&mut self, other: &Frame) -> Result<bool, String> {
+    if self.stack != other.stack {
+      return Err(format!(
+        "stacks {:?} and {:?} meet at a branch target",
+        self.stack, other.stack
+      ));
+    }
+    let mut changed = false;
+    for (n, slot) in self.locals.iter_mut().enumerate() {
+      if *slot != Type::Top && other.locals.get(n) != Some(slot) {
+        *slot = Type::Top;
+        changed = true;
+      }
+    }
+    Ok(changed)
+  }
+}
+
+fn is_branch(mnemonic: &str) -> bool {
+  mnemonic == "goto" || mnemonic.starts_with("if")
+}
+
+fn ends_flow(mnemonic: &str) -> bool {
+  matches!(
+    mnemonic,
+    "goto" | "return" | "ireturn" | "areturn" | "athrow"
+  )
+}
+
+/// Frames at every offset that needs one: branch targets and the
+/// instruction after an unconditional jump or return.
+pub fn frames(
+  code: &[Insn],
+  labels: &HashMap<String, usize>,
+  entry: Frame,
+  class: &str,
+) -> Result<Vec<(usize, Frame)>, String> {
+  let at: HashMap<usize, usize> = code
+    .iter()
+    .enumerate()
+    .map(|(index, insn)| (insn.offset, index))
+    .collect();
+  let target = |insn: &Insn| -> Result<usize, String> {
+    let label = &insn.args[0];
+    labels
+      .get(label)
+      .and_then(|offset| at.get(offset))
+      .copied()
+      .ok_or_else(|| format!("branch to {} does not land on an instruction", label))
+  };
+  let mut needed = Vec::new();
+  for (index, insn) in code.iter().enumerate() {
+    if is_branch(&insn.mnemonic) {
+      needed.push(target(insn)?);
+    }
+    if ends_flow(&insn.mnemonic) && index + 1 < code.len() {
+      needed.push(index + 1);
+    }
+  }
+  needed.sort_unstable();
+  needed.dedup();
+
+  let mut states: Vec<Option<Frame>> = vec![None; code.len()];
+  let mut work = Vec::new();
+  if !code.is_empty() {
+    states[0] = Some(entry);
+    work.push(0);
+  }
+  while let Some(index) = work.pop() {
+    let insn = &code[index];
+    let mut frame = states[index].clone().unwrap();
+    frame.step(insn, class)?;
+    let mut successors = Vec::new();
+    if is_branch(&insn.mnemonic) {
+      successors.push(target(insn)?);
+    }
+    if !ends_flow(&insn.mnemonic) && index + 1 < code.len() {
+      successors.push(index + 1);
+    }
+    for next in successors {
+      let changed = match &mut states[next] {
+        Some(known) => known.merge(&frame)?,
+        slot @ None => {
+          *slot = Some(frame.clone());
+          true
+        }
+      };
+      if changed {
+        work.push(next);
+      }
+    }
+  }
+  needed
+    .into_iter()
+    .map(|index| {
+      let mut frame = states[index].clone().ok_or_else(|| {
+        format!(
+          "unreachable code at offset {} has no known types",
+          code[index].offset
+        )
+      })?;
+      while frame.locals.last() == Some(&Type::Top) {
+        frame.locals.pop();
+      }
+      Ok((code[index].offset, frame))
+    })
+    .collect()
+}
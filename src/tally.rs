@@ -0,0 +1,84 @@
+//! The figures `run --stats-json` reports about a run, counted by an
+//! observer on the interpreter: how many instructions ran and of which
+//! kind, how far right the pointer went and how many bytes came in and
+//! went out.
+
+use std::io::{self, Read};
+use std::time::Duration;
+
+use super::interpreter::observer::{IoEvent, Observer};
+use super::interpreter::Step;
+use super::json::Json;
+
+#[derive(Default)]
+pub struct Tally {
+  pub steps: u64,
+  /// The highest cell the pointer stood on.
+  pub peak: usize,
+  pub written: u64,
+  /// Steps by the name of their operation, in the order first seen.
+  pub ops: Vec<(String, u64)>,
+}
+
+impl Observer for Tally {
+  fn on_step(&mut self, step: &Step) -> io::Result<()> {
+    self.steps += 1;
+    self.peak = self.peak.max(step.ptr);
+    let debug = format!("{:?}", step.inst.op);
+    let name = debug
+      .split(|c: char| !c.is_alphanumeric())
+      .next()
+      .unwrap_or_default();
+    match self.ops.iter_mut().find(|(seen, _)| seen == name) {
+      Some((_, count)) => *count += 1,
+      None => self.ops.push((name.to_string(), 1)),
+    }
+    Ok(())
+  }
+
+  fn on_io(&mut self, _step: &Step, event: IoEvent) -> io::Result<()> {
+    if let IoEvent::Output(bytes) = event {
+      self.written += bytes.len() as u64;
+    }
+    Ok(())
+  }
+}
+
+impl Tally {
+  /// The report of a run that took `wall` and read `read` bytes.
+  pub fn json(&self, wall: Duration, read: u64) -> Json {
+    let count = |count: u64| Json::Number(count as f64);
+    let mut ops = self.ops.clone();
+    ops.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    Json::object(vec![
+      ("steps", count(self.steps)),
+      ("wall_seconds", Json::Number(wall.as_secs_f64())),
+      ("peak_cell", self.peak.into()),
+      ("bytes_read", count(read)),
+      ("bytes_written", count(self.written)),
+      (
+        "ops",
+        Json::Object(
+          ops
+            .into_iter()
+            .map(|(name, steps)| (name, count(steps)))
+            .collect(),
+        ),
+      ),
+    ])
+  }
+}
+
+/// Input that counts the bytes read through it.
+pub struct Counted<'r> {
+  pub input: &'r mut dyn Read,
+  pub read: u64,
+}
+
+impl Read for Counted<'_> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let read = self.input.read(buf)?;
+    self.read += read as u64;
+    Ok(read)
+  }
+}
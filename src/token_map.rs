@@ -0,0 +1,111 @@
+//! Substitution dialects, which spell the eight commands differently, read
+//! from a mapping file with `--token-map`:
+//!
+//! ```text
+//! # Ook!
+//! plus = "Ook. Ook."
+//! minus = "Ook! Ook!"
+//! ```
+//!
+//! Commands are `plus`, `minus`, `right`, `left`, `output`, `input`, `open`
+//! and `close`. Those left out keep their usual character; anything else in
+//! the source is a comment.
+
+use std::error::Error;
+use std::fs;
+
+use super::Token;
+
+const NAMES: &[(&str, Token, char)] = &[
+  ("plus", Token::Plus, '+'),
+  ("minus", Token::Minus, '-'),
+  ("right", Token::Right, '>'),
+  ("left", Token::Left, '<'),
+  ("output", Token::PutChar, '.'),
+  ("input", Token::ReadChar, ','),
+  ("open", Token::JumpIfZero, '['),
+  ("close", Token::JumpIfNonZero, ']'),
+];
+
+#[derive(Debug)]
+pub struct TokenMap {
+  /// The text of each command, longest first so that a command which
+  /// starts with another one is matched whole.
+  commands: Vec<(String, Token)>,
+}
+
+impl TokenMap {
+  pub fn parse(text: &str) -> Result<TokenMap, String> {
+    let mut commands: Vec<(String, Token)> = Vec::new();
+    // Names and texts mapped so far.
+    let mut mapped: Vec<(&str, &str)> = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+      let malformed = || format!("line {}: expected name = \"text\"", number + 1);
+      let (name, value) = line.split_once('=').ok_or_else(malformed)?;
+      let name = name.trim();
+      let value = value
+        .trim()
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .ok_or_else(malformed)?;
+      let &(_, token, _) = NAMES
+        .iter()
+        .find(|(known, _, _)| *known == name)
+        .ok_or_else(|| format!("line {}: unknown command {}", number + 1, name))?;
+      if value.is_empty() {
+        return Err(format!("line {}: {} is empty", number + 1, name));
+      }
+      if mapped.iter().any(|&(known, _)| known == name) {
+        return Err(format!("line {}: {} is mapped twice", number + 1, name));
+      }
+      if let Some((other, _)) = mapped.iter().find(|&&(_, text)| text == value) {
+        return Err(format!(
+          "line {}: \"{}\" already stands for {}",
+          number + 1,
+          value,
+          other
+        ));
+      }
+      mapped.push((name, value));
+      commands.push((value.to_string(), token));
+    }
+    for &(name, token, c) in NAMES {
+      if mapped.iter().all(|&(known, _)| known != name) {
+        commands.push((c.to_string(), token));
+      }
+    }
+    commands.sort_by_key(|(text, _)| std::cmp::Reverse(text.len()));
+    Ok(TokenMap { commands })
+  }
+
+  pub fn load(path: &str) -> Result<TokenMap, Box<dyn Error>> {
+    let text = fs::read_to_string(path)?;
+    TokenMap::parse(&text).map_err(|e| format!("{}: {}", path, e).into())
+  }
+
+  /// Lexes the bytes of `program`, taking the longest command at each
+  /// position. A command is whole characters, so it never matches inside
+  /// one.
+  pub fn lex(&self, program: &[u8]) -> Vec<(Token, usize)> {
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while pos < program.len() {
+      match self
+        .commands
+        .iter()
+        .find(|(text, _)| program[pos..].starts_with(text.as_bytes()))
+      {
+        Some((text, token)) => {
+          tokens.push((*token, pos));
+          pos += text.len();
+        }
+        None => pos += 1,
+      }
+    }
+    tokens
+  }
+}
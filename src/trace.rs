@@ -0,0 +1,266 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{self, BufWriter};
+use std::ops::Range;
+
+use super::interpreter::Step;
+use super::{Ebf, Op, Tape};
+
+/// Magic bytes and format version at the start of a binary trace file.
+pub const MAGIC: &[u8; 5] = b"BFTR\x01";
+
+#[derive(Default)]
+pub struct TraceOptions {
+  pub to_stderr: bool,
+  pub out_file: Option<String>,
+  pub only_io: bool,
+  pub range: Option<Range<usize>>,
+}
+
+/// Parses a source byte range written as `start:end`.
+pub fn parse_range(text: &str) -> Result<Range<usize>, Box<dyn Error>> {
+  let mut parts = text.splitn(2, ':');
+  let start = parts.next().unwrap_or("").trim().parse::<usize>()?;
+  let end = parts
+    .next()
+    .ok_or("range must look like <start>:<end>")?
+    .trim()
+    .parse::<usize>()?;
+  Ok(start..end)
+}
+
+/// Packs an offset into the low half and a value into the high half of an
+/// argument, both as `i16`.
+fn pack(offset: i32, value: i32) -> usize {
+  (offset as u16 as usize) | (value as u16 as usize) << 16
+}
+
+/// Splits an operation into the opcode and argument stored in binary traces.
+pub fn encode(op: &Op) -> (u8, usize) {
+  match *op {
+    Op::Plus(count) => (0, count as usize),
+    Op::Minus(count) => (1, count as usize),
+    Op::Right(count) => (2, count as usize),
+    Op::Left(count) => (3, count as usize),
+    Op::PutChar(count) => (4, count as usize),
+    Op::ReadChar(count) => (5, count as usize),
+    Op::JumpIfZero(target) => (6, target as usize),
+    Op::JumpIfNonZero(target) => (7, target as usize),
+    Op::SetZero => (8, 0),
+    Op::AddTo { offset, factor } => (9, pack(offset, factor)),
+    Op::Add { offset, amount } => (10, pack(offset, amount)),
+    Op::Set { offset, value } => (11, pack(offset, value)),
+    Op::ScanZero { stride } => (12, stride as u32 as usize),
+    Op::PutConst { value, count } => (13, pack(count as i32, value as i32)),
+    Op::Print(ref text) => (14, text.as_bytes().len()),
+    Op::Procedure(end) => (15, end as usize),
+    Op::Return(start) => (16, start as usize),
+    Op::Call => (17, 0),
+    Op::Fork => (18, 0),
+    Op::Ebf(command) => (19, command as usize),
+    Op::Debug => (20, 0),
+    Op::PutNumber => (21, 0),
+    Op::ReadNumber => (22, 0),
+    Op::Random => (23, 0),
+    Op::Tape(command) => (24, command as usize),
+  }
+}
+
+/// Extended Brainfuck commands by the argument `encode` gives them.
+const EBF: [Ebf; 9] = [
+  Ebf::End,
+  Ebf::Store,
+  Ebf::Retrieve,
+  Ebf::ShiftRight,
+  Ebf::ShiftLeft,
+  Ebf::Not,
+  Ebf::Xor,
+  Ebf::And,
+  Ebf::Or,
+];
+
+/// Multi-tape commands by the argument `encode` gives them.
+const TAPE: [Tape; 2] = [Tape::Next, Tape::Copy];
+
+/// Splits an argument packed by `pack`.
+pub fn unpack(argument: usize) -> (i32, i32) {
+  (
+    argument as u16 as i16 as i32,
+    (argument >> 16) as u16 as i16 as i32,
+  )
+}
+
+/// The operation `encode` split into `opcode` and `argument`, except for
+/// `Print`, whose text a trace does not keep.
+pub fn decode(opcode: u8, argument: usize) -> Option<Op> {
+  let (offset, value) = unpack(argument);
+  Some(match opcode {
+    0 => Op::Plus(argument as u32),
+    1 => Op::Minus(argument as u32),
+    2 => Op::Right(argument as u32),
+    3 => Op::Left(argument as u32),
+    4 => Op::PutChar(argument as u32),
+    5 => Op::ReadChar(argument as u32),
+    6 => Op::JumpIfZero(argument as u32),
+    7 => Op::JumpIfNonZero(argument as u32),
+    8 => Op::SetZero,
+    9 => Op::AddTo {
+      offset,
+      factor: value,
+    },
+    10 => Op::Add {
+      offset,
+      amount: value,
+    },
+    11 => Op::Set { offset, value },
+    12 => Op::ScanZero {
+      stride: argument as u32 as i32,
+    },
+    13 => Op::PutConst {
+      value: value as u8,
+      count: offset as u16 as u32,
+    },
+    15 => Op::Procedure(argument as u32),
+    16 => Op::Return(argument as u32),
+    17 => Op::Call,
+    18 => Op::Fork,
+    19 => Op::Ebf(*EBF.get(argument)?),
+    20 => Op::Debug,
+    21 => Op::PutNumber,
+    22 => Op::ReadNumber,
+    23 => Op::Random,
+    24 => Op::Tape(*TAPE.get(argument)?),
+    _ => return None,
+  })
+}
+
+/// One step read back from a binary trace.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Record {
+  pub index: usize,
+  pub opcode: u8,
+  pub argument: usize,
+  pub ptr: usize,
+  pub before: u8,
+  pub after: u8,
+}
+
+impl Record {
+  /// The operation, as `Debug` prints it.
+  pub fn describe(&self) -> String {
+    match decode(self.opcode, self.argument) {
+      Some(op) => format!("{:?}", op),
+      None if self.opcode == 14 => format!("Print({} bytes)", self.argument),
+      None => format!("opcode {}", self.opcode),
+    }
+  }
+}
+
+/// Size of each record `Tracer` writes.
+const RECORD_SIZE: usize = 15;
+
+/// Reads the binary trace at `path`.
+pub fn load(path: &str) -> Result<Vec<Record>, Box<dyn Error>> {
+  let bytes = std::fs::read(path)?;
+  let records = bytes
+    .strip_prefix(&MAGIC[..])
+    .ok_or_else(|| format!("{} is not a binary trace", path))?;
+  if records.len() % RECORD_SIZE != 0 {
+    return Err(format!("{} ends in the middle of a step", path).into());
+  }
+  let word = |bytes: &[u8]| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+  Ok(
+    records
+      .chunks(RECORD_SIZE)
+      .map(|record| Record {
+        index: word(&record[0..4]),
+        opcode: record[4],
+        argument: word(&record[5..9]),
+        ptr: word(&record[9..13]),
+        before: record[13],
+        after: record[14],
+      })
+      .collect(),
+  )
+}
+
+pub struct Tracer {
+  stderr: bool,
+  out: Option<BufWriter<File>>,
+  only_io: bool,
+  range: Option<Range<usize>>,
+}
+
+impl Tracer {
+  /// Returns `None` when tracing was not requested.
+  pub fn new(options: &TraceOptions) -> io::Result<Option<Tracer>> {
+    if !options.to_stderr && options.out_file.is_none() {
+      return Ok(None);
+    }
+    let out = match &options.out_file {
+      Some(path) => {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(MAGIC)?;
+        Some(out)
+      }
+      None => None,
+    };
+    Ok(Some(Tracer {
+      stderr: options.to_stderr,
+      out,
+      only_io: options.only_io,
+      range: options.range.clone(),
+    }))
+  }
+
+  fn wants(&self, step: &Step) -> bool {
+    if self.only_io
+      && !matches!(
+        step.inst.op,
+        Op::PutChar(_)
+          | Op::PutConst { .. }
+          | Op::Print(_)
+          | Op::ReadChar(_)
+          | Op::PutNumber
+          | Op::ReadNumber
+      )
+    {
+      return false;
+    }
+    match &self.range {
+      Some(range) => {
+        let span = step.inst.span.range();
+        span.start < range.end && range.start < span.end
+      }
+      None => true,
+    }
+  }
+
+  pub fn record(&mut self, step: &Step) -> io::Result<()> {
+    if !self.wants(step) {
+      return Ok(());
+    }
+    if self.stderr {
+      eprintln!(
+        "{:>8} {:<20} ptr={:<6} {:>3} -> {}",
+        step.index,
+        format!("{:?}", step.inst.op),
+        step.ptr,
+        step.before,
+        step.after
+      );
+    }
+    if let Some(out) = self.out.as_mut() {
+      // Fixed little-endian records of `RECORD_SIZE` bytes:
+      // index u32, opcode u8, argument u32, ptr u32, before u8, after u8.
+      let (opcode, argument) = encode(&step.inst.op);
+      out.write_all(&(step.index as u32).to_le_bytes())?;
+      out.write_all(&[opcode])?;
+      out.write_all(&(argument as u32).to_le_bytes())?;
+      out.write_all(&(step.ptr as u32).to_le_bytes())?;
+      out.write_all(&[step.before, step.after])?;
+    }
+    Ok(())
+  }
+}
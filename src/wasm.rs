@@ -0,0 +1,626 @@
+//! WebAssembly modules, as binary `.wasm` or as `.wat` text. The tape is the
+//! start of linear memory, which the module exports as `memory`, and I/O goes
+//! through two functions the host provides:
+//!
+//! ```text
+//! (import "env" "read_byte" (func (result i32)))   ;; -1 at end of input
+//! (import "env" "write_byte" (func (param i32)))
+//! ```
+//!
+//! The exported `main` runs the program and returns the exit value, which is
+//! 0 unless an exit cell is set. Touching memory before the tape or past
+//! the texts that follow it traps, and with `--bounds error` so does any
+//! move off the tape. `write_byte` gets characters in UTF-8, or the cells as they are with
+//! `--io bytes`.
+
+use std::convert::TryFrom;
+
+use super::interpreter::{Bounds, Eof, ExitCell};
+use super::jasmin::Config;
+use super::{output_bytes, reject_extensions, writes_bytes, Inst, Op};
+
+const PAGE_SIZE: usize = 65536;
+
+const READ_BYTE: u32 = 0;
+const WRITE_BYTE: u32 = 1;
+const PRINT: u32 = 2;
+const MAIN: u32 = 3;
+
+/// Locals of `main`: the pointer, as a byte address, and a scratch value.
+const PTR: u32 = 0;
+const SCRATCH: u32 = 1;
+
+/// The instructions the generated code uses.
+#[derive(Copy, Clone, Debug)]
+enum Instr {
+  Const(i32),
+  LocalGet(u32),
+  LocalSet(u32),
+  LocalTee(u32),
+  /// Loads a cell from the address on the stack plus a constant offset.
+  Load(u32),
+  Store(u32),
+  Add,
+  Sub,
+  Mul,
+  And,
+  Eqz,
+  LtS,
+  GeU,
+  Select,
+  Call(u32),
+  Block,
+  Loop,
+  If,
+  End,
+  Br(u32),
+  BrIf(u32),
+  Unreachable,
+}
+
+/// Code for one function, along with how wide the cells it accesses are.
+struct Code {
+  instrs: Vec<Instr>,
+  /// 1 for wrapping byte cells, 4 for `i32` ones.
+  width: i32,
+  /// Whether `.` writes cells as bytes rather than as characters.
+  bytes: bool,
+  /// The bytes of the tape, past which a checked cell traps, under
+  /// `Bounds::Error`.
+  bounds: Option<u32>,
+}
+
+impl Code {
+  fn push(&mut self, instrs: &[Instr]) {
+    self.instrs.extend_from_slice(instrs);
+  }
+
+  /// Pushes the address of the cell at `offset` and returns the constant
+  /// offset the access should use, since those cannot be negative.
+  fn address(&mut self, offset: i32) -> u32 {
+    let bytes = offset * self.width;
+    self.push(&[Instr::LocalGet(PTR)]);
+    if bytes < 0 {
+      self.push(&[Instr::Const(bytes), Instr::Add]);
+      0
+    } else {
+      bytes as u32
+    }
+  }
+
+  /// Traps unless the cell at `offset` is on the tape, when bounds are
+  /// checked. An address below zero compares as a large unsigned one.
+  fn check(&mut self, offset: i32) {
+    use Instr::*;
+    if let Some(end) = self.bounds {
+      self.push(&[
+        LocalGet(PTR),
+        Const(offset * self.width),
+        Add,
+        Const(end as i32),
+        GeU,
+        If,
+        Unreachable,
+        End,
+      ]);
+    }
+  }
+
+  fn load(&mut self, offset: i32) {
+    let at = self.address(offset);
+    self.push(&[Instr::Load(at)]);
+  }
+
+  /// Stores the value `value` pushes into the cell at `offset`.
+  fn store(&mut self, offset: i32, value: impl FnOnce(&mut Code)) {
+    let at = self.address(offset);
+    value(self);
+    self.push(&[Instr::Store(at)]);
+  }
+
+  fn add(&mut self, offset: i32, amount: i32) {
+    self.store(offset, |code| {
+      code.load(offset);
+      code.push(&[Instr::Const(amount), Instr::Add]);
+    });
+  }
+
+  fn move_by(&mut self, cells: isize) {
+    self.push(&[
+      Instr::LocalGet(PTR),
+      Instr::Const(cells as i32 * self.width),
+      Instr::Add,
+      Instr::LocalSet(PTR),
+    ]);
+    self.check(0);
+  }
+
+  /// Calls `write_byte` with the low byte of the value on the stack, or
+  /// with its two bytes in UTF-8 from 128 up when writing characters.
+  fn write(&mut self) {
+    use Instr::*;
+    if self.width != 1 {
+      self.push(&[Const(255), And]);
+    }
+    if self.bytes {
+      self.push(&[Call(WRITE_BYTE)]);
+      return;
+    }
+    self.push(&[
+      LocalSet(SCRATCH),
+      Block,
+      LocalGet(SCRATCH),
+      Const(128),
+      LtS,
+      BrIf(0),
+      Const(0xc2),
+      Const(0xc3),
+      LocalGet(SCRATCH),
+      Const(192),
+      LtS,
+      Select,
+      Call(WRITE_BYTE),
+      LocalGet(SCRATCH),
+      Const(63),
+      And,
+      Const(128),
+      Add,
+      LocalSet(SCRATCH),
+      End,
+      LocalGet(SCRATCH),
+      Call(WRITE_BYTE),
+    ]);
+  }
+
+  fn read(&mut self, eof: Eof) {
+    self.push(&[Instr::Call(READ_BYTE), Instr::LocalSet(SCRATCH)]);
+    self.store(0, |code| match eof {
+      Eof::MinusOne => code.push(&[Instr::LocalGet(SCRATCH)]),
+      Eof::Zero | Eof::Unchanged => {
+        if eof == Eof::Zero {
+          code.push(&[Instr::Const(0)]);
+        } else {
+          code.load(0);
+        }
+        code.push(&[
+          Instr::LocalGet(SCRATCH),
+          Instr::LocalGet(SCRATCH),
+          Instr::Const(0),
+          Instr::LtS,
+          Instr::Select,
+        ]);
+      }
+    });
+  }
+}
+
+/// `print(address, length)`: writes `length` bytes of memory.
+fn print_function() -> Vec<Instr> {
+  use Instr::*;
+  vec![
+    Block,
+    Loop,
+    LocalGet(1),
+    Eqz,
+    BrIf(1),
+    LocalGet(0),
+    Load(0),
+    Call(WRITE_BYTE),
+    LocalGet(0),
+    Const(1),
+    Add,
+    LocalSet(0),
+    LocalGet(1),
+    Const(1),
+    Sub,
+    LocalSet(1),
+    Br(0),
+    End,
+    End,
+    End,
+  ]
+}
+
+/// The generated program, ready to be written in either format.
+struct Module {
+  main: Vec<Instr>,
+  /// Whether cells are single bytes; `print` always reads bytes.
+  byte_cells: bool,
+  texts: Vec<u8>,
+  texts_at: u32,
+  pages: u32,
+}
+
+fn build(instructions: &[Inst], config: &Config) -> Result<Module, String> {
+  reject_extensions(instructions)?;
+  if config.tape_from_args || config.embeddable || config.runtime_args {
+    return Err(
+      "--tape-from-args, --embeddable and --runtime-args only apply to JVM output".to_string(),
+    );
+  }
+  let width = if config.wrap { 1 } else { 4 };
+  let tape_bytes = config
+    .tape_size
+    .checked_mul(width as usize)
+    .filter(|&bytes| u32::try_from(bytes).is_ok())
+    .ok_or_else(|| {
+      format!(
+        "a tape of {} cells does not fit in memory",
+        config.tape_size
+      )
+    })?;
+  let mut code = Code {
+    instrs: Vec::new(),
+    width,
+    bytes: writes_bytes(config.io)?,
+    bounds: (config.bounds == Bounds::Error).then_some(tape_bytes as u32),
+  };
+  let mut texts = Vec::new();
+  let mut index = 0;
+  while index < instructions.len() {
+    match instructions[index].op {
+      Op::Procedure(_)
+      | Op::Return(_)
+      | Op::Call
+      | Op::Fork
+      | Op::Ebf(_)
+      | Op::Debug
+      | Op::PutNumber
+      | Op::ReadNumber
+      | Op::Random
+      | Op::Tape(_) => {
+        unreachable!("rejected by reject_extensions")
+      }
+      Op::Plus(count) => code.add(0, count as i32),
+      Op::Minus(count) => code.add(0, -(count as i32)),
+      Op::Right(count) => code.move_by(count as isize),
+      Op::Left(count) => code.move_by(-(count as isize)),
+      Op::PutChar(count) => {
+        for _ in 0..count {
+          code.load(0);
+          code.write();
+        }
+      }
+      Op::ReadChar(count) => {
+        for _ in 0..count {
+          code.read(config.eof);
+        }
+      }
+      Op::JumpIfZero(_) => {
+        code.push(&[Instr::Block, Instr::Loop]);
+        code.load(0);
+        code.push(&[Instr::Eqz, Instr::BrIf(1)]);
+      }
+      Op::JumpIfNonZero(_) => code.push(&[Instr::Br(0), Instr::End, Instr::End]),
+      Op::SetZero => code.store(0, |code| code.push(&[Instr::Const(0)])),
+      Op::AddTo { .. } => {
+        // The offset cells are only touched when the counter is nonzero,
+        // so the loop never traps where the source would not.
+        let targets: Vec<(i32, i32)> = instructions[index..]
+          .iter()
+          .map_while(|inst| match inst.op {
+            Op::AddTo { offset, factor } => Some((offset, factor)),
+            _ => None,
+          })
+          .collect();
+        code.load(0);
+        code.push(&[Instr::LocalTee(SCRATCH), Instr::If]);
+        for &(offset, factor) in &targets {
+          code.check(offset);
+          code.store(offset, |code| {
+            code.load(offset);
+            code.push(&[
+              Instr::LocalGet(SCRATCH),
+              Instr::Const(factor),
+              Instr::Mul,
+              Instr::Add,
+            ]);
+          });
+        }
+        code.push(&[Instr::End]);
+        index += targets.len() - 1;
+      }
+      Op::Add { offset, amount } => {
+        if offset != 0 {
+          code.check(offset);
+        }
+        code.add(offset, amount)
+      }
+      Op::Set { offset, value } => {
+        if offset != 0 {
+          code.check(offset);
+        }
+        code.store(offset, |code| code.push(&[Instr::Const(value)]))
+      }
+      Op::ScanZero { stride } => {
+        code.push(&[Instr::Block, Instr::Loop]);
+        code.load(0);
+        code.push(&[Instr::Eqz, Instr::BrIf(1)]);
+        code.move_by(stride as isize);
+        code.push(&[Instr::Br(0), Instr::End, Instr::End]);
+      }
+      Op::PutConst { value, count } => {
+        for byte in output_bytes(&vec![value; count as usize], config.io) {
+          code.push(&[Instr::Const(byte as i32), Instr::Call(WRITE_BYTE)]);
+        }
+      }
+      Op::Print(ref text) => {
+        let text = output_bytes(text.as_bytes(), config.io);
+        code.push(&[
+          Instr::Const((tape_bytes + texts.len()) as i32),
+          Instr::Const(text.len() as i32),
+          Instr::Call(PRINT),
+        ]);
+        texts.extend(text);
+      }
+    }
+    index += 1;
+  }
+  match config.exit_cell {
+    None => code.push(&[Instr::Const(0)]),
+    Some(cell) => {
+      if cell == ExitCell::First {
+        code.push(&[Instr::Const(0), Instr::LocalSet(PTR)]);
+      }
+      code.load(0);
+    }
+  }
+  code.push(&[Instr::End]);
+  let bytes = tape_bytes + texts.len();
+  let pages = u32::try_from(bytes.div_ceil(PAGE_SIZE).max(1))
+    .map_err(|_| format!("{} bytes of memory is too large", bytes))?;
+  Ok(Module {
+    main: code.instrs,
+    byte_cells: width == 1,
+    texts,
+    texts_at: tape_bytes as u32,
+    pages,
+  })
+}
+
+fn put_u32(out: &mut Vec<u8>, mut value: u32) {
+  loop {
+    let byte = (value & 0x7f) as u8;
+    value >>= 7;
+    if value == 0 {
+      out.push(byte);
+      return;
+    }
+    out.push(byte | 0x80);
+  }
+}
+
+fn put_i32(out: &mut Vec<u8>, mut value: i32) {
+  loop {
+    let byte = (value & 0x7f) as u8;
+    value >>= 7;
+    if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+      out.push(byte);
+      return;
+    }
+    out.push(byte | 0x80);
+  }
+}
+
+fn put_name(out: &mut Vec<u8>, name: &str) {
+  put_u32(out, name.len() as u32);
+  out.extend_from_slice(name.as_bytes());
+}
+
+/// Appends a section with its id and size.
+fn put_section(out: &mut Vec<u8>, id: u8, body: &[u8]) {
+  out.push(id);
+  put_u32(out, body.len() as u32);
+  out.extend_from_slice(body);
+}
+
+fn encode(out: &mut Vec<u8>, instrs: &[Instr], byte_cells: bool) {
+  for &instr in instrs {
+    match instr {
+      Instr::Const(value) => {
+        out.push(0x41);
+        put_i32(out, value);
+      }
+      Instr::LocalGet(local) => {
+        out.push(0x20);
+        put_u32(out, local);
+      }
+      Instr::LocalSet(local) => {
+        out.push(0x21);
+        put_u32(out, local);
+      }
+      Instr::LocalTee(local) => {
+        out.push(0x22);
+        put_u32(out, local);
+      }
+      Instr::Load(offset) => {
+        // `i32.load8_u` or `i32.load`, with the alignment hint.
+        out.extend_from_slice(if byte_cells { &[0x2d, 0] } else { &[0x28, 2] });
+        put_u32(out, offset);
+      }
+      Instr::Store(offset) => {
+        out.extend_from_slice(if byte_cells { &[0x3a, 0] } else { &[0x36, 2] });
+        put_u32(out, offset);
+      }
+      Instr::Add => out.push(0x6a),
+      Instr::Sub => out.push(0x6b),
+      Instr::Mul => out.push(0x6c),
+      Instr::And => out.push(0x71),
+      Instr::Eqz => out.push(0x45),
+      Instr::LtS => out.push(0x48),
+      Instr::GeU => out.push(0x4f),
+      Instr::Select => out.push(0x1b),
+      Instr::Call(function) => {
+        out.push(0x10);
+        put_u32(out, function);
+      }
+      Instr::Block => out.extend_from_slice(&[0x02, 0x40]),
+      Instr::Loop => out.extend_from_slice(&[0x03, 0x40]),
+      Instr::If => out.extend_from_slice(&[0x04, 0x40]),
+      Instr::End => out.push(0x0b),
+      Instr::Br(depth) => {
+        out.push(0x0c);
+        put_u32(out, depth);
+      }
+      Instr::BrIf(depth) => {
+        out.push(0x0d);
+        put_u32(out, depth);
+      }
+      Instr::Unreachable => out.push(0x00),
+    }
+  }
+}
+
+/// A function body: its `i32` locals beyond the parameters, then its code.
+fn function_body(locals: u32, instrs: &[Instr], byte_cells: bool) -> Vec<u8> {
+  let mut body = Vec::new();
+  if locals == 0 {
+    put_u32(&mut body, 0);
+  } else {
+    put_u32(&mut body, 1);
+    put_u32(&mut body, locals);
+    body.push(0x7f);
+  }
+  encode(&mut body, instrs, byte_cells);
+  let mut sized = Vec::new();
+  put_u32(&mut sized, body.len() as u32);
+  sized.extend(body);
+  sized
+}
+
+/// Generates a binary WebAssembly module for `instructions`.
+pub fn produce_wasm(instructions: &[Inst], config: &Config) -> Result<Vec<u8>, String> {
+  let module = build(instructions, config)?;
+  let mut out = b"\0asm".to_vec();
+  out.extend_from_slice(&1u32.to_le_bytes());
+  // Types: 0 is `() -> i32`, 1 is `(i32) -> ()`, 2 is `(i32, i32) -> ()`.
+  put_section(
+    &mut out,
+    1,
+    &[
+      3, 0x60, 0, 1, 0x7f, 0x60, 1, 0x7f, 0, 0x60, 2, 0x7f, 0x7f, 0,
+    ],
+  );
+  let mut imports = vec![2];
+  for (name, kind) in [("read_byte", 0), ("write_byte", 1)] {
+    put_name(&mut imports, "env");
+    put_name(&mut imports, name);
+    imports.extend_from_slice(&[0x00, kind]);
+  }
+  put_section(&mut out, 2, &imports);
+  put_section(&mut out, 3, &[2, 2, 0]);
+  let mut memory = vec![1, 0];
+  put_u32(&mut memory, module.pages);
+  put_section(&mut out, 5, &memory);
+  let mut exports = vec![2];
+  put_name(&mut exports, "memory");
+  exports.extend_from_slice(&[0x02, 0]);
+  put_name(&mut exports, "main");
+  exports.push(0x00);
+  put_u32(&mut exports, MAIN);
+  put_section(&mut out, 7, &exports);
+  let mut code = vec![2];
+  // `print` only ever reads bytes.
+  code.extend(function_body(0, &print_function(), true));
+  code.extend(function_body(2, &module.main, module.byte_cells));
+  put_section(&mut out, 10, &code);
+  if !module.texts.is_empty() {
+    let mut data = vec![1, 0, 0x41];
+    put_i32(&mut data, module.texts_at as i32);
+    data.push(0x0b);
+    put_u32(&mut data, module.texts.len() as u32);
+    data.extend_from_slice(&module.texts);
+    put_section(&mut out, 11, &data);
+  }
+  Ok(out)
+}
+
+fn text(instr: Instr, byte_cells: bool) -> String {
+  let (load, store) = if byte_cells {
+    ("i32.load8_u", "i32.store8")
+  } else {
+    ("i32.load", "i32.store")
+  };
+  let with_offset = |op: &str, offset: u32| match offset {
+    0 => op.to_string(),
+    _ => format!("{} offset={}", op, offset),
+  };
+  match instr {
+    Instr::Const(value) => format!("i32.const {}", value),
+    Instr::LocalGet(local) => format!("local.get {}", local),
+    Instr::LocalSet(local) => format!("local.set {}", local),
+    Instr::LocalTee(local) => format!("local.tee {}", local),
+    Instr::Load(offset) => with_offset(load, offset),
+    Instr::Store(offset) => with_offset(store, offset),
+    Instr::Add => "i32.add".to_string(),
+    Instr::Sub => "i32.sub".to_string(),
+    Instr::Mul => "i32.mul".to_string(),
+    Instr::And => "i32.and".to_string(),
+    Instr::Eqz => "i32.eqz".to_string(),
+    Instr::LtS => "i32.lt_s".to_string(),
+    Instr::GeU => "i32.ge_u".to_string(),
+    Instr::Select => "select".to_string(),
+    Instr::Call(function) => format!("call {}", function),
+    Instr::Block => "block".to_string(),
+    Instr::Loop => "loop".to_string(),
+    Instr::If => "if".to_string(),
+    Instr::End => "end".to_string(),
+    Instr::Br(depth) => format!("br {}", depth),
+    Instr::BrIf(depth) => format!("br_if {}", depth),
+    Instr::Unreachable => "unreachable".to_string(),
+  }
+}
+
+/// Writes a function's code indented by its nesting. The final `end` of the
+/// body is implied by the closing parenthesis in text.
+fn function_text(lines: &mut Vec<String>, instrs: &[Instr], byte_cells: bool) {
+  let mut depth = 2;
+  for &instr in &instrs[..instrs.len() - 1] {
+    if let Instr::End = instr {
+      depth -= 1;
+    }
+    lines.push(format!("{}{}", "  ".repeat(depth), text(instr, byte_cells)));
+    if let Instr::Block | Instr::Loop | Instr::If = instr {
+      depth += 1;
+    }
+  }
+}
+
+/// Escapes `bytes` for a WebAssembly text string.
+fn quote(bytes: &[u8]) -> String {
+  bytes
+    .iter()
+    .map(|&byte| match byte {
+      b' '..=b'~' if byte != b'"' && byte != b'\\' => (byte as char).to_string(),
+      _ => format!("\\{:02x}", byte),
+    })
+    .collect()
+}
+
+/// Generates the same module as `produce_wasm` in WebAssembly text format.
+pub fn produce_wat(instructions: &[Inst], config: &Config) -> Result<String, String> {
+  let module = build(instructions, config)?;
+  let mut lines = vec![
+    "(module".to_string(),
+    "  (import \"env\" \"read_byte\" (func (result i32)))".to_string(),
+    "  (import \"env\" \"write_byte\" (func (param i32)))".to_string(),
+    "  (func (param i32 i32)".to_string(),
+  ];
+  function_text(&mut lines, &print_function(), true);
+  lines.last_mut().unwrap().push(')');
+  lines.push("  (func (result i32) (local i32 i32)".to_string());
+  function_text(&mut lines, &module.main, module.byte_cells);
+  lines.last_mut().unwrap().push(')');
+  lines.push(format!("  (memory (export \"memory\") {})", module.pages));
+  lines.push(format!("  (export \"main\" (func {}))", MAIN));
+  if !module.texts.is_empty() {
+    lines.push(format!(
+      "  (data (i32.const {}) \"{}\")",
+      module.texts_at,
+      quote(&module.texts)
+    ));
+  }
+  lines.push(")".to_string());
+  lines.push(String::new());
+  Ok(lines.join("\n"))
+}
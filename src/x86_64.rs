@@ -0,0 +1,474 @@
+//! x86-64 assembly for the GNU assembler on Linux, in AT&T or Intel syntax.
+//! The pointer lives in `rbx`, which `putchar` and `getchar` preserve, and
+//! the output links against libc with `cc main.s`. Bounds checks use `rdx`
+//! and `rsi`.
+
+use super::interpreter::{Bounds, Eof, ExitCell};
+use super::jasmin::Config;
+use super::native::{self, Step, Target};
+use super::Text;
+
+/// Which operand order and spelling the assembly uses.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Syntax {
+  Att,
+  Intel,
+}
+
+impl Syntax {
+  pub fn parse(text: &str) -> Result<Syntax, String> {
+    match text {
+      "att" => Ok(Syntax::Att),
+      "intel" => Ok(Syntax::Intel),
+      other => Err(format!("unknown assembly syntax {}", other)),
+    }
+  }
+}
+
+pub struct X86_64 {
+  pub syntax: Syntax,
+}
+
+impl X86_64 {
+  /// Pushes one instruction, given in both syntaxes.
+  fn emit(&self, out: &mut Vec<String>, att: String, intel: String) {
+    out.push(format!(
+      "  {}",
+      match self.syntax {
+        Syntax::Att => att,
+        Syntax::Intel => intel,
+      }
+    ));
+  }
+
+  /// The cell at `offset` from the pointer, as (AT&T, Intel) operands.
+  fn cell(&self, offset: isize, config: &Config) -> (String, String) {
+    let bytes = offset * native::cell_width(config);
+    let size = if config.wrap { "BYTE" } else { "DWORD" };
+    match bytes {
+      0 => ("(%rbx)".to_string(), format!("{} PTR [rbx]", size)),
+      _ if bytes > 0 => (
+        format!("{}(%rbx)", bytes),
+        format!("{} PTR [rbx + {}]", size, bytes),
+      ),
+      _ => (
+        format!("{}(%rbx)", bytes),
+        format!("{} PTR [rbx - {}]", size, bytes.unsigned_abs()),
+      ),
+    }
+  }
+
+  /// The `b` or `l` suffix AT&T syntax sizes cell accesses with.
+  fn suffix(config: &Config) -> char {
+    if config.wrap {
+      'b'
+    } else {
+      'l'
+    }
+  }
+
+  /// `value` as an immediate of the cell's size.
+  fn immediate(value: i32, config: &Config) -> i32 {
+    if config.wrap {
+      value as i8 as i32
+    } else {
+      value
+    }
+  }
+
+  /// Loads the cell at `offset`, zero-extended, into the 32-bit `register`.
+  fn load(&self, out: &mut Vec<String>, offset: isize, register: &str, config: &Config) {
+    let (att, intel) = self.cell(offset, config);
+    if config.wrap {
+      self.emit(
+        out,
+        format!("movzbl {}, %{}", att, register),
+        format!("movzx {}, {}", register, intel),
+      );
+    } else {
+      self.emit(
+        out,
+        format!("movl {}, %{}", att, register),
+        format!("mov {}, {}", register, intel),
+      );
+    }
+  }
+
+  /// Stores the low part of `eax` or `ecx`, named by `register`, to the cell
+  /// at `offset`, through `op` (`mov` or `add`).
+  fn store(&self, out: &mut Vec<String>, op: &str, offset: isize, register: char, config: &Config) {
+    let (att, intel) = self.cell(offset, config);
+    let register = if config.wrap {
+      format!("{}l", register)
+    } else {
+      format!("e{}x", register)
+    };
+    self.emit(
+      out,
+      format!("{}{} %{}, {}", op, X86_64::suffix(config), register, att),
+      format!("{} {}, {}", op, intel, register),
+    );
+  }
+
+  fn call(&self, out: &mut Vec<String>, function: &str) {
+    self.emit(
+      out,
+      format!("call {}@PLT", function),
+      format!("call {}@PLT", function),
+    );
+  }
+
+  /// Jumps to `target` when the current cell is zero.
+  fn jump_if_zero(&self, out: &mut Vec<String>, target: String, config: &Config) {
+    let (att, intel) = self.cell(0, config);
+    self.emit(
+      out,
+      format!("cmp{} $0, {}", X86_64::suffix(config), att),
+      format!("cmp {}, 0", intel),
+    );
+    self.emit(out, format!("je {}", target), format!("je {}", target));
+  }
+
+  fn move_by(&self, out: &mut Vec<String>, cells: isize, config: &Config) {
+    let bytes = cells * native::cell_width(config);
+    self.emit(
+      out,
+      format!("addq ${}, %rbx", bytes),
+      format!("add rbx, {}", bytes),
+    );
+  }
+
+  /// Jumps to `.Loff_tape` unless the cell at `offset` lies within the
+  /// tape.
+  fn check(&self, out: &mut Vec<String>, offset: isize, config: &Config) {
+    let bytes = offset * native::cell_width(config);
+    let end = config.tape_size as isize * native::cell_width(config);
+    self.emit(
+      out,
+      format!("leaq {}(%rbx), %rdx", bytes),
+      format!("lea rdx, [rbx + {}]", bytes),
+    );
+    self.emit(
+      out,
+      "leaq tape(%rip), %rsi".to_string(),
+      "lea rsi, [rip + tape]".to_string(),
+    );
+    self.emit(
+      out,
+      "cmpq %rsi, %rdx".to_string(),
+      "cmp rdx, rsi".to_string(),
+    );
+    self.emit(
+      out,
+      "jb .Loff_tape".to_string(),
+      "jb .Loff_tape".to_string(),
+    );
+    self.emit(
+      out,
+      format!("leaq tape+{}(%rip), %rsi", end),
+      format!("lea rsi, [rip + tape + {}]", end),
+    );
+    self.emit(
+      out,
+      "cmpq %rsi, %rdx".to_string(),
+      "cmp rdx, rsi".to_string(),
+    );
+    self.emit(
+      out,
+      "jae .Loff_tape".to_string(),
+      "jae .Loff_tape".to_string(),
+    );
+  }
+
+  /// `.Loff_tape`, which writes `native::OFF_TAPE` to standard error and
+  /// exits with status 1, flushing what `putchar` buffered.
+  fn off_tape(&self, out: &mut Vec<String>) {
+    out.push(".Loff_tape:".to_string());
+    let lines = [
+      ("movl $2, %edi", "mov edi, 2"),
+      (
+        "leaq .Loff_tape_message(%rip), %rsi",
+        "lea rsi, [rip + .Loff_tape_message]",
+      ),
+    ];
+    for (att, intel) in lines {
+      self.emit(out, att.to_string(), intel.to_string());
+    }
+    self.emit(
+      out,
+      format!("movl ${}, %edx", native::OFF_TAPE.len()),
+      format!("mov edx, {}", native::OFF_TAPE.len()),
+    );
+    self.call(out, "write");
+    self.emit(out, "movl $1, %edi".to_string(), "mov edi, 1".to_string());
+    self.call(out, "exit");
+  }
+
+  /// `print(text, length)`, writing `length` bytes through `putchar`.
+  fn print_helper(&self, out: &mut Vec<String>) {
+    out.push("print:".to_string());
+    let lines = [
+      ("pushq %r12", "push r12"),
+      ("pushq %r13", "push r13"),
+      ("subq $8, %rsp", "sub rsp, 8"),
+      ("movq %rdi, %r12", "mov r12, rdi"),
+      ("movq %rsi, %r13", "mov r13, rsi"),
+    ];
+    for (att, intel) in lines {
+      self.emit(out, att.to_string(), intel.to_string());
+    }
+    out.push(".Lprint:".to_string());
+    let lines = [
+      ("testq %r13, %r13", "test r13, r13"),
+      ("je .Lprinted", "je .Lprinted"),
+      ("movzbl (%r12), %edi", "movzx edi, BYTE PTR [r12]"),
+      ("call putchar@PLT", "call putchar@PLT"),
+      ("incq %r12", "inc r12"),
+      ("decq %r13", "dec r13"),
+      ("jmp .Lprint", "jmp .Lprint"),
+    ];
+    for (att, intel) in lines {
+      self.emit(out, att.to_string(), intel.to_string());
+    }
+    out.push(".Lprinted:".to_string());
+    let lines = [
+      ("addq $8, %rsp", "add rsp, 8"),
+      ("popq %r13", "pop r13"),
+      ("popq %r12", "pop r12"),
+      ("ret", "ret"),
+    ];
+    for (att, intel) in lines {
+      self.emit(out, att.to_string(), intel.to_string());
+    }
+  }
+}
+
+impl Target for X86_64 {
+  fn prologue(&self, out: &mut Vec<String>, config: &Config) {
+    if self.syntax == Syntax::Intel {
+      out.push("  .intel_syntax noprefix".to_string());
+    }
+    if let Some(debug) = &config.debug {
+      out.push(format!("  .file \"{}\"", debug.file));
+    }
+    out.push("  .text".to_string());
+    out.push("  .globl main".to_string());
+    out.push("  .type main, @function".to_string());
+    out.push("main:".to_string());
+    // One push realigns the stack to 16 bytes for the calls.
+    self.emit(out, "pushq %rbx".to_string(), "push rbx".to_string());
+    self.emit(
+      out,
+      "leaq tape(%rip), %rbx".to_string(),
+      "lea rbx, [rip + tape]".to_string(),
+    );
+  }
+
+  fn step(&self, out: &mut Vec<String>, step: &Step, config: &Config) {
+    let suffix = X86_64::suffix(config);
+    match step {
+      Step::Add { offset, amount } => {
+        let (att, intel) = self.cell(*offset, config);
+        let amount = X86_64::immediate(*amount, config);
+        self.emit(
+          out,
+          format!("add{} ${}, {}", suffix, amount, att),
+          format!("add {}, {}", intel, amount),
+        );
+      }
+      Step::Set { offset, value } => {
+        let (att, intel) = self.cell(*offset, config);
+        let value = X86_64::immediate(*value, config);
+        self.emit(
+          out,
+          format!("mov{} ${}, {}", suffix, value, att),
+          format!("mov {}, {}", intel, value),
+        );
+      }
+      Step::Move(cells) => self.move_by(out, *cells, config),
+      Step::Check { offset } => self.check(out, *offset, config),
+      Step::Put => {
+        self.load(out, 0, "edi", config);
+        self.call(out, "putchar");
+      }
+      Step::PutUtf8 { label } => {
+        // `rbx` survives the first call, so the trail byte reloads the cell.
+        let done = format!(".Lput{}", label);
+        self.load(out, 0, "edi", config);
+        if !config.wrap {
+          self.emit(
+            out,
+            "movzbl %dil, %edi".to_string(),
+            "movzx edi, dil".to_string(),
+          );
+        }
+        self.emit(
+          out,
+          "cmpl $128, %edi".to_string(),
+          "cmp edi, 128".to_string(),
+        );
+        self.emit(out, format!("jb {}", done), format!("jb {}", done));
+        self.emit(out, "shrl $6, %edi".to_string(), "shr edi, 6".to_string());
+        self.emit(out, "orl $192, %edi".to_string(), "or edi, 192".to_string());
+        self.call(out, "putchar");
+        self.load(out, 0, "edi", config);
+        self.emit(out, "andl $63, %edi".to_string(), "and edi, 63".to_string());
+        self.emit(out, "orl $128, %edi".to_string(), "or edi, 128".to_string());
+        out.push(format!("{}:", done));
+        self.call(out, "putchar");
+      }
+      Step::PutConst(value) => {
+        self.emit(
+          out,
+          format!("movl ${}, %edi", value),
+          format!("mov edi, {}", value),
+        );
+        self.call(out, "putchar");
+      }
+      Step::Read { label } => {
+        self.call(out, "getchar");
+        let done = format!(".Lread{}", label);
+        match config.eof {
+          Eof::MinusOne => self.store(out, "mov", 0, 'a', config),
+          Eof::Zero => {
+            self.emit(
+              out,
+              "testl %eax, %eax".to_string(),
+              "test eax, eax".to_string(),
+            );
+            self.emit(out, format!("jns {}", done), format!("jns {}", done));
+            self.emit(
+              out,
+              "xorl %eax, %eax".to_string(),
+              "xor eax, eax".to_string(),
+            );
+            out.push(format!("{}:", done));
+            self.store(out, "mov", 0, 'a', config);
+          }
+          Eof::Unchanged => {
+            self.emit(
+              out,
+              "testl %eax, %eax".to_string(),
+              "test eax, eax".to_string(),
+            );
+            self.emit(out, format!("js {}", done), format!("js {}", done));
+            self.store(out, "mov", 0, 'a', config);
+            out.push(format!("{}:", done));
+          }
+        }
+      }
+      Step::LoopStart { label } => {
+        out.push(format!(".Lloop{}:", label));
+        self.jump_if_zero(out, format!(".Lend{}", label), config);
+      }
+      Step::LoopEnd { label } => {
+        self.emit(
+          out,
+          format!("jmp .Lloop{}", label),
+          format!("jmp .Lloop{}", label),
+        );
+        out.push(format!(".Lend{}:", label));
+      }
+      Step::Multiply { label, targets } => {
+        let done = format!(".Lmultiplied{}", label);
+        self.load(out, 0, "eax", config);
+        self.emit(
+          out,
+          "testl %eax, %eax".to_string(),
+          "test eax, eax".to_string(),
+        );
+        self.emit(out, format!("je {}", done), format!("je {}", done));
+        for (offset, factor) in targets {
+          if config.bounds == Bounds::Error {
+            self.check(out, *offset, config);
+          }
+          self.emit(
+            out,
+            format!("imull ${}, %eax, %ecx", factor),
+            format!("imul ecx, eax, {}", factor),
+          );
+          self.store(out, "add", *offset, 'c', config);
+        }
+        out.push(format!("{}:", done));
+      }
+      Step::ScanZero { label, stride } => {
+        out.push(format!(".Lscan{}:", label));
+        self.jump_if_zero(out, format!(".Lscanned{}", label), config);
+        self.move_by(out, *stride, config);
+        if config.bounds == Bounds::Error {
+          self.check(out, 0, config);
+        }
+        self.emit(
+          out,
+          format!("jmp .Lscan{}", label),
+          format!("jmp .Lscan{}", label),
+        );
+        out.push(format!(".Lscanned{}:", label));
+      }
+      Step::Print { text, length } => {
+        self.emit(
+          out,
+          format!("leaq .Ltext{}(%rip), %rdi", text),
+          format!("lea rdi, [rip + .Ltext{}]", text),
+        );
+        self.emit(
+          out,
+          format!("movl ${}, %esi", length),
+          format!("mov esi, {}", length),
+        );
+        self.emit(out, "call print".to_string(), "call print".to_string());
+      }
+    }
+  }
+
+  fn epilogue(&self, out: &mut Vec<String>, config: &Config) {
+    match config.exit_cell {
+      None => self.emit(
+        out,
+        "xorl %eax, %eax".to_string(),
+        "xor eax, eax".to_string(),
+      ),
+      Some(ExitCell::Current) => self.load(out, 0, "eax", config),
+      Some(ExitCell::First) => {
+        self.emit(
+          out,
+          "leaq tape(%rip), %rbx".to_string(),
+          "lea rbx, [rip + tape]".to_string(),
+        );
+        self.load(out, 0, "eax", config);
+      }
+    }
+    self.emit(out, "popq %rbx".to_string(), "pop rbx".to_string());
+    self.emit(out, "ret".to_string(), "ret".to_string());
+  }
+
+  fn data(&self, out: &mut Vec<String>, texts: &[Text], config: &Config) {
+    let checked = config.bounds == Bounds::Error;
+    if !texts.is_empty() {
+      self.print_helper(out);
+    }
+    if checked {
+      self.off_tape(out);
+    }
+    if !texts.is_empty() || checked {
+      out.push("  .section .rodata".to_string());
+    }
+    for (index, text) in texts.iter().enumerate() {
+      out.push(format!(".Ltext{}:", index));
+      out.push(format!("  .ascii \"{}\"", native::quote(text.as_bytes())));
+    }
+    if checked {
+      out.push(".Loff_tape_message:".to_string());
+      out.push(format!(
+        "  .ascii \"{}\"",
+        native::quote(native::OFF_TAPE.as_bytes())
+      ));
+    }
+    out.push("  .bss".to_string());
+    out.push(format!(
+      "  .lcomm tape, {}",
+      config.tape_size as isize * native::cell_width(config)
+    ));
+    out.push("  .section .note.GNU-stack,\"\",@progbits".to_string());
+  }
+}